@@ -1,80 +1,203 @@
-use std::io::{Read, Write};
+use std::io::Read;
 
 use colored::Colorize;
 
 use crate::cpu;
-use crate::timer;
+use crate::elf;
+use crate::fdt;
+use crate::halt;
+use crate::sbi;
+use crate::clint;
+use crate::log;
 
 pub struct BootloaderInfo {
-    pub dtb: String,
     pub kernelimg: String,
+    pub kernel_addr: Option<u32>, // where to load a raw image, the start of RAM if unset; ELF files say where they go
+    pub dtb: Option<String>, // a prebuilt DTB to pass instead of the generated one
+    pub dump_dtb: Option<String>, // where to save the DTB handed to the kernel
+    pub bootargs: Option<String>,
+    pub initrd: Option<String>,
+    pub initrd_addr: Option<u32>, // where to load the initrd, picked automatically if unset
 }
 
 impl BootloaderInfo {
-    pub fn from(dtb: String, kernelimg: String) -> BootloaderInfo {
+    pub fn from(kernelimg: String) -> BootloaderInfo {
         BootloaderInfo {
-            dtb,
             kernelimg,
+            kernel_addr: None,
+            dtb: None,
+            dump_dtb: None,
+            bootargs: None,
+            initrd: None,
+            initrd_addr: None,
         }
     }
 }
 
-fn read_into_buffer(filename: &String) -> Vec<u8> {
-    let mut f = std::fs::File::open(filename).expect("file not found");
-    let metadata: std::fs::Metadata = std::fs::metadata(filename).expect("unable to read file metadata");
-    let mut buffer: Vec<u8> = vec![0; metadata.len() as usize];
-    f.read(&mut buffer).expect("buffer overflow");
-    return buffer;
+fn read_into_buffer(filename: &String) -> Result<Vec<u8>, halt::EmuError> {
+    let mut buffer: Vec<u8> = Vec::new();
+    let read: std::io::Result<usize> = std::fs::File::open(filename).and_then(|mut f| f.read_to_end(&mut buffer));
+    if let Err(e) = read {
+        return Err(halt::EmuError::Io(format!("unable to read {}", filename), e));
+    }
+    return Ok(buffer);
+}
+
+/// Copies `buffer` into guest RAM, refusing images that don't fit.
+fn write_to_ram(cpu: &mut cpu::RiscV32, buffer: &[u8], start_addr: u32) -> Result<(), halt::EmuError> {
+    if cpu.bus.ram.load(start_addr, buffer).is_none() {
+        return Err(halt::EmuError::BadImage(format!("image of {} bytes at 0x{:08X} doesn't fit in RAM", buffer.len(), start_addr)));
+    }
+    return Ok(());
 }
 
-fn write_to_ram(ram: &mut Vec<u8>, buffer: &Vec<u8>, start_addr: usize) {
-    unsafe {
-        ram[start_addr..(start_addr + buffer.len())].copy_from_slice(
-            std::slice::from_raw_parts(
-                buffer.as_ptr(),
-                buffer.len() as usize
-            )
-        );
+/// Parses an ELF kernel and checks it was built for the extensions we have.
+fn parse_elf(cpu: &cpu::RiscV32, filename: &String, buffer: &[u8]) -> Result<elf::Elf, halt::EmuError> {
+    let image: elf::Elf = match elf::parse(buffer) {
+        Ok(image) => image,
+        Err(e) => return Err(halt::EmuError::BadImage(format!("{}: {}", filename, e))),
+    };
+    if let Err(e) = image.validate(cpu.regs.csr.misa) {
+        return Err(halt::EmuError::BadImage(format!("{}: {}", filename, e)));
     }
+    return Ok(image);
 }
 
-pub fn rvll(cpu: &mut cpu::RiscV32, blinfo: BootloaderInfo) {
-    println!("{} -- RISC-V Linux Loader for MARV32IMA, v0.1 --", "[rvll]".purple());
-    let len: usize = cpu.mem.ram.len();
-    let mut start_addr: usize;
-    let mut end_addr: usize;
-    {
-        let buffer = read_into_buffer(&blinfo.dtb);
-        start_addr = (len - buffer.len()) - 0x1000;
-        end_addr = len - 0x1000;
-        print!("{} loading devicetree blob at 0x{:08X}->0x{:08X}...", "[rvll]".purple(), start_addr, end_addr);
-        std::io::stdout().flush().unwrap();
-        write_to_ram(&mut cpu.mem.ram, &buffer, start_addr);
+/// Loads the PT_LOAD segments of an ELF executable at their physical addresses and returns its entry point.
+fn load_elf(cpu: &mut cpu::RiscV32, image: elf::Elf) -> Result<u32, halt::EmuError> {
+    for segment in image.segments.iter() {
+        let bss: u32 = segment.memsz - segment.data.len() as u32;
+        log::log!(Bootloader, Info, "{} loading segment at 0x{:08X}->0x{:08X} ({} bytes of BSS)...", "[rvll]".purple(), segment.paddr, segment.paddr.wrapping_add(segment.memsz - 1), bss);
+        write_to_ram(cpu, &segment.data, segment.paddr)?;
+        write_to_ram(cpu, &vec![0u8; bss as usize], segment.paddr.wrapping_add(segment.data.len() as u32))?;
+        log::logln!(Bootloader, Info, "{}", "done".green());
     }
-    println!("{}", "done".green());
-    print!("{} loading hartid into x10 (a0)...", "[rvll]".purple());
+    log::logln!(Bootloader, Info, "{} {} symbols loaded", "[rvll]".purple(), image.symbols.len().to_string().blue());
+    cpu.symbols = image.symbols;
+    return Ok(image.entry);
+}
+
+/// Makes sure every region lies in RAM and none of them overlap, before anything gets written.
+fn check_regions(cpu: &cpu::RiscV32, regions: &[(&str, u32, u32)]) -> Result<(), halt::EmuError> {
+    for (i, (name, start, size)) in regions.iter().enumerate() {
+        if !cpu.bus.ram.contains(*start, *size) {
+            return Err(halt::EmuError::BadImage(format!("{} at 0x{:08X} ({} bytes) doesn't fit in RAM at 0x{:08X}->0x{:08X}", name, start, size, cpu.bus.ram.base(), cpu.bus.ram.base().wrapping_add(cpu.bus.ram.size() - 1))));
+        }
+        for (other, other_start, other_size) in regions[..i].iter() {
+            if *size != 0 && *other_size != 0 && *start < other_start.wrapping_add(*other_size) && *other_start < start.wrapping_add(*size) {
+                return Err(halt::EmuError::BadImage(format!("{} at 0x{:08X} overlaps {} at 0x{:08X}", name, start, other, other_start)));
+            }
+        }
+    }
+    return Ok(());
+}
+
+/// Loads the kernel, initrd and devicetree and points the hart at the entry point, failing before the guest runs.
+pub fn rvll(cpu: &mut cpu::RiscV32, blinfo: BootloaderInfo) -> Result<(), halt::EmuError> {
+    log::logln!(Bootloader, Info, "{} -- RISC-V Linux Loader for MARV32IMA, v0.1 --", "[rvll]".purple());
+    let (ram_base, ram_size): (u32, u32) = (cpu.bus.ram.base(), cpu.bus.ram.size());
+    let ram_end: u32 = ram_base.wrapping_add(ram_size); // 0 if RAM reaches the top of the address space
+    let mut regions: Vec<(&str, u32, u32)> = Vec::new(); // name, start, size
+
+    let kernel: Vec<u8> = read_into_buffer(&blinfo.kernelimg)?;
+    let kernel_addr: u32 = blinfo.kernel_addr.unwrap_or(ram_base);
+    let image: Option<elf::Elf> = if elf::is_elf(&kernel) { Some(parse_elf(cpu, &blinfo.kernelimg, &kernel)?) } else { None };
+    match &image {
+        Some(image) => {
+            for segment in image.segments.iter() {
+                regions.push(("kernel segment", segment.paddr, segment.memsz));
+            }
+        },
+        None => regions.push(("kernel image", kernel_addr, kernel.len() as u32)),
+    }
+
+    let initrd: Option<(u32, Vec<u8>)> = match &blinfo.initrd {
+        Some(path) => {
+            let buffer: Vec<u8> = read_into_buffer(path)?;
+            let start_addr: u32 = match blinfo.initrd_addr {
+                Some(addr) => addr,
+                None => ram_base.wrapping_add((ram_size / 2).min(256 << 20)) & !0xFFF, // same spot as QEMU, well past the kernel's BSS
+            };
+            Some((start_addr, buffer))
+        },
+        None => None,
+    };
+    if let Some((start_addr, buffer)) = &initrd {
+        regions.push(("initrd", *start_addr, buffer.len() as u32));
+    }
+
+    let chosen: fdt::Chosen = fdt::Chosen {
+        bootargs: blinfo.bootargs.clone(),
+        initrd: initrd.as_ref().map(|(start_addr, buffer)| (*start_addr, start_addr.wrapping_add(buffer.len() as u32))),
+    };
+    let dtb: Vec<u8> = match &blinfo.dtb {
+        Some(path) => {
+            log::log!(Bootloader, Info, "{} patching /chosen into devicetree blob {}...", "[rvll]".purple(), path);
+            let buffer: Vec<u8> = match fdt::patch_chosen(&read_into_buffer(path)?, &chosen) {
+                Ok(buffer) => buffer,
+                Err(e) => return Err(halt::EmuError::BadImage(format!("{}: {}", path, e))),
+            };
+            log::logln!(Bootloader, Info, "{}", "done".green());
+            buffer
+        },
+        None => {
+            log::log!(Bootloader, Info, "{} generating devicetree...", "[rvll]".purple());
+            let buffer: Vec<u8> = fdt::generate(cpu, &chosen);
+            log::logln!(Bootloader, Info, "{}, {} bytes", "done".green(), buffer.len().to_string().blue());
+            buffer
+        },
+    };
+    if let Some(path) = &blinfo.dump_dtb {
+        log::log!(Bootloader, Info, "{} dumping devicetree blob to {}...", "[rvll]".purple(), path);
+        if let Err(e) = std::fs::write(path, &dtb) {
+            return Err(halt::EmuError::Io(format!("unable to write {}", path), e));
+        }
+        log::logln!(Bootloader, Info, "{}", "done".green());
+    }
+    let dtb_addr: u32 = ram_end.wrapping_sub(0x1000).wrapping_sub(dtb.len() as u32) & !0x7; // the FDT must be 8-byte aligned
+    regions.push(("devicetree blob", dtb_addr, dtb.len() as u32));
+
+    log::log!(Bootloader, Info, "{} checking memory layout...", "[rvll]".purple());
+    check_regions(cpu, &regions)?;
+    log::logln!(Bootloader, Info, "{}", "done".green());
+
+    log::log!(Bootloader, Info, "{} loading devicetree blob at 0x{:08X}->0x{:08X}...", "[rvll]".purple(), dtb_addr, dtb_addr.wrapping_add(dtb.len() as u32));
+    write_to_ram(cpu, &dtb, dtb_addr)?;
+    log::logln!(Bootloader, Info, "{}", "done".green());
+    log::log!(Bootloader, Info, "{} loading hartid into x10 (a0)...", "[rvll]".purple());
     cpu.regs.x[10] = 0;
-    println!("{}", "done".green());
-    print!("{} loading devicetree blob address (0x{:08X}) into x11 (a1)...", "[rvll]".purple(), start_addr);
-    cpu.regs.x[11] = start_addr as u32;
-    println!("{}", "done".green());
-    print!("{} resetting timer...", "[rvll]".purple());
-    cpu.mem.write_double_word(timer::CLINT_MTIMECMP, 0xFFFFFFFF_FFFFFFFF);
-    let data: u64 = cpu.mem.read_double_word(timer::CLINT_MTIMECMP) as u64;
-    assert_eq!(data, 0xFFFFFFFF_FFFFFFFF);
-    println!("{}", "done".green());
-    {
-        let buffer: Vec<u8> = read_into_buffer(&blinfo.kernelimg);
-        start_addr = 0x80000000;
-        end_addr = start_addr + buffer.len();
-        print!("{} loading kernel image at 0x{:08X}->0x{:08X}...", "[rvll]".purple(), start_addr, end_addr);
-        std::io::stdout().flush().unwrap();
-        write_to_ram(&mut cpu.mem.ram, &buffer, start_addr);
+    log::logln!(Bootloader, Info, "{}", "done".green());
+    log::log!(Bootloader, Info, "{} loading devicetree blob address (0x{:08X}) into x11 (a1)...", "[rvll]".purple(), dtb_addr);
+    cpu.regs.x[11] = dtb_addr;
+    log::logln!(Bootloader, Info, "{}", "done".green());
+    log::log!(Bootloader, Info, "{} resetting timer...", "[rvll]".purple());
+    cpu.bus.write(clint::CLINT_BASE + clint::CLINT_MTIMECMP, 8, 0xFFFFFFFF_FFFFFFFF);
+    let data: Option<u64> = cpu.bus.read(clint::CLINT_BASE + clint::CLINT_MTIMECMP, 8);
+    assert_eq!(data, Some(0xFFFFFFFF_FFFFFFFF));
+    log::logln!(Bootloader, Info, "{}", "done".green());
+    if let Some((start_addr, buffer)) = &initrd {
+        log::log!(Bootloader, Info, "{} loading initrd at 0x{:08X}->0x{:08X}...", "[rvll]".purple(), start_addr, start_addr.wrapping_add(buffer.len() as u32));
+        write_to_ram(cpu, buffer, *start_addr)?;
+        log::logln!(Bootloader, Info, "{}", "done".green());
+    }
+    let start_addr: u32 = match image {
+        Some(image) => {
+            log::logln!(Bootloader, Info, "{} loading ELF kernel image {}...", "[rvll]".purple(), blinfo.kernelimg);
+            load_elf(cpu, image)?
+        },
+        None => {
+            log::log!(Bootloader, Info, "{} loading kernel image at 0x{:08X}->0x{:08X}...", "[rvll]".purple(), kernel_addr, kernel_addr.wrapping_add(kernel.len() as u32));
+            write_to_ram(cpu, &kernel, kernel_addr)?;
+            log::logln!(Bootloader, Info, "{}", "done".green());
+            kernel_addr
+        },
+    };
+    log::log!(Bootloader, Info, "{} setting PC to 0x{:08X}...", "[rvll]".purple(), start_addr);
+    cpu.regs.pc = start_addr;
+    log::logln!(Bootloader, Info, "{}", "done".green());
+    if cpu.sbi {
+        sbi::boot(cpu);
     }
-    println!("{}", "done".green());
-    print!("{} setting PC to 0x{:08X}...", "[rvll]".purple(), start_addr);
-    cpu.regs.pc = start_addr as u32;
-    println!("{}", "done".green());
-    println!("{} starting execution of kernel image...", "[rvll]".purple());
-    return;
+    log::logln!(Bootloader, Info, "{} starting execution of kernel image...", "[rvll]".purple());
+    return Ok(());
 }
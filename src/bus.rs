@@ -0,0 +1,120 @@
+use crate::memory::RV32Memory;
+
+/// A memory-mapped peripheral. Offsets are relative to the device's base address and sizes are 1, 2, 4 or 8 bytes;
+/// returning `None` from an access reports it as unsupported, which the hart turns into an access fault.
+pub trait Device {
+    fn read(&mut self, offset: u32, size: u32) -> Option<u64>;
+    fn write(&mut self, offset: u32, size: u32, data: u64) -> Option<()>;
+    fn reset(&mut self) {}
+    /// Advances the device by `ticks` steps of the emulated clock.
+    fn tick(&mut self, _ticks: u32) {}
+    /// Level of the device's interrupt output, routed to the line it was attached with.
+    fn irq(&self) -> bool {
+        return false;
+    }
+    /// Receives every interrupt line on the bus, one bit per line; only interrupt controllers care.
+    fn set_irq_lines(&mut self, _lines: u32) {}
+    /// Interrupt bits the device drives straight into the hart's `mip`.
+    fn mip(&self) -> u32 {
+        return 0;
+    }
+    /// Hands over a host I/O error the device ran into since it was last asked; the run ends on it.
+    fn take_error(&mut self) -> Option<std::io::Error> {
+        return None;
+    }
+}
+
+struct Mapping {
+    name: &'static str,
+    base: u32,
+    size: u32,
+    irq: Option<u32>,
+    device: Box<dyn Device>,
+}
+
+/// Routes physical accesses to RAM or to the device mapped at that address; anything else is unmapped.
+pub struct Bus {
+    pub ram: RV32Memory,
+    devices: Vec<Mapping>,
+}
+
+impl Bus {
+    pub fn new(ram: RV32Memory) -> Bus {
+        return Bus {
+            ram: ram,
+            devices: Vec::new(),
+        };
+    }
+    /// Maps `device` over `[base, base + size)`, optionally driving interrupt line `irq` of the bus.
+    pub fn attach(&mut self, name: &'static str, base: u32, size: u32, irq: Option<u32>, device: Box<dyn Device>) {
+        if base <= self.ram.base().wrapping_add(self.ram.size() - 1) && self.ram.base() <= base.wrapping_add(size - 1) {
+            panic!("{} at 0x{:08X} overlaps RAM at 0x{:08X}", name, base, self.ram.base());
+        }
+        for mapping in self.devices.iter() {
+            if base <= mapping.base.wrapping_add(mapping.size - 1) && mapping.base <= base.wrapping_add(size - 1) {
+                panic!("{} at 0x{:08X} overlaps {} at 0x{:08X}", name, base, mapping.name, mapping.base);
+            }
+        }
+        self.devices.push(Mapping {
+            name: name,
+            base: base,
+            size: size,
+            irq: irq,
+            device: device,
+        });
+    }
+    fn find(&mut self, paddr: u32, size: u32) -> Option<&mut Mapping> {
+        return self.devices.iter_mut().find(|m| paddr >= m.base && paddr - m.base <= m.size - size);
+    }
+    fn is_device(&self, paddr: u32) -> bool {
+        return self.devices.iter().any(|m| paddr >= m.base && paddr - m.base < m.size);
+    }
+    pub fn read(&mut self, paddr: u32, size: u32) -> Option<u64> {
+        if let Some(mapping) = self.find(paddr, size) {
+            return mapping.device.read(paddr - mapping.base, size);
+        }
+        if self.is_device(paddr) || self.is_device(paddr.wrapping_add(size - 1)) {
+            return None; // straddles the edge of a device
+        }
+        return self.ram.read(paddr, size); // None if it's unmapped
+    }
+    pub fn write(&mut self, paddr: u32, size: u32, data: u64) -> Option<()> {
+        if let Some(mapping) = self.find(paddr, size) {
+            return mapping.device.write(paddr - mapping.base, size, data);
+        }
+        if self.is_device(paddr) || self.is_device(paddr.wrapping_add(size - 1)) {
+            return None;
+        }
+        return self.ram.write(paddr, size, data);
+    }
+    pub fn reset(&mut self) {
+        for mapping in self.devices.iter_mut() {
+            mapping.device.reset();
+        }
+    }
+    /// Ticks every device `ticks` times, propagates their interrupt lines and returns the `mip` bits they drive.
+    pub fn tick(&mut self, ticks: u32) -> u32 {
+        let mut lines: u32 = 0;
+        for mapping in self.devices.iter_mut() {
+            mapping.device.tick(ticks);
+            if let Some(irq) = mapping.irq {
+                if mapping.device.irq() {
+                    lines |= 1 << irq;
+                }
+            }
+        }
+        let mut mip: u32 = 0;
+        for mapping in self.devices.iter_mut() {
+            mapping.device.set_irq_lines(lines);
+            mip |= mapping.device.mip();
+        }
+        return mip;
+    }
+    /// First pending host I/O error of any device, with the device's name.
+    pub fn take_error(&mut self) -> Option<(&'static str, std::io::Error)> {
+        return self.devices.iter_mut().find_map(|m| m.device.take_error().map(|e| (m.name, e)));
+    }
+    pub fn devices(&self) -> impl Iterator<Item = (&'static str, u32, u32)> + '_ {
+        return self.devices.iter().map(|m| (m.name, m.base, m.size));
+    }
+}
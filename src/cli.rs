@@ -0,0 +1,266 @@
+use crate::bootloader;
+use crate::clint;
+use crate::cpu;
+use crate::disasm;
+use crate::gdb;
+use crate::htif;
+use crate::io;
+use crate::log;
+use crate::plic;
+use crate::uart;
+
+pub const USAGE: &str = "\
+usage: marv [options] <kernel>
+
+  <kernel>                 ELF executable or raw image to boot
+  --load-addr <addr>       where to load a raw image (default: start of RAM)
+  --dtb <file>             pass this devicetree blob instead of generating one
+  --dump-dtb <file>        save the devicetree blob handed to the kernel
+  --initrd <file>          load an initramfs image
+  --initrd-addr <addr>     where to load the initrd (default: halfway into RAM, at most 256M in)
+  --append <args>          kernel command line (default: \"console=ttyS0,115200 earlycon=sbi\")
+  --ram <size>             RAM size, with an optional K, M or G suffix (default: 128M)
+  --ram-base <addr>        physical address RAM starts at (default: 0x80000000)
+  --harts <n>              number of harts, only 1 is supported
+  --isa <extensions>       enabled extensions, e.g. rv32imac (default: rv32imafdc)
+  --no-sbi                 start the image in M-mode without the built-in SBI
+  --serial <backend>       stdio, null or file:<path> (default: stdio)
+  --timebase <hz>          mtime frequency (default: 10000000)
+  --host-time              drive mtime from the host clock instead of counting instructions
+  --tlb-entries <n>        entries in the direct-mapped TLB (default: 64)
+  --tlb-split              separate instruction and data TLBs of that size instead of one
+  --log-level <filter>     off, error, warn, info, debug or trace (default: info), for everything
+                           or per category as in warn,decode=debug; categories are cpu, decode,
+                           trap, mmu, uart, timer, bootloader, sbi, gdb and htif
+  --numeric-regs           spell registers x10 and f10 in traces rather than a0 and fa0
+  --gdb <endpoint>         wait for GDB on [host:]port or unix:<path> and run under its control;
+                           the limits below only apply once it detaches
+  --max-instructions <n>   stop after executing n instructions
+  --timeout <seconds>      stop after this much host time
+  --stop-on <what>         ebreak, or trap for any exception, instead of running the guest's handler
+  --riscv-test             run a riscv-tests ISA test in M-mode and print whether it passed, as
+                           reported through its tohost symbol
+  --signature <file>       run a riscv-arch-test test in M-mode and dump the memory between its
+                           begin_signature and end_signature symbols to file, one word per line
+  --tohost <addr>          HTIF mailbox addresses, instead of the ELF's tohost and fromhost
+  --fromhost <addr>        symbols
+  --htif-sandbox <dir>     directory the guest may open files in through HTIF syscalls
+  -h, --help               show this help
+  -V, --version            show the version
+
+exit status: the guest's own when it shuts down, 124 when a limit is hit, 128 + signal when
+it stops on a trap (SIGILL, SIGBUS, SIGSEGV, SIGSYS or SIGTRAP) or the debugger kills it
+(SIGKILL), 1 if the emulator fails and 2 on bad usage
+";
+
+const DEFAULT_BOOTARGS: &str = "console=ttyS0,115200 earlycon=sbi";
+
+pub struct Options {
+    pub config: cpu::MachineConfig,
+    pub boot: bootloader::BootloaderInfo,
+    pub sbi: bool,
+    pub log_filter: Vec<(Option<log::Category>, log::Level)>,
+    pub register_names: disasm::RegisterNames,
+    pub limits: cpu::RunLimits,
+    pub gdb: Option<gdb::Endpoint>,
+    pub riscv_test: bool,
+    pub signature: Option<String>, // where to dump the riscv-arch-test signature
+    pub htif: htif::Config,
+}
+
+pub enum Command {
+    Run(Box<Options>),
+    Help,
+    Version,
+}
+
+/// Decimal or `0x`-prefixed hex.
+fn parse_u64(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => return u64::from_str_radix(&hex.replace('_', ""), 16).ok(),
+        None => return value.replace('_', "").parse::<u64>().ok(),
+    }
+}
+
+fn parse_u32(option: &str, value: &str) -> Result<u32, String> {
+    match parse_u64(value) {
+        Some(n) if n <= u32::MAX as u64 => return Ok(n as u32),
+        _ => return Err(format!("{} expects a 32-bit number, got '{}'", option, value)),
+    }
+}
+
+/// A byte count like `64M`, `1G` or `0x4000000`.
+fn parse_size(option: &str, value: &str) -> Result<u32, String> {
+    let (digits, shift): (&str, u32) = match value.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&value[..i], 10),
+        Some((i, 'M' | 'm')) => (&value[..i], 20),
+        Some((i, 'G' | 'g')) => (&value[..i], 30),
+        _ => (value, 0),
+    };
+    match parse_u64(digits).and_then(|n| n.checked_shl(shift).filter(|size| size >> shift == n)) {
+        Some(size) if size != 0 && size <= u32::MAX as u64 => return Ok(size as u32),
+        Some(size) if size == 1 << 32 => return Err(format!("{} can't cover the whole 4G address space, devices live there too", option)),
+        _ => return Err(format!("{} expects a non-zero size below 4G, got '{}'", option, value)),
+    }
+}
+
+/// `misa` bits for an ISA string such as `rv32imac`; S and U are always there, Linux needs them.
+fn parse_isa(value: &str) -> Result<u32, String> {
+    let lower: String = value.to_ascii_lowercase();
+    let lower: &str = lower.strip_prefix("rv32").unwrap_or(&lower);
+    let mut parts = lower.split('_');
+    let letters: &str = parts.next().unwrap_or("");
+    for z in parts {
+        if z != "zicsr" && z != "zifencei" { // both are always there
+            return Err(format!("unsupported extension '{}'", z));
+        }
+    }
+    let mut isa: u32 = cpu::MISA_S | cpu::MISA_U;
+    for letter in letters.chars() {
+        isa |= match letter {
+            'i' => cpu::MISA_I,
+            'm' => cpu::MISA_M,
+            'a' => cpu::MISA_A,
+            'f' => cpu::MISA_F,
+            'd' => cpu::MISA_D,
+            'c' => cpu::MISA_C,
+            'g' => cpu::MISA_I | cpu::MISA_M | cpu::MISA_A | cpu::MISA_F | cpu::MISA_D,
+            _ => return Err(format!("unsupported extension '{}'", letter)),
+        };
+    }
+    if isa & cpu::MISA_I == 0 {
+        return Err(String::from("the base ISA (I) can't be disabled"));
+    }
+    if isa & cpu::MISA_D != 0 && isa & cpu::MISA_F == 0 {
+        return Err(String::from("D depends on F"));
+    }
+    return Ok(isa);
+}
+
+fn parse_serial(value: &str) -> Result<io::SerialBackend, String> {
+    match value {
+        "stdio" => return Ok(io::SerialBackend::Stdio),
+        "null" => return Ok(io::SerialBackend::Null),
+        _ => match value.strip_prefix("file:") {
+            Some(path) if !path.is_empty() => return Ok(io::SerialBackend::File(String::from(path))),
+            _ => return Err(format!("unknown serial backend '{}'", value)),
+        },
+    }
+}
+
+pub fn parse(args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut config: cpu::MachineConfig = cpu::MachineConfig::default();
+    let mut boot: bootloader::BootloaderInfo = bootloader::BootloaderInfo::from(String::new());
+    boot.bootargs = Some(String::from(DEFAULT_BOOTARGS));
+    let mut kernel: Option<String> = None;
+    let mut sbi: bool = true;
+    let mut log_filter: Vec<(Option<log::Category>, log::Level)> = Vec::new();
+    let mut register_names: disasm::RegisterNames = disasm::RegisterNames::Abi;
+    let mut limits: cpu::RunLimits = cpu::RunLimits::default();
+    let mut gdb: Option<gdb::Endpoint> = None;
+    let mut riscv_test: bool = false;
+    let mut signature: Option<String> = None;
+    let mut htif: htif::Config = htif::Config::default();
+
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        let mut value = |option: &str| -> Result<String, String> {
+            return args.next().ok_or(format!("{} expects a value", option));
+        };
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "--load-addr" => boot.kernel_addr = Some(parse_u32(&arg, &value(&arg)?)?),
+            "--dtb" => boot.dtb = Some(value(&arg)?),
+            "--dump-dtb" => boot.dump_dtb = Some(value(&arg)?),
+            "--initrd" => boot.initrd = Some(value(&arg)?),
+            "--initrd-addr" => boot.initrd_addr = Some(parse_u32(&arg, &value(&arg)?)?),
+            "--append" => boot.bootargs = Some(value(&arg)?),
+            "--ram" => config.ram_size = parse_size(&arg, &value(&arg)?)?,
+            "--ram-base" => config.ram_base = parse_u32(&arg, &value(&arg)?)?,
+            "--harts" => {
+                let harts: u32 = parse_u32(&arg, &value(&arg)?)?;
+                if harts as usize != cpu::HARTS {
+                    return Err(format!("only {} hart is supported", cpu::HARTS));
+                }
+            },
+            "--isa" => config.isa = parse_isa(&value(&arg)?)?,
+            "--no-sbi" => sbi = false,
+            "--serial" => config.serial = parse_serial(&value(&arg)?)?,
+            "--timebase" => {
+                let value: String = value(&arg)?;
+                config.timebase_frequency = match parse_u64(&value) {
+                    Some(hz) if hz != 0 => hz,
+                    _ => return Err(format!("{} expects a frequency in Hz, got '{}'", arg, value)),
+                };
+            },
+            "--host-time" => config.time_source = clint::TimeSource::Host,
+            "--tlb-entries" => {
+                let value: String = value(&arg)?;
+                config.tlb_entries = match parse_u64(&value) {
+                    Some(n) if n != 0 && n <= 1 << 20 => n as usize,
+                    _ => return Err(format!("{} expects a number of entries between 1 and 1048576, got '{}'", arg, value)),
+                };
+            },
+            "--tlb-split" => config.tlb_split = true,
+            "--log-level" => log_filter.extend(log::parse_filter(&value(&arg)?)?),
+            "--numeric-regs" => register_names = disasm::RegisterNames::Numeric,
+            "--gdb" => gdb = Some(gdb::Endpoint::parse(&value(&arg)?)?),
+            "--max-instructions" => {
+                let value: String = value(&arg)?;
+                limits.instructions = Some(parse_u64(&value).ok_or(format!("{} expects a number, got '{}'", arg, value))?);
+            },
+            "--timeout" => {
+                let value: String = value(&arg)?;
+                match value.parse::<f64>().map(std::time::Duration::try_from_secs_f64) { // out of range for negative, infinite and huge values
+                    Ok(Ok(timeout)) if !timeout.is_zero() => limits.timeout = Some(timeout),
+                    _ => return Err(format!("{} expects a number of seconds, got '{}'", arg, value)),
+                }
+            },
+            "--stop-on" => {
+                let value: String = value(&arg)?;
+                limits.stop_on = match value.as_str() {
+                    "ebreak" => cpu::StopOn::Ebreak,
+                    "trap" => cpu::StopOn::Trap,
+                    _ => return Err(format!("{} expects ebreak or trap, got '{}'", arg, value)),
+                };
+            },
+            "--riscv-test" => riscv_test = true,
+            "--signature" => signature = Some(value(&arg)?),
+            "--tohost" => htif.tohost = Some(parse_u32(&arg, &value(&arg)?)?),
+            "--fromhost" => htif.fromhost = Some(parse_u32(&arg, &value(&arg)?)?),
+            "--htif-sandbox" => htif.sandbox = Some(value(&arg)?),
+            _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+            _ if kernel.is_some() => return Err(format!("unexpected argument '{}', the kernel was already given", arg)),
+            _ => kernel = Some(arg),
+        }
+    }
+
+    if config.ram_base & 0xFFF != 0 || config.ram_size & 0xFFF != 0 {
+        return Err(String::from("RAM base and size must be multiples of 4K"));
+    }
+    if config.ram_base as u64 + config.ram_size as u64 > 1 << 32 {
+        return Err(String::from("RAM runs past the end of the 32-bit address space"));
+    }
+    for (name, base, size) in [("CLINT", clint::CLINT_BASE, clint::CLINT_SIZE), ("PLIC", plic::PLIC_BASE, plic::PLIC_SIZE), ("UART", uart::UART_BASE, uart::UART_SIZE)] {
+        if (config.ram_base as u64) < base as u64 + size as u64 && (base as u64) < config.ram_base as u64 + config.ram_size as u64 {
+            return Err(format!("RAM at 0x{:08X}->0x{:08X} overlaps the {} at 0x{:08X}->0x{:08X}", config.ram_base, config.ram_base as u64 + config.ram_size as u64 - 1, name, base, base + size - 1));
+        }
+    }
+    if riscv_test || signature.is_some() {
+        sbi = false; // tests bring their own M-mode environment
+    }
+    boot.kernelimg = kernel.ok_or("no kernel given")?;
+    return Ok(Command::Run(Box::new(Options {
+        config: config,
+        boot: boot,
+        sbi: sbi,
+        log_filter: log_filter,
+        register_names: register_names,
+        limits: limits,
+        gdb: gdb,
+        riscv_test: riscv_test,
+        signature: signature,
+        htif: htif,
+    })));
+}
@@ -0,0 +1,161 @@
+use std::time::Instant;
+
+use crate::bus::Device;
+use crate::cpu;
+use crate::log;
+
+pub const CLINT_BASE: u32 = 0x0200_0000;
+pub const CLINT_SIZE: u32 = 0x1_0000;
+pub const CLINT_MSIP: u32 = 0x0000; // + 4 per hart
+pub const CLINT_MTIMECMP: u32 = 0x4000; // + 8 per hart
+pub const CLINT_MTIME: u32 = 0xBFF8;
+
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000; // 10 MHz, same as QEMU's virt machine
+const MAX_HARTS: usize = 4095; // the MSIP array ends where MTIMECMP starts
+const HOST_BATCH: u32 = 1024; // ticks between two looks at the host clock in `TimeSource::Host`
+
+/// Where `mtime` gets its pace from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeSource {
+    Instructions, // one tick per retired instruction, runs are reproducible
+    Host, // the host's monotonic clock scaled to the timebase frequency, guest time follows wall-clock time
+}
+
+/// Core-local interruptor: a software interrupt bit and a timer comparator per hart, and the shared `mtime`.
+pub struct CLINT {
+    msip: Vec<bool>,
+    mtimecmp: Vec<u64>,
+    mtime: u64, // in `TimeSource::Host`, the value as of the last look at the host clock
+    frequency: u64, // ticks of `mtime` per second
+    source: TimeSource,
+    epoch: Instant, // host time at which `mtime` was `epoch_mtime`
+    epoch_mtime: u64,
+    batch: u32, // ticks since the last look at the host clock
+}
+
+#[allow(dead_code)]
+impl CLINT {
+    pub fn new(harts: usize, frequency: u64, source: TimeSource) -> CLINT {
+        assert!(harts >= 1 && harts <= MAX_HARTS, "the CLINT handles 1 to {} harts", MAX_HARTS);
+        return CLINT {
+            msip: vec![false; harts],
+            mtimecmp: vec![u64::MAX; harts],
+            mtime: 0,
+            frequency: frequency,
+            source: source,
+            epoch: Instant::now(),
+            epoch_mtime: 0,
+            batch: 0,
+        };
+    }
+    pub fn frequency(&self) -> u64 {
+        return self.frequency;
+    }
+    /// Brings `mtime` up to date with the host clock; a no-op when time is counted in instructions.
+    fn sync(&mut self) {
+        if self.source == TimeSource::Host {
+            let elapsed: u128 = self.epoch.elapsed().as_nanos();
+            self.mtime = self.epoch_mtime.wrapping_add((elapsed * self.frequency as u128 / 1_000_000_000) as u64);
+        }
+    }
+    /// Restarts the host clock from the current `mtime`, after the guest wrote it.
+    fn rebase(&mut self) {
+        self.epoch = Instant::now();
+        self.epoch_mtime = self.mtime;
+    }
+}
+
+/// Reads the low (`offset` % 8 == 0) or high half of a 64-bit register, or all of it.
+fn read_u64(register: u64, offset: u32, size: u32) -> Option<u64> {
+    match (offset & 0x7, size) {
+        (0, 8) => return Some(register),
+        (0, 4) => return Some(register & 0xFFFF_FFFF),
+        (4, 4) => return Some(register >> 32),
+        _ => return None,
+    }
+}
+
+fn write_u64(register: &mut u64, offset: u32, size: u32, data: u64) -> Option<()> {
+    match (offset & 0x7, size) {
+        (0, 8) => *register = data,
+        (0, 4) => *register = (*register & !0xFFFF_FFFF) | (data & 0xFFFF_FFFF),
+        (4, 4) => *register = (*register & 0xFFFF_FFFF) | (data << 32),
+        _ => return None,
+    }
+    return Some(());
+}
+
+impl Device for CLINT {
+    fn read(&mut self, offset: u32, size: u32) -> Option<u64> {
+        let harts: u32 = self.msip.len() as u32;
+        if offset & 0x3 != 0 {
+            return None;
+        }
+        if offset < CLINT_MSIP + 4 * harts {
+            return if size == 4 { Some(self.msip[((offset - CLINT_MSIP) / 4) as usize] as u64) } else { None };
+        }
+        if offset >= CLINT_MTIMECMP && offset < CLINT_MTIMECMP + 8 * harts {
+            return read_u64(self.mtimecmp[((offset - CLINT_MTIMECMP) / 8) as usize], offset, size);
+        }
+        if offset & !0x7 == CLINT_MTIME {
+            self.sync();
+            return read_u64(self.mtime, offset, size);
+        }
+        return if size <= 4 { Some(0) } else { None }; // reserved, reads as zero
+    }
+    fn write(&mut self, offset: u32, size: u32, data: u64) -> Option<()> {
+        let harts: u32 = self.msip.len() as u32;
+        if offset & 0x3 != 0 {
+            return None;
+        }
+        if offset < CLINT_MSIP + 4 * harts {
+            if size != 4 {
+                return None;
+            }
+            self.msip[((offset - CLINT_MSIP) / 4) as usize] = data & 0x1 != 0; // only bit 0 is writable
+            return Some(());
+        }
+        if offset >= CLINT_MTIMECMP && offset < CLINT_MTIMECMP + 8 * harts {
+            let hart: usize = ((offset - CLINT_MTIMECMP) / 8) as usize;
+            write_u64(&mut self.mtimecmp[hart], offset, size, data)?;
+            log::logln!(Timer, Trace, "[clint] hart {} mtimecmp = 0x{:016X}, mtime = 0x{:016X}", hart, self.mtimecmp[hart], self.mtime);
+            return Some(());
+        }
+        if offset & !0x7 == CLINT_MTIME {
+            self.sync(); // a 32-bit write keeps the other half as it is now
+            write_u64(&mut self.mtime, offset, size, data)?;
+            self.rebase();
+            return Some(());
+        }
+        return if size <= 4 { Some(()) } else { None };
+    }
+    fn reset(&mut self) {
+        self.msip.fill(false);
+        self.mtimecmp.fill(u64::MAX);
+        self.mtime = 0;
+        self.batch = 0;
+        self.rebase();
+    }
+    fn tick(&mut self, ticks: u32) {
+        match self.source {
+            TimeSource::Instructions => self.mtime = self.mtime.wrapping_add(ticks as u64),
+            TimeSource::Host => { // reading the host clock costs more than an instruction, so MTIP is only refreshed once per batch
+                self.batch += ticks;
+                if self.batch >= HOST_BATCH {
+                    self.batch = 0;
+                    self.sync();
+                }
+            },
+        }
+    }
+    fn mip(&self) -> u32 { // hart 0, the one wired to the bus
+        let mut mip: u32 = 0;
+        if self.msip[0] {
+            mip |= cpu::MIP_MSIP;
+        }
+        if self.mtime >= self.mtimecmp[0] {
+            mip |= cpu::MIP_MTIP;
+        }
+        return mip;
+    }
+}
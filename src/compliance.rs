@@ -0,0 +1,53 @@
+use std::fmt::Write;
+
+use crate::cpu;
+use crate::halt;
+
+/// Makes sure a test binary can tell us how it went: through tohost, and for riscv-arch-test also in its signature.
+pub fn check_image(cpu: &cpu::RiscV32, signature: bool) -> Result<(), halt::EmuError> {
+    if cpu.htif.is_none() {
+        return Err(halt::EmuError::BadImage(String::from("no tohost symbol or --tohost, the test has no way to report back")));
+    }
+    if signature {
+        signature_region(cpu)?;
+    }
+    return Ok(());
+}
+
+/// Where riscv-arch-test tests leave their results, between the `begin_signature` and `end_signature` symbols.
+fn signature_region(cpu: &cpu::RiscV32) -> Result<(u32, u32), halt::EmuError> {
+    match (cpu.symbols.address("begin_signature"), cpu.symbols.address("end_signature")) {
+        (Some(begin), Some(end)) if begin & 0x3 == 0 && begin <= end => return Ok((begin, end)),
+        (Some(begin), Some(end)) => return Err(halt::EmuError::BadImage(format!("bad signature region 0x{:08X}->0x{:08X}", begin, end))),
+        _ => return Err(halt::EmuError::BadImage(String::from("no begin_signature and end_signature symbols"))),
+    }
+}
+
+/// Writes the signature the way the reference models do, one 32-bit word per line in lowercase hex, lowest address first.
+pub fn dump_signature(cpu: &mut cpu::RiscV32, path: &str) -> Result<(), halt::EmuError> {
+    let (begin, end): (u32, u32) = signature_region(cpu)?;
+    let mut text: String = String::new();
+    let mut address: u32 = begin;
+    while address < end { // a region that ends mid-word gets the whole last word
+        let word: u32 = match cpu.bus.read(address, 4) {
+            Some(word) => word as u32,
+            None => return Err(halt::EmuError::BadImage(format!("signature word at 0x{:08X} isn't in RAM", address))),
+        };
+        writeln!(text, "{:08x}", word).unwrap();
+        address += 4;
+    }
+    if let Err(e) = std::fs::write(path, text) {
+        return Err(halt::EmuError::Io(format!("unable to write {}", path), e));
+    }
+    return Ok(());
+}
+
+/// Says whether a riscv-tests binary passed; it exits with 0 if it did, or with the number of the test that failed.
+pub fn report(kernel: &str, result: &Result<halt::HaltReason, halt::EmuError>) {
+    match result {
+        Ok(halt::HaltReason::Shutdown(0)) => println!("{}: PASS", kernel),
+        Ok(halt::HaltReason::Shutdown(test)) => println!("{}: FAIL at test {}", kernel, test),
+        Ok(reason) => println!("{}: FAIL, {}", kernel, reason),
+        Err(_) => {}, // the emulator failed, not the test
+    }
+}
@@ -1,15 +1,63 @@
 use crate::decode;
+use crate::disasm;
 use crate::extensions::Execute;
 use crate::interrupt;
-use crate::memory::RV32Memory;
-use crate::timer;
+use crate::io;
+use crate::log;
+use crate::bus::Bus;
+use crate::clint;
+use crate::elf;
+use crate::gdb;
+use crate::halt;
+use crate::htif;
+use crate::memory;
+use crate::mmu;
+use crate::plic;
+use crate::tlb;
 use crate::trap;
-use crate::uart::UART;
+use crate::uart;
 use crate::instruction::*;
 use std::fmt;
 use colored::Colorize;
 use std::io::Write;
 
+pub const MISA_A: u32 = 1 << 0;
+pub const MISA_C: u32 = 1 << 2;
+pub const MISA_D: u32 = 1 << 3;
+pub const MISA_F: u32 = 1 << 5;
+pub const MISA_I: u32 = 1 << 8;
+pub const MISA_M: u32 = 1 << 12;
+pub const MISA_S: u32 = 1 << 18;
+pub const MISA_U: u32 = 1 << 20;
+pub const MISA_MXL_32: u32 = 1 << 30;
+pub const MISA_EXTENSIONS: u32 = MISA_I | MISA_M | MISA_A | MISA_F | MISA_D | MISA_C | MISA_S | MISA_U; // everything we implement
+
+pub const HARTS: usize = 1;
+
+const TICK_BATCH: u32 = 64; // instructions between two device ticks and interrupt checks
+
+pub const MSTATUS_SIE: u32 = 1 << 1;
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_FS: u32 = 0x3 << 13; // 0 = off, 1 = initial, 2 = clean, 3 = dirty
+pub const MSTATUS_SD: u32 = 1 << 31;
+pub const SSTATUS_MASK: u32 = 0x800D_E762; // mstatus bits visible through sstatus
+
+pub const X_ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+pub const F_ABI_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2", "fa3", "fa4", "fa5",
+    "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+pub const MIP_SSIP: u32 = 1 << 1;
+pub const MIP_STIP: u32 = 1 << 5;
+pub const MIP_MSIP: u32 = 1 << 3;
+pub const MIP_MTIP: u32 = 1 << 7;
+pub const MIP_SEIP: u32 = 1 << 9;
+pub const MIP_MEIP: u32 = 1 << 11;
+
 #[allow(dead_code)]
 pub struct RV32CSRs {
     pub mstatus: u32,
@@ -19,6 +67,7 @@ pub struct RV32CSRs {
     pub mie: u32,
     pub mtvec: u32,
     pub mcounteren: u32,
+    pub scounteren: u32,
     pub mscratch: u32,
     pub mepc: u32,
     pub mcause: u32,
@@ -29,46 +78,108 @@ pub struct RV32CSRs {
     pub marchid: u32,
     pub mimpid: u32,
 
-    pub sstatus: u32,
-    pub sie: u32,
     pub stvec: u32,
     pub sscratch: u32,
     pub sepc: u32,
     pub scause: u32,
     pub stval: u32,
-    pub sip: u32,
     pub satp: u32,
 
     pub cycle: u32,
-    pub time: u32,
     pub instret: u32,
     pub cycleh: u32,
-    pub timeh: u32,
     pub instreth: u32,
+
+    pub fcsr: u32,
 }
 
 #[allow(dead_code)]
 pub struct RV32Regs {
     pub x: [u32; 32],
+    pub f: [u64; 32],
     pub pc: u32,
     pub csr: RV32CSRs,
 }
 
+/// LR/SC reservation of a hart, covering the naturally aligned word at `address`.
+#[derive(Debug, Clone, Copy)]
+pub struct Reservation {
+    pub address: u32, // physical address
+    pub valid: bool,
+}
+
+/// Shape of the machine a hart is built into.
+#[derive(Debug, Clone)]
+pub struct MachineConfig {
+    pub ram_base: u32,
+    pub ram_size: u32,
+    pub timebase_frequency: u64, // rate of mtime, in Hz
+    pub time_source: clint::TimeSource,
+    pub isa: u32, // extensions enabled in misa, a subset of MISA_EXTENSIONS
+    pub serial: io::SerialBackend,
+    pub tlb_entries: usize, // per TLB, so a split one holds twice as many
+    pub tlb_split: bool, // separate instruction and data TLBs
+}
+
+/// Which exceptions end a run instead of going to the guest's own handler.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StopOn {
+    #[default]
+    Never, // only a trap the guest can't get out of, one raised by the first instruction of its handler
+    Ebreak,
+    Trap, // any exception, ebreak included
+}
+
+/// When to give up on a run that doesn't end by itself.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunLimits {
+    pub instructions: Option<u64>,
+    pub timeout: Option<std::time::Duration>,
+    pub stop_on: StopOn,
+}
+
+impl Default for MachineConfig {
+    fn default() -> MachineConfig {
+        return MachineConfig {
+            ram_base: memory::RAM_BASE,
+            ram_size: memory::RAM_SIZE,
+            timebase_frequency: clint::TIMEBASE_FREQUENCY,
+            time_source: clint::TimeSource::Instructions,
+            isa: MISA_EXTENSIONS,
+            serial: io::SerialBackend::Stdio,
+            tlb_entries: tlb::DEFAULT_ENTRIES,
+            tlb_split: false,
+        };
+    }
+}
+
 pub struct RiscV32 {
     pub regs: RV32Regs,
-    pub mem: RV32Memory,
-    pub uart: UART,
+    pub config: MachineConfig,
+    pub bus: Bus,
+    pub tlb: tlb::Tlb,
     pub privilege: u8, // 0 = user, 1 = supervisor, 3 = machine
+    pub ilen: u32, // length in bytes of the instruction being executed
+    pub reservation: Reservation,
+    pub sbi: bool, // S-mode ecalls are serviced by the built-in SBI instead of trapping to M-mode
+    pub symbols: elf::Symbols, // from the loaded ELF, if any
+    pub exit_code: i32, // what the guest asked to exit with, when it shuts the machine down
+    pub watchpoints: Vec<gdb::Watchpoint>, // set by the debugger, checked on every load and store
+    pub watch_hit: Option<(gdb::WatchKind, u32)>, // first watchpoint the last instruction triggered, and the address
+    pub htif: Option<htif::Htif>, // when the guest talks to the host through tohost
+    pub ticks: u32, // instructions retired since the devices last caught up
+    pub resync: bool, // interrupt state changed, end the batch after this instruction
     pub status: bool
 }
 
 impl RV32Regs {
     pub fn new() -> RV32Regs {
-        print!("initializing X registers...");
+        log::log!(Cpu, Info, "initializing X registers...");
         let x: [u32; 32] = [0u32; 32];
-        println!("{}, allocated {} X registers, {} bytes each, {} bytes total", "done".green(), x.len().to_string().blue(), size_of::<u32>().to_string().blue(), (x.len() * size_of::<u32>()).to_string().blue());
+        log::logln!(Cpu, Info, "{}, allocated {} X registers, {} bytes each, {} bytes total", "done".green(), x.len().to_string().blue(), size_of::<u32>().to_string().blue(), (x.len() * size_of::<u32>()).to_string().blue());
         return RV32Regs {
             x: [0u32; 32],
+            f: [0u64; 32],
             pc: 0,
             csr: RV32CSRs {
                 mstatus: 0,
@@ -78,6 +189,7 @@ impl RV32Regs {
                 mie: 0,
                 mtvec: 0,
                 mcounteren: 0,
+                scounteren: 0,
                 mscratch: 0,
                 mepc: 0,
                 mcause: 0,
@@ -85,22 +197,19 @@ impl RV32Regs {
                 mip: 0,
                 mhartid: 0,
 
-                sstatus: 0,
-                sie: 0,
                 stvec: 0,
                 sscratch: 0,
                 sepc: 0,
                 scause: 0,
                 stval: 0,
-                sip: 0,
 
                 cycle: 0,
-                time: 0,
                 instret: 0,
                 cycleh: 0,
-                timeh: 0,
                 instreth: 0,
 
+                fcsr: 0,
+
                 mvendorid: 0x00000000, // Vendor ID
                 marchid: 0x00000000, // Architecture ID
                 mimpid: 0x00000000, // Implementation ID
@@ -108,7 +217,7 @@ impl RV32Regs {
             }
         };
     }
-    pub fn read(&mut self, reg: u8) -> u32 {
+    pub fn read(&self, reg: u8) -> u32 {
         return if reg > 0 && reg < 32 {
             self.x[reg as usize]
         } else {
@@ -121,6 +230,28 @@ impl RV32Regs {
         }
         return;
     }
+    pub fn read_f32(&self, reg: u8) -> u32 {
+        let data: u64 = self.f[reg as usize];
+        return if data >> 32 == 0xFFFF_FFFF {
+            data as u32
+        } else {
+            0x7FC0_0000 // improperly NaN-boxed values read as the canonical NaN
+        };
+    }
+    pub fn write_f32(&mut self, reg: u8, data: u32) {
+        self.f[reg as usize] = 0xFFFF_FFFF_0000_0000 | data as u64;
+        self.mark_fs_dirty();
+    }
+    pub fn read_f64(&self, reg: u8) -> u64 {
+        return self.f[reg as usize];
+    }
+    pub fn write_f64(&mut self, reg: u8, data: u64) {
+        self.f[reg as usize] = data;
+        self.mark_fs_dirty();
+    }
+    pub fn mark_fs_dirty(&mut self) {
+        self.csr.mstatus |= MSTATUS_FS | MSTATUS_SD;
+    }
 }
 impl std::fmt::Display for RV32Regs {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -133,42 +264,96 @@ impl std::fmt::Display for RV32Regs {
 
 #[allow(dead_code)]
 impl RiscV32 {
-    pub fn new() -> RiscV32 {
-        return RiscV32 {
+    pub fn new() -> Result<RiscV32, halt::EmuError> {
+        return RiscV32::with_config(MachineConfig::default());
+    }
+    /// Builds the machine, failing only if the serial backend can't be opened.
+    pub fn with_config(config: MachineConfig) -> Result<RiscV32, halt::EmuError> {
+        return Ok(RiscV32 {
             regs: RV32Regs::new(),
-            mem: RV32Memory::new(),
-            uart: UART::new(),
+            bus: RiscV32::platform(&config)?,
+            tlb: tlb::Tlb::new(config.tlb_entries, config.tlb_split),
+            config: config,
             privilege: 0, // user mode
+            ilen: 4,
+            reservation: Reservation { address: 0, valid: false },
+            sbi: false,
+            symbols: elf::Symbols::default(),
+            exit_code: 0,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            htif: None,
+            ticks: 0,
+            resync: false,
             status: false
-        };
+        });
+    }
+    /// RAM plus the devices every machine has: CLINT, PLIC and a UART.
+    fn platform(config: &MachineConfig) -> Result<Bus, halt::EmuError> {
+        let mut bus: Bus = Bus::new(memory::RV32Memory::new(config.ram_base, config.ram_size));
+        bus.attach("clint", clint::CLINT_BASE, clint::CLINT_SIZE, None, Box::new(clint::CLINT::new(HARTS, config.timebase_frequency, config.time_source)));
+        bus.attach("plic", plic::PLIC_BASE, plic::PLIC_SIZE, None, Box::new(plic::PLIC::new()));
+        bus.attach("uart", uart::UART_BASE, uart::UART_SIZE, Some(plic::UART_IRQ), Box::new(uart::UART::new(&config.serial)?));
+        return Ok(bus);
     }
     pub fn reset(&mut self) {
-        print!("setting processor state...");
+        log::log!(Cpu, Info, "setting processor state...");
         self.status = true;
         self.privilege = 3; // machine mode
-        println!("{}", "done".green());
-        print!("resetting program counter...");
+        log::logln!(Cpu, Info, "{}", "done".green());
+        log::log!(Cpu, Info, "resetting program counter...");
         self.regs.pc = 0;
-        println!("{}, execution starts at <0x{:08X}>", "done".green(), self.regs.pc);
-        print!("clearing X registers...");
+        log::logln!(Cpu, Info, "{}, execution starts at <0x{:08X}>", "done".green(), self.regs.pc);
+        log::log!(Cpu, Info, "clearing X registers...");
         self.regs.x.fill(0);
-        println!("{}, all X registers have been set to 0", "done".green());
-        print!("clearing RAM memory...");
+        log::logln!(Cpu, Info, "{}, all X registers have been set to 0", "done".green());
+        log::log!(Cpu, Info, "clearing F registers...");
+        self.regs.f.fill(0);
+        log::logln!(Cpu, Info, "{}, all F registers have been set to 0", "done".green());
+        log::log!(Cpu, Info, "clearing RAM memory...");
         std::io::stdout().flush().unwrap();
-        self.mem.ram.fill(0);
-        println!("{}", "done".green());
-        print!("resetting CSRs...");
-        self.regs.csr.misa = (1 << 30) | (1 << 20) | (1 << 18) | (1 << 12) | (1 << 8) | (1 << 0);
-        println!("{}, extensions {} + {} have been enabled, XLEN has been set to {}", "done".green(), "IMA".blue(), "SU".blue(), "32".blue());
-        print!("setting hardware thread ID...");
+        self.bus.ram.clear();
+        log::logln!(Cpu, Info, "{}, {} MiB at 0x{:08X}", "done".green(), (self.bus.ram.size() >> 20).to_string().blue(), self.bus.ram.base());
+        log::log!(Cpu, Info, "resetting CSRs...");
+        self.regs.csr.misa = MISA_MXL_32 | (self.config.isa & MISA_EXTENSIONS);
+        self.regs.csr.mstatus = 0; // FS starts off, the guest enables the FPU itself
+        self.regs.csr.fcsr = 0;
+        let letters = |set: &str| -> String { set.chars().filter(|c| self.regs.csr.misa & (1 << (*c as u32 - 'A' as u32)) != 0).collect() };
+        log::logln!(Cpu, Info, "{}, extensions {} + {} have been enabled, XLEN has been set to {}", "done".green(), letters("IMAFDC").blue(), letters("SU").blue(), "32".blue());
+        log::log!(Cpu, Info, "flushing TLB...");
+        self.tlb.flush(None, None);
+        log::logln!(Cpu, Info, "{}", "done".green());
+        log::log!(Cpu, Info, "dropping LR/SC reservation...");
+        self.reservation.valid = false;
+        log::logln!(Cpu, Info, "{}", "done".green());
+        log::log!(Cpu, Info, "setting hardware thread ID...");
         self.regs.csr.mhartid = 0;
-        println!("{}", "done".green());
-        print!("resetting UART...");
-        self.uart.reset();
-        println!("{}, TX line is empty, transmitter is empty", "done".green());
-        println!("{}", "successful RV32 processor reset".on_truecolor(0, 100, 0));
+        log::logln!(Cpu, Info, "{}", "done".green());
+        log::log!(Cpu, Info, "resetting devices...");
+        self.bus.reset();
+        self.ticks = 0;
+        self.resync = false;
+        log::logln!(Cpu, Info, "{}, timebase at {} Hz, counting {}", "done".green(), self.config.timebase_frequency.to_string().blue(), match self.config.time_source {
+            clint::TimeSource::Instructions => "retired instructions",
+            clint::TimeSource::Host => "host time",
+        }.blue());
+        for (name, base, size) in self.bus.devices() {
+            log::logln!(Cpu, Info, "  {} at 0x{:08X}->0x{:08X}", name.blue(), base, base.wrapping_add(size - 1));
+        }
+        log::logln!(Cpu, Info, "{}", "successful RV32 processor reset".on_truecolor(0, 100, 0));
     }
     fn check_privilege(&self, csr: u16) -> bool { // [ ] give names to these constants
+        if csr >= 0x001 && csr <= 0x003 { // fflags, frm and fcsr are user-level, but like the FP instructions they need mstatus.FS on
+            return self.regs.csr.mstatus & MSTATUS_FS != 0;
+        }
+        if (csr >= 0xC00 && csr <= 0xC02) || (csr >= 0xC80 && csr <= 0xC82) { // cycle, time, instret and their upper halves
+            let bit: u32 = 1 << (csr & 0x1F);
+            match self.privilege {
+                3 => return true,
+                1 => return self.regs.csr.mcounteren & bit != 0,
+                _ => return self.regs.csr.mcounteren & self.regs.csr.scounteren & bit != 0,
+            }
+        }
         if (
             self.privilege == 3 ||
             self.privilege == 1
@@ -222,115 +407,373 @@ impl RiscV32 {
         }
         return false;
     }
+    /// Value of `csr` without privilege checks or traps, `None` if there's no such CSR; the debugger uses it directly.
+    pub fn get_csr(&mut self, csr: u16) -> Option<u32> {
+        match csr {
+            0xC00 => return Some(self.regs.csr.cycle),
+            0xC01 => return Some(self.mtime() as u32),
+            0xC02 => return Some(self.regs.csr.instret),
+            0xC80 => return Some(self.regs.csr.cycleh),
+            0xC81 => return Some((self.mtime() >> 32) as u32),
+            0xC82 => return Some(self.regs.csr.instreth),
+            0x001 => return Some(self.regs.csr.fcsr & 0x1F),
+            0x002 => return Some((self.regs.csr.fcsr >> 5) & 0x7),
+            0x003 => return Some(self.regs.csr.fcsr & 0xFF),
+            0x100 => return Some(self.regs.csr.mstatus & SSTATUS_MASK),
+            0x104 => return Some(self.regs.csr.mie & self.regs.csr.mideleg), // sie and sip only show delegated interrupts
+            0x105 => return Some(self.regs.csr.stvec),
+            0x106 => return Some(self.regs.csr.scounteren),
+            //0x10A => return Some(self.regs.csr.senvcfg),
+            0x140 => return Some(self.regs.csr.sscratch),
+            0x141 => return Some(self.regs.csr.sepc),
+            0x142 => return Some(self.regs.csr.scause),
+            0x143 => return Some(self.regs.csr.stval),
+            0x144 => return Some(self.regs.csr.mip & self.regs.csr.mideleg),
+            0x180 => return Some(self.regs.csr.satp),
+            0xF11 => return Some(self.regs.csr.mvendorid),
+            0xF12 => return Some(self.regs.csr.marchid),
+            0xF13 => return Some(self.regs.csr.mimpid),
+            0xF14 => return Some(self.regs.csr.mhartid),
+            0x300 => return Some(self.regs.csr.mstatus),
+            0x301 => return Some(self.regs.csr.misa),
+            0x302 => return Some(self.regs.csr.medeleg),
+            0x303 => return Some(self.regs.csr.mideleg),
+            0x304 => return Some(self.regs.csr.mie),
+            0x305 => return Some(self.regs.csr.mtvec),
+            0x306 => return Some(self.regs.csr.mcounteren),
+            0x340 => return Some(self.regs.csr.mscratch),
+            0x341 => return Some(self.regs.csr.mepc),
+            0x342 => return Some(self.regs.csr.mcause),
+            0x343 => return Some(self.regs.csr.mtval),
+            0x344 => return Some(self.regs.csr.mip),
+            0x3A0..=0x3EF => return Some(0), // implementation specific CSRs
+            _ => return None,
+        }
+    }
     pub fn read_csr(&mut self, csr: u16) -> Result<u32, trap::Trap> {
         if self.check_privilege(csr) {
-            match csr {
-                0xC00 => return Ok(self.regs.csr.cycle),
-                0xC01 => return Ok(self.regs.csr.time),
-                0xC02 => return Ok(self.regs.csr.instret),
-                0xC80 => return Ok(self.regs.csr.cycleh),
-                0xC81 => return Ok(self.regs.csr.timeh),
-                0xC82 => return Ok(self.regs.csr.instreth),
-                0x100 => return Ok(self.regs.csr.sstatus),
-                0x104 => return Ok(self.regs.csr.sie),
-                0x105 => return Ok(self.regs.csr.stvec),
-                //0x106 => return Ok(self.regs.csr.scounteren),
-                //0x10A => return Ok(self.regs.csr.senvcfg),
-                0x140 => return Ok(self.regs.csr.sscratch),
-                0x141 => return Ok(self.regs.csr.sepc),
-                0x142 => return Ok(self.regs.csr.scause),
-                0x143 => return Ok(self.regs.csr.stval),
-                0x144 => return Ok(self.regs.csr.sip),
-                0x180 => return Ok(self.regs.csr.satp),
-                0xF11 => return Ok(self.regs.csr.mvendorid),
-                0xF12 => return Ok(self.regs.csr.marchid),
-                0xF13 => return Ok(self.regs.csr.mimpid),
-                0xF14 => return Ok(self.regs.csr.mhartid),
-                0x300 => return Ok(self.regs.csr.mstatus),
-                0x301 => return Ok(self.regs.csr.misa),
-                0x302 => return Ok(self.regs.csr.medeleg),
-                0x303 => return Ok(self.regs.csr.mideleg),
-                0x304 => return Ok(self.regs.csr.mie),
-                0x305 => return Ok(self.regs.csr.mtvec),
-                0x306 => return Ok(self.regs.csr.mcounteren),
-                0x340 => return Ok(self.regs.csr.sscratch),
-                0x341 => return Ok(self.regs.csr.mepc),
-                0x342 => return Ok(self.regs.csr.mcause),
-                0x343 => return Ok(self.regs.csr.mtval),
-                0x344 => return Ok(self.regs.csr.mip),
-                0x3A0..=0x3EF => return Ok(0), // implementation specific CSRs
-                _ => return Err(trap::Trap::take(trap::Trap::IllegalInstruction, self, self.regs.pc)),
+            if let Some(value) = self.get_csr(csr) {
+                return Ok(value);
             }
         }
         return Err(trap::Trap::take(trap::Trap::IllegalInstruction, self, self.regs.pc));
     }
+    fn write_mstatus(&mut self, data: u32) {
+        let dirty: bool = data & MSTATUS_FS == MSTATUS_FS;
+        self.regs.csr.mstatus = (data & !MSTATUS_SD) | if dirty { MSTATUS_SD } else { 0 }; // SD is read-only and summarizes FS
+    }
+    /// Writes `csr` without privilege checks or traps, `None` if there's no such CSR or it's read-only.
+    pub fn set_csr(&mut self, csr: u16, data: u32) -> Option<()> {
+        match csr {
+            0xC00..=0xCFF => return None, // counters are read-only
+            0x001 => {
+                self.regs.csr.fcsr = (self.regs.csr.fcsr & !0x1F) | (data & 0x1F);
+                self.regs.mark_fs_dirty();
+            },
+            0x002 => {
+                self.regs.csr.fcsr = (self.regs.csr.fcsr & !0xE0) | ((data & 0x7) << 5);
+                self.regs.mark_fs_dirty();
+            },
+            0x003 => {
+                self.regs.csr.fcsr = data & 0xFF;
+                self.regs.mark_fs_dirty();
+            },
+            0x100 => self.write_mstatus((self.regs.csr.mstatus & !SSTATUS_MASK) | (data & SSTATUS_MASK)),
+            0x104 => self.regs.csr.mie = (self.regs.csr.mie & !self.regs.csr.mideleg) | (data & self.regs.csr.mideleg),
+            0x105 => self.regs.csr.stvec = data,
+            0x106 => self.regs.csr.scounteren = data & 0x7, // only the counters we have
+            //0x10A => self.regs.csr.senvcfg = data,
+            0x140 => self.regs.csr.sscratch = data,
+            0x141 => self.regs.csr.sepc = data,
+            0x142 => self.regs.csr.scause = data,
+            0x143 => self.regs.csr.stval = data,
+            0x144 => self.regs.csr.mip = (self.regs.csr.mip & !(MIP_SSIP & self.regs.csr.mideleg)) | (data & MIP_SSIP & self.regs.csr.mideleg), // only SSIP is writable from S-mode
+            0x180 => {
+                self.regs.csr.satp = data;
+                self.tlb.flush(None, None);
+            },
+            0xF11..=0xF14 => return None, // machine information registers are read-only
+            0x300 => self.write_mstatus(data),
+            0x301 => { // WARL: only the extensions --isa enabled can be toggled, and XLEN stays 32
+                let mut misa: u32 = MISA_MXL_32 | MISA_I | MISA_S | MISA_U | (data & self.config.isa & (MISA_M | MISA_A | MISA_F | MISA_D | MISA_C));
+                if misa & MISA_F == 0 {
+                    misa &= !MISA_D; // D depends on F
+                }
+                if self.regs.pc.wrapping_add(self.ilen) & 0x2 != 0 {
+                    misa |= self.regs.csr.misa & MISA_C; // C can't go away while the next instruction is only 2-byte aligned
+                }
+                self.regs.csr.misa = misa;
+            },
+            0x302 => self.regs.csr.medeleg = data,
+            0x303 => self.regs.csr.mideleg = data,
+            0x304 => self.regs.csr.mie = data,
+            0x305 => self.regs.csr.mtvec = data,
+            0x306 => self.regs.csr.mcounteren = data & 0x7,
+            0x340 => self.regs.csr.mscratch = data,
+            0x341 => self.regs.csr.mepc = data,
+            0x342 => self.regs.csr.mcause = data,
+            0x343 => self.regs.csr.mtval = data,
+            0x344 => self.regs.csr.mip = data,
+            0x3A0..=0x3EF => {}, // implementation specific CSRs
+            _ => return None,
+        }
+        if matches!(csr, 0x100 | 0x104 | 0x144 | 0x300 | 0x303 | 0x304 | 0x344) { // an interrupt may be takeable now
+            self.resync = true;
+        }
+        return Some(());
+    }
     pub fn write_csr(&mut self, csr: u16, data: u32) -> Option<trap::Trap> {
-        if self.check_privilege(csr) {
-            match csr {
-                0xC00 => self.regs.csr.cycle = data,
-                0xC01 => self.regs.csr.time = data,
-                0xC02 => self.regs.csr.instret = data,
-                0xC80 => self.regs.csr.cycleh = data,
-                0xC81 => self.regs.csr.timeh = data,
-                0xC82 => self.regs.csr.instreth = data,
-                0x100 => self.regs.csr.sstatus = data,
-                0x104 => self.regs.csr.sie = data,
-                0x105 => self.regs.csr.stvec = data,
-                //0x106 => self.regs.csr.scounteren = data,
-                //0x10A => self.regs.csr.senvcfg = data,
-                0x140 => self.regs.csr.sscratch = data,
-                0x141 => self.regs.csr.sepc = data,
-                0x142 => self.regs.csr.scause = data,
-                0x143 => self.regs.csr.stval = data,
-                0x144 => self.regs.csr.sip = data,
-                0x180 => self.regs.csr.satp = data,
-                0xF11 => self.regs.csr.mvendorid = data,
-                0xF12 => self.regs.csr.marchid = data,
-                0xF13 => self.regs.csr.mimpid = data,
-                0xF14 => self.regs.csr.mhartid = data,
-                0x300 => self.regs.csr.mstatus = data,
-                0x301 => self.regs.csr.misa = data,
-                0x302 => self.regs.csr.medeleg = data,
-                0x303 => self.regs.csr.mideleg = data,
-                0x304 => self.regs.csr.mie = data,
-                0x305 => self.regs.csr.mtvec = data,
-                0x306 => self.regs.csr.mcounteren = data,
-                0x340 => self.regs.csr.sscratch = data,
-                0x341 => self.regs.csr.mepc = data,
-                0x342 => self.regs.csr.mcause = data,
-                0x343 => self.regs.csr.mtval = data,
-                0x344 => self.regs.csr.mip = data,
-                0x3A0..=0x3EF => {}, // implementation specific CSRs
-                _ => return Some(trap::Trap::take(trap::Trap::IllegalInstruction, self, self.regs.pc)),
-            }
+        if self.check_privilege(csr) && self.set_csr(csr, data).is_some() {
             return None;
         }
         return Some(trap::Trap::take(trap::Trap::IllegalInstruction, self, self.regs.pc));
     }
 
-    pub fn execute(&mut self) {
-        let mut instr: u32;
-        let mut decoded: RV32Instruction;
-        while self.status {
-            /*if let Some(c) = kbd.try_read_byte() {
-                self.uart.write(uart::UART_THR, c);
-            }*/
-            instr = self.mem.read_word(self.regs.pc as usize);
-            decoded = decode::rv32_decode(instr);
-            //self.uart.kbd.try_read_byte();
-            eprintln!("[0x{:08X}]:<0x{:08X}> | got {:?}", self.regs.pc, instr, decoded);
-            match decoded.execute(self) {
-                None => {},
-                Some(trap) => {
-                    trap.display(self);
-                    panic!("Emulation halted"); // [ ] display some data like regs, memory
-                },
+    /// Ticks the devices for the instructions retired since they last were and latches the `mip` bits they drive.
+    fn catch_up(&mut self) {
+        let lines: u32 = self.bus.tick(self.ticks);
+        self.ticks = 0;
+        self.drive_mip(lines);
+    }
+    /// Current value of the CLINT's `mtime`, which the `time` CSR mirrors.
+    pub fn mtime(&mut self) -> u64 {
+        self.catch_up(); // counted in instructions, time is exact even mid-batch
+        return self.bus.read(clint::CLINT_BASE + clint::CLINT_MTIME, 8).unwrap_or(0);
+    }
+
+    fn load(&mut self, address: u32, size: u32) -> Result<u64, trap::Trap> {
+        if (address & (mmu::PAGE_SIZE - 1)) + size > mmu::PAGE_SIZE { // access straddles two pages, translate byte by byte
+            let mut data: u64 = 0;
+            for i in 0..size {
+                data |= self.load(address.wrapping_add(i), 1)? << (8 * i);
+            }
+            return Ok(data);
+        }
+        let paddr: u32 = mmu::translate(self, address, mmu::Access::Load)?;
+        match self.bus.read(paddr, size) {
+            Some(data) => {
+                self.loaded(address, size);
+                return Ok(data);
+            },
+            None => return Err(trap::Trap::take(trap::Trap::LoadAccessFault, self, address)),
+        }
+    }
+    fn store(&mut self, address: u32, size: u32, data: u64) -> Option<trap::Trap> {
+        if (address & (mmu::PAGE_SIZE - 1)) + size > mmu::PAGE_SIZE {
+            for i in 0..size {
+                if let Some(trap) = self.store(address.wrapping_add(i), 1, data >> (8 * i)) {
+                    return Some(trap);
+                }
             }
-            self.regs.pc = self.regs.pc.wrapping_add(4);
-            //io::output_to_screen(self);
-            timer::update(self);
+            return None;
+        }
+        let paddr: u32 = match mmu::translate(self, address, mmu::Access::Store) {
+            Ok(paddr) => paddr,
+            Err(trap) => return Some(trap),
+        };
+        if self.bus.write(paddr, size, data).is_none() {
+            return Some(trap::Trap::take(trap::Trap::StoreAccessFault, self, address));
+        }
+        self.stored(address, paddr, size);
+        return None;
+    }
+    /// What every load that reached memory goes through, atomics included: the debugger's read watchpoints.
+    pub fn loaded(&mut self, address: u32, size: u32) {
+        if !self.watchpoints.is_empty() {
+            self.watch(address, size, false);
+        }
+    }
+    /// What every store that reached memory goes through, atomics included: snooping and the debugger's write watchpoints.
+    pub fn stored(&mut self, address: u32, paddr: u32, size: u32) {
+        self.snoop_store(paddr, size);
+        if !self.watchpoints.is_empty() {
+            self.watch(address, size, true);
+        }
+    }
+    /// Records the first watchpoint an access of `size` bytes at virtual `address` triggers.
+    fn watch(&mut self, address: u32, size: u32, write: bool) {
+        if self.watch_hit.is_none() {
+            if let Some(watchpoint) = self.watchpoints.iter().find(|w| w.hit(address, size, write)) {
+                self.watch_hit = Some((watchpoint.kind, address.max(watchpoint.address)));
+            }
+        }
+    }
+    /// Latches the interrupt lines coming from the devices into `mip`.
+    fn drive_mip(&mut self, lines: u32) {
+        let mut lines: u32 = lines;
+        let mut driven: u32 = MIP_MSIP | MIP_MTIP | MIP_SEIP | MIP_MEIP;
+        if self.sbi { // the built-in SBI forwards the timer to S-mode like OpenSBI does
+            driven = (driven & !MIP_MTIP) | MIP_STIP;
+            if lines & MIP_MTIP != 0 {
+                lines = (lines & !MIP_MTIP) | MIP_STIP;
+            }
+        }
+        self.regs.csr.mip = (self.regs.csr.mip & !driven) | (lines & driven);
+    }
+    /// Breaks the reservation if a store of `size` bytes at `paddr` touches the reserved word, and tells HTIF about it.
+    /// Every agent writing to RAM must report here; this hart's own stores do too, which the spec allows.
+    pub fn snoop_store(&mut self, paddr: u32, size: u32) {
+        if self.reservation.valid && paddr < self.reservation.address.wrapping_add(4) && self.reservation.address < paddr.wrapping_add(size) {
+            self.reservation.valid = false;
+        }
+        if let Some(htif) = &mut self.htif {
+            htif.snoop(paddr, size);
+        }
+    }
+    pub fn read_byte(&mut self, address: u32) -> Result<u8, trap::Trap> {
+        return Ok(self.load(address, 1)? as u8);
+    }
+    pub fn read_half_word(&mut self, address: u32) -> Result<u16, trap::Trap> {
+        return Ok(self.load(address, 2)? as u16);
+    }
+    pub fn read_word(&mut self, address: u32) -> Result<u32, trap::Trap> {
+        return Ok(self.load(address, 4)? as u32);
+    }
+    pub fn read_double_word(&mut self, address: u32) -> Result<u64, trap::Trap> {
+        return self.load(address, 8);
+    }
+    pub fn write_byte(&mut self, address: u32, byte: u8) -> Option<trap::Trap> {
+        return self.store(address, 1, byte as u64);
+    }
+    pub fn write_half_word(&mut self, address: u32, half: u16) -> Option<trap::Trap> {
+        return self.store(address, 2, half as u64);
+    }
+    pub fn write_word(&mut self, address: u32, word: u32) -> Option<trap::Trap> {
+        return self.store(address, 4, word as u64);
+    }
+    pub fn write_double_word(&mut self, address: u32, double: u64) -> Option<trap::Trap> {
+        return self.store(address, 8, double);
+    }
+    /// Reads a half of an instruction, device memory that can't be fetched from raises an access fault.
+    fn fetch_half(&mut self, paddr: u32) -> Result<u16, trap::Trap> {
+        match self.bus.read(paddr, 2) {
+            Some(half) => return Ok(half as u16),
+            None => return Err(trap::Trap::take(trap::Trap::InstructionAccessFault, self, self.regs.pc)),
+        }
+    }
+    fn fetch(&mut self) -> Result<u32, trap::Trap> {
+        let pc: u32 = self.regs.pc;
+        let paddr: u32 = mmu::translate(self, pc, mmu::Access::Fetch)?;
+        let low: u16 = self.fetch_half(paddr)?;
+        if low & 0x3 != 0x3 { // compressed instruction
+            self.ilen = 2;
+            return Ok(low as u32);
+        }
+        let high_paddr: u32 = if pc & (mmu::PAGE_SIZE - 1) == mmu::PAGE_SIZE - 2 { // upper half lives on the next page
+            mmu::translate(self, pc.wrapping_add(2), mmu::Access::Fetch)?
+        } else {
+            paddr + 2
+        };
+        let high: u16 = self.fetch_half(high_paddr)?;
+        self.ilen = 4;
+        return Ok(((high as u32) << 16) | low as u32);
+    }
+
+    /// Whether the extension `instr` belongs to is enabled in `misa`.
+    fn extension_enabled(&self, instr: &RV32Instruction) -> bool {
+        let bit: u32 = match instr {
+            RV32Instruction::RV32M(_) => MISA_M,
+            RV32Instruction::RV32A(_) => MISA_A,
+            RV32Instruction::RV32F(_) => MISA_F,
+            RV32Instruction::RV32D(_) => MISA_D,
+            _ => return true,
+        };
+        return self.regs.csr.misa & bit != 0;
+    }
+    /// Executes one instruction and advances the devices, returning the trap it raised unless the built-in SBI serviced it.
+    /// A trap has already been taken when it's returned, so the hart is at the handler.
+    pub fn step(&mut self) -> Option<trap::Trap> {
+        let result: Option<trap::Trap> = match self.fetch() {
+            Ok(instr) => {
+                let mut decoded: RV32Instruction = if self.ilen == 2 {
+                    if self.regs.csr.misa & MISA_C != 0 {
+                        decode::rv32c_decode(instr as u16)
+                    } else {
+                        RV32Instruction::Unknown
+                    }
+                } else {
+                    decode::rv32_decode(instr)
+                };
+                if !self.extension_enabled(&decoded) {
+                    decoded = RV32Instruction::Unknown;
+                }
+                log::logln!(Cpu, Trace, "[0x{:08X}{}]:<0x{:08X}> | {}", self.regs.pc, self.symbols.describe(self.regs.pc), instr, disasm::disassemble(&decoded, self.regs.pc, disasm::register_names(), &self.symbols));
+                decoded.execute(self)
+            },
+            Err(trap) => Some(trap),
+        };
+        let raised: Option<trap::Trap> = match result {
+            None => {
+                self.regs.pc = self.regs.pc.wrapping_add(self.ilen);
+                None
+            },
+            Some(trap::Trap::SModeEnvCall) if self.sbi => { // already serviced, execution resumes after the ecall
+                self.regs.pc = self.regs.pc.wrapping_add(self.ilen);
+                None
+            },
+            Some(trap) => Some(trap), // the PC already points at the handler
+        };
+        htif::poll(self);
+        self.ticks += 1;
+        if self.ticks >= TICK_BATCH || self.resync { // devices catch up and interrupts are looked at once per batch
+            self.catch_up();
+            self.resync = false;
             interrupt::check(self);
         }
+        return raised;
+    }
+    /// Where the last trap was raised and its tval, from whichever of M-mode and S-mode took it.
+    fn trap_site(&self) -> (u32, u32) {
+        if self.privilege == 3 {
+            return (self.regs.csr.mepc, self.regs.csr.mtval);
+        }
+        return (self.regs.csr.sepc, self.regs.csr.stval);
+    }
+    /// Whether a trap `step` returned ends the run, given the PC of the instruction that was stepped.
+    pub fn trap_halt(&self, trap: trap::Trap, pc: u32, stop_on: StopOn) -> Option<halt::HaltReason> {
+        let (epc, tval): (u32, u32) = self.trap_site();
+        let stuck: bool = epc == pc && self.regs.pc == pc; // the handler faulted on its first instruction, it'd spin forever
+        match trap {
+            trap::Trap::Breakpoint if stop_on != StopOn::Never => return Some(halt::HaltReason::Breakpoint(epc)),
+            _ if stop_on == StopOn::Trap || stuck => return Some(halt::HaltReason::Trap { trap: trap, pc: epc, tval: tval }),
+            _ => return None,
+        }
+    }
+    /// Runs until the guest stops the machine, a limit is hit or a trap ends the run as `limits.stop_on` says.
+    pub fn execute(&mut self, limits: RunLimits) -> Result<halt::HaltReason, halt::EmuError> {
+        let mut retired: u64 = 0;
+        let deadline: Option<std::time::Instant> = limits.timeout.map(|timeout| std::time::Instant::now() + timeout);
+        let mut previous: Option<(trap::Trap, u32, u32)> = None; // trap the last instruction raised, with its PC and tval
+        while self.status {
+            if limits.instructions.is_some_and(|max| retired >= max) {
+                return Ok(halt::HaltReason::InstructionLimit(retired));
+            }
+            if retired & 0xFFF == 0 { // looking at the clock and the devices every instruction would be too slow
+                if deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline) {
+                    return Ok(halt::HaltReason::Timeout(retired));
+                }
+                if let Some((device, e)) = self.bus.take_error() {
+                    return Err(halt::EmuError::Io(String::from(device), e));
+                }
+            }
+            retired += 1;
+            let pc: u32 = self.regs.pc;
+            let raised: Option<trap::Trap> = self.step();
+            if let Some(reason) = raised.and_then(|trap| self.trap_halt(trap, pc, limits.stop_on)) {
+                if let (halt::HaltReason::Trap { .. }, Some((trap, pc, tval))) = (reason, previous) {
+                    return Ok(halt::HaltReason::Trap { trap: trap, pc: pc, tval: tval }); // the handler it went to faulted right away, report what started it
+                }
+                return Ok(reason);
+            }
+            previous = raised.map(|trap| {
+                let (pc, tval): (u32, u32) = self.trap_site();
+                (trap, pc, tval)
+            });
+        }
+        if let Some((device, e)) = self.bus.take_error() {
+            return Err(halt::EmuError::Io(String::from(device), e));
+        }
+        return Ok(halt::HaltReason::Shutdown(self.exit_code));
     }
 }
 
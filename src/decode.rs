@@ -2,8 +2,12 @@ use crate::instruction::*;
 use crate::extensions::rv32i::*;
 use crate::extensions::rv32m::*;
 use crate::extensions::rv32a::*;
+use crate::extensions::rv32f::*;
+use crate::extensions::rv32d::*;
 use crate::extensions::rv32zicsr::*;
 use crate::trap::*;
+use crate::mmu::*;
+use crate::log;
 
 pub fn rv32_decode(instr: u32) -> RV32Instruction {
     let opcode: u8 = (instr & 0x7F) as u8;
@@ -22,7 +26,7 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                             0b0000001 => return RV32Instruction::RV32M(RV32MInstruction::Mul(rd, rs1, rs2)),
                             0b0100000 => return RV32Instruction::RV32I(RV32IInstruction::Sub(rd, rs1, rs2)),
                             _ => {
-                                eprintln!("Unknown R-type instruction with funct7: 0b{:07b}", funct7);
+                                log::logln!(Decode, Debug, "Unknown R-type instruction with funct7: 0b{:07b}", funct7);
                                 return RV32Instruction::Unknown;
                             },
                         },
@@ -30,7 +34,7 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                             0b0000000 => return RV32Instruction::RV32I(RV32IInstruction::Sll(rd, rs1, rs2)),
                             0b0000001 => return RV32Instruction::RV32M(RV32MInstruction::Mulh(rd, rs1, rs2)),
                             _ => {
-                                eprintln!("Unknown R-type instruction with funct7: 0b{:07b}", funct7);
+                                log::logln!(Decode, Debug, "Unknown R-type instruction with funct7: 0b{:07b}", funct7);
                                 return RV32Instruction::Unknown;
                             },
                         },
@@ -38,7 +42,7 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                             0b0000000 => return RV32Instruction::RV32I(RV32IInstruction::Slt(rd, rs1, rs2)),
                             0b0000001 => return RV32Instruction::RV32M(RV32MInstruction::Mulhsu(rd, rs1, rs2)),
                             _ => {
-                                eprintln!("Unknown R-type instruction with funct7: 0b{:07b}", funct7);
+                                log::logln!(Decode, Debug, "Unknown R-type instruction with funct7: 0b{:07b}", funct7);
                                 return RV32Instruction::Unknown;
                             },
                         },
@@ -46,7 +50,7 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                             0b0000000 => return RV32Instruction::RV32I(RV32IInstruction::Sltu(rd, rs1, rs2)),
                             0b0000001 => return RV32Instruction::RV32M(RV32MInstruction::Mulhu(rd, rs1, rs2)),
                             _ => {
-                                eprintln!("Unknown R-type instruction with funct7: 0b{:07b}", funct7);
+                                log::logln!(Decode, Debug, "Unknown R-type instruction with funct7: 0b{:07b}", funct7);
                                 return RV32Instruction::Unknown;
                             },
                         },
@@ -54,7 +58,7 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                             0b0000000 => return RV32Instruction::RV32I(RV32IInstruction::Xor(rd, rs1, rs2)),
                             0b0000001 => return RV32Instruction::RV32M(RV32MInstruction::Div(rd, rs1, rs2)),
                             _ => {
-                                eprintln!("Unknown R-type instruction with funct7: 0b{:07b}", funct7);
+                                log::logln!(Decode, Debug, "Unknown R-type instruction with funct7: 0b{:07b}", funct7);
                                 return RV32Instruction::Unknown;
                             },
                         },
@@ -63,7 +67,7 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                             0b0000001 => return RV32Instruction::RV32M(RV32MInstruction::Divu(rd, rs1, rs2)),
                             0b0100000 => return RV32Instruction::RV32I(RV32IInstruction::Sra(rd, rs1, rs2)),
                             _ => {
-                                eprintln!("Unknown R-type instruction with funct7: 0b{:07b}", funct7);
+                                log::logln!(Decode, Debug, "Unknown R-type instruction with funct7: 0b{:07b}", funct7);
                                 return RV32Instruction::Unknown;
                             },
                         },
@@ -71,7 +75,7 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                             0b0000000 => return RV32Instruction::RV32I(RV32IInstruction::Or(rd, rs1, rs2)),
                             0b0000001 => return RV32Instruction::RV32M(RV32MInstruction::Rem(rd, rs1, rs2)),
                             _ => {
-                                eprintln!("Unknown R-type instruction with funct7: 0b{:07b}", funct7);
+                                log::logln!(Decode, Debug, "Unknown R-type instruction with funct7: 0b{:07b}", funct7);
                                 return RV32Instruction::Unknown;
                             },
                         },
@@ -79,40 +83,112 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                             0b0000000 => return RV32Instruction::RV32I(RV32IInstruction::And(rd, rs1, rs2)),
                             0b0000001 => return RV32Instruction::RV32M(RV32MInstruction::Remu(rd, rs1, rs2)),
                             _ => {
-                                eprintln!("Unknown R-type instruction with funct7: 0b{:07b}", funct7);
+                                log::logln!(Decode, Debug, "Unknown R-type instruction with funct7: 0b{:07b}", funct7);
                                 return RV32Instruction::Unknown;
                             },
                         },
                         _ => {
-                            eprintln!("Unknown R-type instruction with funct3: 0b{:03b}", funct3);
+                            log::logln!(Decode, Debug, "Unknown R-type instruction with funct3: 0b{:03b}", funct3);
                             return RV32Instruction::Unknown;
                         },
                     },
-                    0b0101111 => match funct3 {
-                        0b010 => match (funct7 & !0x3) >> 2 {
-                            0b00000 => return RV32Instruction::RV32A(RV32AInstruction::AmoaddW(rd, rs1, rs2)),
-                            0b00001 => return RV32Instruction::RV32A(RV32AInstruction::AmoswapW(rd, rs1, rs2)),
-                            0b00010 => return RV32Instruction::RV32I(RV32IInstruction::Lw(rd, rs1, 0)), // TEMPORARY TRICK: replace with actual instruction
-                            0b00011 => return RV32Instruction::RV32A(RV32AInstruction::ScW(rd, rs1, rs2)),
-                            0b00100 => return RV32Instruction::RV32A(RV32AInstruction::AmoxorW(rd, rs1, rs2)),
-                            0b01100 => return RV32Instruction::RV32A(RV32AInstruction::AmoandW(rd, rs1, rs2)),
-                            0b01000 => return RV32Instruction::RV32A(RV32AInstruction::AmoorW(rd, rs1, rs2)),
-                            0b10000 => return RV32Instruction::RV32A(RV32AInstruction::AmominW(rd, rs1, rs2)),
-                            0b10100 => return RV32Instruction::RV32A(RV32AInstruction::AmomaxW(rd, rs1, rs2)),
-                            0b11000 => return RV32Instruction::RV32A(RV32AInstruction::AmominuW(rd, rs1, rs2)),
-                            0b11100 => return RV32Instruction::RV32A(RV32AInstruction::AmomaxuW(rd, rs1, rs2)),
+                    0b0101111 => {
+                        let aq: bool = funct7 & 0x2 != 0;
+                        let rl: bool = funct7 & 0x1 != 0;
+                        match funct3 {
+                            0b010 => match funct7 >> 2 {
+                                0b00000 => return RV32Instruction::RV32A(RV32AInstruction::AmoaddW(rd, rs1, rs2, aq, rl)),
+                                0b00001 => return RV32Instruction::RV32A(RV32AInstruction::AmoswapW(rd, rs1, rs2, aq, rl)),
+                                0b00010 if rs2 == 0 => return RV32Instruction::RV32A(RV32AInstruction::LrW(rd, rs1, aq, rl)),
+                                0b00011 => return RV32Instruction::RV32A(RV32AInstruction::ScW(rd, rs1, rs2, aq, rl)),
+                                0b00100 => return RV32Instruction::RV32A(RV32AInstruction::AmoxorW(rd, rs1, rs2, aq, rl)),
+                                0b01100 => return RV32Instruction::RV32A(RV32AInstruction::AmoandW(rd, rs1, rs2, aq, rl)),
+                                0b01000 => return RV32Instruction::RV32A(RV32AInstruction::AmoorW(rd, rs1, rs2, aq, rl)),
+                                0b10000 => return RV32Instruction::RV32A(RV32AInstruction::AmominW(rd, rs1, rs2, aq, rl)),
+                                0b10100 => return RV32Instruction::RV32A(RV32AInstruction::AmomaxW(rd, rs1, rs2, aq, rl)),
+                                0b11000 => return RV32Instruction::RV32A(RV32AInstruction::AmominuW(rd, rs1, rs2, aq, rl)),
+                                0b11100 => return RV32Instruction::RV32A(RV32AInstruction::AmomaxuW(rd, rs1, rs2, aq, rl)),
+                                _ => {
+                                    log::logln!(Decode, Debug, "Unknown R-type A instruction with funct5: 0b{:05b}", funct7 >> 2);
+                                    return RV32Instruction::Unknown;
+                                },
+                            },
                             _ => {
-                                eprintln!("Unknown R-type A instruction with funct5: 0b{:05b}", (funct7 & !0x3) >> 2);
+                                log::logln!(Decode, Debug, "Unknown R-type instruction with funct3: 0b{:03b}", funct3);
                                 return RV32Instruction::Unknown;
                             },
-                        },
+                        }
+                    },
+                    0b1010011 => match (funct7, funct3, rs2) {
+                        (0b0000000, _, _) => return RV32Instruction::RV32F(RV32FInstruction::FaddS(rd, rs1, rs2, funct3)),
+                        (0b0000100, _, _) => return RV32Instruction::RV32F(RV32FInstruction::FsubS(rd, rs1, rs2, funct3)),
+                        (0b0001000, _, _) => return RV32Instruction::RV32F(RV32FInstruction::FmulS(rd, rs1, rs2, funct3)),
+                        (0b0001100, _, _) => return RV32Instruction::RV32F(RV32FInstruction::FdivS(rd, rs1, rs2, funct3)),
+                        (0b0101100, _, 0b00000) => return RV32Instruction::RV32F(RV32FInstruction::FsqrtS(rd, rs1, funct3)),
+                        (0b0010000, 0b000, _) => return RV32Instruction::RV32F(RV32FInstruction::FsgnjS(rd, rs1, rs2)),
+                        (0b0010000, 0b001, _) => return RV32Instruction::RV32F(RV32FInstruction::FsgnjnS(rd, rs1, rs2)),
+                        (0b0010000, 0b010, _) => return RV32Instruction::RV32F(RV32FInstruction::FsgnjxS(rd, rs1, rs2)),
+                        (0b0010100, 0b000, _) => return RV32Instruction::RV32F(RV32FInstruction::FminS(rd, rs1, rs2)),
+                        (0b0010100, 0b001, _) => return RV32Instruction::RV32F(RV32FInstruction::FmaxS(rd, rs1, rs2)),
+                        (0b1100000, _, 0b00000) => return RV32Instruction::RV32F(RV32FInstruction::FcvtWS(rd, rs1, funct3)),
+                        (0b1100000, _, 0b00001) => return RV32Instruction::RV32F(RV32FInstruction::FcvtWuS(rd, rs1, funct3)),
+                        (0b1110000, 0b000, 0b00000) => return RV32Instruction::RV32F(RV32FInstruction::FmvXW(rd, rs1)),
+                        (0b1110000, 0b001, 0b00000) => return RV32Instruction::RV32F(RV32FInstruction::FclassS(rd, rs1)),
+                        (0b1010000, 0b010, _) => return RV32Instruction::RV32F(RV32FInstruction::FeqS(rd, rs1, rs2)),
+                        (0b1010000, 0b001, _) => return RV32Instruction::RV32F(RV32FInstruction::FltS(rd, rs1, rs2)),
+                        (0b1010000, 0b000, _) => return RV32Instruction::RV32F(RV32FInstruction::FleS(rd, rs1, rs2)),
+                        (0b1101000, _, 0b00000) => return RV32Instruction::RV32F(RV32FInstruction::FcvtSW(rd, rs1, funct3)),
+                        (0b1101000, _, 0b00001) => return RV32Instruction::RV32F(RV32FInstruction::FcvtSWu(rd, rs1, funct3)),
+                        (0b1111000, 0b000, 0b00000) => return RV32Instruction::RV32F(RV32FInstruction::FmvWX(rd, rs1)),
+                        (0b0000001, _, _) => return RV32Instruction::RV32D(RV32DInstruction::FaddD(rd, rs1, rs2, funct3)),
+                        (0b0000101, _, _) => return RV32Instruction::RV32D(RV32DInstruction::FsubD(rd, rs1, rs2, funct3)),
+                        (0b0001001, _, _) => return RV32Instruction::RV32D(RV32DInstruction::FmulD(rd, rs1, rs2, funct3)),
+                        (0b0001101, _, _) => return RV32Instruction::RV32D(RV32DInstruction::FdivD(rd, rs1, rs2, funct3)),
+                        (0b0101101, _, 0b00000) => return RV32Instruction::RV32D(RV32DInstruction::FsqrtD(rd, rs1, funct3)),
+                        (0b0010001, 0b000, _) => return RV32Instruction::RV32D(RV32DInstruction::FsgnjD(rd, rs1, rs2)),
+                        (0b0010001, 0b001, _) => return RV32Instruction::RV32D(RV32DInstruction::FsgnjnD(rd, rs1, rs2)),
+                        (0b0010001, 0b010, _) => return RV32Instruction::RV32D(RV32DInstruction::FsgnjxD(rd, rs1, rs2)),
+                        (0b0010101, 0b000, _) => return RV32Instruction::RV32D(RV32DInstruction::FminD(rd, rs1, rs2)),
+                        (0b0010101, 0b001, _) => return RV32Instruction::RV32D(RV32DInstruction::FmaxD(rd, rs1, rs2)),
+                        (0b0100000, _, 0b00001) => return RV32Instruction::RV32D(RV32DInstruction::FcvtSD(rd, rs1, funct3)),
+                        (0b0100001, _, 0b00000) => return RV32Instruction::RV32D(RV32DInstruction::FcvtDS(rd, rs1, funct3)),
+                        (0b1010001, 0b010, _) => return RV32Instruction::RV32D(RV32DInstruction::FeqD(rd, rs1, rs2)),
+                        (0b1010001, 0b001, _) => return RV32Instruction::RV32D(RV32DInstruction::FltD(rd, rs1, rs2)),
+                        (0b1010001, 0b000, _) => return RV32Instruction::RV32D(RV32DInstruction::FleD(rd, rs1, rs2)),
+                        (0b1110001, 0b001, 0b00000) => return RV32Instruction::RV32D(RV32DInstruction::FclassD(rd, rs1)),
+                        (0b1100001, _, 0b00000) => return RV32Instruction::RV32D(RV32DInstruction::FcvtWD(rd, rs1, funct3)),
+                        (0b1100001, _, 0b00001) => return RV32Instruction::RV32D(RV32DInstruction::FcvtWuD(rd, rs1, funct3)),
+                        (0b1101001, _, 0b00000) => return RV32Instruction::RV32D(RV32DInstruction::FcvtDW(rd, rs1, funct3)),
+                        (0b1101001, _, 0b00001) => return RV32Instruction::RV32D(RV32DInstruction::FcvtDWu(rd, rs1, funct3)),
                         _ => {
-                            eprintln!("Unknown R-type instruction with funct3: 0b{:03b}", funct3);
+                            log::logln!(Decode, Debug, "Unknown R-type FP instruction with funct7: 0b{:07b}", funct7);
                             return RV32Instruction::Unknown;
                         },
                     },
                     _ => {
-                        eprintln!("Unknown R-type instruction with opcode: 0b{:07b}", opcode);
+                        log::logln!(Decode, Debug, "Unknown R-type instruction with opcode: 0b{:07b}", opcode);
+                        return RV32Instruction::Unknown;
+                    },
+                }
+            },
+            Type::R4 => {
+                let rd: u8 = ((instr >> 7) & 0x1F) as u8;
+                let rm: u8 = ((instr >> (7 + 5)) & 0x7) as u8;
+                let rs1: u8 = ((instr >> 7 + 5 + 3) & 0x1F) as u8;
+                let rs2: u8 = ((instr >> 7 + 5 + 3 + 5) & 0x1F) as u8;
+                let fmt: u8 = ((instr >> 7 + 5 + 3 + 5 + 5) & 0x3) as u8;
+                let rs3: u8 = ((instr >> 7 + 5 + 3 + 5 + 5 + 2) & 0x1F) as u8;
+                match (opcode, fmt) {
+                    (0b1000011, 0b00) => return RV32Instruction::RV32F(RV32FInstruction::FmaddS(rd, rs1, rs2, rs3, rm)),
+                    (0b1000111, 0b00) => return RV32Instruction::RV32F(RV32FInstruction::FmsubS(rd, rs1, rs2, rs3, rm)),
+                    (0b1001011, 0b00) => return RV32Instruction::RV32F(RV32FInstruction::FnmsubS(rd, rs1, rs2, rs3, rm)),
+                    (0b1001111, 0b00) => return RV32Instruction::RV32F(RV32FInstruction::FnmaddS(rd, rs1, rs2, rs3, rm)),
+                    (0b1000011, 0b01) => return RV32Instruction::RV32D(RV32DInstruction::FmaddD(rd, rs1, rs2, rs3, rm)),
+                    (0b1000111, 0b01) => return RV32Instruction::RV32D(RV32DInstruction::FmsubD(rd, rs1, rs2, rs3, rm)),
+                    (0b1001011, 0b01) => return RV32Instruction::RV32D(RV32DInstruction::FnmsubD(rd, rs1, rs2, rs3, rm)),
+                    (0b1001111, 0b01) => return RV32Instruction::RV32D(RV32DInstruction::FnmaddD(rd, rs1, rs2, rs3, rm)),
+                    _ => {
+                        log::logln!(Decode, Debug, "Unknown R4-type instruction with fmt: 0b{:02b}", fmt);
                         return RV32Instruction::Unknown;
                     },
                 }
@@ -131,7 +207,15 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                         0b100 => return RV32Instruction::RV32I(RV32IInstruction::Lbu(rd, rs1, iimm)),
                         0b101 => return RV32Instruction::RV32I(RV32IInstruction::Lhu(rd, rs1, iimm)),
                         _ => {
-                            eprintln!("Unknown I-type instruction with funct3: 0b{:03b}", funct3);
+                            log::logln!(Decode, Debug, "Unknown I-type instruction with funct3: 0b{:03b}", funct3);
+                            return RV32Instruction::Unknown;
+                        },
+                    },
+                    0b0000111 => match funct3 {
+                        0b010 => return RV32Instruction::RV32F(RV32FInstruction::Flw(rd, rs1, iimm)),
+                        0b011 => return RV32Instruction::RV32D(RV32DInstruction::Fld(rd, rs1, iimm)),
+                        _ => {
+                            log::logln!(Decode, Debug, "Unknown I-type instruction with funct3: 0b{:03b}", funct3);
                             return RV32Instruction::Unknown;
                         },
                     },
@@ -147,7 +231,7 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                     },
                     0b0010011 => match funct3 {
                         0b000 => return RV32Instruction::RV32I(RV32IInstruction::Addi(rd, rs1, iimm)),
-                        0b001 => {
+                        0b001 if uimm >> 5 == 0 => {
                             let shamt: u8 = (uimm & 0x1F) as u8;
                             return RV32Instruction::RV32I(RV32IInstruction::Slli(rd, rs1, shamt));
                         },
@@ -160,7 +244,7 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                                 0b0000000 => return RV32Instruction::RV32I(RV32IInstruction::Srli(rd, rs1, shamt)),
                                 0b0100000 => return RV32Instruction::RV32I(RV32IInstruction::Srai(rd, rs1, shamt)),
                                 _ => {
-                                    eprintln!("Unknown I-type instruction with funct3: 0b{:03b}", funct3);
+                                    log::logln!(Decode, Debug, "Unknown I-type instruction with funct3: 0b{:03b}", funct3);
                                     return RV32Instruction::Unknown;
                                 },
                             }
@@ -168,7 +252,7 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                         0b110 => return RV32Instruction::RV32I(RV32IInstruction::Ori(rd, rs1, iimm)),
                         0b111 => return RV32Instruction::RV32I(RV32IInstruction::Andi(rd, rs1, iimm)),
                         _ => {
-                            eprintln!("Unknown I-type instruction with funct3: 0b{:03b}", funct3);
+                            log::logln!(Decode, Debug, "Unknown I-type instruction with funct3: 0b{:03b}", funct3);
                             return RV32Instruction::Unknown;
                         },
                     },
@@ -179,8 +263,10 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                             0b000000000001 => return RV32Instruction::RV32I(RV32IInstruction::Ebreak),
                             0b000100000010 => return RV32Instruction::TrapReturn(TrapRetInstruction::Sret),
                             0b001100000010 => return RV32Instruction::TrapReturn(TrapRetInstruction::Mret),
+                            0b000100000101 if rs1 == 0 && rd == 0 => return RV32Instruction::TrapReturn(TrapRetInstruction::Wfi),
+                            _ if uimm >> 5 == 0b0001001 && rd == 0 => return RV32Instruction::Mmu(MmuInstruction::SfenceVma(rs1, (uimm & 0x1F) as u8)),
                             _ => {
-                                eprintln!("Unknown I-type instruction with imm[11:0]: 0b{:012b}", iimm);
+                                log::logln!(Decode, Debug, "Unknown I-type instruction with imm[11:0]: 0b{:012b}", iimm);
                                 return RV32Instruction::Unknown;
                             },
                         },
@@ -191,12 +277,12 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                         0b110 => return RV32Instruction::RV32Ziscr(RV32ZicsrInstruction::Csrrsi(rd, rs1, (uimm & 0xFFF) as u16)),
                         0b111 => return RV32Instruction::RV32Ziscr(RV32ZicsrInstruction::Csrrci(rd, rs1, (uimm & 0xFFF) as u16)),
                         _ => {
-                            eprintln!("Unknown I-type instruction with funct3: 0b{:03b}", funct3);
+                            log::logln!(Decode, Debug, "Unknown I-type instruction with funct3: 0b{:03b}", funct3);
                             return RV32Instruction::Unknown;
                         },
                     },
                     _ => {
-                        eprintln!("Unknown I-type instruction with opcode: 0b{:07b}", opcode);
+                        log::logln!(Decode, Debug, "Unknown I-type instruction with opcode: 0b{:07b}", opcode);
                         return RV32Instruction::Unknown;
                     },
                 }
@@ -207,7 +293,7 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                 let rs1: u8 = ((instr >> 7 + 5 + 3) & 0x1F) as u8;
                 let rs2: u8 = ((instr >> 7 + 5 + 3 + 5) & 0x1F) as u8;
                 let imm1: u8 = ((instr >> 7 + 5 + 3 + 5 + 5) & 0x7F) as u8;
-                let uimm: u32 = ((imm1 << 5) | imm0) as u32;
+                let uimm: u32 = ((imm1 as u32) << 5) | imm0 as u32; // widened first, imm[11:5] doesn't fit in a byte once shifted
                 let iimm: i32 = ((uimm as i32) << 20) >> 20;
                 match opcode {
                     0b0100011 => match funct3 {
@@ -215,12 +301,20 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                         0b001 => return RV32Instruction::RV32I(RV32IInstruction::Sh(rs1, rs2, iimm)),
                         0b010 => return RV32Instruction::RV32I(RV32IInstruction::Sw(rs1, rs2, iimm)),
                         _ => {
-                            eprintln!("Unknown S-type instruction with funct3: 0b{:03b}", funct3);
+                            log::logln!(Decode, Debug, "Unknown S-type instruction with funct3: 0b{:03b}", funct3);
+                            return RV32Instruction::Unknown;
+                        },
+                    },
+                    0b0100111 => match funct3 {
+                        0b010 => return RV32Instruction::RV32F(RV32FInstruction::Fsw(rs1, rs2, iimm)),
+                        0b011 => return RV32Instruction::RV32D(RV32DInstruction::Fsd(rs1, rs2, iimm)),
+                        _ => {
+                            log::logln!(Decode, Debug, "Unknown S-type instruction with funct3: 0b{:03b}", funct3);
                             return RV32Instruction::Unknown;
                         },
                     },
                     _ => {
-                        eprintln!("Unknown S-type instruction with opcode: 0b{:07b}", opcode);
+                        log::logln!(Decode, Debug, "Unknown S-type instruction with opcode: 0b{:07b}", opcode);
                         return RV32Instruction::Unknown;
                     },
                 }
@@ -242,12 +336,12 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                         0b110 => return RV32Instruction::RV32I(RV32IInstruction::Bltu(rs1, rs2, iimm)),
                         0b111 => return RV32Instruction::RV32I(RV32IInstruction::Bgeu(rs1, rs2, iimm)),
                         _ => {
-                            eprintln!("Unknown B-type instruction with funct3: 0b{:03b}", funct3);
+                            log::logln!(Decode, Debug, "Unknown B-type instruction with funct3: 0b{:03b}", funct3);
                             return RV32Instruction::Unknown;
                         },
                     },
                     _ => {
-                        eprintln!("Unknown B-type instruction with opcode: 0b{:07b}", opcode);
+                        log::logln!(Decode, Debug, "Unknown B-type instruction with opcode: 0b{:07b}", opcode);
                         return RV32Instruction::Unknown;
                     },
                 }
@@ -260,7 +354,7 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                     0b0110111 => return RV32Instruction::RV32I(RV32IInstruction::Lui(rd, iimm)),
                     0b0010111 => return RV32Instruction::RV32I(RV32IInstruction::Auipc(rd, iimm)),
                     _ => {
-                        eprintln!("Unknown U-type instruction with opcode: 0b{:07b}", opcode);
+                        log::logln!(Decode, Debug, "Unknown U-type instruction with opcode: 0b{:07b}", opcode);
                         return RV32Instruction::Unknown;
                     },
                 }
@@ -277,7 +371,7 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
                 match opcode {
                     0b1101111 => return RV32Instruction::RV32I(RV32IInstruction::Jal(rd, iimm)),
                     _ => {
-                        eprintln!("Unknown J-type instruction with opcode: 0b{:07b}", opcode);
+                        log::logln!(Decode, Debug, "Unknown J-type instruction with opcode: 0b{:07b}", opcode);
                         return RV32Instruction::Unknown;
                     },
                 }
@@ -287,3 +381,262 @@ pub fn rv32_decode(instr: u32) -> RV32Instruction {
         return RV32Instruction::Unknown;
     }
 }
+
+/// Expands a 16-bit RVC encoding into the equivalent 32-bit instruction.
+pub fn rv32c_decode(instr: u16) -> RV32Instruction {
+    let instr: u32 = instr as u32;
+    let quadrant: u8 = (instr & 0x3) as u8;
+    let funct3: u8 = ((instr >> 13) & 0x7) as u8;
+    let rd: u8 = ((instr >> 7) & 0x1F) as u8; // also rs1 in CI/CR formats
+    let rs2: u8 = ((instr >> 2) & 0x1F) as u8;
+    let rdp: u8 = (((instr >> 2) & 0x7) + 8) as u8; // rd'/rs2' in CIW/CL/CS formats
+    let rs1p: u8 = (((instr >> 7) & 0x7) + 8) as u8; // rs1'/rd' in CL/CS/CA/CB formats
+    let ciimm: i32 = ((((instr >> 12) & 0x1) << 5 | (instr >> 2) & 0x1F) as i32) << 26 >> 26; // imm[5|4:0]
+    let cjimm: i32 = ((
+        ((instr >> 12) & 0x1) << 11 |
+        ((instr >> 11) & 0x1) << 4 |
+        ((instr >> 9) & 0x3) << 8 |
+        ((instr >> 8) & 0x1) << 10 |
+        ((instr >> 7) & 0x1) << 6 |
+        ((instr >> 6) & 0x1) << 7 |
+        ((instr >> 3) & 0x7) << 1 |
+        ((instr >> 2) & 0x1) << 5
+    ) as i32) << 20 >> 20;
+    let cbimm: i32 = ((
+        ((instr >> 12) & 0x1) << 8 |
+        ((instr >> 10) & 0x3) << 3 |
+        ((instr >> 5) & 0x3) << 6 |
+        ((instr >> 3) & 0x3) << 1 |
+        ((instr >> 2) & 0x1) << 5
+    ) as i32) << 23 >> 23;
+    let clwimm: i32 = (((instr >> 10) & 0x7) << 3 | ((instr >> 6) & 0x1) << 2 | ((instr >> 5) & 0x1) << 6) as i32; // uimm[5:3|2|6]
+    let cldimm: i32 = (((instr >> 10) & 0x7) << 3 | ((instr >> 5) & 0x3) << 6) as i32; // uimm[5:3|7:6]
+    let lwspimm: i32 = (((instr >> 12) & 0x1) << 5 | ((instr >> 4) & 0x7) << 2 | ((instr >> 2) & 0x3) << 6) as i32; // uimm[5|4:2|7:6]
+    let ldspimm: i32 = (((instr >> 12) & 0x1) << 5 | ((instr >> 5) & 0x3) << 3 | ((instr >> 2) & 0x7) << 6) as i32; // uimm[5|4:3|8:6]
+    let swspimm: i32 = (((instr >> 9) & 0xF) << 2 | ((instr >> 7) & 0x3) << 6) as i32; // uimm[5:2|7:6]
+    let sdspimm: i32 = (((instr >> 10) & 0x7) << 3 | ((instr >> 7) & 0x7) << 6) as i32; // uimm[5:3|8:6]
+    match quadrant {
+        0b00 => match funct3 {
+            0b000 => {
+                let nzuimm: i32 = (((instr >> 11) & 0x3) << 4 | ((instr >> 7) & 0xF) << 6 | ((instr >> 6) & 0x1) << 2 | ((instr >> 5) & 0x1) << 3) as i32;
+                if nzuimm == 0 {
+                    log::logln!(Decode, Debug, "Illegal compressed instruction: 0x{:04X}", instr);
+                    return RV32Instruction::Unknown; // also covers the all-zero instruction
+                }
+                return RV32Instruction::RV32I(RV32IInstruction::Addi(rdp, 2, nzuimm)); // C.ADDI4SPN
+            },
+            0b001 => return RV32Instruction::RV32D(RV32DInstruction::Fld(rdp, rs1p, cldimm)), // C.FLD
+            0b010 => return RV32Instruction::RV32I(RV32IInstruction::Lw(rdp, rs1p, clwimm)), // C.LW
+            0b011 => return RV32Instruction::RV32F(RV32FInstruction::Flw(rdp, rs1p, clwimm)), // C.FLW
+            0b101 => return RV32Instruction::RV32D(RV32DInstruction::Fsd(rs1p, rdp, cldimm)), // C.FSD
+            0b110 => return RV32Instruction::RV32I(RV32IInstruction::Sw(rs1p, rdp, clwimm)), // C.SW
+            0b111 => return RV32Instruction::RV32F(RV32FInstruction::Fsw(rs1p, rdp, clwimm)), // C.FSW
+            _ => {
+                log::logln!(Decode, Debug, "Unknown compressed instruction in quadrant 0 with funct3: 0b{:03b}", funct3);
+                return RV32Instruction::Unknown;
+            }, // 0b100 is reserved
+        },
+        0b01 => match funct3 {
+            0b000 => return RV32Instruction::RV32I(RV32IInstruction::Addi(rd, rd, ciimm)), // C.ADDI, C.NOP
+            0b001 => return RV32Instruction::RV32I(RV32IInstruction::Jal(1, cjimm)), // C.JAL
+            0b010 => return RV32Instruction::RV32I(RV32IInstruction::Addi(rd, 0, ciimm)), // C.LI
+            0b011 => {
+                if rd == 2 {
+                    let nzimm: i32 = ((
+                        ((instr >> 12) & 0x1) << 9 |
+                        ((instr >> 6) & 0x1) << 4 |
+                        ((instr >> 5) & 0x1) << 6 |
+                        ((instr >> 3) & 0x3) << 7 |
+                        ((instr >> 2) & 0x1) << 5
+                    ) as i32) << 22 >> 22;
+                    if nzimm == 0 {
+                        log::logln!(Decode, Debug, "Illegal compressed instruction: 0x{:04X}", instr);
+                        return RV32Instruction::Unknown;
+                    }
+                    return RV32Instruction::RV32I(RV32IInstruction::Addi(2, 2, nzimm)); // C.ADDI16SP
+                }
+                if ciimm == 0 {
+                    log::logln!(Decode, Debug, "Illegal compressed instruction: 0x{:04X}", instr);
+                    return RV32Instruction::Unknown;
+                }
+                return RV32Instruction::RV32I(RV32IInstruction::Lui(rd, ciimm << 12)); // C.LUI
+            },
+            0b100 => {
+                let shamt: u8 = (ciimm & 0x3F) as u8;
+                match (instr >> 10) & 0x3 {
+                    0b00 | 0b01 if shamt & 0x20 != 0 => {
+                        log::logln!(Decode, Debug, "Illegal compressed shift amount: {}", shamt);
+                        return RV32Instruction::Unknown; // shamt[5] is reserved on RV32
+                    },
+                    0b00 => return RV32Instruction::RV32I(RV32IInstruction::Srli(rs1p, rs1p, shamt)), // C.SRLI
+                    0b01 => return RV32Instruction::RV32I(RV32IInstruction::Srai(rs1p, rs1p, shamt)), // C.SRAI
+                    0b10 => return RV32Instruction::RV32I(RV32IInstruction::Andi(rs1p, rs1p, ciimm)), // C.ANDI
+                    _ => match ((instr >> 12) & 0x1, (instr >> 5) & 0x3) {
+                        (0, 0b00) => return RV32Instruction::RV32I(RV32IInstruction::Sub(rs1p, rs1p, rdp)), // C.SUB
+                        (0, 0b01) => return RV32Instruction::RV32I(RV32IInstruction::Xor(rs1p, rs1p, rdp)), // C.XOR
+                        (0, 0b10) => return RV32Instruction::RV32I(RV32IInstruction::Or(rs1p, rs1p, rdp)), // C.OR
+                        (0, 0b11) => return RV32Instruction::RV32I(RV32IInstruction::And(rs1p, rs1p, rdp)), // C.AND
+                        _ => {
+                            log::logln!(Decode, Debug, "Unknown compressed arithmetic instruction: 0x{:04X}", instr);
+                            return RV32Instruction::Unknown;
+                        },
+                    },
+                }
+            },
+            0b101 => return RV32Instruction::RV32I(RV32IInstruction::Jal(0, cjimm)), // C.J
+            0b110 => return RV32Instruction::RV32I(RV32IInstruction::Beq(rs1p, 0, cbimm)), // C.BEQZ
+            _ => return RV32Instruction::RV32I(RV32IInstruction::Bne(rs1p, 0, cbimm)), // C.BNEZ
+        },
+        0b10 => match funct3 {
+            0b000 => {
+                let shamt: u8 = (ciimm & 0x3F) as u8;
+                if shamt & 0x20 != 0 {
+                    log::logln!(Decode, Debug, "Illegal compressed shift amount: {}", shamt);
+                    return RV32Instruction::Unknown;
+                }
+                return RV32Instruction::RV32I(RV32IInstruction::Slli(rd, rd, shamt)); // C.SLLI
+            },
+            0b001 => return RV32Instruction::RV32D(RV32DInstruction::Fld(rd, 2, ldspimm)), // C.FLDSP
+            0b010 => {
+                if rd == 0 {
+                    log::logln!(Decode, Debug, "Illegal compressed instruction: 0x{:04X}", instr);
+                    return RV32Instruction::Unknown;
+                }
+                return RV32Instruction::RV32I(RV32IInstruction::Lw(rd, 2, lwspimm)); // C.LWSP
+            },
+            0b011 => return RV32Instruction::RV32F(RV32FInstruction::Flw(rd, 2, lwspimm)), // C.FLWSP
+            0b100 => match ((instr >> 12) & 0x1, rd, rs2) {
+                (0, 0, 0) => {
+                    log::logln!(Decode, Debug, "Illegal compressed instruction: 0x{:04X}", instr);
+                    return RV32Instruction::Unknown;
+                },
+                (0, _, 0) => return RV32Instruction::RV32I(RV32IInstruction::Jalr(0, rd, 0)), // C.JR
+                (0, _, _) => return RV32Instruction::RV32I(RV32IInstruction::Add(rd, 0, rs2)), // C.MV
+                (_, 0, 0) => return RV32Instruction::RV32I(RV32IInstruction::Ebreak), // C.EBREAK
+                (_, _, 0) => return RV32Instruction::RV32I(RV32IInstruction::Jalr(1, rd, 0)), // C.JALR
+                (_, _, _) => return RV32Instruction::RV32I(RV32IInstruction::Add(rd, rd, rs2)), // C.ADD
+            },
+            0b101 => return RV32Instruction::RV32D(RV32DInstruction::Fsd(2, rs2, sdspimm)), // C.FSDSP
+            0b110 => return RV32Instruction::RV32I(RV32IInstruction::Sw(2, rs2, swspimm)), // C.SWSP
+            _ => return RV32Instruction::RV32F(RV32FInstruction::Fsw(2, rs2, swspimm)), // C.FSWSP
+        },
+        _ => return rv32_decode(instr), // not a compressed encoding
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rv32_decode, rv32c_decode};
+    use crate::disasm;
+    use crate::elf;
+    use crate::instruction::RV32Instruction;
+
+    const PC: u32 = 0x8000_0000;
+
+    fn text(instr: RV32Instruction) -> String {
+        return disasm::disassemble(&instr, PC, disasm::RegisterNames::Abi, &elf::Symbols::default());
+    }
+
+    /// Encodings from llvm-mc, with what llvm-objdump makes of them.
+    #[test]
+    fn base_encodings() {
+        let cases: [(u32, &str); 37] = [
+            (0x123451B7, "lui gp, 0x12345"),
+            (0xFFFFF197, "auipc gp, 0xfffff"),
+            (0x801FF0EF, "jal 0x7ffff800"),
+            (0xFFF100E7, "jalr ra, -1(sp)"),
+            (0x80208063, "beq ra, sp, 0x7ffff000"),
+            (0x7E20FFE3, "bgeu ra, sp, 0x80000ffe"),
+            (0xFFF08183, "lb gp, -1(ra)"),
+            (0x7FF0D183, "lhu gp, 2047(ra)"),
+            (0x80208023, "sb sp, -2048(ra)"),
+            (0xFE112E23, "sw ra, -4(sp)"),
+            (0x13F2A623, "sw t6, 300(t0)"),
+            (0xFFF08193, "addi gp, ra, -1"),
+            (0xFFF0B193, "sltiu gp, ra, -1"),
+            (0x7FF0F193, "andi gp, ra, 2047"),
+            (0x01F09193, "slli gp, ra, 31"),
+            (0x0010D193, "srli gp, ra, 1"),
+            (0x4110D193, "srai gp, ra, 17"),
+            (0x402081B3, "sub gp, ra, sp"),
+            (0x41DF5FB3, "sra t6, t5, t4"),
+            (0x0310000F, "fence rw, w"),
+            (0x8330000F, "fence.tso"),
+            (0x0100000F, "pause"),
+            (0x00000073, "ecall"),
+            (0x00100073, "ebreak"),
+            (0x30200073, "mret"),
+            (0x10200073, "sret"),
+            (0x0220A1B3, "mulhsu gp, ra, sp"),
+            (0x0220F1B3, "remu gp, ra, sp"),
+            (0x1000A1AF, "lr.w gp, (ra)"),
+            (0x1820A22F, "sc.w tp, sp, (ra)"),
+            (0x0820A1AF, "amoswap.w gp, sp, (ra)"),
+            (0xE020A1AF, "amomaxu.w gp, sp, (ra)"),
+            (0x6620A1AF, "amoand.w.aqrl gp, sp, (ra)"),
+            (0x340091F3, "csrrw gp, mscratch, ra"),
+            (0xF14021F3, "csrr gp, mhartid"),
+            (0x300FF1F3, "csrrci gp, mstatus, 31"),
+            (0xFFF0D073, "csrwi 0xfff, 1"),
+        ];
+        for (word, expected) in cases {
+            assert_eq!(text(rv32_decode(word)), expected, "0x{:08X}", word);
+        }
+    }
+
+    #[test]
+    fn compressed_encodings() {
+        let cases: [(u16, &str); 27] = [
+            (0x157D, "addi a0, a0, -1"),
+            (0x45FD, "li a1, 31"),
+            (0x7605, "lui a2, 0xfffe1"),
+            (0x7101, "addi sp, sp, -512"),
+            (0x1FE0, "addi s0, sp, 1020"),
+            (0x5DE8, "lw a0, 124(a1)"),
+            (0xC09C, "sw a5, 0(s1)"),
+            (0xB001, "j 0x7ffff800"),
+            (0x2FFD, "jal 0x800007fe"),
+            (0xD081, "beqz s1, 0x7fffff00"),
+            (0xEFFD, "bnez a5, 0x800000fe"),
+            (0x807D, "srli s0, s0, 31"),
+            (0x8505, "srai a0, a0, 1"),
+            (0x9981, "andi a1, a1, -32"),
+            (0x8C05, "sub s0, s0, s1"),
+            (0x8F3D, "xor a4, a4, a5"),
+            (0x8D4D, "or a0, a0, a1"),
+            (0x8CFD, "and s1, s1, a5"),
+            (0x02FE, "slli t0, t0, 31"),
+            (0x50FE, "lw ra, 252(sp)"),
+            (0xC07E, "sw t6, 0(sp)"),
+            (0x8082, "ret"),
+            (0x9282, "jalr t0"),
+            (0x852E, "mv a0, a1"),
+            (0x952E, "add a0, a0, a1"),
+            (0x9002, "ebreak"),
+            (0x0001, "nop"),
+        ];
+        for (half, expected) in cases {
+            assert_eq!(text(rv32c_decode(half)), expected, "0x{:04X}", half);
+        }
+    }
+
+    /// Reserved encodings and bits that must be zero don't decode to anything.
+    #[test]
+    fn reserved_encodings() {
+        let words: [u32; 8] = [
+            0x00000000, // opcode 0, not an instruction
+            0xFFFFFFFF,
+            0x0210D193 | 1 << 25, // slli with imm[11:5] != 0
+            0x2010D193, // srli/srai with a stray funct7 bit
+            0x402091B3 | 1 << 29, // sll with funct7 0b0110000
+            0x0000B183 | 0b110 << 12, // lwu doesn't exist on RV32
+            0x0020B1AF | 0b00101 << 27, // unused AMO funct5
+            0x00200073, // uret is gone
+        ];
+        for word in words {
+            assert!(matches!(rv32_decode(word), RV32Instruction::Unknown), "0x{:08X} decoded to {:?}", word, rv32_decode(word));
+        }
+        assert!(matches!(rv32c_decode(0x0000), RV32Instruction::Unknown), "the all-zero parcel is defined illegal");
+        assert!(matches!(rv32c_decode(0x4002), RV32Instruction::Unknown), "c.lwsp with rd = x0 is reserved");
+    }
+}
@@ -0,0 +1,315 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::cpu;
+use crate::elf;
+use crate::instruction::RV32Instruction;
+use crate::extensions::rv32i::RV32IInstruction;
+use crate::extensions::rv32m::RV32MInstruction;
+use crate::extensions::rv32a::RV32AInstruction;
+use crate::extensions::rv32f::RV32FInstruction;
+use crate::extensions::rv32d::RV32DInstruction;
+use crate::extensions::rv32zicsr::RV32ZicsrInstruction;
+use crate::mmu::MmuInstruction;
+use crate::trap::TrapRetInstruction;
+
+const ROUNDING_MODES: [&str; 8] = ["rne", "rtz", "rdn", "rup", "rmm", "", "", ""]; // 7 is dyn, left out like assemblers do
+
+const CSR_NAMES: [(u16, &str); 42] = [
+    (0x001, "fflags"), (0x002, "frm"), (0x003, "fcsr"),
+    (0x100, "sstatus"), (0x104, "sie"), (0x105, "stvec"), (0x106, "scounteren"), (0x10A, "senvcfg"),
+    (0x140, "sscratch"), (0x141, "sepc"), (0x142, "scause"), (0x143, "stval"), (0x144, "sip"), (0x180, "satp"),
+    (0x300, "mstatus"), (0x301, "misa"), (0x302, "medeleg"), (0x303, "mideleg"), (0x304, "mie"), (0x305, "mtvec"), (0x306, "mcounteren"),
+    (0x30A, "menvcfg"), (0x310, "mstatush"), (0x31A, "menvcfgh"),
+    (0x340, "mscratch"), (0x341, "mepc"), (0x342, "mcause"), (0x343, "mtval"), (0x344, "mip"),
+    (0x3A0, "pmpcfg0"), (0x3B0, "pmpaddr0"),
+    (0xC00, "cycle"), (0xC01, "time"), (0xC02, "instret"), (0xC80, "cycleh"), (0xC81, "timeh"), (0xC82, "instreth"),
+    (0xF11, "mvendorid"), (0xF12, "marchid"), (0xF13, "mimpid"), (0xF14, "mhartid"), (0xF15, "mconfigptr"),
+];
+
+static NUMERIC: AtomicBool = AtomicBool::new(false);
+
+/// How registers are spelled: `a0`/`fa0` or `x10`/`f10`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterNames {
+    Abi,
+    Numeric,
+}
+
+/// Picks the register names traces use.
+pub fn set_register_names(names: RegisterNames) {
+    NUMERIC.store(names == RegisterNames::Numeric, Ordering::Relaxed);
+}
+
+pub fn register_names() -> RegisterNames {
+    return if NUMERIC.load(Ordering::Relaxed) { RegisterNames::Numeric } else { RegisterNames::Abi };
+}
+
+pub fn csr_name(csr: u16) -> Option<&'static str> {
+    return CSR_NAMES.iter().find(|(number, _)| *number == csr).map(|(_, name)| *name);
+}
+
+/// Turns decoded instructions into assembly text.
+struct Disassembler<'a> {
+    pc: u32,
+    names: RegisterNames,
+    symbols: &'a elf::Symbols,
+}
+
+impl Disassembler<'_> {
+    fn x(&self, reg: u8) -> String {
+        match self.names {
+            RegisterNames::Abi => return String::from(cpu::X_ABI_NAMES[reg as usize & 0x1F]),
+            RegisterNames::Numeric => return format!("x{}", reg),
+        }
+    }
+    fn f(&self, reg: u8) -> String {
+        match self.names {
+            RegisterNames::Abi => return String::from(cpu::F_ABI_NAMES[reg as usize & 0x1F]),
+            RegisterNames::Numeric => return format!("f{}", reg),
+        }
+    }
+    fn csr(&self, csr: u16) -> String {
+        return csr_name(csr).map(String::from).unwrap_or(format!("0x{:03x}", csr));
+    }
+    /// `0x80000010 <main+0x4>` for the PC-relative target at `offset`.
+    fn target(&self, offset: i32) -> String {
+        let target: u32 = self.pc.wrapping_add_signed(offset);
+        return format!("0x{:08x}{}", target, self.symbols.describe(target));
+    }
+    fn rm(&self, rm: u8) -> String {
+        return match ROUNDING_MODES[rm as usize & 0x7] {
+            "" => String::new(),
+            mode => format!(", {}", mode),
+        };
+    }
+    fn amo(&self, name: &str, aq: bool, rl: bool) -> String {
+        let ordering: &str = match (aq, rl) {
+            (false, false) => "",
+            (true, false) => ".aq",
+            (false, true) => ".rl",
+            (true, true) => ".aqrl",
+        };
+        return format!("{}{}", name, ordering);
+    }
+    fn fence_set(bits: u8) -> String {
+        let set: String = "iorw".chars().enumerate().filter(|(i, _)| bits & (8 >> i) != 0).map(|(_, c)| c).collect();
+        return if set.is_empty() { String::from("0") } else { set };
+    }
+
+    fn rv32i(&self, instr: &RV32IInstruction) -> String {
+        use RV32IInstruction::*;
+        let x = |reg: u8| self.x(reg);
+        match *instr {
+            Lui(rd, imm) => return format!("lui {}, 0x{:x}", x(rd), (imm as u32) >> 12),
+            Auipc(rd, imm) => return format!("auipc {}, 0x{:x}", x(rd), (imm as u32) >> 12),
+            Jal(0, imm) => return format!("j {}", self.target(imm)),
+            Jal(1, imm) => return format!("jal {}", self.target(imm)),
+            Jal(rd, imm) => return format!("jal {}, {}", x(rd), self.target(imm)),
+            Jalr(0, 1, 0) => return String::from("ret"),
+            Jalr(0, rs1, 0) => return format!("jr {}", x(rs1)),
+            Jalr(1, rs1, 0) => return format!("jalr {}", x(rs1)),
+            Jalr(rd, rs1, imm) => return format!("jalr {}, {}({})", x(rd), imm, x(rs1)),
+            Beq(rs1, 0, imm) => return format!("beqz {}, {}", x(rs1), self.target(imm)),
+            Bne(rs1, 0, imm) => return format!("bnez {}, {}", x(rs1), self.target(imm)),
+            Blt(rs1, 0, imm) => return format!("bltz {}, {}", x(rs1), self.target(imm)),
+            Bge(rs1, 0, imm) => return format!("bgez {}, {}", x(rs1), self.target(imm)),
+            Blt(0, rs2, imm) => return format!("bgtz {}, {}", x(rs2), self.target(imm)),
+            Bge(0, rs2, imm) => return format!("blez {}, {}", x(rs2), self.target(imm)),
+            Beq(rs1, rs2, imm) => return format!("beq {}, {}, {}", x(rs1), x(rs2), self.target(imm)),
+            Bne(rs1, rs2, imm) => return format!("bne {}, {}, {}", x(rs1), x(rs2), self.target(imm)),
+            Blt(rs1, rs2, imm) => return format!("blt {}, {}, {}", x(rs1), x(rs2), self.target(imm)),
+            Bge(rs1, rs2, imm) => return format!("bge {}, {}, {}", x(rs1), x(rs2), self.target(imm)),
+            Bltu(rs1, rs2, imm) => return format!("bltu {}, {}, {}", x(rs1), x(rs2), self.target(imm)),
+            Bgeu(rs1, rs2, imm) => return format!("bgeu {}, {}, {}", x(rs1), x(rs2), self.target(imm)),
+            Lb(rd, rs1, imm) => return format!("lb {}, {}({})", x(rd), imm, x(rs1)),
+            Lh(rd, rs1, imm) => return format!("lh {}, {}({})", x(rd), imm, x(rs1)),
+            Lw(rd, rs1, imm) => return format!("lw {}, {}({})", x(rd), imm, x(rs1)),
+            Lbu(rd, rs1, imm) => return format!("lbu {}, {}({})", x(rd), imm, x(rs1)),
+            Lhu(rd, rs1, imm) => return format!("lhu {}, {}({})", x(rd), imm, x(rs1)),
+            Sb(rs1, rs2, imm) => return format!("sb {}, {}({})", x(rs2), imm, x(rs1)),
+            Sh(rs1, rs2, imm) => return format!("sh {}, {}({})", x(rs2), imm, x(rs1)),
+            Sw(rs1, rs2, imm) => return format!("sw {}, {}({})", x(rs2), imm, x(rs1)),
+            Addi(0, 0, 0) => return String::from("nop"),
+            Addi(rd, 0, imm) => return format!("li {}, {}", x(rd), imm),
+            Addi(rd, rs1, 0) => return format!("mv {}, {}", x(rd), x(rs1)),
+            Addi(rd, rs1, imm) => return format!("addi {}, {}, {}", x(rd), x(rs1), imm),
+            Slti(rd, rs1, imm) => return format!("slti {}, {}, {}", x(rd), x(rs1), imm),
+            Sltiu(rd, rs1, 1) => return format!("seqz {}, {}", x(rd), x(rs1)),
+            Sltiu(rd, rs1, imm) => return format!("sltiu {}, {}, {}", x(rd), x(rs1), imm),
+            Xori(rd, rs1, -1) => return format!("not {}, {}", x(rd), x(rs1)),
+            Xori(rd, rs1, imm) => return format!("xori {}, {}, {}", x(rd), x(rs1), imm),
+            Ori(rd, rs1, imm) => return format!("ori {}, {}, {}", x(rd), x(rs1), imm),
+            Andi(rd, rs1, imm) => return format!("andi {}, {}, {}", x(rd), x(rs1), imm),
+            Slli(rd, rs1, shamt) => return format!("slli {}, {}, {}", x(rd), x(rs1), shamt),
+            Srli(rd, rs1, shamt) => return format!("srli {}, {}, {}", x(rd), x(rs1), shamt),
+            Srai(rd, rs1, shamt) => return format!("srai {}, {}, {}", x(rd), x(rs1), shamt),
+            Add(rd, 0, rs2) => return format!("mv {}, {}", x(rd), x(rs2)), // what c.mv expands to
+            Add(rd, rs1, rs2) => return format!("add {}, {}, {}", x(rd), x(rs1), x(rs2)),
+            Sub(rd, 0, rs2) => return format!("neg {}, {}", x(rd), x(rs2)),
+            Sub(rd, rs1, rs2) => return format!("sub {}, {}, {}", x(rd), x(rs1), x(rs2)),
+            Sll(rd, rs1, rs2) => return format!("sll {}, {}, {}", x(rd), x(rs1), x(rs2)),
+            Slt(rd, rs1, rs2) => return format!("slt {}, {}, {}", x(rd), x(rs1), x(rs2)),
+            Sltu(rd, 0, rs2) => return format!("snez {}, {}", x(rd), x(rs2)),
+            Sltu(rd, rs1, rs2) => return format!("sltu {}, {}, {}", x(rd), x(rs1), x(rs2)),
+            Xor(rd, rs1, rs2) => return format!("xor {}, {}, {}", x(rd), x(rs1), x(rs2)),
+            Srl(rd, rs1, rs2) => return format!("srl {}, {}, {}", x(rd), x(rs1), x(rs2)),
+            Sra(rd, rs1, rs2) => return format!("sra {}, {}, {}", x(rd), x(rs1), x(rs2)),
+            Or(rd, rs1, rs2) => return format!("or {}, {}, {}", x(rd), x(rs1), x(rs2)),
+            And(rd, rs1, rs2) => return format!("and {}, {}, {}", x(rd), x(rs1), x(rs2)),
+            Fence(_, _, 0xF, 0xF, _) => return String::from("fence"),
+            Fence(_, _, succ, pred, _) => return format!("fence {}, {}", Self::fence_set(pred), Self::fence_set(succ)),
+            FenceTSO => return String::from("fence.tso"),
+            Pause => return String::from("pause"),
+            Ecall => return String::from("ecall"),
+            Ebreak => return String::from("ebreak"),
+        }
+    }
+    fn rv32m(&self, instr: &RV32MInstruction) -> String {
+        use RV32MInstruction::*;
+        let (name, rd, rs1, rs2): (&str, u8, u8, u8) = match *instr {
+            Mul(rd, rs1, rs2) => ("mul", rd, rs1, rs2),
+            Mulh(rd, rs1, rs2) => ("mulh", rd, rs1, rs2),
+            Mulhsu(rd, rs1, rs2) => ("mulhsu", rd, rs1, rs2),
+            Mulhu(rd, rs1, rs2) => ("mulhu", rd, rs1, rs2),
+            Div(rd, rs1, rs2) => ("div", rd, rs1, rs2),
+            Divu(rd, rs1, rs2) => ("divu", rd, rs1, rs2),
+            Rem(rd, rs1, rs2) => ("rem", rd, rs1, rs2),
+            Remu(rd, rs1, rs2) => ("remu", rd, rs1, rs2),
+        };
+        return format!("{} {}, {}, {}", name, self.x(rd), self.x(rs1), self.x(rs2));
+    }
+    fn rv32a(&self, instr: &RV32AInstruction) -> String {
+        use RV32AInstruction::*;
+        let (name, rd, rs1, rs2, aq, rl): (&str, u8, u8, u8, bool, bool) = match *instr {
+            LrW(rd, rs1, aq, rl) => return format!("{} {}, ({})", self.amo("lr.w", aq, rl), self.x(rd), self.x(rs1)),
+            ScW(rd, rs1, rs2, aq, rl) => ("sc.w", rd, rs1, rs2, aq, rl),
+            AmoswapW(rd, rs1, rs2, aq, rl) => ("amoswap.w", rd, rs1, rs2, aq, rl),
+            AmoaddW(rd, rs1, rs2, aq, rl) => ("amoadd.w", rd, rs1, rs2, aq, rl),
+            AmoxorW(rd, rs1, rs2, aq, rl) => ("amoxor.w", rd, rs1, rs2, aq, rl),
+            AmoandW(rd, rs1, rs2, aq, rl) => ("amoand.w", rd, rs1, rs2, aq, rl),
+            AmoorW(rd, rs1, rs2, aq, rl) => ("amoor.w", rd, rs1, rs2, aq, rl),
+            AmominW(rd, rs1, rs2, aq, rl) => ("amomin.w", rd, rs1, rs2, aq, rl),
+            AmomaxW(rd, rs1, rs2, aq, rl) => ("amomax.w", rd, rs1, rs2, aq, rl),
+            AmominuW(rd, rs1, rs2, aq, rl) => ("amominu.w", rd, rs1, rs2, aq, rl),
+            AmomaxuW(rd, rs1, rs2, aq, rl) => ("amomaxu.w", rd, rs1, rs2, aq, rl),
+        };
+        return format!("{} {}, {}, ({})", self.amo(name, aq, rl), self.x(rd), self.x(rs2), self.x(rs1));
+    }
+    fn rv32f(&self, instr: &RV32FInstruction) -> String {
+        use RV32FInstruction::*;
+        let (x, f) = (|reg: u8| self.x(reg), |reg: u8| self.f(reg));
+        match *instr {
+            Flw(rd, rs1, imm) => return format!("flw {}, {}({})", f(rd), imm, x(rs1)),
+            Fsw(rs1, rs2, imm) => return format!("fsw {}, {}({})", f(rs2), imm, x(rs1)),
+            FmaddS(rd, rs1, rs2, rs3, rm) => return format!("fmadd.s {}, {}, {}, {}{}", f(rd), f(rs1), f(rs2), f(rs3), self.rm(rm)),
+            FmsubS(rd, rs1, rs2, rs3, rm) => return format!("fmsub.s {}, {}, {}, {}{}", f(rd), f(rs1), f(rs2), f(rs3), self.rm(rm)),
+            FnmsubS(rd, rs1, rs2, rs3, rm) => return format!("fnmsub.s {}, {}, {}, {}{}", f(rd), f(rs1), f(rs2), f(rs3), self.rm(rm)),
+            FnmaddS(rd, rs1, rs2, rs3, rm) => return format!("fnmadd.s {}, {}, {}, {}{}", f(rd), f(rs1), f(rs2), f(rs3), self.rm(rm)),
+            FaddS(rd, rs1, rs2, rm) => return format!("fadd.s {}, {}, {}{}", f(rd), f(rs1), f(rs2), self.rm(rm)),
+            FsubS(rd, rs1, rs2, rm) => return format!("fsub.s {}, {}, {}{}", f(rd), f(rs1), f(rs2), self.rm(rm)),
+            FmulS(rd, rs1, rs2, rm) => return format!("fmul.s {}, {}, {}{}", f(rd), f(rs1), f(rs2), self.rm(rm)),
+            FdivS(rd, rs1, rs2, rm) => return format!("fdiv.s {}, {}, {}{}", f(rd), f(rs1), f(rs2), self.rm(rm)),
+            FsqrtS(rd, rs1, rm) => return format!("fsqrt.s {}, {}{}", f(rd), f(rs1), self.rm(rm)),
+            FsgnjS(rd, rs1, rs2) if rs1 == rs2 => return format!("fmv.s {}, {}", f(rd), f(rs1)),
+            FsgnjnS(rd, rs1, rs2) if rs1 == rs2 => return format!("fneg.s {}, {}", f(rd), f(rs1)),
+            FsgnjxS(rd, rs1, rs2) if rs1 == rs2 => return format!("fabs.s {}, {}", f(rd), f(rs1)),
+            FsgnjS(rd, rs1, rs2) => return format!("fsgnj.s {}, {}, {}", f(rd), f(rs1), f(rs2)),
+            FsgnjnS(rd, rs1, rs2) => return format!("fsgnjn.s {}, {}, {}", f(rd), f(rs1), f(rs2)),
+            FsgnjxS(rd, rs1, rs2) => return format!("fsgnjx.s {}, {}, {}", f(rd), f(rs1), f(rs2)),
+            FminS(rd, rs1, rs2) => return format!("fmin.s {}, {}, {}", f(rd), f(rs1), f(rs2)),
+            FmaxS(rd, rs1, rs2) => return format!("fmax.s {}, {}, {}", f(rd), f(rs1), f(rs2)),
+            FcvtWS(rd, rs1, rm) => return format!("fcvt.w.s {}, {}{}", x(rd), f(rs1), self.rm(rm)),
+            FcvtWuS(rd, rs1, rm) => return format!("fcvt.wu.s {}, {}{}", x(rd), f(rs1), self.rm(rm)),
+            FmvXW(rd, rs1) => return format!("fmv.x.w {}, {}", x(rd), f(rs1)),
+            FeqS(rd, rs1, rs2) => return format!("feq.s {}, {}, {}", x(rd), f(rs1), f(rs2)),
+            FltS(rd, rs1, rs2) => return format!("flt.s {}, {}, {}", x(rd), f(rs1), f(rs2)),
+            FleS(rd, rs1, rs2) => return format!("fle.s {}, {}, {}", x(rd), f(rs1), f(rs2)),
+            FclassS(rd, rs1) => return format!("fclass.s {}, {}", x(rd), f(rs1)),
+            FcvtSW(rd, rs1, rm) => return format!("fcvt.s.w {}, {}{}", f(rd), x(rs1), self.rm(rm)),
+            FcvtSWu(rd, rs1, rm) => return format!("fcvt.s.wu {}, {}{}", f(rd), x(rs1), self.rm(rm)),
+            FmvWX(rd, rs1) => return format!("fmv.w.x {}, {}", f(rd), x(rs1)),
+        }
+    }
+    fn rv32d(&self, instr: &RV32DInstruction) -> String {
+        use RV32DInstruction::*;
+        let (x, f) = (|reg: u8| self.x(reg), |reg: u8| self.f(reg));
+        match *instr {
+            Fld(rd, rs1, imm) => return format!("fld {}, {}({})", f(rd), imm, x(rs1)),
+            Fsd(rs1, rs2, imm) => return format!("fsd {}, {}({})", f(rs2), imm, x(rs1)),
+            FmaddD(rd, rs1, rs2, rs3, rm) => return format!("fmadd.d {}, {}, {}, {}{}", f(rd), f(rs1), f(rs2), f(rs3), self.rm(rm)),
+            FmsubD(rd, rs1, rs2, rs3, rm) => return format!("fmsub.d {}, {}, {}, {}{}", f(rd), f(rs1), f(rs2), f(rs3), self.rm(rm)),
+            FnmsubD(rd, rs1, rs2, rs3, rm) => return format!("fnmsub.d {}, {}, {}, {}{}", f(rd), f(rs1), f(rs2), f(rs3), self.rm(rm)),
+            FnmaddD(rd, rs1, rs2, rs3, rm) => return format!("fnmadd.d {}, {}, {}, {}{}", f(rd), f(rs1), f(rs2), f(rs3), self.rm(rm)),
+            FaddD(rd, rs1, rs2, rm) => return format!("fadd.d {}, {}, {}{}", f(rd), f(rs1), f(rs2), self.rm(rm)),
+            FsubD(rd, rs1, rs2, rm) => return format!("fsub.d {}, {}, {}{}", f(rd), f(rs1), f(rs2), self.rm(rm)),
+            FmulD(rd, rs1, rs2, rm) => return format!("fmul.d {}, {}, {}{}", f(rd), f(rs1), f(rs2), self.rm(rm)),
+            FdivD(rd, rs1, rs2, rm) => return format!("fdiv.d {}, {}, {}{}", f(rd), f(rs1), f(rs2), self.rm(rm)),
+            FsqrtD(rd, rs1, rm) => return format!("fsqrt.d {}, {}{}", f(rd), f(rs1), self.rm(rm)),
+            FsgnjD(rd, rs1, rs2) if rs1 == rs2 => return format!("fmv.d {}, {}", f(rd), f(rs1)),
+            FsgnjnD(rd, rs1, rs2) if rs1 == rs2 => return format!("fneg.d {}, {}", f(rd), f(rs1)),
+            FsgnjxD(rd, rs1, rs2) if rs1 == rs2 => return format!("fabs.d {}, {}", f(rd), f(rs1)),
+            FsgnjD(rd, rs1, rs2) => return format!("fsgnj.d {}, {}, {}", f(rd), f(rs1), f(rs2)),
+            FsgnjnD(rd, rs1, rs2) => return format!("fsgnjn.d {}, {}, {}", f(rd), f(rs1), f(rs2)),
+            FsgnjxD(rd, rs1, rs2) => return format!("fsgnjx.d {}, {}, {}", f(rd), f(rs1), f(rs2)),
+            FminD(rd, rs1, rs2) => return format!("fmin.d {}, {}, {}", f(rd), f(rs1), f(rs2)),
+            FmaxD(rd, rs1, rs2) => return format!("fmax.d {}, {}, {}", f(rd), f(rs1), f(rs2)),
+            FcvtSD(rd, rs1, rm) => return format!("fcvt.s.d {}, {}{}", f(rd), f(rs1), self.rm(rm)),
+            FcvtDS(rd, rs1, _) => return format!("fcvt.d.s {}, {}", f(rd), f(rs1)), // exact, the rounding mode is ignored
+            FeqD(rd, rs1, rs2) => return format!("feq.d {}, {}, {}", x(rd), f(rs1), f(rs2)),
+            FltD(rd, rs1, rs2) => return format!("flt.d {}, {}, {}", x(rd), f(rs1), f(rs2)),
+            FleD(rd, rs1, rs2) => return format!("fle.d {}, {}, {}", x(rd), f(rs1), f(rs2)),
+            FclassD(rd, rs1) => return format!("fclass.d {}, {}", x(rd), f(rs1)),
+            FcvtWD(rd, rs1, rm) => return format!("fcvt.w.d {}, {}{}", x(rd), f(rs1), self.rm(rm)),
+            FcvtWuD(rd, rs1, rm) => return format!("fcvt.wu.d {}, {}{}", x(rd), f(rs1), self.rm(rm)),
+            FcvtDW(rd, rs1, _) => return format!("fcvt.d.w {}, {}", f(rd), x(rs1)),
+            FcvtDWu(rd, rs1, _) => return format!("fcvt.d.wu {}, {}", f(rd), x(rs1)),
+        }
+    }
+    fn zicsr(&self, instr: &RV32ZicsrInstruction) -> String {
+        use RV32ZicsrInstruction::*;
+        let (x, csr) = (|reg: u8| self.x(reg), |csr: u16| self.csr(csr));
+        match *instr {
+            Csrrs(rd, 0, number) => return format!("csrr {}, {}", x(rd), csr(number)),
+            Csrrw(0, rs1, number) => return format!("csrw {}, {}", csr(number), x(rs1)),
+            Csrrs(0, rs1, number) => return format!("csrs {}, {}", csr(number), x(rs1)),
+            Csrrc(0, rs1, number) => return format!("csrc {}, {}", csr(number), x(rs1)),
+            Csrrwi(0, zimm, number) => return format!("csrwi {}, {}", csr(number), zimm),
+            Csrrsi(0, zimm, number) => return format!("csrsi {}, {}", csr(number), zimm),
+            Csrrci(0, zimm, number) => return format!("csrci {}, {}", csr(number), zimm),
+            Csrrw(rd, rs1, number) => return format!("csrrw {}, {}, {}", x(rd), csr(number), x(rs1)),
+            Csrrs(rd, rs1, number) => return format!("csrrs {}, {}, {}", x(rd), csr(number), x(rs1)),
+            Csrrc(rd, rs1, number) => return format!("csrrc {}, {}, {}", x(rd), csr(number), x(rs1)),
+            Csrrwi(rd, zimm, number) => return format!("csrrwi {}, {}, {}", x(rd), csr(number), zimm),
+            Csrrsi(rd, zimm, number) => return format!("csrrsi {}, {}, {}", x(rd), csr(number), zimm),
+            Csrrci(rd, zimm, number) => return format!("csrrci {}, {}, {}", x(rd), csr(number), zimm),
+        }
+    }
+    fn instruction(&self, instr: &RV32Instruction) -> String {
+        match instr {
+            RV32Instruction::Unknown => return String::from("unknown"),
+            RV32Instruction::Nop => return String::from("nop"),
+            RV32Instruction::RV32I(instr) => return self.rv32i(instr),
+            RV32Instruction::RV32M(instr) => return self.rv32m(instr),
+            RV32Instruction::RV32A(instr) => return self.rv32a(instr),
+            RV32Instruction::RV32F(instr) => return self.rv32f(instr),
+            RV32Instruction::RV32D(instr) => return self.rv32d(instr),
+            RV32Instruction::RV32Ziscr(instr) => return self.zicsr(instr),
+            RV32Instruction::TrapReturn(TrapRetInstruction::Sret) => return String::from("sret"),
+            RV32Instruction::TrapReturn(TrapRetInstruction::Mret) => return String::from("mret"),
+            RV32Instruction::TrapReturn(TrapRetInstruction::Wfi) => return String::from("wfi"),
+            RV32Instruction::Mmu(MmuInstruction::SfenceVma(0, 0)) => return String::from("sfence.vma"),
+            RV32Instruction::Mmu(MmuInstruction::SfenceVma(rs1, 0)) => return format!("sfence.vma {}", self.x(*rs1)),
+            RV32Instruction::Mmu(MmuInstruction::SfenceVma(rs1, rs2)) => return format!("sfence.vma {}, {}", self.x(*rs1), self.x(*rs2)),
+        }
+    }
+}
+
+/// Assembly for `instr` found at `pc`, with pseudo-instructions where they apply and branch targets resolved against `symbols`.
+pub fn disassemble(instr: &RV32Instruction, pc: u32, names: RegisterNames, symbols: &elf::Symbols) -> String {
+    let disassembler: Disassembler = Disassembler {
+        pc: pc,
+        names: names,
+        symbols: symbols,
+    };
+    return disassembler.instruction(instr);
+}
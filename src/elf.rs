@@ -0,0 +1,286 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::cpu;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+// e_flags
+const EF_RISCV_RVC: u32 = 0x0001;
+const EF_RISCV_FLOAT_ABI: u32 = 0x0006; // 0 = soft, 2 = single, 4 = double, 6 = quad
+const EF_RISCV_RVE: u32 = 0x0008;
+
+#[derive(Debug)]
+pub enum ElfError {
+    NotElf,
+    Truncated,
+    Unsupported(&'static str), // a valid ELF we can't run, e.g. a 64-bit or big-endian one
+    NeedsExtension(char), // built for an extension that isn't enabled in `misa`
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::Truncated => write!(f, "ELF file is truncated"),
+            ElfError::Unsupported(what) => write!(f, "unsupported ELF file: {}", what),
+            ElfError::NeedsExtension(ext) => write!(f, "ELF file needs the {} extension, which is disabled", ext),
+        }
+    }
+}
+
+/// A PT_LOAD segment: `data` goes at `paddr`, followed by `memsz - data.len()` zeroes of BSS.
+pub struct Segment {
+    pub paddr: u32,
+    pub data: Vec<u8>,
+    pub memsz: u32,
+}
+
+/// Function and object symbols of the loaded program, for traces and the debugger.
+#[derive(Default)]
+pub struct Symbols {
+    by_address: BTreeMap<u32, (String, u32)>, // address -> (name, size)
+    by_name: HashMap<String, u32>,
+}
+
+#[allow(dead_code)]
+impl Symbols {
+    pub fn is_empty(&self) -> bool {
+        return self.by_address.is_empty();
+    }
+    pub fn len(&self) -> usize {
+        return self.by_address.len();
+    }
+    pub fn insert(&mut self, name: String, address: u32, size: u32) {
+        self.by_name.insert(name.clone(), address);
+        self.by_address.insert(address, (name, size));
+    }
+    pub fn address(&self, name: &str) -> Option<u32> {
+        return self.by_name.get(name).copied();
+    }
+    /// Symbol covering `address` and the offset into it; symbols without a size cover everything up to the next one.
+    pub fn lookup(&self, address: u32) -> Option<(&str, u32)> {
+        let (start, (name, size)) = self.by_address.range(..=address).next_back()?;
+        let offset: u32 = address - start;
+        if *size != 0 && offset >= *size {
+            return None;
+        }
+        return Some((name.as_str(), offset));
+    }
+    /// `<name+0x10>` for traces, empty if nothing covers `address`.
+    pub fn describe(&self, address: u32) -> String {
+        match self.lookup(address) {
+            Some((name, 0)) => return format!(" <{}>", name),
+            Some((name, offset)) => return format!(" <{}+0x{:X}>", name, offset),
+            None => return String::new(),
+        }
+    }
+}
+
+pub struct Elf {
+    pub entry: u32,
+    pub flags: u32,
+    pub segments: Vec<Segment>,
+    pub symbols: Symbols,
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    return bytes.len() >= 4 && bytes[0..4] == ELF_MAGIC;
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let field: &[u8] = bytes.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    return Ok(u16::from_le_bytes([field[0], field[1]]));
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let field: &[u8] = bytes.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    return Ok(u32::from_le_bytes([field[0], field[1], field[2], field[3]]));
+}
+
+fn slice_at(bytes: &[u8], offset: u32, size: u32) -> Result<&[u8], ElfError> {
+    return bytes.get(offset as usize..offset as usize + size as usize).ok_or(ElfError::Truncated);
+}
+
+fn c_str_at(bytes: &[u8], offset: usize) -> Result<String, ElfError> {
+    let tail: &[u8] = bytes.get(offset..).ok_or(ElfError::Truncated)?;
+    let len: usize = tail.iter().position(|b| *b == 0).ok_or(ElfError::Truncated)?;
+    return Ok(String::from_utf8_lossy(&tail[..len]).into_owned());
+}
+
+/// Parses a little-endian ELF32 RISC-V executable.
+pub fn parse(bytes: &[u8]) -> Result<Elf, ElfError> {
+    if !is_elf(bytes) {
+        return Err(ElfError::NotElf);
+    }
+    if bytes.len() < 52 {
+        return Err(ElfError::Truncated);
+    }
+    if bytes[4] != ELFCLASS32 {
+        return Err(ElfError::Unsupported("not a 32-bit file"));
+    }
+    if bytes[5] != ELFDATA2LSB {
+        return Err(ElfError::Unsupported("not little-endian"));
+    }
+    if u16_at(bytes, 16)? != ET_EXEC {
+        return Err(ElfError::Unsupported("not an executable"));
+    }
+    if u16_at(bytes, 18)? != EM_RISCV {
+        return Err(ElfError::Unsupported("not a RISC-V file"));
+    }
+    let entry: u32 = u32_at(bytes, 24)?;
+    let (phoff, shoff, flags): (u32, u32, u32) = (u32_at(bytes, 28)?, u32_at(bytes, 32)?, u32_at(bytes, 36)?);
+    let (phentsize, phnum): (u16, u16) = (u16_at(bytes, 42)?, u16_at(bytes, 44)?);
+    let (shentsize, shnum): (u16, u16) = (u16_at(bytes, 46)?, u16_at(bytes, 48)?);
+
+    let mut segments: Vec<Segment> = Vec::new();
+    for i in 0..phnum as usize {
+        let ph: usize = phoff as usize + i * phentsize as usize;
+        if u32_at(bytes, ph)? != PT_LOAD {
+            continue;
+        }
+        let (offset, paddr, filesz, memsz): (u32, u32, u32, u32) = (u32_at(bytes, ph + 4)?, u32_at(bytes, ph + 12)?, u32_at(bytes, ph + 16)?, u32_at(bytes, ph + 20)?);
+        if filesz > memsz {
+            return Err(ElfError::Unsupported("segment is larger in the file than in memory"));
+        }
+        if memsz == 0 {
+            continue;
+        }
+        segments.push(Segment {
+            paddr: paddr,
+            data: slice_at(bytes, offset, filesz)?.to_vec(),
+            memsz: memsz,
+        });
+    }
+
+    let mut symbols: Symbols = Symbols::default();
+    for i in 0..shnum as usize {
+        let sh: usize = shoff as usize + i * shentsize as usize;
+        if u32_at(bytes, sh + 4)? != SHT_SYMTAB {
+            continue;
+        }
+        let (offset, size, link, entsize): (u32, u32, u32, u32) = (u32_at(bytes, sh + 16)?, u32_at(bytes, sh + 20)?, u32_at(bytes, sh + 24)?, u32_at(bytes, sh + 36)?);
+        let strtab_sh: usize = shoff as usize + link as usize * shentsize as usize;
+        let strtab: &[u8] = slice_at(bytes, u32_at(bytes, strtab_sh + 16)?, u32_at(bytes, strtab_sh + 20)?)?;
+        let symtab: &[u8] = slice_at(bytes, offset, size)?;
+        for sym in symtab.chunks_exact(entsize.max(16) as usize) {
+            let kind: u8 = sym[12] & 0xF;
+            let shndx: u16 = u16_at(sym, 14)?;
+            if !matches!(kind, STT_NOTYPE | STT_OBJECT | STT_FUNC) || shndx == 0 { // skip sections, files and undefined symbols
+                continue;
+            }
+            let name: String = c_str_at(strtab, u32_at(sym, 0)? as usize)?;
+            if name.is_empty() || name.starts_with(".L") || name.starts_with('$') { // local labels and mapping symbols
+                continue;
+            }
+            symbols.insert(name, u32_at(sym, 4)?, u32_at(sym, 8)?);
+        }
+    }
+
+    return Ok(Elf {
+        entry: entry,
+        flags: flags,
+        segments: segments,
+        symbols: symbols,
+    });
+}
+
+impl Elf {
+    /// Checks `e_flags` against the extensions enabled in `misa`.
+    pub fn validate(&self, misa: u32) -> Result<(), ElfError> {
+        if self.flags & EF_RISCV_RVE != 0 {
+            return Err(ElfError::Unsupported("built for RV32E"));
+        }
+        if self.flags & EF_RISCV_RVC != 0 && misa & cpu::MISA_C == 0 {
+            return Err(ElfError::NeedsExtension('C'));
+        }
+        match self.flags & EF_RISCV_FLOAT_ABI {
+            0x2 if misa & cpu::MISA_F == 0 => return Err(ElfError::NeedsExtension('F')),
+            0x4 if misa & cpu::MISA_D == 0 => return Err(ElfError::NeedsExtension('D')),
+            0x6 => return Err(ElfError::NeedsExtension('Q')),
+            _ => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, ElfError};
+    use crate::cpu;
+
+    const ENTRY: u32 = 0x8000_0000;
+
+    type Corrupt = fn(&mut Vec<u8>);
+
+    /// A minimal executable: the ELF header, one PT_LOAD program header and 4 bytes of code with 4 more of BSS.
+    fn image() -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![0x7F, b'E', b'L', b'F', 1, 1, 1];
+        bytes.resize(16, 0);
+        for half in [2u16, 243] { // e_type, e_machine
+            bytes.extend_from_slice(&half.to_le_bytes());
+        }
+        for word in [1u32, ENTRY, 52, 0, 0] { // e_version, e_entry, e_phoff, e_shoff, e_flags
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        for half in [52u16, 32, 1, 40, 0, 0] { // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+            bytes.extend_from_slice(&half.to_le_bytes());
+        }
+        for word in [1u32, 84, ENTRY, ENTRY, 4, 8, 5, 4] { // PT_LOAD at offset 84, 4 bytes in the file and 8 in memory
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.extend_from_slice(&0x0000_0013u32.to_le_bytes()); // nop
+        return bytes;
+    }
+
+    #[test]
+    fn loads_segments() {
+        let elf: super::Elf = parse(&image()).unwrap();
+        assert_eq!((elf.entry, elf.segments.len()), (ENTRY, 1));
+        assert_eq!((elf.segments[0].paddr, elf.segments[0].data.as_slice(), elf.segments[0].memsz), (ENTRY, &[0x13, 0, 0, 0][..], 8));
+        assert!(elf.symbols.is_empty());
+        assert!(elf.validate(cpu::MISA_EXTENSIONS).is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_images() {
+        let cases: [(&str, Corrupt, &str); 9] = [
+            ("not elf", |b| b[1] = b'X', "not an ELF file"),
+            ("header cut short", |b| b.truncate(40), "ELF file is truncated"),
+            ("segment cut short", |b| b.truncate(86), "ELF file is truncated"),
+            ("program headers past the end", |b| b[44] = 200, "ELF file is truncated"),
+            ("64-bit", |b| b[4] = 2, "unsupported ELF file: not a 32-bit file"),
+            ("big-endian", |b| b[5] = 2, "unsupported ELF file: not little-endian"),
+            ("shared object", |b| b[16] = 3, "unsupported ELF file: not an executable"),
+            ("x86-64", |b| b[18] = 62, "unsupported ELF file: not a RISC-V file"),
+            ("filesz above memsz", |b| b[52 + 20] = 2, "unsupported ELF file: segment is larger in the file than in memory"),
+        ];
+        for (name, corrupt, expect) in cases {
+            let mut bytes: Vec<u8> = image();
+            corrupt(&mut bytes);
+            match parse(&bytes) {
+                Ok(_) => panic!("{}: parsed", name),
+                Err(e) => assert_eq!(e.to_string(), expect, "{}", name),
+            }
+        }
+    }
+
+    #[test]
+    fn flags_need_extensions() {
+        let mut bytes: Vec<u8> = image();
+        bytes[36] = 0x1 | 0x4; // RVC, double-float ABI
+        let elf: super::Elf = parse(&bytes).unwrap();
+        assert!(matches!(elf.validate(cpu::MISA_EXTENSIONS & !cpu::MISA_C), Err(ElfError::NeedsExtension('C'))));
+        assert!(matches!(elf.validate(cpu::MISA_EXTENSIONS & !cpu::MISA_D), Err(ElfError::NeedsExtension('D'))));
+        assert!(elf.validate(cpu::MISA_EXTENSIONS).is_ok());
+    }
+}
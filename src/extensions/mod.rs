@@ -1,6 +1,8 @@
 pub mod rv32i;
 pub mod rv32m;
 pub mod rv32a;
+pub mod rv32f;
+pub mod rv32d;
 pub mod rv32zicsr;
 
 use crate::cpu;
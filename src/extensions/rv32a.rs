@@ -1,119 +1,237 @@
 use crate::cpu;
 use crate::extensions::Execute;
+use crate::mmu;
 use crate::trap;
 
+// the last two fields are the aq and rl bits, a single in-order hart already satisfies both orderings
 #[allow(dead_code)]
-#[derive(Debug)] // [ ] implement debug
-pub enum RV32AInstruction { // temporary implementation
-    ScW(u8, u8, u8),
-    AmoswapW(u8, u8, u8),
-    AmoaddW(u8, u8, u8),
-    AmoxorW(u8, u8, u8),
-    AmoandW(u8, u8, u8),
-    AmoorW(u8, u8, u8),
-    AmominW(u8, u8, u8),
-    AmomaxW(u8, u8, u8),
-    AmominuW(u8, u8, u8),
-    AmomaxuW(u8, u8, u8),
+#[derive(Debug)]
+pub enum RV32AInstruction {
+    LrW(u8, u8, bool, bool),
+    ScW(u8, u8, u8, bool, bool),
+    AmoswapW(u8, u8, u8, bool, bool),
+    AmoaddW(u8, u8, u8, bool, bool),
+    AmoxorW(u8, u8, u8, bool, bool),
+    AmoandW(u8, u8, u8, bool, bool),
+    AmoorW(u8, u8, u8, bool, bool),
+    AmominW(u8, u8, u8, bool, bool),
+    AmomaxW(u8, u8, u8, bool, bool),
+    AmominuW(u8, u8, u8, bool, bool),
+    AmomaxuW(u8, u8, u8, bool, bool),
+}
+
+fn aligned(cpu: &mut cpu::RiscV32, address: u32, code: trap::Trap) -> Option<trap::Trap> {
+    if address & 0x3 != 0 { // atomics are never split, so misaligned ones trap instead of being emulated
+        return Some(trap::Trap::take(code, cpu, address));
+    }
+    return None;
+}
+
+fn amo(cpu: &mut cpu::RiscV32, rd: u8, rs1: u8, rs2: u8, op: fn(u32, u32) -> u32) -> Option<trap::Trap> {
+    let address: u32 = cpu.regs.read(rs1);
+    if let Some(trap) = aligned(cpu, address, trap::Trap::MisalignedStoreAddr) {
+        return Some(trap);
+    }
+    let paddr: u32 = match mmu::translate(cpu, address, mmu::Access::Store) { // AMOs need write permission
+        Ok(paddr) => paddr,
+        Err(trap) => return Some(trap),
+    };
+    let t: u32 = match cpu.bus.read(paddr, 4) {
+        Some(data) => data as u32,
+        None => return Some(trap::Trap::take(trap::Trap::StoreAccessFault, cpu, address)),
+    };
+    let data: u32 = op(t, cpu.regs.read(rs2));
+    if cpu.bus.write(paddr, 4, data as u64).is_none() {
+        return Some(trap::Trap::take(trap::Trap::StoreAccessFault, cpu, address));
+    }
+    cpu.loaded(address, 4);
+    cpu.stored(address, paddr, 4);
+    cpu.regs.write(rd, t);
+    return None;
 }
 
 impl Execute for RV32AInstruction {
     fn execute(self, cpu: &mut cpu::RiscV32) -> Option<trap::Trap> {
         match self {
-            RV32AInstruction::ScW(rd, rs1, rs2) => {
-                let address: u32 = cpu.regs.read(rs1);
-                cpu.mem.write_word(address as usize, cpu.regs.read(rs2));
-                cpu.regs.write(rd, 0);
-                return None;
-            },
-            RV32AInstruction::AmoswapW(rd, rs1, rs2) => {
-                let address: u32 = cpu.regs.read(rs1);
-                let t: u32 = cpu.mem.read_word(address as usize);
-                let data: u32 = cpu.regs.read(rs2);
-                cpu.mem.write_word(address as usize, data);
-                cpu.regs.write(rd, t);
-                return None;
-            },
-            RV32AInstruction::AmoaddW(rd, rs1, rs2) => {
-                let address: u32 = cpu.regs.read(rs1);
-                let t: u32 = cpu.mem.read_word(address as usize);
-                let data: u32 = cpu.regs.read(rs2) + t;
-                cpu.mem.write_word(address as usize, data);
-                cpu.regs.write(rd, t);
-                return None;
-            },
-            RV32AInstruction::AmoxorW(rd, rs1, rs2) => {
-                let address: u32 = cpu.regs.read(rs1);
-                let t: u32 = cpu.mem.read_word(address as usize);
-                let data: u32 = cpu.regs.read(rs2) ^ t;
-                cpu.mem.write_word(address as usize, data);
-                cpu.regs.write(rd, t);
-                return None;
-            },
-            RV32AInstruction::AmoandW(rd, rs1, rs2) => {
-                let address: u32 = cpu.regs.read(rs1);
-                let t: u32 = cpu.mem.read_word(address as usize);
-                let data: u32 = cpu.regs.read(rs2) & t;
-                cpu.mem.write_word(address as usize, data);
-                cpu.regs.write(rd, t);
-                return None;
-            },
-            RV32AInstruction::AmoorW(rd, rs1, rs2) => {
+            RV32AInstruction::LrW(rd, rs1, ..) => {
                 let address: u32 = cpu.regs.read(rs1);
-                let t: u32 = cpu.mem.read_word(address as usize);
-                let data: u32 = cpu.regs.read(rs2) | t;
-                cpu.mem.write_word(address as usize, data);
-                cpu.regs.write(rd, t);
-                return None;
-            },
-            RV32AInstruction::AmominW(rd, rs1, rs2) => {
-                let address: u32 = cpu.regs.read(rs1);
-                let t: u32 = cpu.mem.read_word(address as usize);
-                let data: u32 = if (cpu.regs.read(rs2) as i32) < (t as i32) {
-                    cpu.regs.read(rs2)
-                } else {
-                    t
+                if let Some(trap) = aligned(cpu, address, trap::Trap::MisalignedLoadAddr) {
+                    return Some(trap);
+                }
+                let paddr: u32 = match mmu::translate(cpu, address, mmu::Access::Load) {
+                    Ok(paddr) => paddr,
+                    Err(trap) => return Some(trap),
                 };
-                cpu.mem.write_word(address as usize, data);
-                cpu.regs.write(rd, t);
-                return None;
-            },
-            RV32AInstruction::AmomaxW(rd, rs1, rs2) => {
-                let address: u32 = cpu.regs.read(rs1);
-                let t: u32 = cpu.mem.read_word(address as usize);
-                let data: u32 = if (cpu.regs.read(rs2) as i32) > (t as i32) {
-                    cpu.regs.read(rs2)
-                } else {
-                    t
+                let data: u32 = match cpu.bus.read(paddr, 4) {
+                    Some(data) => data as u32,
+                    None => return Some(trap::Trap::take(trap::Trap::LoadAccessFault, cpu, address)),
                 };
-                cpu.mem.write_word(address as usize, data);
-                cpu.regs.write(rd, t);
+                cpu.loaded(address, 4);
+                cpu.reservation = cpu::Reservation { address: paddr, valid: true };
+                cpu.regs.write(rd, data);
                 return None;
             },
-            RV32AInstruction::AmominuW(rd, rs1, rs2) => {
+            RV32AInstruction::ScW(rd, rs1, rs2, ..) => {
                 let address: u32 = cpu.regs.read(rs1);
-                let t: u32 = cpu.mem.read_word(address as usize);
-                let data: u32 = if cpu.regs.read(rs2) < t {
-                    cpu.regs.read(rs2)
-                } else {
-                    t
+                if let Some(trap) = aligned(cpu, address, trap::Trap::MisalignedStoreAddr) {
+                    return Some(trap);
+                }
+                let paddr: u32 = match mmu::translate(cpu, address, mmu::Access::Store) {
+                    Ok(paddr) => paddr,
+                    Err(trap) => return Some(trap),
                 };
-                cpu.mem.write_word(address as usize, data);
-                cpu.regs.write(rd, t);
-                return None;
-            },
-            RV32AInstruction::AmomaxuW(rd, rs1, rs2) => {
-                let address: u32 = cpu.regs.read(rs1);
-                let t: u32 = cpu.mem.read_word(address as usize);
-                let data: u32 = if cpu.regs.read(rs2) > t {
-                    cpu.regs.read(rs2)
-                } else {
-                    t
-                };
-                cpu.mem.write_word(address as usize, data);
-                cpu.regs.write(rd, t);
+                let reserved: bool = cpu.reservation.valid && cpu.reservation.address == paddr;
+                cpu.reservation.valid = false; // an SC always consumes the reservation, whether it succeeds or not
+                if !reserved {
+                    cpu.regs.write(rd, 1);
+                    return None;
+                }
+                if cpu.bus.write(paddr, 4, cpu.regs.read(rs2) as u64).is_none() {
+                    return Some(trap::Trap::take(trap::Trap::StoreAccessFault, cpu, address));
+                }
+                cpu.stored(address, paddr, 4); // a failed SC doesn't write, so only a successful one is seen
+                cpu.regs.write(rd, 0);
                 return None;
             },
+            RV32AInstruction::AmoswapW(rd, rs1, rs2, ..) => return amo(cpu, rd, rs1, rs2, |_, data| data),
+            RV32AInstruction::AmoaddW(rd, rs1, rs2, ..) => return amo(cpu, rd, rs1, rs2, |t, data| data.wrapping_add(t)),
+            RV32AInstruction::AmoxorW(rd, rs1, rs2, ..) => return amo(cpu, rd, rs1, rs2, |t, data| data ^ t),
+            RV32AInstruction::AmoandW(rd, rs1, rs2, ..) => return amo(cpu, rd, rs1, rs2, |t, data| data & t),
+            RV32AInstruction::AmoorW(rd, rs1, rs2, ..) => return amo(cpu, rd, rs1, rs2, |t, data| data | t),
+            RV32AInstruction::AmominW(rd, rs1, rs2, ..) => return amo(cpu, rd, rs1, rs2, |t, data| (data as i32).min(t as i32) as u32),
+            RV32AInstruction::AmomaxW(rd, rs1, rs2, ..) => return amo(cpu, rd, rs1, rs2, |t, data| (data as i32).max(t as i32) as u32),
+            RV32AInstruction::AmominuW(rd, rs1, rs2, ..) => return amo(cpu, rd, rs1, rs2, |t, data| data.min(t)),
+            RV32AInstruction::AmomaxuW(rd, rs1, rs2, ..) => return amo(cpu, rd, rs1, rs2, |t, data| data.max(t)),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu;
+    use crate::gdb;
+    use crate::htif;
+    use crate::testing::{self, asm, DATA};
+    use crate::trap;
+
+    type Amo = fn(u8, u8, u8) -> u32;
+
+    /// `code` with x1 pointing at DATA, which holds `memory`, and x2 = `operand`.
+    fn run(code: &[u32], memory: u32, operand: u32) -> (cpu::RiscV32, Option<trap::Trap>) {
+        let mut cpu: cpu::RiscV32 = testing::program(code);
+        testing::write_word(&mut cpu, DATA, memory);
+        cpu.regs.write(1, DATA);
+        cpu.regs.write(2, operand);
+        let trap: Option<trap::Trap> = testing::run(&mut cpu, code.len());
+        return (cpu, trap);
+    }
+
+    #[test]
+    fn amos() {
+        let cases: [(&str, Amo, u32, u32, u32); 13] = [
+            // name, instruction, memory, x2, memory afterwards
+            ("amoswap.w", asm::amoswap_w, 0x1111_1111, 0x2222_2222, 0x2222_2222),
+            ("amoadd.w", asm::amoadd_w, 0xFFFF_FFFF, 2, 1),
+            ("amoxor.w", asm::amoxor_w, 0xFF00_FF00, 0x0FF0_0FF0, 0xF0F0_F0F0),
+            ("amoand.w", asm::amoand_w, 0xFF00_FF00, 0x0FF0_0FF0, 0x0F00_0F00),
+            ("amoor.w", asm::amoor_w, 0xFF00_FF00, 0x0FF0_0FF0, 0xFFF0_FFF0),
+            ("amomin.w", asm::amomin_w, 0xFFFF_FFFF, 1, 0xFFFF_FFFF),
+            ("amomin.w", asm::amomin_w, 0x8000_0000, 0x7FFF_FFFF, 0x8000_0000),
+            ("amomax.w", asm::amomax_w, 0xFFFF_FFFF, 1, 1),
+            ("amomax.w", asm::amomax_w, 0x8000_0000, 0x7FFF_FFFF, 0x7FFF_FFFF),
+            ("amominu.w", asm::amominu_w, 0xFFFF_FFFF, 1, 1),
+            ("amominu.w", asm::amominu_w, 0x8000_0000, 0x7FFF_FFFF, 0x7FFF_FFFF),
+            ("amomaxu.w", asm::amomaxu_w, 0xFFFF_FFFF, 1, 0xFFFF_FFFF),
+            ("amomaxu.w", asm::amomaxu_w, 0x8000_0000, 0x7FFF_FFFF, 0x8000_0000),
+        ];
+        for (name, op, memory, operand, expected) in cases {
+            let (mut cpu, trap) = run(&[op(3, 1, 2)], memory, operand);
+            assert_eq!(trap, None, "{}", name);
+            assert_eq!(testing::read_word(&mut cpu, DATA), expected, "{} 0x{:08X}, 0x{:08X}", name, memory, operand);
+            assert_eq!(cpu.regs.read(3), memory, "{} returns the old value", name);
+        }
+
+        // rd = rs2: the operand is read before the old value lands in the register
+        let (mut cpu, _) = run(&[asm::amoadd_w(2, 1, 2)], 5, 6);
+        assert_eq!((cpu.regs.read(2), testing::read_word(&mut cpu, DATA)), (5, 11));
+        let (mut cpu, _) = run(&[asm::amoadd_w(0, 1, 2)], 5, 6);
+        assert_eq!((cpu.regs.read(0), testing::read_word(&mut cpu, DATA)), (0, 11), "rd = x0 still updates memory");
+    }
+
+    #[test]
+    fn lr_sc() {
+        let (mut cpu, trap) = run(&[asm::lr_w(3, 1), asm::addi(3, 3, 1), asm::sc_w(4, 1, 3)], 41, 0);
+        assert_eq!(trap, None);
+        assert_eq!((cpu.regs.read(4), testing::read_word(&mut cpu, DATA)), (0, 42), "sc.w after lr.w succeeds");
+        assert!(!cpu.reservation.valid, "and uses up the reservation");
+
+        let (mut cpu, _) = run(&[asm::sc_w(4, 1, 2)], 41, 7);
+        assert_eq!((cpu.regs.read(4), testing::read_word(&mut cpu, DATA)), (1, 41), "sc.w without lr.w fails");
+
+        let (mut cpu, _) = run(&[asm::lr_w(3, 1), asm::sc_w(4, 1, 2), asm::sc_w(5, 1, 2)], 41, 7);
+        assert_eq!((cpu.regs.read(4), cpu.regs.read(5), testing::read_word(&mut cpu, DATA)), (0, 1, 7), "a second sc.w fails");
+
+        let (mut cpu, _) = run(&[asm::lr_w(3, 1), asm::sb(0, 1, 3), asm::sc_w(4, 1, 2)], 41, 7);
+        assert_eq!((cpu.regs.read(4), testing::read_word(&mut cpu, DATA)), (1, 41), "a store to the reserved word breaks the reservation");
+
+        let (mut cpu, _) = run(&[asm::lr_w(3, 1), asm::sw(0, 1, 4), asm::sc_w(4, 1, 2)], 41, 7);
+        assert_eq!((cpu.regs.read(4), testing::read_word(&mut cpu, DATA)), (0, 7), "a store next to it doesn't");
+
+        let (mut cpu, _) = run(&[asm::lr_w(3, 1), asm::amoswap_w(0, 1, 0), asm::sc_w(4, 1, 2)], 41, 7);
+        assert_eq!((cpu.regs.read(4), testing::read_word(&mut cpu, DATA)), (1, 0), "an AMO to the reserved word breaks it too");
+
+        let (mut cpu, _) = run(&[asm::lr_w(3, 1), asm::addi(1, 1, 4), asm::sc_w(4, 1, 2)], 41, 7);
+        assert_eq!((cpu.regs.read(4), testing::read_word(&mut cpu, DATA + 4)), (1, 0), "sc.w to another address fails");
+
+        // a successful sc.w is a store like any other to HTIF, which then takes the exit request it holds
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::lr_w(3, 1), asm::sc_w(4, 1, 2), asm::jal(0, 0)]);
+        cpu.htif = Some(htif::Htif::new(DATA, None, None));
+        cpu.regs.write(1, DATA);
+        cpu.regs.write(2, 3 << 1 | 1);
+        assert_eq!(testing::run(&mut cpu, 64), None);
+        assert_eq!((cpu.regs.read(4), cpu.status, cpu.exit_code), (0, false, 3));
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::sc_w(4, 1, 2), asm::jal(0, 0)]);
+        cpu.htif = Some(htif::Htif::new(DATA, None, None));
+        cpu.regs.write(1, DATA);
+        cpu.regs.write(2, 3 << 1 | 1);
+        testing::write_word(&mut cpu, DATA, 3 << 1 | 1); // already there, a store is what gets it noticed
+        assert_eq!(testing::run(&mut cpu, 64), None);
+        assert_eq!((cpu.regs.read(4), cpu.status), (1, true), "a failed one isn't");
+    }
+
+    #[test]
+    fn watchpoints() {
+        let cases: [(&str, &[u32], gdb::WatchKind, bool); 7] = [
+            ("lr.w", &[asm::lr_w(3, 1)], gdb::WatchKind::Read, true),
+            ("lr.w", &[asm::lr_w(3, 1)], gdb::WatchKind::Write, false),
+            ("amoadd.w", &[asm::amoadd_w(3, 1, 2)], gdb::WatchKind::Read, true),
+            ("amoadd.w", &[asm::amoadd_w(3, 1, 2)], gdb::WatchKind::Write, true),
+            ("amoadd.w", &[asm::amoadd_w(3, 1, 2)], gdb::WatchKind::Access, true),
+            ("failed sc.w", &[asm::sc_w(3, 1, 2)], gdb::WatchKind::Write, false),
+            ("sc.w", &[asm::lr_w(3, 1), asm::sc_w(3, 1, 2)], gdb::WatchKind::Write, true),
+        ];
+        for (name, code, kind, hit) in cases {
+            let mut cpu: cpu::RiscV32 = testing::program(code);
+            cpu.regs.write(1, DATA);
+            cpu.watchpoints.push(gdb::Watchpoint { address: DATA, len: 4, kind: kind });
+            assert_eq!(testing::run(&mut cpu, code.len()), None, "{}", name);
+            assert_eq!(cpu.watch_hit.map(|(_, address)| address), if hit { Some(DATA) } else { None }, "{} {:?}", name, kind);
+        }
+    }
+
+    #[test]
+    fn misaligned() {
+        let cases: [(&str, u32, trap::Trap); 3] = [
+            ("lr.w", asm::lr_w(3, 1), trap::Trap::MisalignedLoadAddr),
+            ("sc.w", asm::sc_w(3, 1, 2), trap::Trap::MisalignedStoreAddr),
+            ("amoadd.w", asm::amoadd_w(3, 1, 2), trap::Trap::MisalignedStoreAddr),
+        ];
+        for (name, instruction, expected) in cases {
+            let mut cpu: cpu::RiscV32 = testing::program(&[instruction]);
+            cpu.regs.write(1, DATA + 2);
+            cpu.regs.write(3, 7);
+            assert_eq!(testing::run(&mut cpu, 1), Some(expected), "{}", name);
+            assert_eq!((cpu.regs.csr.mtval, cpu.regs.read(3)), (DATA + 2, 7), "{}", name);
+        }
+    }
+}
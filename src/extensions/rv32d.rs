@@ -0,0 +1,181 @@
+use crate::cpu;
+use crate::extensions::Execute;
+use crate::extensions::rv32f::*;
+use crate::softfloat;
+use crate::softfloat::{Format, RoundingMode};
+use crate::trap;
+
+#[derive(Debug)]
+pub enum RV32DInstruction {
+    Fld(u8, u8, i32),
+    Fsd(u8, u8, i32),
+    FmaddD(u8, u8, u8, u8, u8),
+    FmsubD(u8, u8, u8, u8, u8),
+    FnmsubD(u8, u8, u8, u8, u8),
+    FnmaddD(u8, u8, u8, u8, u8),
+    FaddD(u8, u8, u8, u8),
+    FsubD(u8, u8, u8, u8),
+    FmulD(u8, u8, u8, u8),
+    FdivD(u8, u8, u8, u8),
+    FsqrtD(u8, u8, u8),
+    FsgnjD(u8, u8, u8),
+    FsgnjnD(u8, u8, u8),
+    FsgnjxD(u8, u8, u8),
+    FminD(u8, u8, u8),
+    FmaxD(u8, u8, u8),
+    FcvtSD(u8, u8, u8),
+    FcvtDS(u8, u8, u8),
+    FeqD(u8, u8, u8),
+    FltD(u8, u8, u8),
+    FleD(u8, u8, u8),
+    FclassD(u8, u8),
+    FcvtWD(u8, u8, u8),
+    FcvtWuD(u8, u8, u8),
+    FcvtDW(u8, u8, u8),
+    FcvtDWu(u8, u8, u8),
+}
+
+fn convert(cpu: &mut cpu::RiscV32, from: Format, to: Format, rd: u8, rs1: u8, rm: u8) -> Option<trap::Trap> {
+    let mode: RoundingMode = match rounding_mode(cpu, rm) {
+        Ok(mode) => mode,
+        Err(trap) => return Some(trap),
+    };
+    let mut flags: u8 = 0;
+    let data: u64 = softfloat::convert(from, to, read_fp(cpu, from, rs1), mode, &mut flags);
+    write_fp(cpu, to, rd, data);
+    accrue(cpu, flags);
+    return None;
+}
+
+impl Execute for RV32DInstruction {
+    fn execute(self, cpu: &mut cpu::RiscV32) -> Option<trap::Trap> {
+        if let Some(trap) = check_enabled(cpu) {
+            return Some(trap);
+        }
+        let fmt: Format = softfloat::F64;
+        match self {
+            RV32DInstruction::Fld(rd, rs1, imm) => {
+                let address: u32 = cpu.regs.read(rs1).wrapping_add_signed(imm);
+                let data: u64 = match cpu.read_double_word(address) {
+                    Ok(data) => data,
+                    Err(trap) => return Some(trap),
+                };
+                cpu.regs.write_f64(rd, data);
+                return None;
+            },
+            RV32DInstruction::Fsd(rs1, rs2, imm) => {
+                let address: u32 = cpu.regs.read(rs1).wrapping_add_signed(imm);
+                let data: u64 = cpu.regs.f[rs2 as usize];
+                return cpu.write_double_word(address, data);
+            },
+            RV32DInstruction::FmaddD(rd, rs1, rs2, rs3, rm) => return fused(cpu, fmt, rd, (rs1, rs2, rs3), rm, false, false),
+            RV32DInstruction::FmsubD(rd, rs1, rs2, rs3, rm) => return fused(cpu, fmt, rd, (rs1, rs2, rs3), rm, false, true),
+            RV32DInstruction::FnmsubD(rd, rs1, rs2, rs3, rm) => return fused(cpu, fmt, rd, (rs1, rs2, rs3), rm, true, false),
+            RV32DInstruction::FnmaddD(rd, rs1, rs2, rs3, rm) => return fused(cpu, fmt, rd, (rs1, rs2, rs3), rm, true, true),
+            RV32DInstruction::FaddD(rd, rs1, rs2, rm) => return arith(cpu, fmt, rd, rs1, rs2, rm, softfloat::add),
+            RV32DInstruction::FsubD(rd, rs1, rs2, rm) => return arith(cpu, fmt, rd, rs1, rs2, rm, softfloat::sub),
+            RV32DInstruction::FmulD(rd, rs1, rs2, rm) => return arith(cpu, fmt, rd, rs1, rs2, rm, softfloat::mul),
+            RV32DInstruction::FdivD(rd, rs1, rs2, rm) => return arith(cpu, fmt, rd, rs1, rs2, rm, softfloat::div),
+            RV32DInstruction::FsqrtD(rd, rs1, rm) => return sqrt(cpu, fmt, rd, rs1, rm),
+            RV32DInstruction::FsgnjD(rd, rs1, rs2) => return sign_inject(cpu, fmt, rd, rs1, rs2, 0),
+            RV32DInstruction::FsgnjnD(rd, rs1, rs2) => return sign_inject(cpu, fmt, rd, rs1, rs2, 1),
+            RV32DInstruction::FsgnjxD(rd, rs1, rs2) => return sign_inject(cpu, fmt, rd, rs1, rs2, 2),
+            RV32DInstruction::FminD(rd, rs1, rs2) => return min_max(cpu, fmt, rd, rs1, rs2, false),
+            RV32DInstruction::FmaxD(rd, rs1, rs2) => return min_max(cpu, fmt, rd, rs1, rs2, true),
+            RV32DInstruction::FcvtSD(rd, rs1, rm) => return convert(cpu, softfloat::F64, softfloat::F32, rd, rs1, rm),
+            RV32DInstruction::FcvtDS(rd, rs1, rm) => return convert(cpu, softfloat::F32, softfloat::F64, rd, rs1, rm),
+            RV32DInstruction::FeqD(rd, rs1, rs2) => return compare(cpu, fmt, rd, rs1, rs2, softfloat::eq),
+            RV32DInstruction::FltD(rd, rs1, rs2) => return compare(cpu, fmt, rd, rs1, rs2, softfloat::lt),
+            RV32DInstruction::FleD(rd, rs1, rs2) => return compare(cpu, fmt, rd, rs1, rs2, softfloat::le),
+            RV32DInstruction::FclassD(rd, rs1) => return classify(cpu, fmt, rd, rs1),
+            RV32DInstruction::FcvtWD(rd, rs1, rm) => return to_int(cpu, fmt, rd, rs1, rm, true),
+            RV32DInstruction::FcvtWuD(rd, rs1, rm) => return to_int(cpu, fmt, rd, rs1, rm, false),
+            RV32DInstruction::FcvtDW(rd, rs1, rm) => return from_int(cpu, fmt, rd, rs1, rm, true),
+            RV32DInstruction::FcvtDWu(rd, rs1, rm) => return from_int(cpu, fmt, rd, rs1, rm, false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RV32DInstruction;
+    use crate::cpu;
+    use crate::extensions::Execute;
+    use crate::softfloat::{DZ, NV, NX, OF};
+    use crate::testing;
+
+    const SIGN: u64 = 1 << 63;
+    const ONE: u64 = 0x3FF0_0000_0000_0000;
+    const ULP_HALF: u64 = 0x3CA0_0000_0000_0000; // 2^-53, half an ulp of 1.0
+    const MAX: u64 = 0x7FEF_FFFF_FFFF_FFFF;
+    const INF: u64 = 0x7FF0_0000_0000_0000;
+    const QNAN: u64 = 0x7FF8_0000_0000_0000; // canonical
+    const SNAN: u64 = 0x7FF0_0000_0000_0001;
+    const BOX: u64 = 0xFFFF_FFFF_0000_0000;
+
+    const RNE: u8 = 0b000;
+    const RTZ: u8 = 0b001;
+    const RUP: u8 = 0b011;
+    const RMM: u8 = 0b100;
+
+    /// A hart with the FPU on, f1 = `a` and f2 = `b`, as raw register contents.
+    fn machine(a: u64, b: u64) -> cpu::RiscV32 {
+        let mut cpu: cpu::RiscV32 = testing::machine();
+        cpu.regs.csr.mstatus |= 1 << 13; // FS initial
+        cpu.regs.f[1] = a;
+        cpu.regs.f[2] = b;
+        return cpu;
+    }
+
+    #[test]
+    fn edge_cases() {
+        let cases: [(&str, RV32DInstruction, u64, u64, u64, u8); 20] = [
+            ("fadd ties to even", RV32DInstruction::FaddD(3, 1, 2, RNE), ONE, ULP_HALF, ONE, NX),
+            ("fadd up", RV32DInstruction::FaddD(3, 1, 2, RUP), ONE, ULP_HALF, ONE + 1, NX),
+            ("fadd ties away", RV32DInstruction::FaddD(3, 1, 2, RMM), ONE, ULP_HALF, ONE + 1, NX),
+            ("fadd inf - inf", RV32DInstruction::FaddD(3, 1, 2, RNE), INF, INF | SIGN, QNAN, NV),
+            ("fadd signaling NaN", RV32DInstruction::FaddD(3, 1, 2, RNE), SNAN, ONE, QNAN, NV),
+            ("fmul overflow to inf", RV32DInstruction::FmulD(3, 1, 2, RNE), MAX, MAX, INF, OF | NX),
+            ("fmul overflow towards zero", RV32DInstruction::FmulD(3, 1, 2, RTZ), MAX, MAX, MAX, OF | NX),
+            ("fdiv 1 / 0", RV32DInstruction::FdivD(3, 1, 2, RNE), ONE, 0, INF, DZ),
+            ("fdiv 0 / 0", RV32DInstruction::FdivD(3, 1, 2, RNE), 0, 0, QNAN, NV),
+            ("fsqrt -1", RV32DInstruction::FsqrtD(3, 1, RNE), ONE | SIGN, 0, QNAN, NV),
+            ("fmin -0 +0", RV32DInstruction::FminD(3, 1, 2), SIGN, 0, SIGN, 0),
+            ("fmin signaling NaN", RV32DInstruction::FminD(3, 1, 2), SNAN, ONE, ONE, NV),
+            ("fcvt.s.d ties to even", RV32DInstruction::FcvtSD(3, 1, RNE), 0x3FF0_0000_1000_0000, 0, BOX | 0x3F80_0000, NX),
+            ("fcvt.s.d up", RV32DInstruction::FcvtSD(3, 1, RUP), 0x3FF0_0000_1000_0000, 0, BOX | 0x3F80_0001, NX),
+            ("fcvt.s.d overflow", RV32DInstruction::FcvtSD(3, 1, RNE), MAX, 0, BOX | 0x7F80_0000, OF | NX),
+            ("fcvt.s.d overflow towards zero", RV32DInstruction::FcvtSD(3, 1, RTZ), MAX, 0, BOX | 0x7F7F_FFFF, OF | NX),
+            ("fcvt.s.d signaling NaN", RV32DInstruction::FcvtSD(3, 1, RNE), SNAN, 0, BOX | 0x7FC0_0000, NV),
+            ("fcvt.d.s exact", RV32DInstruction::FcvtDS(3, 1, RNE), BOX | 0x3F80_0000, 0, ONE, 0),
+            ("fcvt.d.s signaling NaN", RV32DInstruction::FcvtDS(3, 1, RNE), BOX | 0x7F80_0001, 0, QNAN, NV),
+            ("fcvt.d.s unboxed", RV32DInstruction::FcvtDS(3, 1, RNE), 0x3F80_0000, 0, QNAN, 0),
+        ];
+        for (name, instruction, a, b, result, expected) in cases {
+            let mut cpu: cpu::RiscV32 = machine(a, b);
+            assert!(instruction.execute(&mut cpu).is_none(), "{}", name);
+            assert_eq!((cpu.regs.f[3], (cpu.regs.csr.fcsr & 0x1F) as u8), (result, expected), "{}", name);
+        }
+    }
+
+    #[test]
+    fn conversions() {
+        let cases: [(&str, RV32DInstruction, u64, u32, u8); 6] = [
+            ("fcvt.w.d 2.5 ties to even", RV32DInstruction::FcvtWD(3, 1, RNE), 0x4004_0000_0000_0000, 2, NX),
+            ("fcvt.w.d 2.5 ties away", RV32DInstruction::FcvtWD(3, 1, RMM), 0x4004_0000_0000_0000, 3, NX),
+            ("fcvt.w.d 2^31 - 0.5", RV32DInstruction::FcvtWD(3, 1, RTZ), 0x41DF_FFFF_FFE0_0000, 0x7FFF_FFFF, NX),
+            ("fcvt.w.d NaN", RV32DInstruction::FcvtWD(3, 1, RNE), QNAN, 0x7FFF_FFFF, NV),
+            ("fcvt.wu.d -1", RV32DInstruction::FcvtWuD(3, 1, RNE), ONE | SIGN, 0, NV),
+            ("fcvt.wu.d 2^32 - 1", RV32DInstruction::FcvtWuD(3, 1, RNE), 0x41EF_FFFF_FFE0_0000, 0xFFFF_FFFF, 0),
+        ];
+        for (name, instruction, a, result, expected) in cases {
+            let mut cpu: cpu::RiscV32 = machine(a, 0);
+            assert!(instruction.execute(&mut cpu).is_none(), "{}", name);
+            assert_eq!((cpu.regs.read(3), (cpu.regs.csr.fcsr & 0x1F) as u8), (result, expected), "{}", name);
+        }
+        let mut cpu: cpu::RiscV32 = machine(0, 0);
+        cpu.regs.write(1, 0x8000_0000);
+        assert!(RV32DInstruction::FcvtDW(3, 1, RNE).execute(&mut cpu).is_none());
+        assert_eq!((cpu.regs.f[3], cpu.regs.csr.fcsr & 0x1F), (0xC1E0_0000_0000_0000, 0), "every int32 is exact");
+    }
+}
@@ -0,0 +1,433 @@
+use crate::cpu;
+use crate::extensions::Execute;
+use crate::softfloat;
+use crate::softfloat::{Format, RoundingMode};
+use crate::trap;
+
+#[derive(Debug)]
+pub enum RV32FInstruction {
+    Flw(u8, u8, i32),
+    Fsw(u8, u8, i32),
+    FmaddS(u8, u8, u8, u8, u8),
+    FmsubS(u8, u8, u8, u8, u8),
+    FnmsubS(u8, u8, u8, u8, u8),
+    FnmaddS(u8, u8, u8, u8, u8),
+    FaddS(u8, u8, u8, u8),
+    FsubS(u8, u8, u8, u8),
+    FmulS(u8, u8, u8, u8),
+    FdivS(u8, u8, u8, u8),
+    FsqrtS(u8, u8, u8),
+    FsgnjS(u8, u8, u8),
+    FsgnjnS(u8, u8, u8),
+    FsgnjxS(u8, u8, u8),
+    FminS(u8, u8, u8),
+    FmaxS(u8, u8, u8),
+    FcvtWS(u8, u8, u8),
+    FcvtWuS(u8, u8, u8),
+    FmvXW(u8, u8),
+    FeqS(u8, u8, u8),
+    FltS(u8, u8, u8),
+    FleS(u8, u8, u8),
+    FclassS(u8, u8),
+    FcvtSW(u8, u8, u8),
+    FcvtSWu(u8, u8, u8),
+    FmvWX(u8, u8),
+}
+
+// helpers shared with the D extension, every operation is expressed in terms of a softfloat::Format
+
+/// FP instructions and CSRs are illegal while mstatus.FS is Off.
+pub fn check_enabled(cpu: &mut cpu::RiscV32) -> Option<trap::Trap> {
+    if cpu.regs.csr.mstatus & cpu::MSTATUS_FS == 0 {
+        return Some(trap::Trap::take(trap::Trap::IllegalInstruction, cpu, cpu.regs.pc));
+    }
+    return None;
+}
+
+/// Resolves the instruction's rm field, 0b111 selects the dynamic mode from frm.
+pub fn rounding_mode(cpu: &mut cpu::RiscV32, rm: u8) -> Result<RoundingMode, trap::Trap> {
+    let rm: u8 = if rm == 0b111 { ((cpu.regs.csr.fcsr >> 5) & 0x7) as u8 } else { rm };
+    match RoundingMode::from(rm) {
+        Some(mode) => return Ok(mode),
+        None => return Err(trap::Trap::take(trap::Trap::IllegalInstruction, cpu, cpu.regs.pc)),
+    }
+}
+
+pub fn read_fp(cpu: &cpu::RiscV32, fmt: Format, reg: u8) -> u64 {
+    return if fmt == softfloat::F32 {
+        cpu.regs.read_f32(reg) as u64
+    } else {
+        cpu.regs.read_f64(reg)
+    };
+}
+
+pub fn write_fp(cpu: &mut cpu::RiscV32, fmt: Format, reg: u8, data: u64) {
+    if fmt == softfloat::F32 {
+        cpu.regs.write_f32(reg, data as u32);
+    } else {
+        cpu.regs.write_f64(reg, data);
+    }
+}
+
+pub fn accrue(cpu: &mut cpu::RiscV32, flags: u8) {
+    if flags != 0 {
+        cpu.regs.csr.fcsr |= flags as u32;
+        cpu.regs.mark_fs_dirty();
+    }
+}
+
+pub fn arith(cpu: &mut cpu::RiscV32, fmt: Format, rd: u8, rs1: u8, rs2: u8, rm: u8, op: fn(Format, u64, u64, RoundingMode, &mut u8) -> u64) -> Option<trap::Trap> {
+    let mode: RoundingMode = match rounding_mode(cpu, rm) {
+        Ok(mode) => mode,
+        Err(trap) => return Some(trap),
+    };
+    let mut flags: u8 = 0;
+    let data: u64 = op(fmt, read_fp(cpu, fmt, rs1), read_fp(cpu, fmt, rs2), mode, &mut flags);
+    write_fp(cpu, fmt, rd, data);
+    accrue(cpu, flags);
+    return None;
+}
+
+/// FMADD/FMSUB/FNMSUB/FNMADD, expressed as an FMA over sign-flipped operands.
+pub fn fused(cpu: &mut cpu::RiscV32, fmt: Format, rd: u8, (rs1, rs2, rs3): (u8, u8, u8), rm: u8, negate_product: bool, negate_addend: bool) -> Option<trap::Trap> {
+    let mode: RoundingMode = match rounding_mode(cpu, rm) {
+        Ok(mode) => mode,
+        Err(trap) => return Some(trap),
+    };
+    let mut flags: u8 = 0;
+    let a: u64 = read_fp(cpu, fmt, rs1) ^ if negate_product { fmt.sign_bit() } else { 0 };
+    let c: u64 = read_fp(cpu, fmt, rs3) ^ if negate_addend { fmt.sign_bit() } else { 0 };
+    let data: u64 = softfloat::fma(fmt, a, read_fp(cpu, fmt, rs2), c, mode, &mut flags);
+    write_fp(cpu, fmt, rd, data);
+    accrue(cpu, flags);
+    return None;
+}
+
+pub fn sqrt(cpu: &mut cpu::RiscV32, fmt: Format, rd: u8, rs1: u8, rm: u8) -> Option<trap::Trap> {
+    let mode: RoundingMode = match rounding_mode(cpu, rm) {
+        Ok(mode) => mode,
+        Err(trap) => return Some(trap),
+    };
+    let mut flags: u8 = 0;
+    let data: u64 = softfloat::sqrt(fmt, read_fp(cpu, fmt, rs1), mode, &mut flags);
+    write_fp(cpu, fmt, rd, data);
+    accrue(cpu, flags);
+    return None;
+}
+
+/// FSGNJ (mode 0), FSGNJN (mode 1) and FSGNJX (mode 2).
+pub fn sign_inject(cpu: &mut cpu::RiscV32, fmt: Format, rd: u8, rs1: u8, rs2: u8, mode: u8) -> Option<trap::Trap> {
+    let a: u64 = read_fp(cpu, fmt, rs1);
+    let b: u64 = read_fp(cpu, fmt, rs2);
+    let sign: u64 = match mode {
+        0 => b & fmt.sign_bit(),
+        1 => !b & fmt.sign_bit(),
+        _ => (a ^ b) & fmt.sign_bit(),
+    };
+    write_fp(cpu, fmt, rd, (a & !fmt.sign_bit()) | sign);
+    return None;
+}
+
+pub fn min_max(cpu: &mut cpu::RiscV32, fmt: Format, rd: u8, rs1: u8, rs2: u8, max: bool) -> Option<trap::Trap> {
+    let mut flags: u8 = 0;
+    let data: u64 = softfloat::min_max(fmt, read_fp(cpu, fmt, rs1), read_fp(cpu, fmt, rs2), max, &mut flags);
+    write_fp(cpu, fmt, rd, data);
+    accrue(cpu, flags);
+    return None;
+}
+
+pub fn compare(cpu: &mut cpu::RiscV32, fmt: Format, rd: u8, rs1: u8, rs2: u8, op: fn(Format, u64, u64, &mut u8) -> bool) -> Option<trap::Trap> {
+    let mut flags: u8 = 0;
+    let result: bool = op(fmt, read_fp(cpu, fmt, rs1), read_fp(cpu, fmt, rs2), &mut flags);
+    cpu.regs.write(rd, result as u32);
+    accrue(cpu, flags);
+    return None;
+}
+
+pub fn classify(cpu: &mut cpu::RiscV32, fmt: Format, rd: u8, rs1: u8) -> Option<trap::Trap> {
+    let data: u32 = softfloat::classify(fmt, read_fp(cpu, fmt, rs1));
+    cpu.regs.write(rd, data);
+    return None;
+}
+
+pub fn to_int(cpu: &mut cpu::RiscV32, fmt: Format, rd: u8, rs1: u8, rm: u8, signed: bool) -> Option<trap::Trap> {
+    let mode: RoundingMode = match rounding_mode(cpu, rm) {
+        Ok(mode) => mode,
+        Err(trap) => return Some(trap),
+    };
+    let mut flags: u8 = 0;
+    let data: u32 = softfloat::to_int(fmt, read_fp(cpu, fmt, rs1), signed, mode, &mut flags);
+    cpu.regs.write(rd, data);
+    accrue(cpu, flags);
+    return None;
+}
+
+pub fn from_int(cpu: &mut cpu::RiscV32, fmt: Format, rd: u8, rs1: u8, rm: u8, signed: bool) -> Option<trap::Trap> {
+    let mode: RoundingMode = match rounding_mode(cpu, rm) {
+        Ok(mode) => mode,
+        Err(trap) => return Some(trap),
+    };
+    let mut flags: u8 = 0;
+    let data: u64 = softfloat::from_int(fmt, cpu.regs.read(rs1), signed, mode, &mut flags);
+    write_fp(cpu, fmt, rd, data);
+    accrue(cpu, flags);
+    return None;
+}
+
+impl Execute for RV32FInstruction {
+    fn execute(self, cpu: &mut cpu::RiscV32) -> Option<trap::Trap> {
+        if let Some(trap) = check_enabled(cpu) {
+            return Some(trap);
+        }
+        let fmt: Format = softfloat::F32;
+        match self {
+            RV32FInstruction::Flw(rd, rs1, imm) => {
+                let address: u32 = cpu.regs.read(rs1).wrapping_add_signed(imm);
+                let data: u32 = match cpu.read_word(address) {
+                    Ok(data) => data,
+                    Err(trap) => return Some(trap),
+                };
+                cpu.regs.write_f32(rd, data);
+                return None;
+            },
+            RV32FInstruction::Fsw(rs1, rs2, imm) => {
+                let address: u32 = cpu.regs.read(rs1).wrapping_add_signed(imm);
+                let data: u32 = cpu.regs.f[rs2 as usize] as u32; // stores the raw low bits, NaN-boxed or not
+                return cpu.write_word(address, data);
+            },
+            RV32FInstruction::FmaddS(rd, rs1, rs2, rs3, rm) => return fused(cpu, fmt, rd, (rs1, rs2, rs3), rm, false, false),
+            RV32FInstruction::FmsubS(rd, rs1, rs2, rs3, rm) => return fused(cpu, fmt, rd, (rs1, rs2, rs3), rm, false, true),
+            RV32FInstruction::FnmsubS(rd, rs1, rs2, rs3, rm) => return fused(cpu, fmt, rd, (rs1, rs2, rs3), rm, true, false),
+            RV32FInstruction::FnmaddS(rd, rs1, rs2, rs3, rm) => return fused(cpu, fmt, rd, (rs1, rs2, rs3), rm, true, true),
+            RV32FInstruction::FaddS(rd, rs1, rs2, rm) => return arith(cpu, fmt, rd, rs1, rs2, rm, softfloat::add),
+            RV32FInstruction::FsubS(rd, rs1, rs2, rm) => return arith(cpu, fmt, rd, rs1, rs2, rm, softfloat::sub),
+            RV32FInstruction::FmulS(rd, rs1, rs2, rm) => return arith(cpu, fmt, rd, rs1, rs2, rm, softfloat::mul),
+            RV32FInstruction::FdivS(rd, rs1, rs2, rm) => return arith(cpu, fmt, rd, rs1, rs2, rm, softfloat::div),
+            RV32FInstruction::FsqrtS(rd, rs1, rm) => return sqrt(cpu, fmt, rd, rs1, rm),
+            RV32FInstruction::FsgnjS(rd, rs1, rs2) => return sign_inject(cpu, fmt, rd, rs1, rs2, 0),
+            RV32FInstruction::FsgnjnS(rd, rs1, rs2) => return sign_inject(cpu, fmt, rd, rs1, rs2, 1),
+            RV32FInstruction::FsgnjxS(rd, rs1, rs2) => return sign_inject(cpu, fmt, rd, rs1, rs2, 2),
+            RV32FInstruction::FminS(rd, rs1, rs2) => return min_max(cpu, fmt, rd, rs1, rs2, false),
+            RV32FInstruction::FmaxS(rd, rs1, rs2) => return min_max(cpu, fmt, rd, rs1, rs2, true),
+            RV32FInstruction::FcvtWS(rd, rs1, rm) => return to_int(cpu, fmt, rd, rs1, rm, true),
+            RV32FInstruction::FcvtWuS(rd, rs1, rm) => return to_int(cpu, fmt, rd, rs1, rm, false),
+            RV32FInstruction::FmvXW(rd, rs1) => {
+                let data: u32 = cpu.regs.f[rs1 as usize] as u32;
+                cpu.regs.write(rd, data);
+                return None;
+            },
+            RV32FInstruction::FeqS(rd, rs1, rs2) => return compare(cpu, fmt, rd, rs1, rs2, softfloat::eq),
+            RV32FInstruction::FltS(rd, rs1, rs2) => return compare(cpu, fmt, rd, rs1, rs2, softfloat::lt),
+            RV32FInstruction::FleS(rd, rs1, rs2) => return compare(cpu, fmt, rd, rs1, rs2, softfloat::le),
+            RV32FInstruction::FclassS(rd, rs1) => return classify(cpu, fmt, rd, rs1),
+            RV32FInstruction::FcvtSW(rd, rs1, rm) => return from_int(cpu, fmt, rd, rs1, rm, true),
+            RV32FInstruction::FcvtSWu(rd, rs1, rm) => return from_int(cpu, fmt, rd, rs1, rm, false),
+            RV32FInstruction::FmvWX(rd, rs1) => {
+                let data: u32 = cpu.regs.read(rs1);
+                cpu.regs.write_f32(rd, data);
+                return None;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RV32FInstruction;
+    use crate::cpu;
+    use crate::extensions::Execute;
+    use crate::softfloat::{DZ, NV, NX, OF, UF};
+    use crate::testing;
+    use crate::trap;
+
+    const SIGN: u32 = 0x8000_0000;
+    const ONE: u32 = 0x3F80_0000;
+    const TWO: u32 = 0x4000_0000;
+    const HALF: u32 = 0x3F00_0000;
+    const ULP_HALF: u32 = 0x3380_0000; // 2^-24, half an ulp of 1.0
+    const MAX: u32 = 0x7F7F_FFFF;
+    const MIN_NORMAL: u32 = 0x0080_0000;
+    const INF: u32 = 0x7F80_0000;
+    const QNAN: u32 = 0x7FC0_0000; // canonical
+    const SNAN: u32 = 0x7F80_0001;
+
+    const RNE: u8 = 0b000;
+    const RTZ: u8 = 0b001;
+    const RDN: u8 = 0b010;
+    const RUP: u8 = 0b011;
+    const RMM: u8 = 0b100;
+    const DYN: u8 = 0b111;
+
+    type Op = fn(u8, u8, u8, u8) -> RV32FInstruction;
+    type Cvt = fn(u8, u8, u8) -> RV32FInstruction;
+
+    /// A hart with the FPU on, f1 = `a` and f2 = `b`, both NaN-boxed.
+    fn machine(a: u32, b: u32) -> cpu::RiscV32 {
+        let mut cpu: cpu::RiscV32 = testing::machine();
+        cpu.regs.csr.mstatus |= 1 << 13; // FS initial
+        cpu.regs.write_f32(1, a);
+        cpu.regs.write_f32(2, b);
+        return cpu;
+    }
+
+    fn flags(cpu: &cpu::RiscV32) -> u8 {
+        return (cpu.regs.csr.fcsr & 0x1F) as u8;
+    }
+
+    #[test]
+    fn edge_cases() {
+        let cases: [(&str, Op, u32, u32, u8, u32, u8); 32] = [
+            ("fadd ties to even", RV32FInstruction::FaddS, ONE, ULP_HALF, RNE, ONE, NX),
+            ("fadd ties away", RV32FInstruction::FaddS, ONE, ULP_HALF, RMM, 0x3F80_0001, NX),
+            ("fadd towards zero", RV32FInstruction::FaddS, ONE, ULP_HALF, RTZ, ONE, NX),
+            ("fadd down", RV32FInstruction::FaddS, ONE, ULP_HALF, RDN, ONE, NX),
+            ("fadd up", RV32FInstruction::FaddS, ONE, ULP_HALF, RUP, 0x3F80_0001, NX),
+            ("fadd negative down", RV32FInstruction::FaddS, ONE | SIGN, ULP_HALF | SIGN, RDN, 0xBF80_0001, NX),
+            ("fadd negative up", RV32FInstruction::FaddS, ONE | SIGN, ULP_HALF | SIGN, RUP, ONE | SIGN, NX),
+            ("fadd exact", RV32FInstruction::FaddS, ONE, ONE, RNE, TWO, 0),
+            ("fsub to +0", RV32FInstruction::FsubS, ONE, ONE, RNE, 0, 0),
+            ("fsub to -0 rounding down", RV32FInstruction::FsubS, ONE, ONE, RDN, SIGN, 0),
+            ("fadd inf - inf", RV32FInstruction::FaddS, INF, INF | SIGN, RNE, QNAN, NV),
+            ("fadd signaling NaN", RV32FInstruction::FaddS, SNAN, ONE, RNE, QNAN, NV),
+            ("fadd quiet NaN", RV32FInstruction::FaddS, 0x7FC0_1234, ONE, RNE, QNAN, 0),
+            ("fadd inf + 1", RV32FInstruction::FaddS, INF, ONE, RNE, INF, 0),
+            ("fmul overflow to inf", RV32FInstruction::FmulS, MAX, TWO, RNE, INF, OF | NX),
+            ("fmul overflow towards zero", RV32FInstruction::FmulS, MAX, TWO, RTZ, MAX, OF | NX),
+            ("fmul overflow down", RV32FInstruction::FmulS, MAX, TWO, RDN, MAX, OF | NX),
+            ("fmul negative overflow down", RV32FInstruction::FmulS, MAX | SIGN, TWO, RDN, INF | SIGN, OF | NX),
+            ("fmul negative overflow up", RV32FInstruction::FmulS, MAX | SIGN, TWO, RUP, MAX | SIGN, OF | NX),
+            ("fmul exact subnormal", RV32FInstruction::FmulS, MIN_NORMAL, HALF, RNE, 0x0040_0000, 0),
+            ("fmul inexact subnormal", RV32FInstruction::FmulS, MIN_NORMAL + 1, HALF, RNE, 0x0040_0000, UF | NX),
+            ("fmul inexact subnormal up", RV32FInstruction::FmulS, MIN_NORMAL + 1, HALF, RUP, 0x0040_0001, UF | NX),
+            ("fmul underflow to zero", RV32FInstruction::FmulS, MIN_NORMAL, MIN_NORMAL, RNE, 0, UF | NX),
+            ("fmul underflow up", RV32FInstruction::FmulS, MIN_NORMAL, MIN_NORMAL, RUP, 1, UF | NX),
+            ("fmul inf * 0", RV32FInstruction::FmulS, INF, 0, RNE, QNAN, NV),
+            ("fmul sign", RV32FInstruction::FmulS, TWO | SIGN, HALF, RNE, ONE | SIGN, 0),
+            ("fdiv 1 / 0", RV32FInstruction::FdivS, ONE, 0, RNE, INF, DZ),
+            ("fdiv -1 / 0", RV32FInstruction::FdivS, ONE | SIGN, 0, RNE, INF | SIGN, DZ),
+            ("fdiv 0 / 0", RV32FInstruction::FdivS, 0, 0, RNE, QNAN, NV),
+            ("fdiv inf / 0", RV32FInstruction::FdivS, INF, 0, RNE, INF, 0),
+            ("fdiv 1 / 3", RV32FInstruction::FdivS, ONE, 0x4040_0000, RNE, 0x3EAA_AAAB, NX),
+            ("fdiv 1 / 3 towards zero", RV32FInstruction::FdivS, ONE, 0x4040_0000, RTZ, 0x3EAA_AAAA, NX),
+        ];
+        for (name, op, a, b, rm, result, expected) in cases {
+            let mut cpu: cpu::RiscV32 = machine(a, b);
+            assert!(op(3, 1, 2, rm).execute(&mut cpu).is_none(), "{}", name);
+            assert_eq!((cpu.regs.f[3], flags(&cpu)), (0xFFFF_FFFF_0000_0000 | result as u64, expected), "{}", name);
+        }
+    }
+
+    #[test]
+    fn conversions() {
+        let cases: [(&str, Cvt, u32, u8, u32, u8); 18] = [
+            ("fcvt.w.s 2.5 ties to even", RV32FInstruction::FcvtWS, 0x4020_0000, RNE, 2, NX),
+            ("fcvt.w.s 2.5 ties away", RV32FInstruction::FcvtWS, 0x4020_0000, RMM, 3, NX),
+            ("fcvt.w.s 2.5 towards zero", RV32FInstruction::FcvtWS, 0x4020_0000, RTZ, 2, NX),
+            ("fcvt.w.s 2.5 down", RV32FInstruction::FcvtWS, 0x4020_0000, RDN, 2, NX),
+            ("fcvt.w.s 2.5 up", RV32FInstruction::FcvtWS, 0x4020_0000, RUP, 3, NX),
+            ("fcvt.w.s -2.5 ties away", RV32FInstruction::FcvtWS, 0xC020_0000, RMM, -3i32 as u32, NX),
+            ("fcvt.w.s -2.5 towards zero", RV32FInstruction::FcvtWS, 0xC020_0000, RTZ, -2i32 as u32, NX),
+            ("fcvt.w.s -2.5 down", RV32FInstruction::FcvtWS, 0xC020_0000, RDN, -3i32 as u32, NX),
+            ("fcvt.w.s -2^31", RV32FInstruction::FcvtWS, 0xCF00_0000, RNE, 0x8000_0000, 0),
+            ("fcvt.w.s 2^31", RV32FInstruction::FcvtWS, 0x4F00_0000, RNE, 0x7FFF_FFFF, NV),
+            ("fcvt.w.s -inf", RV32FInstruction::FcvtWS, INF | SIGN, RNE, 0x8000_0000, NV),
+            ("fcvt.w.s NaN", RV32FInstruction::FcvtWS, QNAN, RNE, 0x7FFF_FFFF, NV),
+            ("fcvt.w.s -NaN", RV32FInstruction::FcvtWS, QNAN | SIGN, RNE, 0x7FFF_FFFF, NV),
+            ("fcvt.wu.s 3e9", RV32FInstruction::FcvtWuS, 0x4F32_D05E, RNE, 0xB2D0_5E00, 0),
+            ("fcvt.wu.s -1", RV32FInstruction::FcvtWuS, ONE | SIGN, RTZ, 0, NV),
+            ("fcvt.wu.s -0.5 towards zero", RV32FInstruction::FcvtWuS, HALF | SIGN, RTZ, 0, NX),
+            ("fcvt.wu.s inf", RV32FInstruction::FcvtWuS, INF, RNE, 0xFFFF_FFFF, NV),
+            ("fcvt.wu.s NaN", RV32FInstruction::FcvtWuS, QNAN, RNE, 0xFFFF_FFFF, NV),
+        ];
+        for (name, op, a, rm, result, expected) in cases {
+            let mut cpu: cpu::RiscV32 = machine(a, 0);
+            assert!(op(3, 1, rm).execute(&mut cpu).is_none(), "{}", name);
+            assert_eq!((cpu.regs.read(3), flags(&cpu)), (result, expected), "{}", name);
+        }
+        let mut cpu: cpu::RiscV32 = machine(0, 0);
+        cpu.regs.write(1, 0x0100_0001); // 2^24 + 1 has one bit too many
+        assert!(RV32FInstruction::FcvtSW(3, 1, RNE).execute(&mut cpu).is_none());
+        assert_eq!((cpu.regs.read_f32(3), flags(&cpu)), (0x4B80_0000, NX));
+    }
+
+    #[test]
+    fn dynamic_rounding() {
+        let mut cpu: cpu::RiscV32 = machine(ONE, ULP_HALF);
+        cpu.regs.csr.fcsr = (RUP as u32) << 5;
+        assert!(RV32FInstruction::FaddS(3, 1, 2, DYN).execute(&mut cpu).is_none());
+        assert_eq!(cpu.regs.read_f32(3), 0x3F80_0001, "rm = 7 rounds as frm says");
+
+        for (name, frm, rm) in [("reserved frm", 0b101, DYN), ("reserved rm", RNE, 0b101), ("rm = 7 in frm", DYN, DYN)] {
+            let mut cpu: cpu::RiscV32 = machine(ONE, ULP_HALF);
+            cpu.regs.csr.fcsr = (frm as u32) << 5;
+            assert_eq!(RV32FInstruction::FaddS(3, 1, 2, rm).execute(&mut cpu), Some(trap::Trap::IllegalInstruction), "{}", name);
+            assert_eq!(cpu.regs.f[3], 0, "{} leaves rd alone", name);
+        }
+
+        let mut cpu: cpu::RiscV32 = machine(ONE, ULP_HALF);
+        cpu.regs.csr.mstatus &= !cpu::MSTATUS_FS;
+        assert_eq!(RV32FInstruction::FaddS(3, 1, 2, RNE).execute(&mut cpu), Some(trap::Trap::IllegalInstruction), "FS off");
+    }
+
+    #[test]
+    fn nan_boxing() {
+        let mut cpu: cpu::RiscV32 = machine(0, ONE);
+        cpu.regs.f[1] = ONE as u64; // 1.0 without the upper half set
+        assert!(RV32FInstruction::FaddS(3, 1, 2, RNE).execute(&mut cpu).is_none());
+        assert_eq!((cpu.regs.f[3], flags(&cpu)), (0xFFFF_FFFF_7FC0_0000, 0), "reads as the canonical NaN, which is quiet");
+        assert!(RV32FInstruction::FsgnjnS(3, 1, 1).execute(&mut cpu).is_none());
+        assert_eq!(cpu.regs.f[3], 0xFFFF_FFFF_FFC0_0000, "sign injection too");
+        assert!(RV32FInstruction::FmvXW(3, 1).execute(&mut cpu).is_none());
+        assert_eq!(cpu.regs.read(3), ONE, "moves are transfers, they take the raw bits");
+        cpu.regs.write(4, TWO);
+        assert!(RV32FInstruction::FmvWX(4, 4).execute(&mut cpu).is_none());
+        assert_eq!(cpu.regs.f[4], 0xFFFF_FFFF_4000_0000, "and box what they write");
+    }
+
+    #[test]
+    fn min_max_and_compares() {
+        let cases: [(&str, RV32FInstruction, u32, u32, u32, u8); 11] = [
+            ("fmin -0 +0", RV32FInstruction::FminS(3, 1, 2), SIGN, 0, SIGN, 0),
+            ("fmax -0 +0", RV32FInstruction::FmaxS(3, 1, 2), SIGN, 0, 0, 0),
+            ("fmin quiet NaN", RV32FInstruction::FminS(3, 1, 2), QNAN, ONE, ONE, 0),
+            ("fmin signaling NaN", RV32FInstruction::FminS(3, 1, 2), SNAN, ONE, ONE, NV),
+            ("fmax two NaNs", RV32FInstruction::FmaxS(3, 1, 2), SNAN, 0x7FC0_1234, QNAN, NV),
+            ("fsqrt 4", RV32FInstruction::FsqrtS(3, 1, RNE), 0x4080_0000, 0, TWO, 0),
+            ("fsqrt -1", RV32FInstruction::FsqrtS(3, 1, RNE), ONE | SIGN, 0, QNAN, NV),
+            ("fsqrt -0", RV32FInstruction::FsqrtS(3, 1, RNE), SIGN, 0, SIGN, 0),
+            ("fsqrt 2", RV32FInstruction::FsqrtS(3, 1, RNE), TWO, 0, 0x3FB5_04F3, NX),
+            ("fmadd without intermediate rounding", RV32FInstruction::FmaddS(3, 1, 1, 2, RNE), 0x3F80_0001, 0xBF80_0002, 0x2880_0000, 0),
+            ("fnmsub", RV32FInstruction::FnmsubS(3, 1, 1, 2, RNE), TWO, ONE, 0xC040_0000, 0),
+        ];
+        for (name, instruction, a, b, result, expected) in cases {
+            let mut cpu: cpu::RiscV32 = machine(a, b);
+            assert!(instruction.execute(&mut cpu).is_none(), "{}", name);
+            assert_eq!((cpu.regs.read_f32(3), flags(&cpu)), (result, expected), "{}", name);
+        }
+
+        let compares: [(&str, RV32FInstruction, u32, u32, u32, u8); 7] = [
+            ("feq quiet NaN", RV32FInstruction::FeqS(3, 1, 2), QNAN, QNAN, 0, 0),
+            ("feq signaling NaN", RV32FInstruction::FeqS(3, 1, 2), SNAN, ONE, 0, NV),
+            ("flt quiet NaN", RV32FInstruction::FltS(3, 1, 2), QNAN, ONE, 0, NV),
+            ("fle quiet NaN", RV32FInstruction::FleS(3, 1, 2), ONE, QNAN, 0, NV),
+            ("feq -0 +0", RV32FInstruction::FeqS(3, 1, 2), SIGN, 0, 1, 0),
+            ("flt -0 +0", RV32FInstruction::FltS(3, 1, 2), SIGN, 0, 0, 0),
+            ("fle -inf max", RV32FInstruction::FleS(3, 1, 2), INF | SIGN, MAX, 1, 0),
+        ];
+        for (name, instruction, a, b, result, expected) in compares {
+            let mut cpu: cpu::RiscV32 = machine(a, b);
+            assert!(instruction.execute(&mut cpu).is_none(), "{}", name);
+            assert_eq!((cpu.regs.read(3), flags(&cpu)), (result, expected), "{}", name);
+        }
+    }
+
+    #[test]
+    fn flags_accrue() {
+        let mut cpu: cpu::RiscV32 = machine(ONE, 0);
+        assert!(RV32FInstruction::FdivS(3, 1, 2, RNE).execute(&mut cpu).is_none());
+        assert!(RV32FInstruction::FdivS(3, 2, 2, RNE).execute(&mut cpu).is_none());
+        assert!(RV32FInstruction::FaddS(3, 1, 1, RNE).execute(&mut cpu).is_none());
+        assert_eq!(flags(&cpu), DZ | NV, "flags only ever get set");
+        assert_eq!(cpu.regs.csr.mstatus & cpu::MSTATUS_FS, cpu::MSTATUS_FS);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{cpu, uart};
+use crate::cpu;
 use crate::extensions::Execute;
 use crate::trap;
 
@@ -97,6 +97,16 @@ impl std::fmt::Debug for RV32IInstruction {
     }
 }
 
+/// Redirects execution to `target`, compensating for the PC increment that follows every instruction.
+fn jump(cpu: &mut cpu::RiscV32, target: u32) -> Option<trap::Trap> {
+    let ialign: u32 = if cpu.regs.csr.misa & cpu::MISA_C != 0 { 0x1 } else { 0x3 };
+    if target & ialign != 0 {
+        return Some(trap::Trap::take(trap::Trap::MisalignedInstructionAddress, cpu, target));
+    }
+    cpu.regs.pc = target.wrapping_sub(cpu.ilen);
+    return None;
+}
+
 impl Execute for RV32IInstruction {
     fn execute(self, cpu: &mut cpu::RiscV32) -> Option<trap::Trap> {
         match self {
@@ -105,167 +115,122 @@ impl Execute for RV32IInstruction {
                 return None;
             },
             RV32IInstruction::Auipc(rd, imm) => {
-                cpu.regs.write(rd, cpu.regs.pc.wrapping_add_signed(imm-4)); // PC is updated right after - sure with branch instructions, further investigation required for loads
+                cpu.regs.write(rd, cpu.regs.pc.wrapping_add_signed(imm));
                 return None;
             },
             RV32IInstruction::Jal(rd, imm) => {
-                if rd > 0 {
-                    cpu.regs.write(
-                        rd,
-                        cpu.regs.pc.wrapping_add(4) // PC is updated right after - but needs the +4 since we do -4+4 when restoring and advancing the value
-                    );
-                }
-                cpu.regs.pc = cpu.regs.pc.wrapping_add_signed(imm-4);
-                if cpu.regs.pc & 0x3 != 0 {
-                    return Some(trap::Trap::take(trap::Trap::MisalignedInstructionAddress, cpu, cpu.regs.pc));
+                let t: u32 = cpu.regs.pc.wrapping_add(cpu.ilen);
+                if let Some(trap) = jump(cpu, cpu.regs.pc.wrapping_add_signed(imm)) {
+                    return Some(trap);
                 }
+                cpu.regs.write(rd, t);
                 return None;
             },
             RV32IInstruction::Jalr(rd, rs1, imm) => {
-                let t: u32 = cpu.regs.pc.wrapping_add(4); // PC is updated right after - but needs the +4 since we do -4+4 when restoring the value
-                cpu.regs.pc = cpu.regs.read(rs1).wrapping_add_signed(imm-4);
-                if cpu.regs.pc & 0x3 != 0 {
-                    return Some(trap::Trap::take(trap::Trap::MisalignedInstructionAddress, cpu, cpu.regs.pc));
+                let t: u32 = cpu.regs.pc.wrapping_add(cpu.ilen);
+                if let Some(trap) = jump(cpu, cpu.regs.read(rs1).wrapping_add_signed(imm) & !0x1) {
+                    return Some(trap);
                 }
-                if rd > 0 {
-                    cpu.regs.write(rd, t);
-                }
-                //println!("{:08X} {}", cpu.regs.x[rs1 as usize], imm);
+                cpu.regs.write(rd, t);
                 return None;
             },
             RV32IInstruction::Beq(rs1, rs2, imm) => {
                 if cpu.regs.read(rs1) == cpu.regs.read(rs2) {
-                    cpu.regs.pc = cpu.regs.pc.wrapping_add_signed(imm-4); // cause PC gets updated right after
-                    if cpu.regs.pc & 0x3 != 0 {
-                        return Some(trap::Trap::take(trap::Trap::MisalignedInstructionAddress, cpu, cpu.regs.pc));
-                    }
+                    return jump(cpu, cpu.regs.pc.wrapping_add_signed(imm));
                 }
                 return None;
             },
             RV32IInstruction::Bne(rs1, rs2, imm) => {
                 if cpu.regs.read(rs1) != cpu.regs.read(rs2) {
-                    cpu.regs.pc = cpu.regs.pc.wrapping_add_signed(imm-4);
-                    if cpu.regs.pc & 0x3 != 0 {
-                        return Some(trap::Trap::take(trap::Trap::MisalignedInstructionAddress, cpu, cpu.regs.pc));
-                    }
+                    return jump(cpu, cpu.regs.pc.wrapping_add_signed(imm));
                 }
                 return None;
             },
             RV32IInstruction::Blt(rs1, rs2, imm) => {
                 if (cpu.regs.read(rs1) as i32) < (cpu.regs.read(rs2) as i32) {
-                    cpu.regs.pc = cpu.regs.pc.wrapping_add_signed(imm-4);
-                    if cpu.regs.pc & 0x3 != 0 {
-                        return Some(trap::Trap::take(trap::Trap::MisalignedInstructionAddress, cpu, cpu.regs.pc));
-                    }
+                    return jump(cpu, cpu.regs.pc.wrapping_add_signed(imm));
                 }
                 return None;
             },
             RV32IInstruction::Bge(rs1, rs2, imm) => {
                 if (cpu.regs.read(rs1) as i32) >= (cpu.regs.read(rs2) as i32) {
-                    cpu.regs.pc = cpu.regs.pc.wrapping_add_signed(imm-4);
-                    if cpu.regs.pc & 0x3 != 0 {
-                        return Some(trap::Trap::take(trap::Trap::MisalignedInstructionAddress, cpu, cpu.regs.pc));
-                    }
+                    return jump(cpu, cpu.regs.pc.wrapping_add_signed(imm));
                 }
                 return None;
             },
             RV32IInstruction::Bltu(rs1, rs2, imm) => {
                 if cpu.regs.read(rs1) < cpu.regs.read(rs2) {
-                    cpu.regs.pc = cpu.regs.pc.wrapping_add_signed(imm-4);
-                    if cpu.regs.pc & 0x3 != 0 {
-                        return Some(trap::Trap::take(trap::Trap::MisalignedInstructionAddress, cpu, cpu.regs.pc));
-                    }
+                    return jump(cpu, cpu.regs.pc.wrapping_add_signed(imm));
                 }
                 return None;
             },
             RV32IInstruction::Bgeu(rs1, rs2, imm) => {
                 if cpu.regs.read(rs1) >= cpu.regs.read(rs2) {
-                    cpu.regs.pc = cpu.regs.pc.wrapping_add_signed(imm-4);
-                    if cpu.regs.pc & 0x3 != 0 {
-                        return Some(trap::Trap::take(trap::Trap::MisalignedInstructionAddress, cpu, cpu.regs.pc));
-                    }
+                    return jump(cpu, cpu.regs.pc.wrapping_add_signed(imm));
                 }
                 return None;
             },
             RV32IInstruction::Lb(rd, rs1, imm) => {
                 let address: u32 = cpu.regs.read(rs1).wrapping_add_signed(imm);
-                let mut ubyte: u8 = cpu.mem.read_byte(address as usize);
-                if uart::match_addr(address) {
-                    if let Some(data) = cpu.uart.read(address) {
-                        ubyte = data;
-                    } else {
-                        ubyte = 0;
-                    }
-                }
+                let ubyte: u8 = match cpu.read_byte(address) {
+                    Ok(data) => data,
+                    Err(trap) => return Some(trap),
+                };
                 let idata: i32 = ((ubyte as i32) << 24) >> 24;
                 cpu.regs.write(rd, idata as u32);
                 return None;
             },
             RV32IInstruction::Lh(rd, rs1, imm) => {
                 let address: u32 = cpu.regs.read(rs1).wrapping_add_signed(imm);
-                if uart::match_addr(address) {
-                    return Some(trap::Trap::take(trap::Trap::LoadAccessFault, cpu, cpu.regs.pc));
-                }
-                let uhalf: u16 = cpu.mem.read_half_word(address as usize);
+                let uhalf: u16 = match cpu.read_half_word(address) {
+                    Ok(data) => data,
+                    Err(trap) => return Some(trap),
+                };
                 let idata: i32 = ((uhalf as i32) << 16) >> 16;
                 cpu.regs.write(rd, idata as u32);
                 return None;
             },
             RV32IInstruction::Lw(rd, rs1, imm) => {
                 let address: u32 = cpu.regs.read(rs1).wrapping_add_signed(imm);
-                if uart::match_addr(address) {
-                    return Some(trap::Trap::take(trap::Trap::LoadAccessFault, cpu, cpu.regs.pc));
-                }
-                let udata: u32 = cpu.mem.read_word(address as usize);
+                let udata: u32 = match cpu.read_word(address) {
+                    Ok(data) => data,
+                    Err(trap) => return Some(trap),
+                };
                 cpu.regs.write(rd, udata);
                 return None;
             },
             RV32IInstruction::Lbu(rd, rs1, imm) => {
                 let address: u32 = cpu.regs.read(rs1).wrapping_add_signed(imm);
-                let mut ubyte: u8 = cpu.mem.read_byte(address as usize);
-                if uart::match_addr(address) {
-                    if let Some(data) = cpu.uart.read(address) {
-                        ubyte = data;
-                    }
-                }
+                let ubyte: u8 = match cpu.read_byte(address) {
+                    Ok(data) => data,
+                    Err(trap) => return Some(trap),
+                };
                 cpu.regs.write(rd, (ubyte as u32) & 0xFF);
                 return None;
             },
             RV32IInstruction::Lhu(rd, rs1, imm) => {
                 let address: u32 = cpu.regs.read(rs1).wrapping_add_signed(imm);
-                if uart::match_addr(address) {
-                    return Some(trap::Trap::take(trap::Trap::LoadAccessFault, cpu, cpu.regs.pc));
-                }
-                let uhalf: u16 = cpu.mem.read_half_word(address as usize);
+                let uhalf: u16 = match cpu.read_half_word(address) {
+                    Ok(data) => data,
+                    Err(trap) => return Some(trap),
+                };
                 cpu.regs.write(rd, (uhalf as u32) & 0xFFFF);
                 return None;
             },
             RV32IInstruction::Sb(rs1, rs2, imm) => {
                 let address: u32 = cpu.regs.read(rs1).wrapping_add_signed(imm);
                 let byte: u8 = (cpu.regs.read(rs2) & 0xFF) as u8;
-                if uart::match_addr(address) {
-                    cpu.uart.write(address, byte);
-                } else {
-                    cpu.mem.write_byte(address as usize, byte);
-                }
-                return None;
+                return cpu.write_byte(address, byte);
             },
             RV32IInstruction::Sh(rs1, rs2, imm) => {
                 let address: u32 = cpu.regs.read(rs1).wrapping_add_signed(imm);
-                if uart::match_addr(address) {
-                    return Some(trap::Trap::take(trap::Trap::StoreAccessFault, cpu, cpu.regs.pc));
-                }
                 let half: u16 = (cpu.regs.read(rs2) & 0xFFFF) as u16;
-                cpu.mem.write_half_word(address as usize, half);
-                return None;
+                return cpu.write_half_word(address, half);
             },
             RV32IInstruction::Sw(rs1, rs2, imm) => {
                 let address: u32 = cpu.regs.read(rs1).wrapping_add_signed(imm);
-                if uart::match_addr(address) {
-                    return Some(trap::Trap::take(trap::Trap::StoreAccessFault, cpu, cpu.regs.pc));
-                }
-                cpu.mem.write_word(address as usize, cpu.regs.read(rs2));
-                return None;
+                let word: u32 = cpu.regs.read(rs2);
+                return cpu.write_word(address, word);
             },
             RV32IInstruction::Addi(rd, rs1, imm) => {
                 let data: u32 = cpu.regs.read(rs1).wrapping_add_signed(imm);
@@ -313,13 +278,10 @@ impl Execute for RV32IInstruction {
                 cpu.regs.write(rd, data);
                 return None;
             },
-            RV32IInstruction::Srai(/*rd, rs1, shamt*/_, _, _) => {
-                /*
+            RV32IInstruction::Srai(rd, rs1, shamt) => {
                 let data: u32 = ((cpu.regs.read(rs1) as i32) >> shamt) as u32;
                 cpu.regs.write(rd, data);
-                */
-                //panic!("Illegal instruction: SRAI");
-                return Some(trap::Trap::take(trap::Trap::IllegalInstruction, cpu, cpu.regs.pc));
+                return None;
             },
             RV32IInstruction::Add(rd, rs1, rs2) => {
                 let data: u32 = cpu.regs.read(rs1).wrapping_add(cpu.regs.read(rs2));
@@ -332,7 +294,7 @@ impl Execute for RV32IInstruction {
                 return None;
             },
             RV32IInstruction::Sll(rd, rs1, rs2) => {
-                let data: u32 = cpu.regs.read(rs1) << cpu.regs.read(rs2) & 0x1F;
+                let data: u32 = cpu.regs.read(rs1) << (cpu.regs.read(rs2) & 0x1F); // only the low 5 bits of rs2 count
                 cpu.regs.write(rd, data);
                 return None;
             },
@@ -358,12 +320,12 @@ impl Execute for RV32IInstruction {
                 return None;
             },
             RV32IInstruction::Srl(rd, rs1, rs2) => {
-                let data: u32 = cpu.regs.read(rs1) >> cpu.regs.read(rs2) & 0x1F;
+                let data: u32 = cpu.regs.read(rs1) >> (cpu.regs.read(rs2) & 0x1F);
                 cpu.regs.write(rd, data);
                 return None;
             },
             RV32IInstruction::Sra(rd, rs1, rs2) => {
-                let data: u32 = ((cpu.regs.read(rs1) as i32) >> cpu.regs.read(rs2) & 0x1F) as u32;
+                let data: u32 = ((cpu.regs.read(rs1) as i32) >> (cpu.regs.read(rs2) & 0x1F)) as u32;
                 cpu.regs.write(rd, data);
                 return None;
             },
@@ -395,3 +357,237 @@ impl Execute for RV32IInstruction {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu;
+    use crate::testing::{self, asm, DATA, RAM_BASE};
+    use crate::trap;
+
+    const NEG1: u32 = 0xFFFF_FFFF;
+
+    type Branch = fn(u8, u8, i32) -> u32;
+
+    #[test]
+    fn alu() {
+        let cases: [(&str, u32, u32, u32, u32); 48] = [
+            // name, instruction reading x1 and x2 into x3, x1, x2, x3 afterwards
+            ("lui", asm::lui(3, 0xFFFFF), 0, 0, 0xFFFF_F000),
+            ("auipc", asm::auipc(3, 0x1), 0, 0, RAM_BASE + 0x1000),
+            ("auipc wraps", asm::auipc(3, 0x80000), 0, 0, 0),
+            ("addi", asm::addi(3, 1, -1), 1, 0, 0),
+            ("addi wraps", asm::addi(3, 1, 1), NEG1, 0, 0),
+            ("addi largest", asm::addi(3, 1, 2047), 1, 0, 2048),
+            ("addi smallest", asm::addi(3, 1, -2048), 0, 0, 0xFFFF_F800),
+            ("slti", asm::slti(3, 1, 0), NEG1, 0, 1),
+            ("slti equal", asm::slti(3, 1, -1), NEG1, 0, 0),
+            ("sltiu", asm::sltiu(3, 1, 1), 0, 0, 1),
+            ("sltiu sign-extended", asm::sltiu(3, 1, -1), 0xFFFF_FFFE, 0, 1),
+            ("xori not", asm::xori(3, 1, -1), 0x0F0F_0F0F, 0, 0xF0F0_F0F0),
+            ("ori", asm::ori(3, 1, 0x0F0), 0x0000_F00F, 0, 0x0000_F0FF),
+            ("andi sign-extended", asm::andi(3, 1, -16), 0x1234_5678, 0, 0x1234_5670),
+            ("slli", asm::slli(3, 1, 31), 0x3, 0, 0x8000_0000),
+            ("slli 0", asm::slli(3, 1, 0), 0x1234_5678, 0, 0x1234_5678),
+            ("srli", asm::srli(3, 1, 31), 0x8000_0000, 0, 1),
+            ("srai", asm::srai(3, 1, 31), 0x8000_0000, 0, NEG1),
+            ("srai positive", asm::srai(3, 1, 4), 0x7000_0000, 0, 0x0700_0000),
+            ("add", asm::add(3, 1, 2), 0x7FFF_FFFF, 1, 0x8000_0000),
+            ("add wraps", asm::add(3, 1, 2), NEG1, 2, 1),
+            ("sub", asm::sub(3, 1, 2), 0, 1, NEG1),
+            ("sub smallest", asm::sub(3, 1, 2), 0x8000_0000, 1, 0x7FFF_FFFF),
+            ("sll", asm::sll(3, 1, 2), 1, 4, 0x10),
+            ("sll by 32 is by 0", asm::sll(3, 1, 2), 1, 32, 1),
+            ("sll by 33 is by 1", asm::sll(3, 1, 2), 1, 33, 2),
+            ("slt", asm::slt(3, 1, 2), 0x8000_0000, 0x7FFF_FFFF, 1),
+            ("slt not", asm::slt(3, 1, 2), 0x7FFF_FFFF, 0x8000_0000, 0),
+            ("slt equal", asm::slt(3, 1, 2), 5, 5, 0),
+            ("sltu", asm::sltu(3, 1, 2), 0x7FFF_FFFF, 0x8000_0000, 1),
+            ("sltu not", asm::sltu(3, 1, 2), 0x8000_0000, 0x7FFF_FFFF, 0),
+            ("snez", asm::sltu(3, 0, 2), 0, 7, 1),
+            ("xor", asm::xor(3, 1, 2), 0xFF00_FF00, 0x0FF0_0FF0, 0xF0F0_F0F0),
+            ("srl", asm::srl(3, 1, 2), 0x8000_0000, 31, 1),
+            ("srl by 32 is by 0", asm::srl(3, 1, 2), 0x8000_0000, 32, 0x8000_0000),
+            ("srl by -1 is by 31", asm::srl(3, 1, 2), 0x8000_0000, NEG1, 1),
+            ("sra", asm::sra(3, 1, 2), 0x8000_0000, 31, NEG1),
+            ("sra by 36 is by 4", asm::sra(3, 1, 2), 0x8000_0000, 36, 0xF800_0000),
+            ("sra positive", asm::sra(3, 1, 2), 0x4000_0000, 30, 1),
+            ("or", asm::or(3, 1, 2), 0xFF00_0000, 0x0000_00FF, 0xFF00_00FF),
+            ("and", asm::and(3, 1, 2), 0xFF0F_0000, 0x0FFF_00FF, 0x0F0F_0000),
+            ("rd = x0", asm::addi(0, 1, 1), 1, 0, 0),
+            ("fence", asm::fence(0b1111, 0b1111), 0, 0, 0),
+            ("fence rw, w", asm::fence(0b0011, 0b0001), 0, 0, 0),
+            ("fence.tso", asm::FENCE_TSO, 0, 0, 0),
+            ("pause", asm::PAUSE, 0, 0, 0),
+            ("x0 reads as zero", asm::add(3, 0, 0), 1, 2, 0),
+            ("nop", asm::addi(0, 0, 0), 0, 0, 0),
+        ];
+        for (name, instruction, a, b, expected) in cases {
+            testing::check(name, &[instruction], &[(1, a), (2, b), (3, 0)], &[(3, expected), (0, 0)]);
+        }
+        testing::check("rd = rs1", &[asm::add(1, 1, 2)], &[(1, 3), (2, 4)], &[(1, 7), (2, 4)]);
+    }
+
+    #[test]
+    fn branches() {
+        let cases: [(&str, Branch, u32, u32, bool); 18] = [
+            ("beq", asm::beq, 5, 5, true),
+            ("beq", asm::beq, 5, 6, false),
+            ("bne", asm::bne, 5, 6, true),
+            ("bne", asm::bne, 5, 5, false),
+            ("blt", asm::blt, NEG1, 0, true),
+            ("blt", asm::blt, 0, NEG1, false),
+            ("blt", asm::blt, 5, 5, false),
+            ("bge", asm::bge, 0, NEG1, true),
+            ("bge", asm::bge, 5, 5, true),
+            ("bge", asm::bge, NEG1, 0, false),
+            ("bltu", asm::bltu, 0, NEG1, true),
+            ("bltu", asm::bltu, NEG1, 0, false),
+            ("bltu", asm::bltu, 5, 5, false),
+            ("bgeu", asm::bgeu, NEG1, 0, true),
+            ("bgeu", asm::bgeu, 5, 5, true),
+            ("bgeu", asm::bgeu, 0, NEG1, false),
+            ("blt extremes", asm::blt, 0x8000_0000, 0x7FFF_FFFF, true),
+            ("bltu extremes", asm::bltu, 0x8000_0000, 0x7FFF_FFFF, false),
+        ];
+        for (name, branch, a, b, taken) in cases {
+            // the branch skips over setting x3 when taken, and lands on setting x4
+            let mut cpu: cpu::RiscV32 = testing::program(&[branch(1, 2, 8), asm::addi(3, 0, 1), asm::addi(4, 0, 1)]);
+            cpu.regs.write(1, a);
+            cpu.regs.write(2, b);
+            assert_eq!(testing::run(&mut cpu, 2), None, "{} 0x{:08X}, 0x{:08X}", name, a, b);
+            let (x3, x4, pc): (u32, u32, u32) = if taken { (0, 1, RAM_BASE + 12) } else { (1, 0, RAM_BASE + 8) };
+            assert_eq!((cpu.regs.read(3), cpu.regs.read(4), cpu.regs.pc), (x3, x4, pc), "{} 0x{:08X}, 0x{:08X}", name, a, b);
+        }
+    }
+
+    #[test]
+    fn backward_loop() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[
+            asm::addi(1, 0, 5),
+            asm::addi(2, 2, 3), // loop:
+            asm::addi(1, 1, -1),
+            asm::bne(1, 0, -8), // bnez x1, loop
+            asm::EBREAK,
+        ]);
+        assert_eq!(testing::run(&mut cpu, 1 + 5 * 3), None);
+        assert_eq!(cpu.regs.read(2), 15);
+        assert_eq!(cpu.regs.pc, RAM_BASE + 16);
+    }
+
+    #[test]
+    fn jumps() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::jal(1, 8)]);
+        assert_eq!(testing::run(&mut cpu, 1), None);
+        assert_eq!((cpu.regs.read(1), cpu.regs.pc), (RAM_BASE + 4, RAM_BASE + 8), "jal links the next instruction");
+
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::jal(0, 0)]);
+        assert_eq!(testing::run(&mut cpu, 3), None);
+        assert_eq!(cpu.regs.pc, RAM_BASE, "jal to itself spins in place");
+
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::jalr(1, 5, -1)]);
+        cpu.regs.write(5, RAM_BASE + 13);
+        assert_eq!(testing::run(&mut cpu, 1), None);
+        assert_eq!((cpu.regs.read(1), cpu.regs.pc), (RAM_BASE + 4, RAM_BASE + 12), "jalr clears bit 0 of the target");
+
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::jalr(5, 5, 0)]);
+        cpu.regs.write(5, RAM_BASE + 8);
+        assert_eq!(testing::run(&mut cpu, 1), None);
+        assert_eq!((cpu.regs.read(5), cpu.regs.pc), (RAM_BASE + 4, RAM_BASE + 8), "jalr reads rs1 before linking into it");
+
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::jalr(1, 5, 2)]);
+        cpu.regs.write(5, RAM_BASE);
+        assert_eq!(testing::run(&mut cpu, 1), None);
+        assert_eq!(cpu.regs.pc, RAM_BASE + 2, "2-byte aligned targets are fine with C");
+    }
+
+    #[test]
+    fn misaligned_jump_without_c() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::jalr(1, 5, 2)]);
+        cpu.regs.csr.misa &= !cpu::MISA_C;
+        cpu.regs.write(5, RAM_BASE);
+        assert_eq!(testing::run(&mut cpu, 1), Some(trap::Trap::MisalignedInstructionAddress));
+        assert_eq!((cpu.regs.csr.mepc, cpu.regs.csr.mtval), (RAM_BASE, RAM_BASE + 2));
+        assert_eq!(cpu.regs.read(1), 0, "rd isn't written when the jump traps");
+
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::beq(0, 0, 6)]);
+        cpu.regs.csr.misa &= !cpu::MISA_C;
+        assert_eq!(testing::run(&mut cpu, 1), Some(trap::Trap::MisalignedInstructionAddress));
+        assert_eq!(cpu.regs.csr.mtval, RAM_BASE + 6);
+    }
+
+    #[test]
+    fn loads() {
+        let loads: [(&str, u32, u32); 10] = [
+            // x1 points just past the word 0x80FF7F01, bytes 01 7F FF 80
+            ("lb", asm::lb(3, 1, -4), 0x01),
+            ("lb", asm::lb(3, 1, -3), 0x7F),
+            ("lb sign-extends", asm::lb(3, 1, -2), NEG1),
+            ("lb sign-extends", asm::lb(3, 1, -1), 0xFFFF_FF80),
+            ("lbu", asm::lbu(3, 1, -1), 0x80),
+            ("lh", asm::lh(3, 1, -4), 0x7F01),
+            ("lh sign-extends", asm::lh(3, 1, -2), 0xFFFF_80FF),
+            ("lhu", asm::lhu(3, 1, -2), 0x80FF),
+            ("lw", asm::lw(3, 1, -4), 0x80FF_7F01),
+            ("lw from the next word", asm::lw(3, 1, 0), 0x1234_5678),
+        ];
+        for (name, load, expected) in loads {
+            let mut cpu: cpu::RiscV32 = testing::program(&[load]);
+            testing::write_word(&mut cpu, DATA, 0x80FF_7F01);
+            testing::write_word(&mut cpu, DATA + 4, 0x1234_5678);
+            cpu.regs.write(1, DATA + 4);
+            assert_eq!(testing::run(&mut cpu, 1), None, "{}", name);
+            assert_eq!(cpu.regs.read(3), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn stores() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[
+            asm::sw(2, 1, 300),
+            asm::sh(2, 1, 306),
+            asm::sb(2, 1, 309),
+            asm::sw(2, 1, -4),
+            asm::sb(0, 1, -1),
+        ]);
+        cpu.regs.write(1, DATA);
+        cpu.regs.write(2, 0xA1B2_C3D4);
+        assert_eq!(testing::run(&mut cpu, 5), None);
+        assert_eq!(testing::read_word(&mut cpu, DATA + 300), 0xA1B2_C3D4);
+        assert_eq!(testing::read_word(&mut cpu, DATA + 304), 0xC3D4_0000, "sh writes the low half");
+        assert_eq!(testing::read_word(&mut cpu, DATA + 308), 0x0000_D400, "sb writes the low byte");
+        assert_eq!(testing::read_word(&mut cpu, DATA - 4), 0x00B2_C3D4);
+    }
+
+    #[test]
+    fn access_faults() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::lw(3, 0, 0)]);
+        cpu.regs.write(3, 7);
+        assert_eq!(testing::run(&mut cpu, 1), Some(trap::Trap::LoadAccessFault));
+        assert_eq!((cpu.regs.csr.mepc, cpu.regs.csr.mtval, cpu.regs.csr.mcause), (RAM_BASE, 0, 5));
+        assert_eq!(cpu.regs.read(3), 7, "rd isn't written when the load faults");
+
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::sw(0, 1, 0)]);
+        cpu.regs.write(1, RAM_BASE + testing::RAM_SIZE);
+        assert_eq!(testing::run(&mut cpu, 1), Some(trap::Trap::StoreAccessFault));
+        assert_eq!((cpu.regs.csr.mtval, cpu.regs.csr.mcause), (RAM_BASE + testing::RAM_SIZE, 7));
+    }
+
+    #[test]
+    fn environment_calls() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::ECALL]);
+        cpu.regs.csr.mtvec = RAM_BASE + 0x100;
+        assert_eq!(testing::run(&mut cpu, 1), Some(trap::Trap::MModeEnvCall));
+        assert_eq!((cpu.regs.csr.mepc, cpu.regs.csr.mcause, cpu.regs.pc), (RAM_BASE, 11, RAM_BASE + 0x100));
+
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::ECALL]);
+        cpu.regs.csr.mtvec = RAM_BASE + 0x100;
+        cpu.privilege = 0;
+        assert_eq!(testing::run(&mut cpu, 1), Some(trap::Trap::UModeEnvCall));
+        assert_eq!((cpu.regs.csr.mcause, cpu.privilege), (8, 3));
+        assert_eq!(cpu.regs.csr.mstatus >> 11 & 0x3, 0, "MPP holds the mode the call came from");
+
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::EBREAK]);
+        assert_eq!(testing::run(&mut cpu, 1), Some(trap::Trap::Breakpoint));
+        assert_eq!((cpu.regs.csr.mepc, cpu.regs.csr.mtval, cpu.regs.csr.mcause), (RAM_BASE, RAM_BASE, 3));
+    }
+}
@@ -37,12 +37,12 @@ impl Execute for RV32MInstruction {
                 return None;
             },
             RV32MInstruction::Mulh(rd, rs1, rs2) => {
-                let data: i64 = (cpu.regs.read(rs1) as i32 * cpu.regs.read(rs2) as i32) as i64;
+                let data: i64 = cpu.regs.read(rs1) as i32 as i64 * cpu.regs.read(rs2) as i32 as i64; // widened first, the product needs 64 bits
                 cpu.regs.write(rd, (data >> 32) as u32);
                 return None;
             },
             RV32MInstruction::Mulhsu(rd, rs1, rs2) => {
-                let data: i64 = cpu.regs.read(rs1) as i64 * cpu.regs.read(rs2) as u64 as i64;
+                let data: i64 = cpu.regs.read(rs1) as i32 as i64 * cpu.regs.read(rs2) as i64; // rs1 signed, rs2 zero-extended; fits in i64 either way
                 cpu.regs.write(rd, (data >> 32) as u32);
                 return None;
             },
@@ -52,32 +52,36 @@ impl Execute for RV32MInstruction {
                 return None;
             },
             RV32MInstruction::Div(rd, rs1, rs2) => {
-                if cpu.regs.read(rs2) == 0 {
-                    panic!("Division by zero");
+                if cpu.regs.read(rs2) == 0 { // no trap on RISC-V, the quotient has all bits set
+                    cpu.regs.write(rd, u32::MAX);
+                    return None;
                 }
-                let data: i32 = cpu.regs.read(rs1) as i32 / cpu.regs.read(rs2) as i32;
+                let data: i32 = (cpu.regs.read(rs1) as i32).wrapping_div(cpu.regs.read(rs2) as i32); // MIN / -1 overflows back to MIN
                 cpu.regs.write(rd, data as u32);
                 return None;
             },
             RV32MInstruction::Divu(rd, rs1, rs2) => {
                 if cpu.regs.read(rs2) == 0 {
-                    panic!("Division by zero");
+                    cpu.regs.write(rd, u32::MAX);
+                    return None;
                 }
                 let data: u32 = cpu.regs.read(rs1) / cpu.regs.read(rs2);
                 cpu.regs.write(rd, data);
                 return None;
             },
             RV32MInstruction::Rem(rd, rs1, rs2) => {
-                if cpu.regs.read(rs2) == 0 {
-                    panic!("Division by zero");
+                if cpu.regs.read(rs2) == 0 { // the remainder is the dividend
+                    cpu.regs.write(rd, cpu.regs.read(rs1));
+                    return None;
                 }
-                let data: i32 = cpu.regs.read(rs1) as i32 % cpu.regs.read(rs2) as i32;
+                let data: i32 = (cpu.regs.read(rs1) as i32).wrapping_rem(cpu.regs.read(rs2) as i32); // and MIN % -1 is 0
                 cpu.regs.write(rd, data as u32);
                 return None;
             },
             RV32MInstruction::Remu(rd, rs1, rs2) => {
                 if cpu.regs.read(rs2) == 0 {
-                    panic!("Division by zero");
+                    cpu.regs.write(rd, cpu.regs.read(rs1));
+                    return None;
                 }
                 let data: u32 = cpu.regs.read(rs1) % cpu.regs.read(rs2);
                 cpu.regs.write(rd, data);
@@ -85,4 +89,160 @@ impl Execute for RV32MInstruction {
             },
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::RV32MInstruction;
+    use crate::cpu;
+    use crate::extensions::Execute;
+    use crate::testing::{self, asm};
+
+    const MIN: u32 = 0x8000_0000; // i32::MIN
+    const MAX: u32 = 0x7FFF_FFFF; // i32::MAX
+    const NEG1: u32 = 0xFFFF_FFFF;
+
+    // operands worth multiplying together: zero, ones, the signed and unsigned extremes and their neighbours
+    const EDGES: [u32; 12] = [0, 1, 2, 3, NEG1, 0xFFFF_FFFE, MIN, MIN + 1, MAX, MAX - 1, 0x0000_FFFF, 0x0001_0000];
+
+    type Op = fn(u8, u8, u8) -> RV32MInstruction;
+
+    fn run(op: Op, a: u32, b: u32) -> u32 {
+        let mut cpu: cpu::RiscV32 = testing::machine();
+        cpu.regs.write(1, a);
+        cpu.regs.write(2, b);
+        assert!(op(3, 1, 2).execute(&mut cpu).is_none(), "M instructions never trap");
+        return cpu.regs.read(3);
+    }
+
+    #[test]
+    fn edge_cases() {
+        let cases: [(&str, Op, u32, u32, u32); 57] = [
+            ("mul", RV32MInstruction::Mul, MAX, 2, 0xFFFF_FFFE),
+            ("mul", RV32MInstruction::Mul, MIN, NEG1, MIN),
+            ("mul", RV32MInstruction::Mul, NEG1, NEG1, 1),
+            ("mul", RV32MInstruction::Mul, 0x0001_0000, 0x0001_0000, 0),
+            ("mul", RV32MInstruction::Mul, 0, MIN, 0),
+            ("mulh", RV32MInstruction::Mulh, NEG1, NEG1, 0),
+            ("mulh", RV32MInstruction::Mulh, MIN, MIN, 0x4000_0000),
+            ("mulh", RV32MInstruction::Mulh, MIN, NEG1, 0),
+            ("mulh", RV32MInstruction::Mulh, MIN, 1, NEG1),
+            ("mulh", RV32MInstruction::Mulh, MAX, MAX, 0x3FFF_FFFF),
+            ("mulh", RV32MInstruction::Mulh, MIN, MAX, 0xC000_0000),
+            ("mulh", RV32MInstruction::Mulh, 2, 0x4000_0000, 0),
+            ("mulh", RV32MInstruction::Mulh, 0xFFFF_FFFE, 0x4000_0000, NEG1),
+            ("mulhsu", RV32MInstruction::Mulhsu, NEG1, NEG1, NEG1),
+            ("mulhsu", RV32MInstruction::Mulhsu, MIN, NEG1, MIN),
+            ("mulhsu", RV32MInstruction::Mulhsu, MAX, NEG1, 0x7FFF_FFFE),
+            ("mulhsu", RV32MInstruction::Mulhsu, 1, NEG1, 0),
+            ("mulhsu", RV32MInstruction::Mulhsu, NEG1, 1, NEG1),
+            ("mulhsu", RV32MInstruction::Mulhsu, 0, NEG1, 0),
+            ("mulhsu", RV32MInstruction::Mulhsu, 2, MIN, 1),
+            ("mulhsu", RV32MInstruction::Mulhsu, 0xFFFF_FFFE, MIN, NEG1),
+            ("mulhu", RV32MInstruction::Mulhu, NEG1, NEG1, 0xFFFF_FFFE),
+            ("mulhu", RV32MInstruction::Mulhu, MIN, 2, 1),
+            ("mulhu", RV32MInstruction::Mulhu, MIN, MIN, 0x4000_0000),
+            ("mulhu", RV32MInstruction::Mulhu, MAX, 1, 0),
+            ("div", RV32MInstruction::Div, 7, 2, 3),
+            ("div", RV32MInstruction::Div, (-7i32) as u32, 2, (-3i32) as u32),
+            ("div", RV32MInstruction::Div, 7, (-2i32) as u32, (-3i32) as u32),
+            ("div", RV32MInstruction::Div, (-7i32) as u32, (-2i32) as u32, 3),
+            ("div", RV32MInstruction::Div, 5, 0, NEG1),
+            ("div", RV32MInstruction::Div, MIN, 0, NEG1),
+            ("div", RV32MInstruction::Div, 0, 0, NEG1),
+            ("div", RV32MInstruction::Div, MIN, NEG1, MIN),
+            ("div", RV32MInstruction::Div, MIN, 1, MIN),
+            ("divu", RV32MInstruction::Divu, 7, 2, 3),
+            ("divu", RV32MInstruction::Divu, NEG1, 1, NEG1),
+            ("divu", RV32MInstruction::Divu, 0xFFFF_FFFE, NEG1, 0),
+            ("divu", RV32MInstruction::Divu, MIN, NEG1, 0),
+            ("divu", RV32MInstruction::Divu, 5, 0, NEG1),
+            ("divu", RV32MInstruction::Divu, 0, 0, NEG1),
+            ("rem", RV32MInstruction::Rem, 7, 2, 1),
+            ("rem", RV32MInstruction::Rem, (-7i32) as u32, 2, NEG1),
+            ("rem", RV32MInstruction::Rem, 7, (-2i32) as u32, 1),
+            ("rem", RV32MInstruction::Rem, (-7i32) as u32, (-2i32) as u32, NEG1),
+            ("rem", RV32MInstruction::Rem, 5, 0, 5),
+            ("rem", RV32MInstruction::Rem, MIN, 0, MIN),
+            ("rem", RV32MInstruction::Rem, (-3i32) as u32, 0, (-3i32) as u32),
+            ("rem", RV32MInstruction::Rem, MIN, NEG1, 0),
+            ("rem", RV32MInstruction::Rem, MIN, 1, 0),
+            ("remu", RV32MInstruction::Remu, 7, 2, 1),
+            ("remu", RV32MInstruction::Remu, NEG1, 0x10, 0xF),
+            ("remu", RV32MInstruction::Remu, NEG1, 0, NEG1),
+            ("remu", RV32MInstruction::Remu, 0, 0, 0),
+            ("remu", RV32MInstruction::Remu, 5, NEG1, 5),
+            ("remu", RV32MInstruction::Remu, MIN, NEG1, MIN),
+            ("remu", RV32MInstruction::Remu, NEG1, MIN, MAX),
+            ("remu", RV32MInstruction::Remu, MIN, 0, MIN),
+        ];
+        for (name, op, a, b, expected) in cases {
+            assert_eq!(run(op, a, b), expected, "{} 0x{:08X}, 0x{:08X}", name, a, b);
+        }
+    }
+
+    /// Every pair of edge operands against the products worked out in 128 bits.
+    #[test]
+    fn products_of_edges() {
+        for a in EDGES {
+            for b in EDGES {
+                let signed: i128 = a as i32 as i128 * b as i32 as i128;
+                let mixed: i128 = a as i32 as i128 * b as i128;
+                let unsigned: u128 = a as u128 * b as u128;
+                assert_eq!(run(RV32MInstruction::Mul, a, b), unsigned as u32, "mul 0x{:08X}, 0x{:08X}", a, b);
+                assert_eq!(run(RV32MInstruction::Mulh, a, b), (signed >> 32) as u32, "mulh 0x{:08X}, 0x{:08X}", a, b);
+                assert_eq!(run(RV32MInstruction::Mulhsu, a, b), (mixed >> 32) as u32, "mulhsu 0x{:08X}, 0x{:08X}", a, b);
+                assert_eq!(run(RV32MInstruction::Mulhu, a, b), (unsigned >> 32) as u32, "mulhu 0x{:08X}, 0x{:08X}", a, b);
+            }
+        }
+    }
+
+    /// The quotient and remainder always put the dividend back together, overflow and division by zero included.
+    #[test]
+    fn division_identity() {
+        for a in EDGES {
+            for b in EDGES {
+                let (q, r): (u32, u32) = (run(RV32MInstruction::Div, a, b), run(RV32MInstruction::Rem, a, b));
+                assert_eq!(q.wrapping_mul(b).wrapping_add(r), a, "div/rem 0x{:08X}, 0x{:08X}", a, b);
+                let (q, r): (u32, u32) = (run(RV32MInstruction::Divu, a, b), run(RV32MInstruction::Remu, a, b));
+                assert_eq!(q.wrapping_mul(b).wrapping_add(r), a, "divu/remu 0x{:08X}, 0x{:08X}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn destination_aliases_source() {
+        let mut cpu: cpu::RiscV32 = testing::machine();
+        cpu.regs.write(1, 9);
+        cpu.regs.write(2, 0);
+        assert!(RV32MInstruction::Rem(1, 1, 2).execute(&mut cpu).is_none());
+        assert_eq!(cpu.regs.read(1), 9);
+        assert!(RV32MInstruction::Div(0, 1, 2).execute(&mut cpu).is_none());
+        assert_eq!(cpu.regs.read(0), 0, "x0 stays zero");
+    }
+
+    /// The same operations fetched and decoded from memory, rather than built by hand.
+    #[test]
+    fn decoded() {
+        let code: [u32; 8] = [
+            asm::mul(10, 1, 2),
+            asm::mulh(11, 1, 2),
+            asm::mulhsu(12, 1, 2),
+            asm::mulhu(13, 1, 2),
+            asm::div(14, 1, 2),
+            asm::divu(15, 1, 2),
+            asm::rem(16, 1, 2),
+            asm::remu(17, 1, 2),
+        ];
+        let (a, b): (u32, u32) = ((-7i32) as u32, 3);
+        testing::check("rv32m", &code, &[(1, a), (2, b)], &[
+            (10, (-21i32) as u32),
+            (11, NEG1),
+            (12, NEG1),
+            (13, 2),
+            (14, (-2i32) as u32),
+            (15, 0x5555_5553),
+            (16, NEG1),
+            (17, 0),
+        ]);
+    }
+}
@@ -28,92 +28,228 @@ impl Execute for RV32ZicsrInstruction {
     fn execute(self, cpu: &mut cpu::RiscV32) -> Option<trap::Trap> {
         match self {
             RV32ZicsrInstruction::Csrrw(rd, rs1, csr) => {
-                if rd > 0 {
-                    let t: u32 = cpu.read_csr(csr).unwrap(); // temporary error handling
-                    //eprintln!("[csr<{csr}>]:<0x{:08X}>", t);
-                    cpu.regs.write(rd, t);
+                let data: u32 = cpu.regs.read(rs1); // before rd is written, it may be the same register
+                let t: u32 = if rd > 0 { // csrrw with rd = x0 doesn't read the CSR, nor has its side effects
+                    match cpu.read_csr(csr) {
+                        Ok(t) => t,
+                        Err(trap) => return Some(trap),
+                    }
+                } else {
+                    0
+                };
+                if let Some(trap) = cpu.write_csr(csr, data) {
+                    return Some(trap);
                 }
-                let data: u32 = cpu.regs.read(rs1);
-                //eprintln!("[data]:<0x{:08X}>", data);
-                cpu.write_csr(csr, data)?; // temporary error handling
-                //let t: u32 = cpu.read_csr(csr).unwrap();
-                //eprintln!("[csr<{csr}>]:<0x{:08X}>", t);
+                cpu.regs.write(rd, t); // only once the access is known to be legal
                 return None;
             },
             RV32ZicsrInstruction::Csrrs(rd, rs1, csr) => {
-                let t: u32 = cpu.read_csr(csr).unwrap();
-                if rd > 0 {
-                    //eprintln!("[csr<{csr}>]:<0x{:08X}>", t);
-                    cpu.regs.write(rd, t);
-                }
+                let data: u32 = cpu.regs.read(rs1);
+                let t: u32 = match cpu.read_csr(csr) {
+                    Ok(t) => t,
+                    Err(trap) => return Some(trap),
+                };
                 if rs1 > 0 {
-                    let data: u32 = t | cpu.regs.read(rs1);
-                    cpu.write_csr(csr, data)?;
-                    //eprintln!("[data]:<0x{:08X}>", data);
-                    //let t: u32 = cpu.read_csr(csr).unwrap();
-                    //eprintln!("[csr<{csr}>]:<0x{:08X}>", t);
+                    if let Some(trap) = cpu.write_csr(csr, t | data) {
+                        return Some(trap);
+                    }
                 }
+                cpu.regs.write(rd, t);
                 return None;
             },
             RV32ZicsrInstruction::Csrrc(rd, rs1, csr) => {
-                let t: u32 = cpu.read_csr(csr).unwrap();
-                if rd > 0 {
-                    //eprintln!("[csr<{csr}>]:<0x{:08X}>", t);
-                    cpu.regs.write(rd, t);
-                }
+                let data: u32 = cpu.regs.read(rs1);
+                let t: u32 = match cpu.read_csr(csr) {
+                    Ok(t) => t,
+                    Err(trap) => return Some(trap),
+                };
                 if rs1 > 0 {
-                    let data: u32 = t & !cpu.regs.read(rs1);
-                    cpu.write_csr(csr, data)?;
-                    //eprintln!("[data]:<0x{:08X}>", data);
-                    //let t: u32 = cpu.read_csr(csr).unwrap();
-                    //eprintln!("[csr<{csr}>]:<0x{:08X}>", t);
+                    if let Some(trap) = cpu.write_csr(csr, t & !data) {
+                        return Some(trap);
+                    }
                 }
+                cpu.regs.write(rd, t);
                 return None;
             },
             RV32ZicsrInstruction::Csrrwi(rd, zimm, csr) => {
-                if rd > 0 {
-                    let t: u32 = cpu.read_csr(csr).unwrap();
-                    //eprintln!("[csr<{csr}>]:<0x{:08X}>", t);
-                    cpu.regs.write(rd, t);
-                }
-                if zimm > 0 {
-                    cpu.write_csr(csr, (zimm & 0x1F) as u32)?;
-                    //eprintln!("[zimm]:<0x{:08X}>", (zimm & 0x1F));
-                    //let t: u32 = cpu.read_csr(csr).unwrap();
-                    //eprintln!("[csr<{csr}>]:<0x{:08X}>", t);
+                let data: u32 = (zimm & 0x1F) as u32;
+                let t: u32 = if rd > 0 {
+                    match cpu.read_csr(csr) {
+                        Ok(t) => t,
+                        Err(trap) => return Some(trap),
+                    }
+                } else {
+                    0
+                };
+                if let Some(trap) = cpu.write_csr(csr, data) {
+                    return Some(trap);
                 }
+                cpu.regs.write(rd, t);
                 return None;
             },
             RV32ZicsrInstruction::Csrrsi(rd, zimm, csr) => {
-                let t: u32 = cpu.read_csr(csr).unwrap();
-                if rd > 0 {
-                    //eprintln!("[csr<{csr}>]:<0x{:08X}>", t);
-                    cpu.regs.write(rd, t);
-                }
+                let data: u32 = (zimm & 0x1F) as u32;
+                let t: u32 = match cpu.read_csr(csr) {
+                    Ok(t) => t,
+                    Err(trap) => return Some(trap),
+                };
                 if zimm > 0 {
-                    let data: u32 = t | (zimm & 0x1F) as u32;
-                    cpu.write_csr(csr, data)?;
-                    //eprintln!("[data]:<0x{:08X}>", data);
-                    //let t: u32 = cpu.read_csr(csr).unwrap();
-                    //eprintln!("[csr<{csr}>]:<0x{:08X}>", t);
+                    if let Some(trap) = cpu.write_csr(csr, t | data) {
+                        return Some(trap);
+                    }
                 }
+                cpu.regs.write(rd, t);
                 return None;
             },
             RV32ZicsrInstruction::Csrrci(rd, zimm, csr) => {
-                let t: u32 = cpu.read_csr(csr).unwrap();
-                if rd > 0 {
-                    //eprintln!("[csr<{csr}>]:<0x{:08X}>", t);
-                    cpu.regs.write(rd, t);
-                }
+                let data: u32 = (zimm & 0x1F) as u32;
+                let t: u32 = match cpu.read_csr(csr) {
+                    Ok(t) => t,
+                    Err(trap) => return Some(trap),
+                };
                 if zimm > 0 {
-                    let data: u32 = t & !(zimm & 0x1F) as u32;
-                    cpu.write_csr(csr, data)?;
-                    //eprintln!("[data]:<0x{:08X}>", data);
-                    //let t: u32 = cpu.read_csr(csr).unwrap();
-                    //eprintln!("[csr<{csr}>]:<0x{:08X}>", t);
+                    if let Some(trap) = cpu.write_csr(csr, t & !data) {
+                        return Some(trap);
+                    }
                 }
+                cpu.regs.write(rd, t);
                 return None;
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu;
+    use crate::io;
+    use crate::log;
+    use crate::testing::{self, asm};
+    use crate::trap;
+
+    const MISA: u16 = 0x301;
+    const MSCRATCH: u16 = 0x340;
+    const MHARTID: u16 = 0xF14;
+    const FFLAGS: u16 = 0x001;
+    const FCSR: u16 = 0x003;
+
+    #[test]
+    fn read_modify_write() {
+        let cases: [(&str, u32, u32); 10] = [
+            // name, instruction on mscratch = 0xF0F0_F0F0 with x1 = 0x0000_FFFF, mscratch afterwards
+            ("csrrw", asm::csrrw(3, 1, MSCRATCH), 0x0000_FFFF),
+            ("csrrs", asm::csrrs(3, 1, MSCRATCH), 0xF0F0_FFFF),
+            ("csrrc", asm::csrrc(3, 1, MSCRATCH), 0xF0F0_0000),
+            ("csrrwi", asm::csrrwi(3, 0x15, MSCRATCH), 0x0000_0015),
+            ("csrrsi", asm::csrrsi(3, 0x0F, MSCRATCH), 0xF0F0_F0FF),
+            ("csrrci", asm::csrrci(3, 0x10, MSCRATCH), 0xF0F0_F0E0),
+            ("csrr", asm::csrrs(3, 0, MSCRATCH), 0xF0F0_F0F0),
+            ("csrrc with x0", asm::csrrc(3, 0, MSCRATCH), 0xF0F0_F0F0),
+            ("csrrsi with 0", asm::csrrsi(3, 0, MSCRATCH), 0xF0F0_F0F0),
+            ("csrrci with 0", asm::csrrci(3, 0, MSCRATCH), 0xF0F0_F0F0),
+        ];
+        for (name, instruction, expected) in cases {
+            let mut cpu: cpu::RiscV32 = testing::program(&[instruction]);
+            cpu.regs.csr.mscratch = 0xF0F0_F0F0;
+            cpu.regs.write(1, 0x0000_FFFF);
+            assert_eq!(testing::run(&mut cpu, 1), None, "{}", name);
+            assert_eq!((cpu.regs.read(3), cpu.regs.csr.mscratch), (0xF0F0_F0F0, expected), "{}", name);
+        }
+    }
+
+    #[test]
+    fn destination_aliases_source() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::csrrw(1, 1, MSCRATCH)]);
+        cpu.regs.csr.mscratch = 1;
+        cpu.regs.write(1, 2);
+        assert_eq!(testing::run(&mut cpu, 1), None);
+        assert_eq!((cpu.regs.read(1), cpu.regs.csr.mscratch), (1, 2), "rs1 is read before the old value lands in rd");
+
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::csrrs(1, 1, MSCRATCH)]);
+        cpu.regs.csr.mscratch = 1;
+        cpu.regs.write(1, 2);
+        assert_eq!(testing::run(&mut cpu, 1), None);
+        assert_eq!((cpu.regs.read(1), cpu.regs.csr.mscratch), (1, 3));
+    }
+
+    #[test]
+    fn read_only() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::csrrs(3, 0, MHARTID), asm::csrrsi(4, 0, MHARTID)]);
+        assert_eq!(testing::run(&mut cpu, 2), None, "reading a read-only CSR is fine");
+        assert_eq!((cpu.regs.read(3), cpu.regs.read(4)), (0, 0));
+
+        let writes: [(&str, u32); 4] = [
+            ("csrrw", asm::csrrw(3, 1, MHARTID)),
+            ("csrrw to x0", asm::csrrw(0, 1, MHARTID)),
+            ("csrrs", asm::csrrs(3, 1, MHARTID)),
+            ("csrrwi", asm::csrrwi(3, 0, MHARTID)), // writes zero, but still writes
+        ];
+        for (name, instruction) in writes {
+            let mut cpu: cpu::RiscV32 = testing::program(&[instruction]);
+            cpu.regs.write(1, 1);
+            cpu.regs.write(3, 7);
+            assert_eq!(testing::run(&mut cpu, 1), Some(trap::Trap::IllegalInstruction), "{}", name);
+            assert_eq!(cpu.regs.read(3), 7, "{} leaves rd alone when it traps", name);
+        }
+
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::csrrw(3, 1, 0xC00)]);
+        assert_eq!(testing::run(&mut cpu, 1), Some(trap::Trap::IllegalInstruction), "cycle is read-only too");
+    }
+
+    #[test]
+    fn illegal_accesses() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::csrrs(3, 0, MSCRATCH)]);
+        cpu.privilege = 0;
+        cpu.regs.write(3, 7);
+        assert_eq!(testing::run(&mut cpu, 1), Some(trap::Trap::IllegalInstruction), "M-mode CSRs from U-mode");
+        assert_eq!((cpu.regs.read(3), cpu.regs.csr.mepc, cpu.privilege), (7, testing::RAM_BASE, 3));
+
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::csrrw(3, 1, 0x140)]);
+        cpu.privilege = 0;
+        assert_eq!(testing::run(&mut cpu, 1), Some(trap::Trap::IllegalInstruction), "S-mode CSRs from U-mode");
+
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::csrrs(3, 0, 0x7FF)]);
+        assert_eq!(testing::run(&mut cpu, 1), Some(trap::Trap::IllegalInstruction), "a CSR that doesn't exist");
+    }
+
+    #[test]
+    fn fp_csrs_need_fs() {
+        let code: [u32; 2] = [asm::csrrs(3, 0, FCSR), asm::csrrsi(0, 0x1, FFLAGS)];
+        let mut cpu: cpu::RiscV32 = testing::program(&code);
+        cpu.regs.csr.fcsr = 0x20;
+        assert_eq!(testing::run(&mut cpu, 1), Some(trap::Trap::IllegalInstruction), "fcsr with mstatus.FS off");
+
+        let mut cpu: cpu::RiscV32 = testing::program(&code);
+        cpu.regs.csr.fcsr = 0x20;
+        cpu.regs.csr.mstatus |= 1 << 13; // initial
+        cpu.privilege = 0;
+        assert_eq!(testing::run(&mut cpu, 2), None, "user-level once FS is on");
+        assert_eq!((cpu.regs.read(3), cpu.regs.csr.fcsr), (0x20, 0x21));
+        assert_eq!(cpu.regs.csr.mstatus & cpu::MSTATUS_FS, cpu::MSTATUS_FS, "writing them dirties the FP state");
+    }
+
+    #[test]
+    fn misa_is_warl() {
+        log::apply_filter(&[(None, log::Level::Off)]);
+        let mut cpu: cpu::RiscV32 = cpu::RiscV32::with_config(cpu::MachineConfig {
+            ram_size: testing::RAM_SIZE,
+            serial: io::SerialBackend::Null,
+            isa: cpu::MISA_I | cpu::MISA_S | cpu::MISA_U,
+            ..cpu::MachineConfig::default()
+        }).unwrap();
+        cpu.reset();
+        cpu.regs.pc = testing::RAM_BASE;
+        testing::load(&mut cpu, testing::RAM_BASE, &[asm::csrrw(0, 1, MISA), asm::mul(3, 1, 1)]);
+        cpu.regs.write(1, u32::MAX);
+        assert_eq!(testing::run(&mut cpu, 2), Some(trap::Trap::IllegalInstruction), "M stays off after csrw misa, -1");
+        assert_eq!(cpu.regs.csr.misa, cpu::MISA_MXL_32 | cpu::MISA_I | cpu::MISA_S | cpu::MISA_U);
+
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::csrrw(0, 1, MISA), asm::mul(3, 2, 2)]);
+        cpu.regs.write(1, cpu::MISA_F);
+        assert_eq!(testing::run(&mut cpu, 2), Some(trap::Trap::IllegalInstruction), "what --isa enabled can be turned off");
+        assert_eq!(cpu.regs.csr.misa, cpu::MISA_MXL_32 | cpu::MISA_I | cpu::MISA_S | cpu::MISA_U | cpu::MISA_F);
+        cpu.regs.write(1, cpu::MISA_D | cpu::MISA_M);
+        assert!(cpu.write_csr(MISA, cpu.regs.read(1)).is_none());
+        assert_eq!(cpu.regs.csr.misa & (cpu::MISA_D | cpu::MISA_M), cpu::MISA_M, "and back on, but not D without F");
+    }
+}
@@ -0,0 +1,486 @@
+use crate::cpu;
+use crate::plic;
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+const HEADER_SIZE: usize = 40;
+
+const UART_CLOCK: u32 = 1_843_200; // divisor 1 gives 115200 baud, what the UART resets to
+
+// phandles of the nodes other nodes point at
+const PHANDLE_PLIC: u32 = 1;
+const PHANDLE_CPU_INTC: u32 = 2; // + hart ID
+
+/// Optional contents of `/chosen`.
+#[derive(Debug, Clone, Default)]
+pub struct Chosen {
+    pub bootargs: Option<String>,
+    pub initrd: Option<(u32, u32)>, // [start, end)
+}
+
+/// Writes a flattened device tree: nodes are opened and closed in order, properties go to the innermost open node.
+pub struct FdtBuilder {
+    reserved: Vec<(u64, u64)>, // memory reservation map
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    depth: usize,
+}
+
+#[allow(dead_code)]
+impl FdtBuilder {
+    pub fn new() -> FdtBuilder {
+        return FdtBuilder {
+            reserved: Vec::new(),
+            structure: Vec::new(),
+            strings: Vec::new(),
+            depth: 0,
+        };
+    }
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+    fn pad(&mut self) {
+        while self.structure.len() & 0x3 != 0 {
+            self.structure.push(0);
+        }
+    }
+    /// Offset of `name` in the strings block, shared between every property with that name.
+    fn string(&mut self, name: &str) -> u32 {
+        let mut offset: usize = 0;
+        for s in self.strings.split(|b| *b == 0) {
+            if s == name.as_bytes() && offset < self.strings.len() {
+                return offset as u32;
+            }
+            offset += s.len() + 1;
+        }
+        let offset: u32 = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        return offset;
+    }
+    /// Adds an entry to the memory reservation map, a range the kernel must leave alone.
+    pub fn reserve(&mut self, base: u64, size: u64) {
+        self.reserved.push((base, size));
+    }
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.pad();
+        self.depth += 1;
+    }
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "no node to close");
+        self.token(FDT_END_NODE);
+        self.depth -= 1;
+    }
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        assert!(self.depth > 0, "properties belong to a node");
+        let nameoff: u32 = self.string(name);
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(nameoff);
+        self.structure.extend_from_slice(value);
+        self.pad();
+    }
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+    pub fn property_u32s(&mut self, name: &str, values: &[u32]) {
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+        self.property(name, &bytes);
+    }
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property(name, &value.to_be_bytes());
+    }
+    pub fn property_str(&mut self, name: &str, value: &str) {
+        self.property_strs(name, &[value]);
+    }
+    pub fn property_strs(&mut self, name: &str, values: &[&str]) {
+        let mut bytes: Vec<u8> = Vec::new();
+        for value in values {
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+        }
+        self.property(name, &bytes);
+    }
+    /// `reg` with two address and two size cells per entry.
+    pub fn property_reg(&mut self, name: &str, regions: &[(u64, u64)]) {
+        let mut cells: Vec<u32> = Vec::new();
+        for (base, size) in regions {
+            cells.extend_from_slice(&[(base >> 32) as u32, *base as u32, (size >> 32) as u32, *size as u32]);
+        }
+        self.property_u32s(name, &cells);
+    }
+    /// Closes the tree and lays out the blob: header, memory reservation map, structure and strings.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert!(self.depth == 0, "{} node(s) left open", self.depth);
+        self.token(FDT_END);
+        let off_mem_rsvmap: usize = HEADER_SIZE;
+        let off_dt_struct: usize = off_mem_rsvmap + 16 * (self.reserved.len() + 1); // plus the terminating entry
+        let off_dt_strings: usize = off_dt_struct + self.structure.len();
+        let totalsize: usize = off_dt_strings + self.strings.len();
+        let mut blob: Vec<u8> = Vec::with_capacity(totalsize);
+        for field in [FDT_MAGIC, totalsize as u32, off_dt_struct as u32, off_dt_strings as u32, off_mem_rsvmap as u32, FDT_VERSION, FDT_LAST_COMP_VERSION, 0, self.strings.len() as u32, self.structure.len() as u32] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        for (base, size) in self.reserved.iter() {
+            blob.extend_from_slice(&base.to_be_bytes());
+            blob.extend_from_slice(&size.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0u8; 16]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        return blob;
+    }
+}
+
+fn chosen_properties(fdt: &mut FdtBuilder, chosen: &Chosen) {
+    if let Some(bootargs) = &chosen.bootargs {
+        fdt.property_str("bootargs", bootargs);
+    }
+    if let Some((start, end)) = chosen.initrd {
+        fdt.property_u64("linux,initrd-start", start as u64);
+        fdt.property_u64("linux,initrd-end", end as u64);
+    }
+}
+
+fn be32(blob: &[u8], offset: usize) -> Result<u32, &'static str> {
+    let field: &[u8] = blob.get(offset..offset + 4).ok_or("devicetree blob is truncated")?;
+    return Ok(u32::from_be_bytes([field[0], field[1], field[2], field[3]]));
+}
+
+fn c_str(blob: &[u8], offset: usize) -> Result<&str, &'static str> {
+    let tail: &[u8] = blob.get(offset..).ok_or("devicetree blob is truncated")?;
+    let len: usize = tail.iter().position(|b| *b == 0).ok_or("devicetree blob is truncated")?;
+    return std::str::from_utf8(&tail[..len]).map_err(|_| "devicetree blob has a name that isn't UTF-8");
+}
+
+/// Checks the header of a DTB that didn't come from us.
+pub fn check(blob: &[u8]) -> Result<(), &'static str> {
+    if blob.len() < HEADER_SIZE || be32(blob, 0)? != FDT_MAGIC {
+        return Err("not a devicetree blob");
+    }
+    if be32(blob, 4)? as usize > blob.len() {
+        return Err("devicetree blob is truncated");
+    }
+    if be32(blob, 24)? < FDT_LAST_COMP_VERSION {
+        return Err("devicetree blob is too old, version 16 or later is needed");
+    }
+    return Ok(());
+}
+
+/// Rewrites `blob` with the `/chosen` properties in `chosen` replacing the ones it had, creating the node if needed.
+pub fn patch_chosen(blob: &[u8], chosen: &Chosen) -> Result<Vec<u8>, &'static str> {
+    check(blob)?;
+    let (off_dt_struct, off_dt_strings, off_mem_rsvmap): (usize, usize, usize) = (be32(blob, 8)? as usize, be32(blob, 12)? as usize, be32(blob, 16)? as usize);
+    let mut fdt: FdtBuilder = FdtBuilder::new();
+    let mut offset: usize = off_mem_rsvmap;
+    loop {
+        let base: u64 = ((be32(blob, offset)? as u64) << 32) | be32(blob, offset + 4)? as u64;
+        let size: u64 = ((be32(blob, offset + 8)? as u64) << 32) | be32(blob, offset + 12)? as u64;
+        offset += 16;
+        if base == 0 && size == 0 {
+            break;
+        }
+        fdt.reserve(base, size);
+    }
+    let replaced = |name: &str| -> bool {
+        match name {
+            "bootargs" => return chosen.bootargs.is_some(),
+            "linux,initrd-start" | "linux,initrd-end" => return chosen.initrd.is_some(),
+            _ => return false,
+        }
+    };
+    let mut offset: usize = off_dt_struct;
+    let mut in_chosen: bool = false;
+    let mut seen_chosen: bool = false;
+    loop {
+        let token: u32 = be32(blob, offset)?;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name: &str = c_str(blob, offset)?;
+                offset = (offset + name.len() + 1 + 3) & !0x3;
+                fdt.begin_node(name);
+                if fdt.depth == 2 && name == "chosen" {
+                    in_chosen = true;
+                    seen_chosen = true;
+                }
+            },
+            FDT_END_NODE => {
+                if fdt.depth == 0 {
+                    return Err("devicetree blob closes more nodes than it opens");
+                }
+                if in_chosen && fdt.depth == 2 {
+                    chosen_properties(&mut fdt, chosen);
+                    in_chosen = false;
+                }
+                if fdt.depth == 1 && !seen_chosen {
+                    fdt.begin_node("chosen");
+                    chosen_properties(&mut fdt, chosen);
+                    fdt.end_node();
+                }
+                fdt.end_node();
+            },
+            FDT_PROP => {
+                let (len, nameoff): (usize, usize) = (be32(blob, offset)? as usize, be32(blob, offset + 4)? as usize);
+                let value: &[u8] = blob.get(offset + 8..offset + 8 + len).ok_or("devicetree blob is truncated")?;
+                offset = (offset + 8 + len + 3) & !0x3;
+                let name: &str = c_str(blob, off_dt_strings + nameoff)?;
+                if fdt.depth == 0 {
+                    return Err("devicetree blob has a property outside of any node");
+                }
+                if !(in_chosen && fdt.depth == 2 && replaced(name)) {
+                    fdt.property(name, value);
+                }
+            },
+            FDT_NOP => {},
+            FDT_END => break,
+            _ => return Err("devicetree blob has an unknown token"),
+        }
+    }
+    if fdt.depth != 0 {
+        return Err("devicetree blob leaves nodes open");
+    }
+    return Ok(fdt.finish());
+}
+
+/// ISA string in canonical order, e.g. `rv32imafdc_zicsr_zifencei`, and the extensions one by one.
+fn isa(misa: u32) -> (String, Vec<String>) {
+    let mut extensions: Vec<String> = Vec::new();
+    for letter in "imafdqc".chars() {
+        if misa & (1 << (letter as u32 - 'a' as u32)) != 0 {
+            extensions.push(letter.to_string());
+        }
+    }
+    let mut isa: String = format!("rv32{}", extensions.concat());
+    for z in ["zicsr", "zifencei"] {
+        isa.push('_');
+        isa.push_str(z);
+        extensions.push(z.to_string());
+    }
+    return (isa, extensions);
+}
+
+/// Builds the device tree of `cpu`'s machine: its harts, RAM and every device attached to the bus.
+pub fn generate(cpu: &cpu::RiscV32, chosen: &Chosen) -> Vec<u8> {
+    let mut fdt: FdtBuilder = FdtBuilder::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_str("compatible", "marv,rv32");
+    fdt.property_str("model", "MARV RISC-V RV32 emulator");
+
+    fdt.begin_node("chosen");
+    chosen_properties(&mut fdt, chosen);
+    for (name, base, _) in cpu.bus.devices() {
+        if name == "uart" {
+            fdt.property_str("stdout-path", &format!("/soc/serial@{:x}", base));
+        }
+    }
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    if cpu.config.timebase_frequency <= u32::MAX as u64 {
+        fdt.property_u32("timebase-frequency", cpu.config.timebase_frequency as u32);
+    } else {
+        fdt.property_u64("timebase-frequency", cpu.config.timebase_frequency);
+    }
+    let (isa, extensions): (String, Vec<String>) = isa(cpu.regs.csr.misa);
+    let extensions: Vec<&str> = extensions.iter().map(|e| e.as_str()).collect();
+    for hart in 0..cpu::HARTS as u32 {
+        fdt.begin_node(&format!("cpu@{:x}", hart));
+        fdt.property_str("device_type", "cpu");
+        fdt.property_u32("reg", hart);
+        fdt.property_str("status", "okay");
+        fdt.property_str("compatible", "riscv");
+        fdt.property_str("riscv,isa", &isa);
+        fdt.property_str("riscv,isa-base", "rv32i");
+        fdt.property_strs("riscv,isa-extensions", &extensions);
+        fdt.property_str("mmu-type", "riscv,sv32");
+        fdt.begin_node("interrupt-controller");
+        fdt.property_str("compatible", "riscv,cpu-intc");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_u32("phandle", PHANDLE_CPU_INTC + hart);
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    let (ram_base, ram_size): (u32, u32) = (cpu.bus.ram.base(), cpu.bus.ram.size());
+    fdt.begin_node(&format!("memory@{:x}", ram_base));
+    fdt.property_str("device_type", "memory");
+    fdt.property_reg("reg", &[(ram_base as u64, ram_size as u64)]);
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_str("compatible", "simple-bus");
+    fdt.property_empty("ranges");
+    for (name, base, size) in cpu.bus.devices() {
+        let intc = |irqs: &[u32]| -> Vec<u32> { // interrupts-extended pointing at the intc of every hart
+            let mut cells: Vec<u32> = Vec::new();
+            for hart in 0..cpu::HARTS as u32 {
+                for irq in irqs {
+                    cells.extend_from_slice(&[PHANDLE_CPU_INTC + hart, *irq]);
+                }
+            }
+            return cells;
+        };
+        match name {
+            "clint" => {
+                fdt.begin_node(&format!("clint@{:x}", base));
+                fdt.property_strs("compatible", &["sifive,clint0", "riscv,clint0"]);
+                fdt.property_reg("reg", &[(base as u64, size as u64)]);
+                fdt.property_u32s("interrupts-extended", &intc(&[3, 7])); // MSIP, MTIP
+                fdt.end_node();
+            },
+            "plic" => {
+                fdt.begin_node(&format!("plic@{:x}", base));
+                fdt.property_strs("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+                fdt.property_reg("reg", &[(base as u64, size as u64)]);
+                fdt.property_u32("#address-cells", 0);
+                fdt.property_u32("#interrupt-cells", 1);
+                fdt.property_empty("interrupt-controller");
+                fdt.property_u32("riscv,ndev", plic::SOURCES as u32 - 1);
+                fdt.property_u32s("interrupts-extended", &intc(&[11, 9])); // one M-mode and one S-mode context per hart
+                fdt.property_u32("phandle", PHANDLE_PLIC);
+                fdt.end_node();
+            },
+            "uart" => {
+                fdt.begin_node(&format!("serial@{:x}", base));
+                fdt.property_str("compatible", "ns16550a");
+                fdt.property_reg("reg", &[(base as u64, size as u64)]);
+                fdt.property_u32("clock-frequency", UART_CLOCK);
+                fdt.property_u32("current-speed", 115200);
+                fdt.property_u32("reg-io-width", 1);
+                fdt.property_u32("reg-shift", 0);
+                fdt.property_u32("interrupt-parent", PHANDLE_PLIC);
+                fdt.property_u32("interrupts", plic::UART_IRQ);
+                fdt.end_node();
+            },
+            _ => {}, // devices without a binding stay out of the tree
+        }
+    }
+    fdt.end_node();
+
+    fdt.end_node();
+    return fdt.finish();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{be32, c_str, check, patch_chosen, Chosen, FdtBuilder};
+
+    type Properties = Vec<(String, Vec<u8>)>;
+
+    /// Properties of `/chosen`, in order, or `None` if the blob has no such node.
+    fn chosen_of(blob: &[u8]) -> Option<Properties> {
+        let (mut offset, strings): (usize, usize) = (be32(blob, 8).unwrap() as usize, be32(blob, 12).unwrap() as usize);
+        let mut path: Vec<String> = Vec::new();
+        let mut chosen: Option<Properties> = None;
+        loop {
+            let token: u32 = be32(blob, offset).unwrap();
+            offset += 4;
+            match token {
+                super::FDT_BEGIN_NODE => {
+                    let name: &str = c_str(blob, offset).unwrap();
+                    offset = (offset + name.len() + 1 + 3) & !0x3;
+                    path.push(String::from(name));
+                    if path == ["", "chosen"] {
+                        chosen = Some(Vec::new());
+                    }
+                },
+                super::FDT_END_NODE => drop(path.pop()),
+                super::FDT_PROP => {
+                    let (len, nameoff): (usize, usize) = (be32(blob, offset).unwrap() as usize, be32(blob, offset + 4).unwrap() as usize);
+                    if path == ["", "chosen"] {
+                        let name: String = String::from(c_str(blob, strings + nameoff).unwrap());
+                        chosen.as_mut().unwrap().push((name, blob[offset + 8..offset + 8 + len].to_vec()));
+                    }
+                    offset = (offset + 8 + len + 3) & !0x3;
+                },
+                super::FDT_END => return chosen,
+                _ => {},
+            }
+        }
+    }
+
+    fn blob(with_chosen: bool) -> Vec<u8> {
+        let mut fdt: FdtBuilder = FdtBuilder::new();
+        fdt.reserve(0x8000_0000, 0x1000);
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        if with_chosen {
+            fdt.begin_node("chosen");
+            fdt.property_str("bootargs", "console=hvc0");
+            fdt.property_str("stdout-path", "/soc/serial@10000000");
+            fdt.end_node();
+        }
+        fdt.begin_node("memory@80000000");
+        fdt.property_str("device_type", "memory");
+        fdt.end_node();
+        fdt.end_node();
+        return fdt.finish();
+    }
+
+    fn property(name: &str, value: &[u8]) -> (String, Vec<u8>) {
+        return (String::from(name), value.to_vec());
+    }
+
+    #[test]
+    fn chosen_round_trips() {
+        let chosen: Chosen = Chosen {
+            bootargs: Some(String::from("console=ttyS0 quiet")),
+            initrd: Some((0x8400_0000, 0x8420_0000)),
+        };
+        let patched: Vec<u8> = patch_chosen(&blob(true), &chosen).unwrap();
+        assert!(check(&patched).is_ok());
+        assert_eq!(chosen_of(&patched).unwrap(), vec![
+            property("stdout-path", b"/soc/serial@10000000\0"),
+            property("bootargs", b"console=ttyS0 quiet\0"),
+            property("linux,initrd-start", &0x8400_0000u64.to_be_bytes()),
+            property("linux,initrd-end", &0x8420_0000u64.to_be_bytes()),
+        ]);
+        assert_eq!(patch_chosen(&patched, &chosen).unwrap(), patched, "patching again changes nothing");
+        assert_eq!(patch_chosen(&patched, &Chosen::default()).unwrap(), patched, "properties left unset are kept");
+        let rsvmap: usize = be32(&patched, 16).unwrap() as usize;
+        assert_eq!((be32(&patched, rsvmap + 4).unwrap(), be32(&patched, rsvmap + 12).unwrap()), (0x8000_0000, 0x1000), "reservations are kept");
+    }
+
+    #[test]
+    fn chosen_is_created() {
+        let chosen: Chosen = Chosen {
+            bootargs: Some(String::from("root=/dev/ram")),
+            initrd: None,
+        };
+        let original: Vec<u8> = blob(false);
+        assert_eq!(chosen_of(&original), None);
+        assert_eq!(chosen_of(&patch_chosen(&original, &chosen).unwrap()).unwrap(), vec![property("bootargs", b"root=/dev/ram\0")]);
+    }
+
+    #[test]
+    fn bad_blobs() {
+        let original: Vec<u8> = blob(true);
+        assert_eq!(patch_chosen(&original[..20], &Chosen::default()), Err("not a devicetree blob"));
+        assert_eq!(patch_chosen(&original[..original.len() - 8], &Chosen::default()), Err("devicetree blob is truncated"));
+        let mut old: Vec<u8> = original.clone();
+        old[24..28].copy_from_slice(&15u32.to_be_bytes()); // last compatible version
+        assert_eq!(patch_chosen(&old, &Chosen::default()), Err("devicetree blob is too old, version 16 or later is needed"));
+    }
+}
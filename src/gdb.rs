@@ -0,0 +1,884 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use crate::cpu;
+use crate::halt;
+use crate::log;
+use crate::mmu;
+use crate::trap;
+
+// signal numbers as GDB knows them, whatever the host uses
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 10;
+const SIGSEGV: u8 = 11;
+
+// register numbers, same layout as GDB's RISC-V target
+const REG_PC: u32 = 32;
+const REG_F0: u32 = 33;
+const REG_CSR0: u32 = 65; // CSR n is register 65 + n
+const REG_PRIV: u32 = REG_CSR0 + 4096;
+
+const PACKET_SIZE: usize = 0x4000;
+const POLL_INTERVAL: u64 = 0x1000; // instructions between two looks for a Ctrl-C while running
+
+const CSRS: [(&str, u16); 32] = [
+    ("sstatus", 0x100), ("sie", 0x104), ("stvec", 0x105), ("scounteren", 0x106),
+    ("sscratch", 0x140), ("sepc", 0x141), ("scause", 0x142), ("stval", 0x143), ("sip", 0x144), ("satp", 0x180),
+    ("mstatus", 0x300), ("misa", 0x301), ("medeleg", 0x302), ("mideleg", 0x303), ("mie", 0x304), ("mtvec", 0x305), ("mcounteren", 0x306),
+    ("mscratch", 0x340), ("mepc", 0x341), ("mcause", 0x342), ("mtval", 0x343), ("mip", 0x344),
+    ("cycle", 0xC00), ("time", 0xC01), ("instret", 0xC02), ("cycleh", 0xC80), ("timeh", 0xC81), ("instreth", 0xC82),
+    ("mvendorid", 0xF11), ("marchid", 0xF12), ("mimpid", 0xF13), ("mhartid", 0xF14),
+];
+
+/// Where the stub waits for the debugger.
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    Tcp(String), // host:port
+    Unix(String), // socket path
+}
+
+impl Endpoint {
+    /// `1234`, `host:1234` or `unix:/path/to/socket`; a bare port only listens on localhost.
+    pub fn parse(value: &str) -> Result<Endpoint, String> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(String::from("unix: needs a socket path"));
+            }
+            return Ok(Endpoint::Unix(String::from(path)));
+        }
+        if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+            return Ok(Endpoint::Tcp(format!("127.0.0.1:{}", value)));
+        }
+        match value.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => return Ok(Endpoint::Tcp(String::from(value))),
+            _ => return Err(format!("expected a port, host:port or unix:<path>, got '{}'", value)),
+        }
+    }
+    fn accept(&self) -> io::Result<Connection> {
+        log::logln!(Gdb, Info, "[gdb] waiting for a debugger on {}", self);
+        match self {
+            Endpoint::Tcp(address) => {
+                let (stream, peer) = TcpListener::bind(address)?.accept()?;
+                stream.set_nodelay(true)?; // packets are tiny and latency is all that matters
+                log::logln!(Gdb, Info, "[gdb] debugger connected from {}", peer);
+                return Ok(Connection::Tcp(stream));
+            },
+            Endpoint::Unix(path) => {
+                if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) { // left over by an earlier run
+                    std::fs::remove_file(path)?;
+                }
+                let (stream, _) = UnixListener::bind(path)?.accept()?;
+                log::logln!(Gdb, Info, "[gdb] debugger connected");
+                return Ok(Connection::Unix(stream));
+            },
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(address) => write!(f, "{}", address),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path),
+        }
+    }
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Connection {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => return stream.set_nonblocking(nonblocking),
+            Connection::Unix(stream) => return stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => return stream.read(buf),
+            Connection::Unix(stream) => return stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => return stream.write(buf),
+            Connection::Unix(stream) => return stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => return stream.flush(),
+            Connection::Unix(stream) => return stream.flush(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access, // either
+}
+
+/// A data watchpoint over `[address, address + len)`, in virtual addresses like the debugger sees them.
+#[derive(Debug, Clone, Copy)]
+pub struct Watchpoint {
+    pub address: u32,
+    pub len: u32,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    pub fn hit(&self, address: u32, size: u32, write: bool) -> bool {
+        let overlaps: bool = address < self.address.wrapping_add(self.len) && self.address < address.wrapping_add(size);
+        match self.kind {
+            WatchKind::Write => return overlaps && write,
+            WatchKind::Read => return overlaps && !write,
+            WatchKind::Access => return overlaps,
+        }
+    }
+}
+
+/// Why the hart stopped and control went back to the debugger.
+enum Stop {
+    Signal(u8),
+    SoftwareBreakpoint,
+    HardwareBreakpoint,
+    Watch(WatchKind, u32),
+    Exited(i32),
+}
+
+impl Stop {
+    fn reply(&self) -> String {
+        match self {
+            Stop::Signal(signal) => return format!("S{:02x}", signal),
+            Stop::SoftwareBreakpoint => return format!("T{:02x}swbreak:;", SIGTRAP),
+            Stop::HardwareBreakpoint => return format!("T{:02x}hwbreak:;", SIGTRAP),
+            Stop::Watch(kind, address) => {
+                let name: &str = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                return format!("T{:02x}{}:{:x};", SIGTRAP, name, address);
+            },
+            Stop::Exited(code) => return format!("W{:02x}", *code as u8),
+        }
+    }
+}
+
+/// What to do after a packet was handled.
+enum Action {
+    Reply(String),
+    StartNoAck, // reply OK, then stop acknowledging packets
+    Resume(bool), // true for a single step
+    Detach,
+    Kill,
+}
+
+/// The signal GDB reports for a trap the guest raised.
+fn signal(trap: trap::Trap) -> u8 {
+    match trap {
+        trap::Trap::IllegalInstruction => return SIGILL,
+        trap::Trap::MisalignedInstructionAddress | trap::Trap::MisalignedLoadAddr | trap::Trap::MisalignedStoreAddr => return SIGBUS,
+        trap::Trap::InstructionAccessFault | trap::Trap::LoadAccessFault | trap::Trap::StoreAccessFault => return SIGSEGV,
+        trap::Trap::InstructionPageFault | trap::Trap::LoadPageFault | trap::Trap::StorePageFault => return SIGSEGV,
+        _ => return SIGTRAP,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    return u32::from_str_radix(text, 16).ok();
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if text.len() & 1 != 0 {
+        return None;
+    }
+    return (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect();
+}
+
+/// `addr,length` as found in memory and breakpoint packets.
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+    let (address, len) = text.split_once(',')?;
+    return Some((parse_hex(address)?, parse_hex(len)?));
+}
+
+/// Reads guest memory at a virtual address through the MMU and the bus, stopping at the first byte that isn't there.
+fn read_memory(cpu: &mut cpu::RiscV32, address: u32, len: u32) -> Vec<u8> {
+    if matches!(len, 1 | 2 | 4 | 8) && address & (len - 1) == 0 { // try one access of the natural size first, devices may not like bytes
+        if let Some(data) = mmu::probe(cpu, address).and_then(|paddr| cpu.bus.read(paddr, len)) {
+            return data.to_le_bytes()[..len as usize].to_vec();
+        }
+    }
+    let mut bytes: Vec<u8> = Vec::new();
+    for i in 0..len {
+        let address: u32 = address.wrapping_add(i);
+        match mmu::probe(cpu, address).and_then(|paddr| cpu.bus.read(paddr, 1)) {
+            Some(byte) => bytes.push(byte as u8),
+            None => break,
+        }
+    }
+    return bytes;
+}
+
+fn write_memory(cpu: &mut cpu::RiscV32, address: u32, bytes: &[u8]) -> bool {
+    let len: u32 = bytes.len() as u32;
+    if matches!(len, 1 | 2 | 4 | 8) && address & (len - 1) == 0 {
+        let mut data: [u8; 8] = [0; 8];
+        data[..bytes.len()].copy_from_slice(bytes);
+        if let Some(paddr) = mmu::probe(cpu, address) {
+            if cpu.bus.write(paddr, len, u64::from_le_bytes(data)).is_some() {
+                cpu.snoop_store(paddr, len);
+                return true;
+            }
+        }
+    }
+    for (i, byte) in bytes.iter().enumerate() {
+        let paddr: Option<u32> = mmu::probe(cpu, address.wrapping_add(i as u32));
+        match paddr.and_then(|paddr| cpu.bus.write(paddr, 1, *byte as u64)) {
+            Some(()) => cpu.snoop_store(paddr.unwrap_or(0), 1),
+            None => return false,
+        }
+    }
+    return true;
+}
+
+/// Register `number` and its width in bytes, `None` if the hart doesn't have it.
+fn read_register(cpu: &mut cpu::RiscV32, number: u32) -> Option<(u64, usize)> {
+    let misa: u32 = cpu.regs.csr.misa;
+    match number {
+        0..=31 => return Some((cpu.regs.read(number as u8) as u64, 4)),
+        REG_PC => return Some((cpu.regs.pc as u64, 4)),
+        _ if number >= REG_F0 && number < REG_F0 + 32 && misa & cpu::MISA_F != 0 => {
+            let f: u64 = cpu.regs.f[(number - REG_F0) as usize];
+            return if misa & cpu::MISA_D != 0 { Some((f, 8)) } else { Some((f & 0xFFFF_FFFF, 4)) };
+        },
+        REG_PRIV => return Some((cpu.privilege as u64, 4)),
+        _ if number >= REG_CSR0 && number < REG_PRIV => return cpu.get_csr((number - REG_CSR0) as u16).map(|value| (value as u64, 4)),
+        _ => return None,
+    }
+}
+
+fn write_register(cpu: &mut cpu::RiscV32, number: u32, value: u64) -> bool {
+    let misa: u32 = cpu.regs.csr.misa;
+    match number {
+        0..=31 => cpu.regs.write(number as u8, value as u32),
+        REG_PC => cpu.regs.pc = value as u32,
+        _ if number >= REG_F0 && number < REG_F0 + 32 && misa & cpu::MISA_F != 0 => {
+            if misa & cpu::MISA_D != 0 {
+                cpu.regs.write_f64((number - REG_F0) as u8, value);
+            } else {
+                cpu.regs.write_f32((number - REG_F0) as u8, value as u32);
+            }
+        },
+        REG_PRIV => match value {
+            0 | 1 | 3 => cpu.privilege = value as u8,
+            _ => return false,
+        },
+        _ if number >= REG_CSR0 && number < REG_PRIV => return cpu.set_csr((number - REG_CSR0) as u16, value as u32).is_some(),
+        _ => return false,
+    }
+    return true;
+}
+
+/// Target description handed to GDB, matching the extensions enabled in `misa`.
+fn target_xml(misa: u32) -> String {
+    let mut xml: String = String::from("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n<architecture>riscv:rv32</architecture>\n");
+    xml += "<feature name=\"org.gnu.gdb.riscv.cpu\">\n";
+    for (i, name) in cpu::X_ABI_NAMES.iter().enumerate() {
+        let kind: &str = match *name {
+            "ra" => "code_ptr",
+            "sp" | "gp" | "tp" => "data_ptr",
+            _ => "int",
+        };
+        xml += &format!("<reg name=\"{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>\n", name, kind, i);
+    }
+    xml += &format!("<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/>\n</feature>\n", REG_PC);
+    if misa & cpu::MISA_F != 0 {
+        let (bits, kind): (u32, &str) = if misa & cpu::MISA_D != 0 { (64, "ieee_double") } else { (32, "ieee_single") };
+        xml += "<feature name=\"org.gnu.gdb.riscv.fpu\">\n";
+        for (i, name) in cpu::F_ABI_NAMES.iter().enumerate() {
+            xml += &format!("<reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>\n", name, bits, kind, REG_F0 + i as u32);
+        }
+        for (name, csr) in [("fflags", 0x001), ("frm", 0x002), ("fcsr", 0x003)] {
+            xml += &format!("<reg name=\"{}\" bitsize=\"32\" type=\"int\" regnum=\"{}\" group=\"float\"/>\n", name, REG_CSR0 + csr);
+        }
+        xml += "</feature>\n";
+    }
+    xml += "<feature name=\"org.gnu.gdb.riscv.csr\">\n";
+    for (name, csr) in CSRS.iter() {
+        xml += &format!("<reg name=\"{}\" bitsize=\"32\" type=\"int\" regnum=\"{}\" group=\"csr\"/>\n", name, REG_CSR0 + *csr as u32);
+    }
+    xml += "</feature>\n<feature name=\"org.gnu.gdb.riscv.virtual\">\n";
+    xml += &format!("<reg name=\"priv\" bitsize=\"32\" type=\"int\" regnum=\"{}\" group=\"system\"/>\n</feature>\n</target>\n", REG_PRIV);
+    return xml;
+}
+
+/// A debugger session over one connection.
+struct Stub {
+    connection: Connection,
+    acks: bool, // until the debugger asks for QStartNoAckMode
+    software: Vec<u32>, // breakpoint addresses; nothing is patched into guest memory, so both kinds work the same
+    hardware: Vec<u32>,
+    xml: String,
+    last_stop: String,
+    stop_on: cpu::StopOn, // which guest traps stop the target, the rest go to the guest's handlers
+}
+
+impl Stub {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte: [u8; 1] = [0];
+        loop {
+            match self.connection.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    /// Next packet from the debugger with escapes undone, `None` once it hung up.
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {},
+                Some(_) => continue, // acks, and Ctrl-C while already stopped
+            }
+            let mut packet: Vec<u8> = Vec::new();
+            let mut checksum: u8 = 0;
+            let mut escaped: bool = false;
+            loop {
+                let byte: u8 = self.read_byte()?.ok_or(io::ErrorKind::UnexpectedEof)?;
+                if byte == b'#' {
+                    break;
+                }
+                checksum = checksum.wrapping_add(byte);
+                if escaped {
+                    packet.push(byte ^ 0x20);
+                    escaped = false;
+                } else if byte == b'}' {
+                    escaped = true;
+                } else {
+                    packet.push(byte);
+                }
+            }
+            let high: u8 = self.read_byte()?.ok_or(io::ErrorKind::UnexpectedEof)?;
+            let low: u8 = self.read_byte()?.ok_or(io::ErrorKind::UnexpectedEof)?;
+            if self.acks {
+                let sent: Option<u8> = std::str::from_utf8(&[high, low]).ok().and_then(|digits| u8::from_str_radix(digits, 16).ok());
+                if sent != Some(checksum) {
+                    self.connection.write_all(b"-")?;
+                    continue;
+                }
+                self.connection.write_all(b"+")?;
+            }
+            log::logln!(Gdb, Trace, "[gdb] <- {}", String::from_utf8_lossy(&packet));
+            return Ok(Some(packet));
+        }
+    }
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        log::logln!(Gdb, Trace, "[gdb] -> {}", reply);
+        let checksum: u8 = reply.bytes().fold(0, |sum, b| sum.wrapping_add(b));
+        let packet: String = format!("${}#{:02x}", reply, checksum);
+        loop {
+            self.connection.write_all(packet.as_bytes())?;
+            if !self.acks {
+                return Ok(());
+            }
+            loop {
+                match self.read_byte()? {
+                    Some(b'+') => return Ok(()),
+                    Some(b'-') => break, // send it again
+                    Some(_) => continue,
+                    None => return Err(io::ErrorKind::UnexpectedEof.into()),
+                }
+            }
+        }
+    }
+    /// Whether the debugger sent a Ctrl-C since the last look; the connection is non-blocking while the hart runs.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        let mut byte: [u8; 1] = [0];
+        match self.connection.read(&mut byte) {
+            Ok(0) => return Ok(true), // hung up, stop so the next read notices
+            Ok(_) => return Ok(byte[0] == 0x03),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+    fn resume(&mut self, cpu: &mut cpu::RiscV32, step: bool) -> io::Result<Stop> {
+        self.connection.set_nonblocking(true)?;
+        let stop: io::Result<Stop> = self.run(cpu, step);
+        self.connection.set_nonblocking(false)?;
+        return stop;
+    }
+    fn run(&mut self, cpu: &mut cpu::RiscV32, step: bool) -> io::Result<Stop> {
+        let mut executed: u64 = 0;
+        cpu.watch_hit = None;
+        loop {
+            if !cpu.status {
+                return Ok(Stop::Exited(cpu.exit_code));
+            }
+            if executed != 0 { // resuming from a breakpoint executes the instruction under it
+                if self.software.contains(&cpu.regs.pc) {
+                    return Ok(Stop::SoftwareBreakpoint);
+                }
+                if self.hardware.contains(&cpu.regs.pc) {
+                    return Ok(Stop::HardwareBreakpoint);
+                }
+            }
+            let pc: u32 = cpu.regs.pc;
+            let trap: Option<trap::Trap> = cpu.step();
+            executed += 1;
+            if !cpu.status {
+                return Ok(Stop::Exited(cpu.exit_code));
+            }
+            if let Some(trap) = trap.filter(|trap| cpu.trap_halt(*trap, pc, self.stop_on).is_some()) {
+                log::logln!(Gdb, Debug, "[gdb] stopped on {:?}", trap);
+                return Ok(Stop::Signal(signal(trap)));
+            }
+            if let Some((kind, address)) = cpu.watch_hit.take() {
+                return Ok(Stop::Watch(kind, address));
+            }
+            if step {
+                return Ok(Stop::Signal(SIGTRAP));
+            }
+            if executed & (POLL_INTERVAL - 1) == 0 && self.interrupted()? {
+                return Ok(Stop::Signal(SIGINT));
+            }
+        }
+    }
+    fn breakpoint(&mut self, cpu: &mut cpu::RiscV32, args: &str, insert: bool) -> String {
+        let mut fields = args.split(',');
+        let (kind, address, len): (Option<&str>, Option<u32>, Option<u32>) = (fields.next(), fields.next().and_then(parse_hex), fields.next().and_then(parse_hex));
+        let (address, len): (u32, u32) = match (address, len) {
+            (Some(address), Some(len)) => (address, len),
+            _ => return String::from("E01"),
+        };
+        let list: &mut Vec<u32> = match kind {
+            Some("0") => &mut self.software,
+            Some("1") => &mut self.hardware,
+            Some("2") | Some("3") | Some("4") => {
+                let kind: WatchKind = match kind {
+                    Some("2") => WatchKind::Write,
+                    Some("3") => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                if insert {
+                    cpu.watchpoints.push(Watchpoint { address: address, len: len.max(1), kind: kind });
+                } else if let Some(i) = cpu.watchpoints.iter().position(|w| w.address == address && w.len == len.max(1) && w.kind == kind) {
+                    cpu.watchpoints.remove(i);
+                }
+                return String::from("OK");
+            },
+            _ => return String::new(),
+        };
+        if insert {
+            if !list.contains(&address) {
+                list.push(address);
+            }
+        } else {
+            list.retain(|a| *a != address);
+        }
+        return String::from("OK");
+    }
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+;vContSupported+", PACKET_SIZE);
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:") {
+            let (annex, range): (&str, &str) = args.split_once(':').unwrap_or((args, ""));
+            if annex != "target.xml" {
+                return String::from("E00");
+            }
+            let (offset, len): (u32, u32) = match parse_range(range) {
+                Some(range) => range,
+                None => return String::from("E01"),
+            };
+            let start: usize = (offset as usize).min(self.xml.len());
+            let end: usize = start.saturating_add(len as usize).min(self.xml.len());
+            let more: char = if end < self.xml.len() { 'm' } else { 'l' };
+            return format!("{}{}", more, &self.xml[start..end]);
+        }
+        match packet {
+            "qAttached" => return String::from("1"), // detaching leaves the machine running rather than killing it
+            "qC" => return String::from("QC1"),
+            "qfThreadInfo" => return String::from("m1"),
+            "qsThreadInfo" => return String::from("l"),
+            "qSymbol::" => return String::from("OK"),
+            _ => return String::new(),
+        }
+    }
+    fn handle(&mut self, cpu: &mut cpu::RiscV32, packet: &[u8]) -> Action {
+        if let Some(data) = packet.strip_prefix(b"X") { // binary write, the only packet that isn't text
+            let colon: usize = match data.iter().position(|b| *b == b':') {
+                Some(colon) => colon,
+                None => return Action::Reply(String::from("E01")),
+            };
+            let range: Option<(u32, u32)> = std::str::from_utf8(&data[..colon]).ok().and_then(parse_range);
+            return match range {
+                Some((address, len)) if len as usize == data.len() - colon - 1 => {
+                    Action::Reply(String::from(if write_memory(cpu, address, &data[colon + 1..]) { "OK" } else { "E14" }))
+                },
+                _ => Action::Reply(String::from("E01")),
+            };
+        }
+        let packet: String = String::from_utf8_lossy(packet).into_owned();
+        let (command, args): (char, &str) = match packet.chars().next() {
+            Some(command) if command.is_ascii() => (command, &packet[1..]),
+            _ => return Action::Reply(String::new()), // empty, or not a command we know
+        };
+        match command {
+            '?' => return Action::Reply(self.last_stop.clone()),
+            'q' => return Action::Reply(self.query(&packet)),
+            'Q' if packet == "QStartNoAckMode" => return Action::StartNoAck,
+            'H' | 'T' => return Action::Reply(String::from("OK")), // a single thread, always alive
+            'g' => {
+                let mut regs: String = String::new();
+                for number in 0..=REG_PC {
+                    let (value, _) = read_register(cpu, number).unwrap_or((0, 4));
+                    regs += &hex(&(value as u32).to_le_bytes());
+                }
+                return Action::Reply(regs);
+            },
+            'G' => {
+                let bytes: Vec<u8> = match parse_hex_bytes(args) {
+                    Some(bytes) => bytes,
+                    None => return Action::Reply(String::from("E01")),
+                };
+                for (number, value) in bytes.chunks_exact(4).take(REG_PC as usize + 1).enumerate() {
+                    write_register(cpu, number as u32, u32::from_le_bytes([value[0], value[1], value[2], value[3]]) as u64);
+                }
+                return Action::Reply(String::from("OK"));
+            },
+            'p' => match parse_hex(args).and_then(|number| read_register(cpu, number)) {
+                Some((value, size)) => return Action::Reply(hex(&value.to_le_bytes()[..size])),
+                None => return Action::Reply(String::from("E01")),
+            },
+            'P' => {
+                let (number, value) = match args.split_once('=') {
+                    Some((number, value)) => (parse_hex(number), parse_hex_bytes(value)),
+                    None => (None, None),
+                };
+                match (number, value) {
+                    (Some(number), Some(bytes)) if bytes.len() <= 8 => {
+                        let mut value: [u8; 8] = [0; 8];
+                        value[..bytes.len()].copy_from_slice(&bytes);
+                        return Action::Reply(String::from(if write_register(cpu, number, u64::from_le_bytes(value)) { "OK" } else { "E01" }));
+                    },
+                    _ => return Action::Reply(String::from("E01")),
+                }
+            },
+            'm' => match parse_range(args) {
+                Some((address, len)) => {
+                    let bytes: Vec<u8> = read_memory(cpu, address, len.min(PACKET_SIZE as u32 / 2 - 4));
+                    return Action::Reply(if bytes.is_empty() && len != 0 { String::from("E14") } else { hex(&bytes) });
+                },
+                None => return Action::Reply(String::from("E01")),
+            },
+            'M' => {
+                let (range, data) = args.split_once(':').unwrap_or((args, ""));
+                match (parse_range(range), parse_hex_bytes(data)) {
+                    (Some((address, len)), Some(bytes)) if len as usize == bytes.len() => {
+                        return Action::Reply(String::from(if write_memory(cpu, address, &bytes) { "OK" } else { "E14" }));
+                    },
+                    _ => return Action::Reply(String::from("E01")),
+                }
+            },
+            'c' | 's' | 'C' | 'S' => {
+                let address: &str = match command {
+                    'c' | 's' => args,
+                    _ => args.split_once(';').map(|(_, address)| address).unwrap_or(""), // the signal is dropped, the guest has no handlers for it
+                };
+                if let Some(address) = parse_hex(address) {
+                    cpu.regs.pc = address;
+                }
+                return Action::Resume(command == 's' || command == 'S');
+            },
+            'v' => {
+                if packet == "vCont?" {
+                    return Action::Reply(String::from("vCont;c;C;s;S"));
+                }
+                if let Some(actions) = packet.strip_prefix("vCont;") { // one hart, so the first action is the one for it
+                    match actions.chars().next() {
+                        Some('c' | 'C') => return Action::Resume(false),
+                        Some('s' | 'S') => return Action::Resume(true),
+                        _ => return Action::Reply(String::from("E01")),
+                    }
+                }
+                if packet.starts_with("vKill") {
+                    return Action::Kill;
+                }
+                return Action::Reply(String::new());
+            },
+            'Z' => return Action::Reply(self.breakpoint(cpu, args, true)),
+            'z' => return Action::Reply(self.breakpoint(cpu, args, false)),
+            'D' => return Action::Detach,
+            'k' => return Action::Kill,
+            _ => return Action::Reply(String::new()),
+        }
+    }
+}
+
+/// Serves one debugger session, `None` if it detached or went away with the machine still running.
+fn session(cpu: &mut cpu::RiscV32, endpoint: &Endpoint, stop_on: cpu::StopOn) -> io::Result<Option<halt::HaltReason>> {
+    let mut stub: Stub = Stub {
+        connection: endpoint.accept()?,
+        acks: true,
+        software: Vec::new(),
+        hardware: Vec::new(),
+        xml: target_xml(cpu.regs.csr.misa),
+        last_stop: Stop::Signal(SIGTRAP).reply(),
+        stop_on: stop_on,
+    };
+    loop {
+        let packet: Vec<u8> = match stub.read_packet()? {
+            Some(packet) => packet,
+            None => {
+                log::logln!(Gdb, Info, "[gdb] debugger went away, running on");
+                break;
+            },
+        };
+        match stub.handle(cpu, &packet) {
+            Action::Reply(reply) => stub.send(&reply)?,
+            Action::StartNoAck => {
+                stub.send("OK")?;
+                stub.acks = false;
+            },
+            Action::Resume(step) => {
+                let stop: Stop = stub.resume(cpu, step)?;
+                stub.last_stop = stop.reply();
+                let reply: String = stub.last_stop.clone();
+                stub.send(&reply)?;
+                if let Stop::Exited(code) = stop {
+                    return Ok(Some(halt::HaltReason::Shutdown(code)));
+                }
+            },
+            Action::Detach => {
+                stub.send("OK")?;
+                log::logln!(Gdb, Info, "[gdb] debugger detached, running on");
+                break;
+            },
+            Action::Kill => {
+                if packet.starts_with(b"vKill") {
+                    stub.send("OK")?;
+                }
+                return Ok(Some(halt::HaltReason::Killed));
+            },
+        }
+    }
+    return Ok(None);
+}
+
+/// Waits for GDB on `endpoint` and runs the hart under its control until the machine halts.
+/// If the debugger detaches or goes away, the machine keeps running on its own within `limits`.
+pub fn serve(cpu: &mut cpu::RiscV32, endpoint: &Endpoint, limits: cpu::RunLimits) -> Result<halt::HaltReason, halt::EmuError> {
+    match session(cpu, endpoint, limits.stop_on) {
+        Ok(Some(reason)) => return Ok(reason),
+        Ok(None) => {
+            cpu.watchpoints.clear();
+            return cpu.execute(limits);
+        },
+        Err(e) => return Err(halt::EmuError::Io(format!("debugger connection on {}", endpoint), e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{target_xml, Action, Connection, Stub};
+    use crate::cpu;
+    use crate::testing::{self, asm, DATA, RAM_BASE};
+    use std::os::unix::net::UnixStream;
+
+    /// A stub on one end of a socket pair; the other end is returned to keep the connection open.
+    fn stub(cpu: &cpu::RiscV32) -> (Stub, UnixStream) {
+        let (stream, peer) = UnixStream::pair().unwrap();
+        let stub: Stub = Stub {
+            connection: Connection::Unix(stream),
+            acks: true,
+            software: Vec::new(),
+            hardware: Vec::new(),
+            xml: target_xml(cpu.regs.csr.misa),
+            last_stop: String::new(),
+            stop_on: cpu::StopOn::Never,
+        };
+        return (stub, peer);
+    }
+
+    fn reply(stub: &mut Stub, cpu: &mut cpu::RiscV32, packet: &[u8]) -> String {
+        match stub.handle(cpu, packet) {
+            Action::Reply(reply) => return reply,
+            _ => panic!("{} doesn't get a plain reply", String::from_utf8_lossy(packet)),
+        }
+    }
+
+    /// Handles a packet that resumes the hart and returns the stop reply.
+    fn resume(stub: &mut Stub, cpu: &mut cpu::RiscV32, packet: &[u8]) -> String {
+        match stub.handle(cpu, packet) {
+            Action::Resume(step) => return stub.resume(cpu, step).unwrap().reply(),
+            _ => panic!("{} doesn't resume", String::from_utf8_lossy(packet)),
+        }
+    }
+
+    #[test]
+    fn non_ascii_packets_are_unsupported() {
+        let mut cpu: cpu::RiscV32 = testing::machine();
+        let (mut stub, _peer) = stub(&cpu);
+        for packet in [&b"\xFF"[..], b"\xC3\xA9g", b""] {
+            assert!(matches!(stub.handle(&mut cpu, packet), Action::Reply(reply) if reply.is_empty()), "{:?}", packet);
+        }
+        assert!(matches!(stub.handle(&mut cpu, b"?"), Action::Reply(_)));
+    }
+
+    #[test]
+    fn registers() {
+        let mut cpu: cpu::RiscV32 = testing::machine();
+        let (mut stub, _peer) = stub(&cpu);
+        cpu.regs.write(1, 0x1234_5678);
+        let all: String = reply(&mut stub, &mut cpu, b"g");
+        assert_eq!(all.len(), 33 * 8, "x0 to x31 and pc");
+        assert_eq!((&all[8..16], &all[32 * 8..]), ("78563412", "00000080"), "little-endian, pc last");
+        let mut written: String = all.clone();
+        written.replace_range(16..24, "efbeadde");
+        assert_eq!(reply(&mut stub, &mut cpu, format!("G{}", written).as_bytes()), "OK");
+        assert_eq!(cpu.regs.read(2), 0xDEAD_BEEF);
+
+        assert_eq!(reply(&mut stub, &mut cpu, b"P20=04000080"), "OK");
+        assert_eq!((reply(&mut stub, &mut cpu, b"p20"), cpu.regs.pc), (String::from("04000080"), RAM_BASE + 4), "pc is 32");
+        assert_eq!(reply(&mut stub, &mut cpu, b"P0=01000000"), "OK");
+        assert_eq!(cpu.regs.read(0), 0, "x0 stays zero");
+
+        cpu.regs.f[1] = 0x4000_0000_0000_0000;
+        assert_eq!(reply(&mut stub, &mut cpu, b"p22"), "0000000000000040", "f1 is 33 + 1, 64 bits with D");
+        assert_eq!(reply(&mut stub, &mut cpu, b"P22=0000803f"), "OK");
+        assert_eq!(cpu.regs.f[1], 0x0000_0000_3F80_0000, "a short value is zero-extended, then written as a double");
+        assert!(cpu.write_csr(0x301, cpu::MISA_F).is_none()); // D off
+        assert_eq!(reply(&mut stub, &mut cpu, b"P22=0000803f"), "OK");
+        assert_eq!((reply(&mut stub, &mut cpu, b"p22"), cpu.regs.f[1]), (String::from("0000803f"), 0xFFFF_FFFF_3F80_0000), "32 bits and NaN-boxed with only F");
+        assert!(cpu.write_csr(0x301, 0).is_none()); // F off
+        assert_eq!(reply(&mut stub, &mut cpu, b"p22"), "E01", "no FP registers without F");
+        assert_eq!(reply(&mut stub, &mut cpu, b"P22=00000000"), "E01");
+
+        let mscratch: String = format!("{:x}", 65 + 0x340);
+        assert_eq!(reply(&mut stub, &mut cpu, format!("P{}=78563412", mscratch).as_bytes()), "OK");
+        assert_eq!((reply(&mut stub, &mut cpu, format!("p{}", mscratch).as_bytes()), cpu.regs.csr.mscratch), (String::from("78563412"), 0x1234_5678), "CSR n is 65 + n");
+        assert_eq!(reply(&mut stub, &mut cpu, format!("p{:x}", 65 + 0x7C0).as_bytes()), "E01", "no such CSR");
+        let privilege: String = format!("{:x}", 65 + 4096);
+        assert_eq!(reply(&mut stub, &mut cpu, format!("p{}", privilege).as_bytes()), "03000000");
+        assert_eq!(reply(&mut stub, &mut cpu, format!("P{}=01000000", privilege).as_bytes()), "OK");
+        assert_eq!(cpu.privilege, 1);
+        assert_eq!(reply(&mut stub, &mut cpu, format!("P{}=02000000", privilege).as_bytes()), "E01", "no H-mode");
+    }
+
+    #[test]
+    fn memory() {
+        let mut cpu: cpu::RiscV32 = testing::machine();
+        let (mut stub, _peer) = stub(&cpu);
+        assert_eq!(reply(&mut stub, &mut cpu, format!("M{:x},4:78563412", DATA).as_bytes()), "OK");
+        assert_eq!(testing::read_word(&mut cpu, DATA), 0x1234_5678);
+        assert_eq!(reply(&mut stub, &mut cpu, format!("m{:x},6", DATA + 1).as_bytes()), "563412000000", "byte by byte when unaligned");
+        let mut binary: Vec<u8> = format!("X{:x},3:", DATA).into_bytes();
+        binary.extend_from_slice(&[0x7D, 0x23, 0x00]); // '}' and '#' escaped by GDB are undone before handle sees them
+        assert_eq!(reply(&mut stub, &mut cpu, &binary), "OK");
+        assert_eq!(testing::read_word(&mut cpu, DATA), 0x1200_237D);
+        assert_eq!(reply(&mut stub, &mut cpu, format!("M{:x},4:00", DATA).as_bytes()), "E01", "length and data disagree");
+        assert_eq!(reply(&mut stub, &mut cpu, b"m10,4"), "E14", "nothing there");
+
+        cpu.reservation = cpu::Reservation { address: DATA, valid: true };
+        assert_eq!(reply(&mut stub, &mut cpu, format!("M{:x},1:ff", DATA + 3).as_bytes()), "OK");
+        assert!(!cpu.reservation.valid, "a debugger write breaks a reservation like any other store");
+
+        // with Sv32 on, addresses are virtual: 0x00400000 maps to DATA through a two-level table
+        testing::write_word(&mut cpu, RAM_BASE + 0x1000 + 4, ((RAM_BASE + 0x2000) >> 12) << 10 | 0x1);
+        testing::write_word(&mut cpu, RAM_BASE + 0x2000, (DATA >> 12) << 10 | 0xC7); // V, R, W, A, D
+        cpu.regs.csr.satp = 1 << 31 | (RAM_BASE + 0x1000) >> 12;
+        cpu.privilege = 1;
+        assert_eq!(reply(&mut stub, &mut cpu, b"M400004,4:efbeadde"), "OK");
+        assert_eq!(testing::read_word(&mut cpu, DATA + 4), 0xDEAD_BEEF);
+        assert_eq!(reply(&mut stub, &mut cpu, b"m400004,4"), "efbeadde");
+        assert_eq!(reply(&mut stub, &mut cpu, b"m401000,4"), "E14", "unmapped");
+        assert_eq!(cpu.regs.csr.scause | cpu.regs.csr.mcause, 0, "and no trap for it");
+    }
+
+    #[test]
+    fn breakpoints_and_stepping() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::addi(1, 1, 1); 8]);
+        let (mut stub, _peer) = stub(&cpu);
+        assert_eq!(resume(&mut stub, &mut cpu, b"s"), "S05");
+        assert_eq!((cpu.regs.pc, cpu.regs.read(1)), (RAM_BASE + 4, 1), "one instruction");
+        assert_eq!(reply(&mut stub, &mut cpu, format!("Z0,{:x},4", RAM_BASE + 12).as_bytes()), "OK");
+        assert_eq!(reply(&mut stub, &mut cpu, format!("Z1,{:x},4", RAM_BASE + 20).as_bytes()), "OK");
+        assert_eq!(resume(&mut stub, &mut cpu, b"c"), "T05swbreak:;");
+        assert_eq!((cpu.regs.pc, cpu.regs.read(1)), (RAM_BASE + 12, 3), "stopped before the instruction");
+        assert_eq!(resume(&mut stub, &mut cpu, b"vCont;c"), "T05hwbreak:;", "and continues from it");
+        assert_eq!(cpu.regs.pc, RAM_BASE + 20);
+        assert_eq!(reply(&mut stub, &mut cpu, format!("z1,{:x},4", RAM_BASE + 20).as_bytes()), "OK");
+        assert_eq!(reply(&mut stub, &mut cpu, format!("Z0,{:x},4", RAM_BASE + 28).as_bytes()), "OK");
+        assert_eq!(resume(&mut stub, &mut cpu, b"c"), "T05swbreak:;", "a removed breakpoint doesn't stop it");
+        assert_eq!(cpu.regs.pc, RAM_BASE + 28);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let cases: [(&str, &str, u32); 4] = [
+            // Z packet type, stop reply name, instruction
+            ("2", "watch", asm::sw(0, 5, 4)),
+            ("3", "rwatch", asm::lw(6, 5, 4)),
+            ("4", "awatch", asm::lw(6, 5, 4)),
+            ("4", "awatch", asm::sb(0, 5, 7)),
+        ];
+        for (kind, name, instruction) in cases {
+            let mut cpu: cpu::RiscV32 = testing::program(&[asm::lw(6, 5, 0), asm::sw(0, 5, 0), instruction, asm::addi(0, 0, 0)]);
+            cpu.regs.write(5, DATA);
+            let (mut stub, _peer) = stub(&cpu);
+            assert_eq!(reply(&mut stub, &mut cpu, format!("Z{},{:x},4", kind, DATA + 4).as_bytes()), "OK");
+            let expected: String = format!("T05{}:{:x};", name, if instruction == asm::sb(0, 5, 7) { DATA + 7 } else { DATA + 4 });
+            assert_eq!(resume(&mut stub, &mut cpu, b"c"), expected, "Z{} {}", kind, name);
+            assert_eq!(cpu.regs.pc, RAM_BASE + 12, "stops after the access");
+            assert_eq!(reply(&mut stub, &mut cpu, format!("z{},{:x},4", kind, DATA + 4).as_bytes()), "OK");
+            assert!(cpu.watchpoints.is_empty());
+        }
+    }
+
+    #[test]
+    fn target_description() {
+        let mut cpu: cpu::RiscV32 = testing::machine();
+        let (mut stub, _peer) = stub(&cpu);
+        let xml: String = target_xml(cpu.regs.csr.misa);
+        assert!(xml.contains("riscv.fpu") && xml.contains("ieee_double"));
+        let mut read: String = String::new();
+        loop {
+            let chunk: String = reply(&mut stub, &mut cpu, format!("qXfer:features:read:target.xml:{:x},100", read.len()).as_bytes());
+            read += &chunk[1..];
+            match chunk.chars().next() {
+                Some('m') => assert_eq!(chunk.len(), 0x101, "full pages until the last one"),
+                Some('l') => break,
+                _ => panic!("{}", chunk),
+            }
+        }
+        assert_eq!(read, xml);
+        assert_eq!(reply(&mut stub, &mut cpu, format!("qXfer:features:read:target.xml:{:x},100", xml.len()).as_bytes()), "l", "nothing past the end");
+        assert_eq!(reply(&mut stub, &mut cpu, b"qXfer:features:read:other.xml:0,100"), "E00");
+        assert!(!target_xml(cpu::MISA_I).contains("riscv.fpu"), "no FP registers without F");
+        assert!(target_xml(cpu::MISA_I | cpu::MISA_F).contains("ieee_single"));
+    }
+}
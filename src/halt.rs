@@ -0,0 +1,63 @@
+use std::fmt;
+
+use crate::trap;
+
+/// Why a run ended, when the emulator itself didn't fail.
+#[derive(Debug, Clone, Copy)]
+pub enum HaltReason {
+    Shutdown(i32), // the guest powered the machine off with this exit code
+    InstructionLimit(u64),
+    Timeout(u64), // instructions executed before the deadline passed
+    Breakpoint(u32), // an ebreak at this address, when the run stops on them
+    Trap { trap: trap::Trap, pc: u32, tval: u32 }, // an exception the guest didn't get to handle, the hart is already at its handler
+    Killed, // by the debugger
+}
+
+impl HaltReason {
+    /// Exit status for the host: the guest's own, 124 for limits like timeout(1), 128 + signal when it died like a crashed process.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            HaltReason::Shutdown(code) => return *code,
+            HaltReason::InstructionLimit(_) | HaltReason::Timeout(_) => return 124,
+            HaltReason::Breakpoint(_) => return 128 + libc::SIGTRAP,
+            HaltReason::Trap { trap, .. } => match trap {
+                trap::Trap::IllegalInstruction => return 128 + libc::SIGILL,
+                trap::Trap::MisalignedInstructionAddress | trap::Trap::MisalignedLoadAddr | trap::Trap::MisalignedStoreAddr => return 128 + libc::SIGBUS,
+                trap::Trap::Breakpoint => return 128 + libc::SIGTRAP,
+                trap::Trap::UModeEnvCall | trap::Trap::SModeEnvCall | trap::Trap::MModeEnvCall => return 128 + libc::SIGSYS,
+                _ => return 128 + libc::SIGSEGV,
+            },
+            HaltReason::Killed => return 128 + libc::SIGKILL,
+        }
+    }
+}
+
+impl fmt::Display for HaltReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HaltReason::Shutdown(0) => write!(f, "guest shut down"),
+            HaltReason::Shutdown(code) => write!(f, "guest shut down with status {}", code),
+            HaltReason::InstructionLimit(retired) => write!(f, "instruction limit of {} reached", retired),
+            HaltReason::Timeout(retired) => write!(f, "timed out after {} instructions", retired),
+            HaltReason::Breakpoint(pc) => write!(f, "breakpoint at 0x{:08X}", pc),
+            HaltReason::Trap { trap, pc, tval } => write!(f, "unhandled {:?} at 0x{:08X}, tval 0x{:08X}", trap, pc, tval),
+            HaltReason::Killed => write!(f, "killed by the debugger"),
+        }
+    }
+}
+
+/// Something on the host side that keeps the machine from starting or running.
+#[derive(Debug)]
+pub enum EmuError {
+    Io(String, std::io::Error), // what we were doing with which file or device, and what went wrong
+    BadImage(String), // a kernel, initrd or devicetree that can't be booted as given
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmuError::Io(what, e) => write!(f, "{}: {}", what, e),
+            EmuError::BadImage(what) => write!(f, "{}", what),
+        }
+    }
+}
@@ -0,0 +1,443 @@
+use std::ffi::OsStr;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::cpu;
+use crate::halt;
+use crate::log;
+use crate::sbi;
+
+const SETTLE: u32 = 16; // instructions from the first store to tohost until the request is taken, time for both halves to land
+const CONSOLE_POLL: u32 = 0x100; // instructions between looks at the console while a getchar is outstanding
+
+const DEVICE_SYSCALL: u64 = 0; // also exit, when the payload's LSB is set
+const DEVICE_CONSOLE: u64 = 1;
+const CONSOLE_GETCHAR: u64 = 0;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+// frontend syscalls, numbered as in Spike's fesvr, which follows the RISC-V Linux ABI
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_OPEN: u64 = 1024;
+
+const AT_FDCWD: i64 = -100;
+// open flags of the RISC-V Linux ABI, which newlib uses too
+const O_ACCMODE: u64 = 0x3;
+const O_CREAT: u64 = 0x40;
+const O_EXCL: u64 = 0x80;
+const O_TRUNC: u64 = 0x200;
+const O_APPEND: u64 = 0x400;
+
+const MAX_TRANSFER: u64 = 1 << 20; // bytes moved by a single read or write, the guest asks again for the rest
+const MAX_PATH: u64 = 4096;
+
+/// What the command line says about HTIF; addresses it leaves out come from the ELF's symbols.
+#[derive(Default)]
+pub struct Config {
+    pub tohost: Option<u32>,
+    pub fromhost: Option<u32>,
+    pub sandbox: Option<String>, // directory the guest's files live in, it can't open any without one
+}
+
+/// Host-target interface of Spike and the riscv-tests environments: the guest hands the host a request by storing
+/// it to `tohost`, a 64-bit word in RAM, and the host answers in `fromhost`. A request is a device in the top byte,
+/// a command in the next and a 48-bit payload.
+pub struct Htif {
+    pub tohost: u32, // physical addresses
+    pub fromhost: Option<u32>, // without it nothing gets answered, only exit works
+    countdown: u32, // instructions left until a request stored to tohost is taken, 0 if there's none
+    sandbox: Option<PathBuf>, // canonical
+    files: Vec<Option<std::fs::File>>, // guest descriptors from 3 up, 0 to 2 are the console
+    getchar: bool, // a console read waits for the next key
+    polls: u32,
+}
+
+impl Htif {
+    pub fn new(tohost: u32, fromhost: Option<u32>, sandbox: Option<PathBuf>) -> Htif {
+        return Htif {
+            tohost: tohost,
+            fromhost: fromhost,
+            countdown: 0,
+            sandbox: sandbox,
+            files: Vec::new(),
+            getchar: false,
+            polls: 0,
+        };
+    }
+    /// Notes a store of `size` bytes at `paddr` if it touches tohost.
+    pub fn snoop(&mut self, paddr: u32, size: u32) {
+        if self.countdown == 0 && paddr < self.tohost.wrapping_add(8) && self.tohost < paddr.wrapping_add(size) {
+            self.countdown = SETTLE;
+        }
+    }
+    /// Where `path` is on the host, as long as that's inside the sandbox; an absolute path starts at its root.
+    fn resolve(&self, path: &[u8]) -> Result<PathBuf, i32> {
+        let sandbox: &Path = self.sandbox.as_deref().ok_or(libc::EACCES)?;
+        let mut full: PathBuf = sandbox.to_path_buf();
+        for component in Path::new(OsStr::from_bytes(path)).components() {
+            match component {
+                Component::Normal(name) => full.push(name),
+                Component::RootDir | Component::CurDir => {},
+                Component::ParentDir | Component::Prefix(_) => return Err(libc::EACCES),
+            }
+        }
+        let real: PathBuf = match full.canonicalize() { // a symlink inside could still lead out
+            Ok(real) => real,
+            Err(_) => match (full.parent().map(Path::canonicalize), full.file_name()) { // a file about to be created
+                (Some(Ok(parent)), Some(name)) => parent.join(name),
+                _ => return Err(libc::ENOENT),
+            },
+        };
+        if !real.starts_with(sandbox) {
+            return Err(libc::EACCES);
+        }
+        return Ok(real);
+    }
+    fn open(&mut self, path: &[u8], flags: u64, mode: u64) -> Result<u64, i32> {
+        let path: PathBuf = self.resolve(path)?;
+        let mut options: std::fs::OpenOptions = std::fs::OpenOptions::new();
+        match flags & O_ACCMODE {
+            0 => options.read(true),
+            1 => options.write(true),
+            2 => options.read(true).write(true),
+            _ => return Err(libc::EINVAL),
+        };
+        options.append(flags & O_APPEND != 0).truncate(flags & O_TRUNC != 0).mode((mode & 0o777) as u32);
+        options.custom_flags(libc::O_NOFOLLOW); // resolve() only leaves a symlink in place when it dangles, and that one could point anywhere
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+        let file: std::fs::File = options.open(&path).map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        let slot: usize = match self.files.iter().position(Option::is_none) { // lowest free descriptor, like the kernel
+            Some(slot) => slot,
+            None => {
+                self.files.push(None);
+                self.files.len() - 1
+            },
+        };
+        self.files[slot] = Some(file);
+        return Ok(slot as u64 + 3);
+    }
+    fn file(&mut self, fd: u64) -> Result<&mut std::fs::File, i32> {
+        let slot: usize = fd.checked_sub(3).ok_or(libc::EBADF)? as usize;
+        return self.files.get_mut(slot).and_then(Option::as_mut).ok_or(libc::EBADF);
+    }
+    fn close(&mut self, fd: u64) -> Result<u64, i32> {
+        if fd < 3 {
+            return Ok(0); // the console stays
+        }
+        self.file(fd)?;
+        self.files[fd as usize - 3] = None;
+        return Ok(0);
+    }
+}
+
+/// Enables HTIF if the command line or the loaded ELF says where tohost is.
+pub fn attach(cpu: &mut cpu::RiscV32, config: Config) -> Result<(), halt::EmuError> {
+    let tohost: u32 = match config.tohost.or_else(|| cpu.symbols.address("tohost")) {
+        Some(tohost) => tohost,
+        None => return Ok(()),
+    };
+    let fromhost: Option<u32> = config.fromhost.or_else(|| cpu.symbols.address("fromhost"));
+    let sandbox: Option<PathBuf> = match config.sandbox {
+        Some(dir) => match std::fs::canonicalize(&dir).and_then(|path| std::fs::read_dir(&path).map(|_| path)) {
+            Ok(path) => Some(path),
+            Err(e) => return Err(halt::EmuError::Io(format!("unable to use {} as the HTIF sandbox", dir), e)),
+        },
+        None => None,
+    };
+    match fromhost {
+        Some(fromhost) => log::logln!(Htif, Info, "[htif] tohost at 0x{:08X}, fromhost at 0x{:08X}", tohost, fromhost),
+        None => log::logln!(Htif, Info, "[htif] tohost at 0x{:08X}, no fromhost so only exit works", tohost),
+    }
+    cpu.htif = Some(Htif::new(tohost, fromhost, sandbox));
+    return Ok(());
+}
+
+/// Called after every instruction. Like Spike, we look at tohost a while after the guest wrote it rather than right
+/// away: RV32 guests store the two halves of a request separately, and they keep rewriting it until the host answers.
+pub fn poll(cpu: &mut cpu::RiscV32) {
+    let htif: &mut Htif = match &mut cpu.htif {
+        Some(htif) => htif,
+        None => return,
+    };
+    if htif.getchar {
+        htif.polls += 1;
+        if htif.polls >= CONSOLE_POLL {
+            htif.polls = 0;
+            deliver_key(cpu);
+        }
+    }
+    let htif: &mut Htif = cpu.htif.as_mut().unwrap();
+    if htif.countdown == 0 {
+        return;
+    }
+    htif.countdown -= 1;
+    if htif.countdown > 0 {
+        return;
+    }
+    let tohost: u32 = htif.tohost;
+    let request: u64 = cpu.bus.read(tohost, 8).unwrap_or(0);
+    if request == 0 {
+        return;
+    }
+    cpu.bus.write(tohost, 8, 0); // the request has been taken
+    let (device, command, payload): (u64, u64, u64) = (request >> 56, request >> 48 & 0xFF, request & 0xFFFF_FFFF_FFFF);
+    match (device, command) {
+        (DEVICE_SYSCALL, 0) if payload & 1 != 0 => exit(cpu, payload >> 1),
+        (DEVICE_SYSCALL, 0) => {
+            syscall(cpu, payload as u32);
+            respond(cpu, DEVICE_SYSCALL, 0, 1);
+        },
+        (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+            sbi::putc(cpu, payload as u8);
+            respond(cpu, DEVICE_CONSOLE, CONSOLE_PUTCHAR, 0);
+        },
+        (DEVICE_CONSOLE, CONSOLE_GETCHAR) => { // answered once there's a key, the guest polls fromhost meanwhile
+            cpu.htif.as_mut().unwrap().getchar = true;
+            deliver_key(cpu);
+        },
+        _ => log::logln!(Htif, Warn, "[htif] unsupported request 0x{:016X} (device {}, command {}), ignored", request, device, command),
+    }
+}
+
+fn exit(cpu: &mut cpu::RiscV32, code: u64) {
+    log::logln!(Htif, Info, "\n[htif] exit with status {}", code);
+    cpu.exit_code = code as i32; // riscv-tests report the number of the first failing test
+    cpu.status = false;
+}
+
+/// Stores an answer to fromhost, unless the guest hasn't taken the previous one yet.
+fn respond(cpu: &mut cpu::RiscV32, device: u64, command: u64, payload: u64) {
+    let fromhost: u32 = match cpu.htif.as_ref().and_then(|htif| htif.fromhost) {
+        Some(fromhost) => fromhost,
+        None => return,
+    };
+    if cpu.bus.read(fromhost, 8) != Some(0) {
+        log::logln!(Htif, Warn, "[htif] fromhost is still busy, answer to device {} dropped", device);
+        return;
+    }
+    cpu.bus.write(fromhost, 8, device << 56 | command << 48 | payload & 0xFFFF_FFFF_FFFF);
+    cpu.snoop_store(fromhost, 8);
+}
+
+/// Answers the outstanding getchar if a key came in and fromhost is free for it.
+fn deliver_key(cpu: &mut cpu::RiscV32) {
+    let fromhost: Option<u32> = cpu.htif.as_ref().and_then(|htif| htif.fromhost);
+    if fromhost.is_none_or(|fromhost| cpu.bus.read(fromhost, 8) != Some(0)) {
+        return;
+    }
+    if let Some(byte) = sbi::getc(cpu) {
+        respond(cpu, DEVICE_CONSOLE, CONSOLE_GETCHAR, byte as u64);
+        cpu.htif.as_mut().unwrap().getchar = false;
+    }
+}
+
+/// Copies `len` bytes of guest RAM at `paddr`.
+fn read_guest(cpu: &cpu::RiscV32, paddr: u32, len: u64) -> Result<Vec<u8>, i32> {
+    if len > u32::MAX as u64 || !cpu.bus.ram.contains(paddr, len as u32) {
+        return Err(libc::EFAULT);
+    }
+    return Ok((0..len as u32).map(|i| cpu.bus.ram.read(paddr + i, 1).unwrap() as u8).collect());
+}
+
+fn write_guest(cpu: &mut cpu::RiscV32, paddr: u32, data: &[u8]) -> Result<(), i32> {
+    if cpu.bus.ram.load(paddr, data).is_none() {
+        return Err(libc::EFAULT);
+    }
+    cpu.snoop_store(paddr, data.len() as u32);
+    return Ok(());
+}
+
+/// A path the guest passes along with its length, terminating NUL included.
+fn read_path(cpu: &cpu::RiscV32, paddr: u32, len: u64) -> Result<Vec<u8>, i32> {
+    if len > MAX_PATH {
+        return Err(libc::ENAMETOOLONG);
+    }
+    let mut path: Vec<u8> = read_guest(cpu, paddr, len)?;
+    if let Some(end) = path.iter().position(|byte| *byte == 0) {
+        path.truncate(end);
+    }
+    if path.is_empty() {
+        return Err(libc::ENOENT);
+    }
+    return Ok(path);
+}
+
+fn sys_read(cpu: &mut cpu::RiscV32, fd: u64, buffer: u32, len: u64) -> Result<u64, i32> {
+    let mut data: Vec<u8> = vec![0u8; len.min(MAX_TRANSFER) as usize];
+    let n: usize = if fd == 0 { // whatever the console has, it never blocks
+        let mut n: usize = 0;
+        while n < data.len() {
+            match sbi::getc(cpu) {
+                Some(byte) => data[n] = byte,
+                None => break,
+            }
+            n += 1;
+        }
+        n
+    } else {
+        let file: &mut std::fs::File = cpu.htif.as_mut().unwrap().file(fd)?;
+        file.read(&mut data).map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?
+    };
+    write_guest(cpu, buffer, &data[..n])?;
+    return Ok(n as u64);
+}
+
+fn sys_write(cpu: &mut cpu::RiscV32, fd: u64, buffer: u32, len: u64) -> Result<u64, i32> {
+    let data: Vec<u8> = read_guest(cpu, buffer, len.min(MAX_TRANSFER))?;
+    if fd == 1 || fd == 2 { // both go to the console, it's all the guest has
+        for byte in data.iter() {
+            sbi::putc(cpu, *byte);
+        }
+        return Ok(data.len() as u64);
+    }
+    let file: &mut std::fs::File = cpu.htif.as_mut().unwrap().file(fd)?;
+    return file.write(&data).map(|n| n as u64).map_err(|e| e.raw_os_error().unwrap_or(libc::EIO));
+}
+
+/// Runs the syscall described by the eight 64-bit words at `magic`, number first, and leaves the result in the first.
+fn syscall(cpu: &mut cpu::RiscV32, magic: u32) {
+    let mut args: [u64; 8] = [0u64; 8];
+    for (i, arg) in args.iter_mut().enumerate() {
+        *arg = match cpu.bus.ram.read(magic.wrapping_add(8 * i as u32), 8) {
+            Some(arg) => arg,
+            None => {
+                log::logln!(Htif, Warn, "[htif] syscall block at 0x{:08X} isn't in RAM, ignored", magic);
+                return;
+            },
+        };
+    }
+    let (n, a): (u64, &[u64]) = (args[0], &args[1..]);
+    let result: Result<u64, i32> = match n {
+        SYS_EXIT | SYS_EXIT_GROUP => {
+            exit(cpu, a[0]);
+            Ok(0)
+        },
+        SYS_READ => sys_read(cpu, a[0], a[1] as u32, a[2]),
+        SYS_WRITE => sys_write(cpu, a[0], a[1] as u32, a[2]),
+        SYS_OPENAT if a[0] as i32 as i64 != AT_FDCWD => Err(libc::EBADF), // only paths from the sandbox root
+        SYS_OPENAT => read_path(cpu, a[1] as u32, a[2]).and_then(|path| cpu.htif.as_mut().unwrap().open(&path, a[3], a[4])),
+        SYS_OPEN => read_path(cpu, a[0] as u32, a[1]).and_then(|path| cpu.htif.as_mut().unwrap().open(&path, a[2], a[3])),
+        SYS_CLOSE => cpu.htif.as_mut().unwrap().close(a[0]),
+        _ => Err(libc::ENOSYS),
+    };
+    log::logln!(Htif, Debug, "[htif] syscall {}(0x{:X}, 0x{:X}, 0x{:X}, 0x{:X}) = {:?}", n, a[0], a[1], a[2], a[3], result);
+    let value: i64 = match result {
+        Ok(value) => value as i64,
+        Err(errno) => -(errno as i64),
+    };
+    cpu.bus.ram.write(magic, 8, value as u64);
+    cpu.snoop_store(magic, 8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Htif;
+    use crate::cpu;
+    use crate::testing::{self, asm, DATA};
+
+    const FROMHOST: u32 = DATA + 8;
+    const MAGIC: u32 = DATA + 0x100;
+    const BUFFER: u32 = DATA + 0x200;
+
+    fn machine(code: &[u32]) -> cpu::RiscV32 {
+        let mut cpu: cpu::RiscV32 = testing::program(code);
+        cpu.htif = Some(Htif::new(DATA, Some(FROMHOST), None));
+        cpu.regs.write(1, DATA);
+        return cpu;
+    }
+
+    /// Runs syscall `n` the way fesvr would get it, returns what the guest would read back.
+    fn syscall(cpu: &mut cpu::RiscV32, n: u64, args: &[u64]) -> i64 {
+        cpu.bus.ram.write(MAGIC, 8, n);
+        for (i, arg) in args.iter().enumerate() {
+            cpu.bus.ram.write(MAGIC + 8 * (i as u32 + 1), 8, *arg);
+        }
+        super::syscall(cpu, MAGIC);
+        return cpu.bus.ram.read(MAGIC, 8).unwrap() as i64;
+    }
+
+    #[test]
+    fn exit_after_both_halves() {
+        let mut cpu: cpu::RiscV32 = machine(&[asm::addi(2, 0, 5 << 1 | 1), asm::sw(2, 1, 0), asm::sw(0, 1, 4), asm::jal(0, 0)]);
+        assert_eq!(testing::run(&mut cpu, 2), None);
+        assert!(cpu.status, "not with only half of the request in");
+        assert_eq!(testing::run(&mut cpu, super::SETTLE as usize), None);
+        assert_eq!((cpu.status, cpu.exit_code), (false, 5));
+    }
+
+    #[test]
+    fn exit_with_one_store() {
+        let mut cpu: cpu::RiscV32 = machine(&[asm::addi(2, 0, 1), asm::sw(2, 1, 0), asm::jal(0, 0)]);
+        assert_eq!(testing::run(&mut cpu, 2 + super::SETTLE as usize), None);
+        assert_eq!((cpu.status, cpu.exit_code), (false, 0));
+    }
+
+    #[test]
+    fn other_requests_are_dropped() {
+        let mut cpu: cpu::RiscV32 = machine(&[asm::lui(2, 0x0FF00), asm::sw(2, 1, 4), asm::addi(2, 0, 1), asm::sw(2, 1, 0), asm::jal(0, 0)]);
+        assert_eq!(testing::run(&mut cpu, 4 + super::SETTLE as usize), None);
+        assert!(cpu.status, "device 0x0F isn't an exit");
+        assert_eq!((testing::read_word(&mut cpu, DATA), testing::read_word(&mut cpu, DATA + 4)), (0, 0), "but it's taken");
+        assert_eq!(testing::read_word(&mut cpu, FROMHOST), 0, "nor answered");
+    }
+
+    #[test]
+    fn putchar_is_answered() {
+        let mut cpu: cpu::RiscV32 = machine(&[asm::addi(2, 0, 'x' as i32), asm::sw(2, 1, 0), asm::lui(2, 0x01010), asm::sw(2, 1, 4), asm::jal(0, 0)]);
+        assert_eq!(testing::run(&mut cpu, 4 + super::SETTLE as usize), None);
+        assert!(cpu.status);
+        assert_eq!((testing::read_word(&mut cpu, DATA), testing::read_word(&mut cpu, DATA + 4)), (0, 0));
+        assert_eq!((testing::read_word(&mut cpu, FROMHOST), testing::read_word(&mut cpu, FROMHOST + 4)), (0, 0x0101_0000));
+    }
+
+    #[test]
+    fn files_stay_in_the_sandbox() {
+        let dir: std::path::PathBuf = std::env::temp_dir().join(format!("marv-htif-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut cpu: cpu::RiscV32 = testing::machine();
+        cpu.htif = Some(Htif::new(DATA, Some(FROMHOST), Some(dir.canonicalize().unwrap())));
+        let open = |cpu: &mut cpu::RiscV32, path: &str, flags: u64| -> i64 {
+            cpu.bus.ram.load(BUFFER, format!("{}\0", path).as_bytes()).unwrap();
+            return syscall(cpu, super::SYS_OPENAT, &[super::AT_FDCWD as u64, BUFFER as u64, path.len() as u64 + 1, flags, 0o644]);
+        };
+        assert_eq!(open(&mut cpu, "/out.txt", 1 | super::O_CREAT | super::O_TRUNC), 3, "absolute paths start at the sandbox");
+        cpu.bus.ram.load(BUFFER, b"hello").unwrap();
+        assert_eq!(syscall(&mut cpu, super::SYS_WRITE, &[3, BUFFER as u64, 5]), 5);
+        assert_eq!(syscall(&mut cpu, super::SYS_CLOSE, &[3]), 0);
+        assert_eq!(syscall(&mut cpu, super::SYS_CLOSE, &[3]), -libc::EBADF as i64);
+        assert_eq!(std::fs::read(dir.join("out.txt")).unwrap(), b"hello");
+
+        assert_eq!(open(&mut cpu, "out.txt", 0), 3, "descriptors are reused");
+        assert_eq!(syscall(&mut cpu, super::SYS_READ, &[3, DATA as u64 + 0x300, 16]), 5);
+        assert_eq!(cpu.bus.ram.read(DATA + 0x300, 4), Some(u32::from_le_bytes(*b"hell") as u64));
+        assert_eq!(open(&mut cpu, "../escape", 1 | super::O_CREAT), -libc::EACCES as i64);
+        assert_eq!(open(&mut cpu, "missing/file", 0), -libc::ENOENT as i64);
+        let outside: std::path::PathBuf = dir.with_extension("outside");
+        std::os::unix::fs::symlink(&outside, dir.join("dangling")).unwrap();
+        assert_eq!(open(&mut cpu, "dangling", 1 | super::O_CREAT), -libc::ELOOP as i64, "not created through a dangling symlink");
+        assert_eq!(syscall(&mut cpu, 9999, &[]), -libc::ENOSYS as i64);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!dir.parent().unwrap().join("escape").exists());
+        assert!(!outside.exists());
+    }
+
+    #[test]
+    fn no_sandbox_no_files() {
+        let mut cpu: cpu::RiscV32 = testing::machine();
+        cpu.htif = Some(Htif::new(DATA, Some(FROMHOST), None));
+        cpu.bus.ram.load(BUFFER, b"/etc/passwd\0").unwrap();
+        assert_eq!(syscall(&mut cpu, super::SYS_OPEN, &[BUFFER as u64, 12, 0, 0]), -libc::EACCES as i64);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::mmu;
 use crate::trap;
 use crate::extensions::*;
 
@@ -9,8 +10,11 @@ pub enum RV32Instruction { // [ ] impl execute() for each extension {decode, the
     RV32I(rv32i::RV32IInstruction),
     RV32M(rv32m::RV32MInstruction),
     RV32A(rv32a::RV32AInstruction),
+    RV32F(rv32f::RV32FInstruction),
+    RV32D(rv32d::RV32DInstruction),
     RV32Ziscr(rv32zicsr::RV32ZicsrInstruction),
-    TrapReturn(trap::TrapRetInstruction)
+    TrapReturn(trap::TrapRetInstruction),
+    Mmu(mmu::MmuInstruction),
 }
 
 impl Execute for RV32Instruction {
@@ -21,14 +25,18 @@ impl Execute for RV32Instruction {
             Self::RV32I(instr) => return instr.execute(cpu),
             Self::RV32M(instr) => return instr.execute(cpu),
             Self::RV32A(instr) => return instr.execute(cpu),
+            Self::RV32F(instr) => return instr.execute(cpu),
+            Self::RV32D(instr) => return instr.execute(cpu),
             Self::RV32Ziscr(instr) => return instr.execute(cpu),
             Self::TrapReturn(instr) => return instr.execute(cpu),
+            Self::Mmu(instr) => return instr.execute(cpu),
         }
     }
 }
 
 pub enum Type {
     R,
+    R4,
     I,
     S,
     B,
@@ -44,7 +52,7 @@ pub const OPTABLE: [Option<Type>; 128] = [
     /* 0b0000100 */ None,
     /* 0b0000101 */ None,
     /* 0b0000110 */ None,
-    /* 0b0000111 */ None,
+    /* 0b0000111 */ Some(Type::I),
     /* 0b0001000 */ None,
     /* 0b0001001 */ None,
     /* 0b0001010 */ None,
@@ -76,7 +84,7 @@ pub const OPTABLE: [Option<Type>; 128] = [
     /* 0b0100100 */ None,
     /* 0b0100101 */ None,
     /* 0b0100110 */ None,
-    /* 0b0100111 */ None,
+    /* 0b0100111 */ Some(Type::S),
     /* 0b0101000 */ None,
     /* 0b0101001 */ None,
     /* 0b0101010 */ None,
@@ -104,23 +112,23 @@ pub const OPTABLE: [Option<Type>; 128] = [
     /* 0b1000000 */ None,
     /* 0b1000001 */ None,
     /* 0b1000010 */ None,
-    /* 0b1000011 */ None,
+    /* 0b1000011 */ Some(Type::R4),
     /* 0b1000100 */ None,
     /* 0b1000101 */ None,
     /* 0b1000110 */ None,
-    /* 0b1000111 */ None,
+    /* 0b1000111 */ Some(Type::R4),
     /* 0b1001000 */ None,
     /* 0b1001001 */ None,
     /* 0b1001010 */ None,
-    /* 0b1001011 */ None,
+    /* 0b1001011 */ Some(Type::R4),
     /* 0b1001100 */ None,
     /* 0b1001101 */ None,
     /* 0b1001110 */ None,
-    /* 0b1001111 */ None,
+    /* 0b1001111 */ Some(Type::R4),
     /* 0b1010000 */ None,
     /* 0b1010001 */ None,
     /* 0b1010010 */ None,
-    /* 0b1010011 */ None,
+    /* 0b1010011 */ Some(Type::R),
     /* 0b1010100 */ None,
     /* 0b1010101 */ None,
     /* 0b1010110 */ None,
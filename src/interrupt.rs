@@ -1,32 +1,123 @@
 use crate::cpu;
+use crate::log;
+use crate::trap;
 
+const INTERRUPT: u32 = 1 << 31; // set in mcause and scause for interrupts
+const PRIORITY: [u32; 6] = [11, 3, 7, 9, 1, 5]; // MEI, MSI, MTI, SEI, SSI, STI, highest first
+
+/// Takes the highest priority interrupt that is pending, enabled and not masked at the current privilege, if any.
+/// At most one is taken; the next one gets its turn after the handler's first instruction.
 pub fn check(cpu: &mut cpu::RiscV32) {
-    let pending_m: u32 = cpu.regs.csr.mie & cpu.regs.csr.mip & !cpu.regs.csr.mideleg;
-    let pending_s: u32 = cpu.regs.csr.sie & cpu.regs.csr.sip & cpu.regs.csr.mideleg;
-    let mut i: i8 = 11;
-    while i > 0 {
-        if (pending_m & (1 << i) != 0) && (cpu.regs.csr.mstatus & (1 << 3) > 0) {
-            cpu.regs.csr.mepc = cpu.regs.pc; // set MEPC to current PC
-            cpu.regs.csr.mcause = (1 << 31) | (1 << i); // set cause and interrupt bits (1 << 31) and (1 << i) of MCAUSE
-            cpu.regs.csr.mtval = 0; // set MTVAL to 0
-            cpu.regs.csr.mstatus &= !((3 << 11) | (1 << 7) | (1 << 3)); // clear MPP and MPIE, then set MIE to 0
-            cpu.regs.csr.mstatus |= (((cpu.privilege & 0x3) as u32) << 11) as u32; // set MPP to current privilege level
-            let mie: u8 = ((cpu.regs.csr.mstatus >> 3) & 0x1) as u8; // get field MIE of mstatus
-            cpu.regs.csr.mstatus |= (mie << 7) as u32; // set MPIE to previous value of MIE
-            cpu.privilege = 3; // set privilege to M-mode
-            cpu.regs.pc = cpu.regs.csr.mtvec /* & !0x3  // for alignment */; // set PC to interrupt handler installed by guest OS
-        }
-        if (pending_s & (1 << i) != 0) && (cpu.regs.csr.sstatus & (1 << 1) > 0) {
-            cpu.regs.csr.sepc = cpu.regs.pc;
-            cpu.regs.csr.scause = (1 << 31) | (1 << i);
-            cpu.regs.csr.stval = 0;
-            cpu.regs.csr.sstatus &= !((1 << 8) | (1 << 5) | (1 << 1));
-            cpu.regs.csr.sstatus |= (((cpu.privilege & 0x1) as u32) << 8) as u32;
-            let sie: u8 = ((cpu.regs.csr.sstatus >> 1) & 0x1) as u8;
-            cpu.regs.csr.sstatus |= (sie << 5) as u32;
-            cpu.privilege = 1;
-            cpu.regs.pc = cpu.regs.csr.stvec /* & !0x3  // for alignment */;
+    let pending: u32 = cpu.regs.csr.mie & cpu.regs.csr.mip;
+    if pending == 0 {
+        return;
+    }
+    // M-level interrupts are always on below M-mode, S-level ones never preempt M-mode and are always on in U-mode
+    let m_enabled: bool = cpu.privilege < 3 || cpu.regs.csr.mstatus & cpu::MSTATUS_MIE != 0;
+    let s_enabled: bool = cpu.privilege == 0 || (cpu.privilege == 1 && cpu.regs.csr.mstatus & cpu::MSTATUS_SIE != 0);
+    let m_pending: u32 = if m_enabled { pending & !cpu.regs.csr.mideleg } else { 0 };
+    let s_pending: u32 = if s_enabled { pending & cpu.regs.csr.mideleg } else { 0 };
+    let (code, smode): (u32, bool) = match PRIORITY.iter().find(|i| m_pending & (1 << *i) != 0) {
+        Some(code) => (*code, false),
+        None => match PRIORITY.iter().find(|i| s_pending & (1 << *i) != 0) {
+            Some(code) => (*code, true),
+            None => return,
+        },
+    };
+    cpu.reservation.valid = false; // interrupts break LR/SC pairs just like exceptions do
+    log::logln!(Trap, Debug, "[trap] interrupt {} at PC 0x{:08X}, taken in {}-mode", code, cpu.regs.pc, if smode { 'S' } else { 'M' });
+    if smode {
+        trap::Trap::handle_smode(cpu, INTERRUPT | code, 0);
+    } else {
+        trap::Trap::handle_mmode(cpu, INTERRUPT | code, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clint;
+    use crate::cpu;
+    use crate::plic;
+    use crate::testing::{self, asm, RAM_BASE};
+    use crate::trap;
+    use crate::uart;
+
+    const HANDLER: u32 = RAM_BASE + 0x100;
+
+    #[test]
+    fn plic_line_raises_meip() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::addi(0, 0, 0), asm::addi(0, 0, 0)]);
+        testing::write_word(&mut cpu, plic::PLIC_BASE + 4 * plic::UART_IRQ, 1); // priority above the threshold of 0
+        testing::write_word(&mut cpu, plic::PLIC_BASE + plic::PLIC_ENABLE, 1 << plic::UART_IRQ); // M-mode context
+        cpu.bus.write(uart::UART_BASE + uart::UART_IER, 1, 1 << 1).unwrap(); // the empty transmitter raises the line right away
+        cpu.regs.csr.mtvec = HANDLER;
+        cpu.set_csr(0x304, cpu::MIP_MEIP).unwrap();
+        cpu.set_csr(0x300, cpu::MSTATUS_MIE).unwrap();
+        assert_eq!(testing::run(&mut cpu, 1), None);
+        assert_eq!((cpu.regs.csr.mcause, cpu.regs.csr.mepc, cpu.regs.pc), (0x8000_000B, RAM_BASE + 4, HANDLER));
+        assert_eq!(cpu.regs.csr.mstatus & (cpu::MSTATUS_MIE | 1 << 7), 1 << 7, "MIE saved into MPIE, then cleared");
+        cpu.set_csr(0x300, cpu::MSTATUS_MIE).unwrap();
+        cpu.set_csr(0x304, 0).unwrap();
+        testing::load(&mut cpu, HANDLER, &[asm::addi(0, 0, 0)]);
+        assert_eq!(testing::run(&mut cpu, 1), None);
+        assert_eq!(cpu.regs.pc, HANDLER + 4, "masked in mie");
+    }
+
+    #[test]
+    fn enables_follow_privilege() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::addi(0, 0, 0); 4]);
+        cpu.regs.csr.stvec = HANDLER;
+        cpu.set_csr(0x303, cpu::MIP_SSIP).unwrap();
+        cpu.set_csr(0x304, cpu::MIP_SSIP).unwrap();
+        cpu.set_csr(0x344, cpu::MIP_SSIP).unwrap();
+        cpu.set_csr(0x300, cpu::MSTATUS_SIE).unwrap();
+        assert_eq!(testing::run(&mut cpu, 1), None);
+        assert_eq!(cpu.regs.pc, RAM_BASE + 4, "S-level interrupts don't preempt M-mode");
+        cpu.privilege = 1;
+        cpu.set_csr(0x300, 0).unwrap();
+        assert_eq!(testing::run(&mut cpu, 1), None);
+        assert_eq!(cpu.regs.pc, RAM_BASE + 8, "nor S-mode with SIE clear");
+        cpu.privilege = 0;
+        cpu.resync = true; // as the sret that would have got us here does
+        assert_eq!(testing::run(&mut cpu, 1), None);
+        assert_eq!((cpu.regs.csr.scause, cpu.regs.csr.sepc, cpu.regs.pc, cpu.privilege), (0x8000_0001, RAM_BASE + 12, HANDLER, 1), "but U-mode always takes them");
+    }
+
+    #[test]
+    fn checked_once_per_batch() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::addi(0, 0, 0); 64]);
+        cpu.regs.csr.mtvec = HANDLER;
+        cpu.regs.csr.mie = cpu::MIP_MSIP; // behind the hart's back, nothing ends the batch
+        cpu.regs.csr.mstatus |= cpu::MSTATUS_MIE;
+        testing::write_word(&mut cpu, clint::CLINT_BASE + clint::CLINT_MSIP, 1);
+        assert_eq!(testing::run(&mut cpu, 1), None);
+        assert_eq!(cpu.regs.pc, RAM_BASE + 4, "not noticed mid-batch");
+        assert_eq!(testing::run(&mut cpu, 62), None);
+        assert_eq!(cpu.regs.pc, RAM_BASE + 4 * 63);
+        assert_eq!(testing::run(&mut cpu, 1), None);
+        assert_eq!((cpu.regs.csr.mcause, cpu.regs.csr.mepc, cpu.regs.pc), (0x8000_0003, RAM_BASE + 4 * 64, HANDLER), "but by the end of it");
+
+        for (name, code) in [("csrw mie", asm::csrrs(0, 5, 0x304)), ("csrs mstatus", asm::csrrsi(0, 8, 0x300)), ("wfi", asm::WFI)] {
+            let mut cpu: cpu::RiscV32 = testing::program(&[code, asm::addi(0, 0, 0)]);
+            cpu.regs.csr.mtvec = HANDLER;
+            cpu.regs.write(5, cpu::MIP_MSIP);
+            cpu.regs.csr.mie = if name == "csrw mie" { 0 } else { cpu::MIP_MSIP };
+            cpu.regs.csr.mstatus |= if name == "csrs mstatus" { 0 } else { cpu::MSTATUS_MIE };
+            testing::write_word(&mut cpu, clint::CLINT_BASE + clint::CLINT_MSIP, 1);
+            assert_eq!(testing::run(&mut cpu, 1), None, "{}", name);
+            assert_eq!((cpu.regs.csr.mepc, cpu.regs.pc), (RAM_BASE + 4, HANDLER), "{} ends the batch", name);
         }
-        i -= 2;
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn wfi_privilege() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::WFI; 3]);
+        cpu.privilege = 1;
+        assert_eq!(testing::run(&mut cpu, 1), None, "S-mode may wait");
+        cpu.regs.csr.mstatus |= 1 << 21; // TW
+        assert_eq!(testing::run(&mut cpu, 1), Some(trap::Trap::IllegalInstruction), "unless TW is set");
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::WFI]);
+        cpu.privilege = 0;
+        assert_eq!(testing::run(&mut cpu, 1), Some(trap::Trap::IllegalInstruction), "U-mode never");
+    }
+}
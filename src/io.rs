@@ -1,52 +1,137 @@
-use std::io::Read;
+use std::io::{Read, Write};
 use std::os::fd::AsRawFd;
+use std::sync::mpsc;
+use std::sync::OnceLock;
 
+// terminal settings from before we switched to raw mode, restored however the emulator goes down
+static ORIGINAL: OnceLock<(std::os::fd::RawFd, termios::Termios)> = OnceLock::new();
+
+fn restore_terminal() {
+    if let Some((fd, termios)) = ORIGINAL.get() {
+        let _ = termios::tcsetattr(*fd, termios::TCSANOW, termios);
+    }
+}
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    restore_terminal(); // tcsetattr is async-signal-safe
+    unsafe {
+        libc::_exit(128 + signal);
+    }
+}
+
+/// Puts the terminal in raw mode once, keeping output post-processing so the host side still prints `\n` as a new line.
+fn enter_raw_mode(fd: std::os::fd::RawFd) {
+    let termios: termios::Termios = match termios::Termios::from_fd(fd) {
+        Ok(termios) => termios,
+        Err(_) => return, // not a terminal, input is piped in
+    };
+    if ORIGINAL.set((fd, termios)).is_err() {
+        return; // already raw
+    }
+    let mut termios_raw: termios::Termios = termios;
+    termios::cfmakeraw(&mut termios_raw);
+    termios_raw.c_oflag |= termios::OPOST | termios::ONLCR;
+    termios::tcsetattr(fd, termios::TCSANOW, &termios_raw).unwrap();
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        hook(info);
+    }));
+    for signal in [libc::SIGTERM, libc::SIGHUP, libc::SIGQUIT] { // SIGINT can't happen, ^C goes to the guest in raw mode
+        unsafe {
+            libc::signal(signal, on_signal as *const () as libc::sighandler_t);
+        }
+    }
+}
+
+/// Host keyboard, read by a background thread so the guest never waits on stdin.
 pub struct KbdIn {
-    stdin: std::io::Stdin,
-    fd: std::os::fd::RawFd,
-    termios: termios::Termios,
-    termios_raw: termios::Termios,
-    buf: [u8; 1],
+    rx: mpsc::Receiver<u8>,
 }
 
 impl KbdIn {
     pub fn new() -> KbdIn {
         let stdin: std::io::Stdin = std::io::stdin();
-        let fd: std::os::fd::RawFd = stdin.as_raw_fd();
-        let termios: termios::Termios = termios::Termios::from_fd(fd).unwrap();
-        let mut termios_raw: termios::Termios = termios.clone();
-        termios::cfmakeraw(&mut termios_raw);
-        let flags: i32 = unsafe {
-            libc::fcntl(fd, libc::F_GETFL)
-        };
-        unsafe {
-            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
-        }
+        enter_raw_mode(stdin.as_raw_fd());
+        let (tx, rx): (mpsc::Sender<u8>, mpsc::Receiver<u8>) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf: [u8; 64] = [0u8; 64];
+            loop {
+                let n: usize = match stdin.lock().read(&mut buf) {
+                    Ok(0) => return, // EOF
+                    Ok(n) => n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(_) => return,
+                };
+                for byte in &buf[..n] {
+                    if tx.send(*byte).is_err() {
+                        return; // the UART is gone
+                    }
+                }
+            }
+        });
         return KbdIn {
-            stdin: stdin,
-            fd: fd,
-            termios: termios,
-            termios_raw: termios_raw,
-            buf: [0u8; 1],
+            rx: rx,
         };
     }
     pub fn try_read_byte(&mut self) -> Option<u8> {
-        termios::tcsetattr(self.fd, termios::TCSANOW, &mut self.termios_raw).unwrap();
-        let data: Option<u8>;
-        match self.stdin.lock().read(&mut self.buf) {
-            Ok(1) => data = Some(self.buf[0]),
-            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => data = None, // no data available this cycle
-            Err(e) => panic!("error reading stdin: {:?}", e),
-            _ => data = None, // no data
+        return self.rx.try_recv().ok();
+    }
+}
+
+impl Drop for KbdIn {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+/// Where the UART's bytes go and come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SerialBackend {
+    Stdio, // the host terminal, in raw mode
+    Null, // output is dropped and there's never any input
+    File(String), // output goes to the file, there's never any input
+}
+
+impl std::fmt::Display for SerialBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SerialBackend::Stdio => write!(f, "stdio"),
+            SerialBackend::Null => write!(f, "null"),
+            SerialBackend::File(path) => write!(f, "file:{}", path),
         }
-        termios::tcsetattr(self.fd, termios::TCSANOW, &mut self.termios).unwrap();
-        return data;
     }
 }
 
-/*pub fn output_to_screen(cpu: &mut cpu::RiscV32) {
-    while let Some(c) = cpu.uart.read(uart::UART_THR) {
-        print!("{}", c as char);
+pub enum Console {
+    Stdio(KbdIn),
+    File(std::fs::File),
+    Null,
+}
+
+impl Console {
+    pub fn open(backend: &SerialBackend) -> std::io::Result<Console> {
+        match backend {
+            SerialBackend::Stdio => return Ok(Console::Stdio(KbdIn::new())),
+            SerialBackend::Null => return Ok(Console::Null),
+            SerialBackend::File(path) => return Ok(Console::File(std::fs::File::create(path)?)),
+        }
+    }
+    pub fn write_byte(&mut self, byte: u8) -> std::io::Result<()> {
+        match self {
+            Console::Stdio(_) => {
+                let mut stdout: std::io::Stdout = std::io::stdout();
+                stdout.write_all(&[byte])?;
+                return stdout.flush();
+            },
+            Console::File(file) => return file.write_all(&[byte]),
+            Console::Null => return Ok(()),
+        }
     }
-    std::io::stdout().flush().unwrap();
-}*/
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        match self {
+            Console::Stdio(kbd) => return kbd.try_read_byte(),
+            _ => return None,
+        }
+    }
+}
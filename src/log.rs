@@ -0,0 +1,128 @@
+use std::fmt;
+use std::io::Write;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How much the emulator says about itself on stderr, from nothing to every executed instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+/// Part of the emulator a message comes from; each one has its own level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Cpu = 0,
+    Decode = 1,
+    Trap = 2,
+    Mmu = 3,
+    Uart = 4,
+    Timer = 5,
+    Bootloader = 6,
+    Sbi = 7,
+    Gdb = 8,
+    Htif = 9,
+}
+
+const CATEGORIES: [(&str, Category); 10] = [
+    ("cpu", Category::Cpu),
+    ("decode", Category::Decode),
+    ("trap", Category::Trap),
+    ("mmu", Category::Mmu),
+    ("uart", Category::Uart),
+    ("timer", Category::Timer),
+    ("bootloader", Category::Bootloader),
+    ("sbi", Category::Sbi),
+    ("gdb", Category::Gdb),
+    ("htif", Category::Htif),
+];
+
+static LEVELS: [AtomicU8; CATEGORIES.len()] = [const { AtomicU8::new(Level::Info as u8) }; CATEGORIES.len()];
+
+impl Level {
+    pub fn parse(name: &str) -> Option<Level> {
+        match name {
+            "off" => return Some(Level::Off),
+            "error" => return Some(Level::Error),
+            "warn" => return Some(Level::Warn),
+            "info" => return Some(Level::Info),
+            "debug" => return Some(Level::Debug),
+            "trace" => return Some(Level::Trace),
+            _ => return None,
+        }
+    }
+}
+
+impl Category {
+    pub fn parse(name: &str) -> Option<Category> {
+        return CATEGORIES.iter().find(|(n, _)| *n == name).map(|(_, category)| *category);
+    }
+}
+
+/// Parses a filter such as `warn,decode=debug,cpu=trace`: a bare level applies to every category, later entries win.
+pub fn parse_filter(filter: &str) -> Result<Vec<(Option<Category>, Level)>, String> {
+    let mut entries: Vec<(Option<Category>, Level)> = Vec::new();
+    for entry in filter.split(',').filter(|e| !e.is_empty()) {
+        let (category, level): (Option<&str>, &str) = match entry.split_once('=') {
+            Some((category, level)) => (Some(category), level),
+            None => (None, entry),
+        };
+        let level: Level = Level::parse(level).ok_or(format!("unknown log level '{}'", level))?;
+        match category {
+            Some(name) => entries.push((Some(Category::parse(name).ok_or(format!("unknown log category '{}'", name))?), level)),
+            None => entries.push((None, level)),
+        }
+    }
+    return Ok(entries);
+}
+
+pub fn apply_filter(entries: &[(Option<Category>, Level)]) {
+    for (category, level) in entries {
+        match category {
+            Some(category) => LEVELS[*category as usize].store(*level as u8, Ordering::Relaxed),
+            None => {
+                for slot in LEVELS.iter() {
+                    slot.store(*level as u8, Ordering::Relaxed);
+                }
+            },
+        }
+    }
+}
+
+#[inline(always)]
+pub fn enabled(category: Category, level: Level) -> bool {
+    return level as u8 <= LEVELS[category as usize].load(Ordering::Relaxed);
+}
+
+/// Backend of the macros; messages go to stderr so stdout only carries what the guest prints.
+pub fn write(args: fmt::Arguments) {
+    let mut stderr: std::io::StderrLock = std::io::stderr().lock();
+    let _ = stderr.write_fmt(args);
+    let _ = stderr.flush();
+}
+
+/// `log!(Category, Level, ...)`, like `print!` but only when the category is at that level or more verbose.
+macro_rules! log {
+    ($category:ident, $level:ident, $($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Category::$category, $crate::log::Level::$level) {
+            $crate::log::write(format_args!($($arg)*));
+        }
+    };
+}
+
+/// Same as `log!`, ending the line.
+macro_rules! logln {
+    ($category:ident, $level:ident) => {
+        $crate::log::log!($category, $level, "\n")
+    };
+    ($category:ident, $level:ident, $fmt:literal $($arg:tt)*) => {
+        $crate::log::log!($category, $level, concat!($fmt, "\n") $($arg)*)
+    };
+}
+
+pub(crate) use log;
+pub(crate) use logln;
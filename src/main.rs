@@ -1,22 +1,122 @@
+#![allow(
+    clippy::needless_return,
+    clippy::precedence,
+    clippy::unnecessary_cast,
+    clippy::redundant_field_names,
+    clippy::manual_range_contains,
+    clippy::upper_case_acronyms,
+    clippy::enum_variant_names,
+    clippy::identity_op,
+    clippy::collapsible_if,
+)]
 mod bootloader;
+mod bus;
+mod cli;
+mod clint;
+mod compliance;
 mod cpu;
 mod decode;
+mod disasm;
+mod elf;
 mod extensions;
+mod fdt;
+mod gdb;
+mod halt;
+mod htif;
 mod instruction;
 mod interrupt;
 mod io;
+mod log;
 mod memory;
-mod timer;
+mod mmu;
+mod plic;
+mod sbi;
+mod softfloat;
+#[cfg(test)]
+mod testing;
+mod tlb;
 mod trap;
 mod uart;
 fn main() {
-    println!("== MARV RISC-V RV32IMA EMULATOR v0.1 ==\n== written by <franzageek> ==");
-    let mut marv: cpu::RiscV32 = cpu::RiscV32::new();
+    let options: cli::Options = match cli::parse(std::env::args().skip(1)) {
+        Ok(cli::Command::Run(options)) => *options,
+        Ok(cli::Command::Help) => {
+            print!("{}", cli::USAGE);
+            return;
+        },
+        Ok(cli::Command::Version) => {
+            println!("marv {}", env!("CARGO_PKG_VERSION"));
+            return;
+        },
+        Err(e) => {
+            eprintln!("marv: {}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        },
+    };
+    log::apply_filter(&options.log_filter);
+    disasm::set_register_names(options.register_names);
+    log::logln!(Cpu, Info, "== MARV RISC-V RV32IMA EMULATOR v0.1 ==\n== written by <franzageek> ==");
+    let mut marv: cpu::RiscV32 = match cpu::RiscV32::with_config(options.config) {
+        Ok(marv) => marv,
+        Err(e) => {
+            eprintln!("marv: {}", e);
+            std::process::exit(1);
+        },
+    };
     marv.reset();
-    bootloader::rvll(&mut marv, bootloader::BootloaderInfo::from(
-        String::from("src/devicetree/dtree.dtb"),
-        String::from("buildroot/output/images/Image"),
-    ));
-    marv.execute();
-    println!("[emulator] emulation terminated normally");
+    marv.sbi = options.sbi;
+    let kernel: String = options.boot.kernelimg.clone();
+    if let Err(e) = bootloader::rvll(&mut marv, options.boot) {
+        eprintln!("\nmarv: {}", e);
+        drop(marv);
+        std::process::exit(1);
+    }
+    if let Err(e) = htif::attach(&mut marv, options.htif) {
+        eprintln!("\nmarv: {}", e);
+        drop(marv);
+        std::process::exit(1);
+    }
+    if options.riscv_test || options.signature.is_some() {
+        if let Err(e) = compliance::check_image(&marv, options.signature.is_some()) {
+            eprintln!("\nmarv: {}: {}", kernel, e);
+            drop(marv);
+            std::process::exit(1);
+        }
+    }
+    let mut result: Result<halt::HaltReason, halt::EmuError> = match options.gdb {
+        Some(endpoint) => gdb::serve(&mut marv, &endpoint, options.limits),
+        None => marv.execute(options.limits),
+    };
+    if let (Some(path), Ok(_)) = (&options.signature, &result) {
+        if let Err(e) = compliance::dump_signature(&mut marv, path) {
+            result = Err(e);
+        }
+    }
+    if options.riscv_test {
+        compliance::report(&kernel, &result);
+    }
+    let status: i32 = match result {
+        Ok(halt::HaltReason::Shutdown(0)) => {
+            log::logln!(Cpu, Info, "[emulator] emulation terminated normally");
+            0
+        },
+        Ok(halt::HaltReason::Shutdown(code)) => {
+            log::logln!(Cpu, Info, "[emulator] emulation terminated with status {}", code);
+            code
+        },
+        Ok(reason) => {
+            if let halt::HaltReason::Trap { trap, pc, .. } = reason {
+                trap.display(&marv, pc);
+            }
+            log::logln!(Cpu, Warn, "\n[emulator] {}", reason);
+            reason.exit_code()
+        },
+        Err(e) => {
+            eprintln!("\nmarv: {}", e);
+            1
+        },
+    };
+    log::logln!(Mmu, Info, "[emulator] {}", marv.tlb);
+    drop(marv); // puts the terminal back, exit() doesn't run destructors
+    std::process::exit(status);
 }
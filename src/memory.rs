@@ -1,63 +1,106 @@
 use colored::Colorize;
 
+use crate::log;
+
+pub const RAM_BASE: u32 = 0x8000_0000;
+pub const RAM_SIZE: u32 = 128 << 20; // 128 MiB
+
+const PAGE_SHIFT: u32 = 12;
+const PAGE_SIZE: u32 = 1 << PAGE_SHIFT;
+
+/// Guest RAM covering `[base, base + size)`. Pages are allocated on first write, untouched ones read as zero.
 pub struct RV32Memory {
-    pub ram: Vec<u8>,
+    base: u32,
+    size: u32,
+    pages: Vec<Option<Box<[u8]>>>,
 }
 
 impl RV32Memory {
-    pub fn new() -> RV32Memory {
-        print!("initializing memory...");
-        let ram: Vec<u8> = vec![0u8; (1u64 << 32) as usize];
-        println!("{}, allocated {} bytes", "done".green(), ram.len().to_string().blue());
+    pub fn new(base: u32, size: u32) -> RV32Memory {
+        assert!(size != 0 && size & (PAGE_SIZE - 1) == 0, "RAM size must be a non-zero multiple of {} bytes", PAGE_SIZE);
+        assert!(base & (PAGE_SIZE - 1) == 0, "RAM base must be page aligned");
+        assert!(base.checked_add(size - 1).is_some(), "RAM doesn't fit in the physical address space");
+        log::log!(Cpu, Info, "initializing memory...");
+        let pages: Vec<Option<Box<[u8]>>> = (0..size >> PAGE_SHIFT).map(|_| None).collect();
+        log::logln!(Cpu, Info, "{}, {} MiB at 0x{:08X}->0x{:08X}", "done".green(), (size >> 20).to_string().blue(), base, base + (size - 1));
         return RV32Memory {
-            ram: ram,
+            base: base,
+            size: size,
+            pages: pages,
         };
     }
 
-    pub fn read_byte(&mut self, address: usize) -> u8 {
-        return self.ram[address];
+    pub fn base(&self) -> u32 {
+        return self.base;
+    }
+
+    pub fn size(&self) -> u32 {
+        return self.size;
     }
 
-    pub fn write_byte(&mut self, address: usize, byte: u8) { // maybe use the equivalent of memcpy for write ops
-        self.ram[address] = byte;
-        return;
+    /// Whether `[paddr, paddr + len)` lies entirely inside RAM.
+    pub fn contains(&self, paddr: u32, len: u32) -> bool {
+        return paddr >= self.base && len <= self.size && paddr - self.base <= self.size - len;
     }
 
-    pub fn read_half_word(&mut self, address: usize) -> u16 {
-        return u16::from_le_bytes([self.ram[address], self.ram[address+1]]);
+    /// Frees every page, bringing RAM back to all zeroes.
+    pub fn clear(&mut self) {
+        self.pages.fill(None);
     }
 
-    pub fn write_half_word(&mut self, address: usize, half: u16) {
-        self.ram[address] = (half & 0xFF) as u8;
-        self.ram[address+1] = ((half >> 8) & 0xFF) as u8;
-        return;
+    fn read_byte(&self, offset: u32) -> u8 {
+        match &self.pages[(offset >> PAGE_SHIFT) as usize] {
+            Some(page) => return page[(offset & (PAGE_SIZE - 1)) as usize],
+            None => return 0,
+        }
     }
 
-    pub fn read_word(&mut self, address: usize) -> u32 {
-        return u32::from_le_bytes([self.ram[address], self.ram[address+1], self.ram[address+2], self.ram[address+3]]);
+    fn write_byte(&mut self, offset: u32, byte: u8) {
+        let page: &mut Box<[u8]> = self.pages[(offset >> PAGE_SHIFT) as usize].get_or_insert_with(|| vec![0u8; PAGE_SIZE as usize].into_boxed_slice());
+        page[(offset & (PAGE_SIZE - 1)) as usize] = byte;
     }
 
-    pub fn write_word(&mut self, address: usize, word: u32) {
-        self.ram[address] = (word & 0xFF) as u8;
-        self.ram[address+1] = ((word >> 8) & 0xFF) as u8;
-        self.ram[address+2] = ((word >> 16) & 0xFF) as u8;
-        self.ram[address+3] = ((word >> 24) & 0xFF) as u8;
-        return;
+    /// Little-endian read of 1, 2, 4 or 8 bytes, `None` if any of them is outside RAM.
+    pub fn read(&self, paddr: u32, size: u32) -> Option<u64> {
+        if !self.contains(paddr, size) {
+            return None;
+        }
+        let offset: u32 = paddr - self.base;
+        let start: usize = (offset & (PAGE_SIZE - 1)) as usize;
+        if start + size as usize <= PAGE_SIZE as usize { // fast path, the whole access is on one page
+            let mut bytes: [u8; 8] = [0u8; 8];
+            if let Some(page) = &self.pages[(offset >> PAGE_SHIFT) as usize] {
+                bytes[..size as usize].copy_from_slice(&page[start..start + size as usize]);
+            }
+            return Some(u64::from_le_bytes(bytes));
+        }
+        let mut data: u64 = 0;
+        for i in 0..size {
+            data |= (self.read_byte(offset + i) as u64) << (8 * i);
+        }
+        return Some(data);
     }
 
-    pub fn read_double_word(&mut self, address: usize) -> u64 {
-        return u64::from_le_bytes([self.ram[address], self.ram[address+1], self.ram[address+2], self.ram[address+3], self.ram[address+4], self.ram[address+5], self.ram[address+6], self.ram[address+7]]);
+    pub fn write(&mut self, paddr: u32, size: u32, data: u64) -> Option<()> {
+        if !self.contains(paddr, size) {
+            return None;
+        }
+        let offset: u32 = paddr - self.base;
+        for i in 0..size {
+            self.write_byte(offset + i, (data >> (8 * i)) as u8);
+        }
+        return Some(());
     }
 
-    pub fn write_double_word(&mut self, address: usize, double: u64) {
-        self.ram[address] = (double & 0xFF) as u8;
-        self.ram[address+1] = ((double >> 8) & 0xFF) as u8;
-        self.ram[address+2] = ((double >> 16) & 0xFF) as u8;
-        self.ram[address+3] = ((double >> 24) & 0xFF) as u8;
-        self.ram[address+4] = ((double >> 32) & 0xFF) as u8;
-        self.ram[address+5] = ((double >> 40) & 0xFF) as u8;
-        self.ram[address+6] = ((double >> 48) & 0xFF) as u8;
-        self.ram[address+7] = ((double >> 56) & 0xFF) as u8;
-        return;
+    /// Copies `buffer` into RAM at `paddr`, used to load images before the hart starts.
+    pub fn load(&mut self, paddr: u32, buffer: &[u8]) -> Option<()> {
+        if buffer.len() > u32::MAX as usize || !self.contains(paddr, buffer.len() as u32) {
+            return None;
+        }
+        let offset: u32 = paddr - self.base;
+        for (i, byte) in buffer.iter().enumerate() {
+            self.write_byte(offset + i as u32, *byte);
+        }
+        return Some(());
     }
 }
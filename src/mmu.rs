@@ -0,0 +1,312 @@
+use crate::cpu;
+use crate::extensions::Execute;
+use crate::log;
+use crate::trap;
+
+pub const PAGE_SIZE: u32 = 0x1000;
+
+pub const SATP_MODE: u32 = 1 << 31; // 0 = bare, 1 = Sv32
+pub const SATP_ASID: u32 = 0x1FF << 22;
+pub const SATP_PPN: u32 = 0x003F_FFFF;
+
+pub const MSTATUS_MPRV: u32 = 1 << 17;
+pub const MSTATUS_SUM: u32 = 1 << 18;
+pub const MSTATUS_MXR: u32 = 1 << 19;
+pub const MSTATUS_TVM: u32 = 1 << 20;
+
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+const PTE_U: u32 = 1 << 4;
+const PTE_A: u32 = 1 << 6;
+const PTE_D: u32 = 1 << 7;
+
+const LEVELS: u32 = 2;
+const PTE_SIZE: u64 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store, // AMOs translate as stores
+}
+
+impl Access {
+    fn page_fault(self) -> trap::Trap {
+        match self {
+            Access::Fetch => return trap::Trap::InstructionPageFault,
+            Access::Load => return trap::Trap::LoadPageFault,
+            Access::Store => return trap::Trap::StorePageFault,
+        }
+    }
+    fn access_fault(self) -> trap::Trap {
+        match self {
+            Access::Fetch => return trap::Trap::InstructionAccessFault,
+            Access::Load => return trap::Trap::LoadAccessFault,
+            Access::Store => return trap::Trap::StoreAccessFault,
+        }
+    }
+}
+
+fn effective_privilege(cpu: &cpu::RiscV32, access: Access) -> u8 {
+    if access != Access::Fetch && cpu.privilege == 3 && cpu.regs.csr.mstatus & MSTATUS_MPRV != 0 {
+        return ((cpu.regs.csr.mstatus >> 11) & 0x3) as u8; // M-mode loads and stores use MPP when MPRV is set
+    }
+    return cpu.privilege;
+}
+
+fn check_leaf(cpu: &cpu::RiscV32, pte: u32, privilege: u8, access: Access) -> bool {
+    if pte & PTE_U != 0 {
+        if privilege == 1 && (access == Access::Fetch || cpu.regs.csr.mstatus & MSTATUS_SUM == 0) {
+            return false; // S-mode never executes U pages and only touches them with SUM set
+        }
+    } else if privilege == 0 {
+        return false;
+    }
+    match access {
+        Access::Fetch => return pte & PTE_X != 0,
+        Access::Load => return pte & PTE_R != 0 || (cpu.regs.csr.mstatus & MSTATUS_MXR != 0 && pte & PTE_X != 0),
+        Access::Store => return pte & PTE_W != 0,
+    }
+}
+
+/// Performs the Sv32 page-table walk, returning the physical address, leaf PTE and its level, or the exception to raise.
+/// A `debug` walk only looks: it skips permission checks and leaves the A and D bits alone.
+fn walk(cpu: &mut cpu::RiscV32, vaddr: u32, privilege: u8, access: Access, debug: bool) -> Result<(u32, u32, u32), trap::Trap> {
+    let vpn: [u32; 2] = [(vaddr >> 12) & 0x3FF, (vaddr >> 22) & 0x3FF];
+    let mut table: u64 = ((cpu.regs.csr.satp & SATP_PPN) as u64) << 12;
+    let mut level: u32 = LEVELS - 1;
+    loop {
+        let pte_addr: u64 = table + vpn[level as usize] as u64 * PTE_SIZE;
+        if pte_addr > u32::MAX as u64 {
+            return Err(access.access_fault());
+        }
+        let mut pte: u32 = match cpu.bus.read(pte_addr as u32, 4) {
+            Some(pte) => pte as u32,
+            None => return Err(access.access_fault()), // page tables must live in memory
+        };
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+            return Err(access.page_fault());
+        }
+        if pte & (PTE_R | PTE_X) == 0 { // pointer to the next level
+            if level == 0 {
+                return Err(access.page_fault());
+            }
+            level -= 1;
+            table = ((pte >> 10) as u64) << 12;
+            continue;
+        }
+        if !debug && !check_leaf(cpu, pte, privilege, access) {
+            return Err(access.page_fault());
+        }
+        if level == 1 && (pte >> 10) & 0x3FF != 0 {
+            return Err(access.page_fault()); // misaligned superpage
+        }
+        let dirty: bool = access == Access::Store;
+        if !debug && (pte & PTE_A == 0 || (dirty && pte & PTE_D == 0)) {
+            pte |= PTE_A | if dirty { PTE_D } else { 0 };
+            if cpu.bus.write(pte_addr as u32, 4, pte as u64).is_none() {
+                return Err(access.access_fault());
+            }
+            cpu.snoop_store(pte_addr as u32, 4);
+        }
+        let ppn: u64 = (pte >> 10) as u64;
+        let paddr: u64 = if level == 1 {
+            ((ppn >> 10) << 22) | (vaddr & 0x003F_FFFF) as u64
+        } else {
+            (ppn << 12) | (vaddr & 0xFFF) as u64
+        };
+        if paddr > u32::MAX as u64 {
+            return Err(access.access_fault()); // 34-bit physical addresses beyond what we emulate
+        }
+        return Ok((paddr as u32, pte, level));
+    }
+}
+
+/// Translates a virtual address for the given access type, taking the matching trap (with `vaddr` as tval) on failure.
+pub fn translate(cpu: &mut cpu::RiscV32, vaddr: u32, access: Access) -> Result<u32, trap::Trap> {
+    let privilege: u8 = effective_privilege(cpu, access);
+    if privilege == 3 || cpu.regs.csr.satp & SATP_MODE == 0 {
+        return Ok(vaddr);
+    }
+    let asid: u32 = (cpu.regs.csr.satp & SATP_ASID) >> 22;
+    let vpn: u32 = vaddr >> 12;
+    let fetch: bool = access == Access::Fetch;
+    if let Some((ppn, pte)) = cpu.tlb.lookup(vpn, asid, fetch) {
+        if check_leaf(cpu, pte, privilege, access) && (access != Access::Store || pte & PTE_D != 0) {
+            return Ok((ppn << 12) | (vaddr & 0xFFF));
+        }
+        // otherwise walk again, which either raises the fault or sets the dirty bit
+    }
+    match walk(cpu, vaddr, privilege, access, false) {
+        Ok((paddr, pte, level)) => {
+            cpu.tlb.insert(vpn, asid, paddr >> 12, pte, level, fetch);
+            return Ok(paddr);
+        },
+        Err(code) => {
+            log::logln!(Mmu, Debug, "[mmu] {:?} for {:?} of 0x{:08X} at PC 0x{:08X}", code, access, vaddr, cpu.regs.pc);
+            return Err(trap::Trap::take(code, cpu, vaddr));
+        },
+    }
+}
+
+/// Translates `vaddr` as a load in the current context would, for the debugger: no permission checks, no side effects, no trap.
+pub fn probe(cpu: &mut cpu::RiscV32, vaddr: u32) -> Option<u32> {
+    let privilege: u8 = effective_privilege(cpu, Access::Load);
+    if privilege == 3 || cpu.regs.csr.satp & SATP_MODE == 0 {
+        return Some(vaddr);
+    }
+    return walk(cpu, vaddr, privilege, Access::Load, true).ok().map(|(paddr, _, _)| paddr);
+}
+
+#[derive(Debug)]
+pub enum MmuInstruction {
+    SfenceVma(u8, u8),
+}
+
+impl Execute for MmuInstruction {
+    fn execute(self, cpu: &mut cpu::RiscV32) -> Option<trap::Trap> {
+        match self {
+            MmuInstruction::SfenceVma(rs1, rs2) => {
+                if cpu.privilege == 0 || (cpu.privilege == 1 && cpu.regs.csr.mstatus & MSTATUS_TVM != 0) {
+                    return Some(trap::Trap::take(trap::Trap::IllegalInstruction, cpu, cpu.regs.pc));
+                }
+                let vaddr: Option<u32> = if rs1 != 0 { Some(cpu.regs.read(rs1)) } else { None };
+                let asid: Option<u32> = if rs2 != 0 { Some(cpu.regs.read(rs2) & 0x1FF) } else { None };
+                cpu.tlb.flush(vaddr, asid);
+                return None;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, RAM_BASE};
+
+    const ROOT: u32 = RAM_BASE + 0x1000;
+    const TABLE: u32 = RAM_BASE + 0x2000; // second level for VPN[1] = 1
+    const PAGE: u32 = RAM_BASE + 0x4000;
+    const VA: u32 = 0x0040_0000; // through TABLE to PAGE
+    const SUPER: u32 = 0x8000_0000; // a 4 MiB superpage mapping RAM onto itself
+    const MISALIGNED: u32 = 0x00C0_0000; // a superpage leaf whose PPN[0] isn't zero
+    const UNMAPPED: u32 = 0x0100_0000;
+    const RWX: u32 = PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+    const MPP_S: u32 = 1 << 11;
+
+    fn pte(paddr: u32, flags: u32) -> u32 {
+        return (paddr >> 12) << 10 | flags;
+    }
+
+    /// An S-mode hart with Sv32 on and VA mapped to PAGE with `flags`.
+    fn machine(flags: u32) -> cpu::RiscV32 {
+        let mut cpu: cpu::RiscV32 = testing::machine();
+        testing::write_word(&mut cpu, ROOT + 4 * (VA >> 22), pte(TABLE, PTE_V));
+        testing::write_word(&mut cpu, TABLE + 4 * ((VA >> 12) & 0x3FF), pte(PAGE, flags));
+        testing::write_word(&mut cpu, ROOT + 4 * (SUPER >> 22), pte(RAM_BASE, RWX));
+        testing::write_word(&mut cpu, ROOT + 4 * (MISALIGNED >> 22), pte(RAM_BASE + 0x1000, RWX));
+        cpu.regs.csr.satp = SATP_MODE | (ROOT >> 12);
+        cpu.regs.csr.mtvec = RAM_BASE + 0x40;
+        cpu.privilege = 1;
+        return cpu;
+    }
+
+    fn leaf(cpu: &mut cpu::RiscV32) -> u32 {
+        return testing::read_word(cpu, TABLE + 4 * ((VA >> 12) & 0x3FF));
+    }
+
+    #[test]
+    fn walks() {
+        let mut cpu: cpu::RiscV32 = machine(RWX);
+        assert_eq!(translate(&mut cpu, VA + 0x123, Access::Load), Ok(PAGE + 0x123), "two levels");
+        assert_eq!(translate(&mut cpu, SUPER + 0x12_3456, Access::Fetch), Ok(RAM_BASE + 0x12_3456), "superpage");
+        assert_eq!(translate(&mut cpu, MISALIGNED, Access::Load), Err(trap::Trap::LoadPageFault), "misaligned superpage");
+        cpu.regs.csr.satp = 0;
+        assert_eq!(translate(&mut cpu, VA, Access::Load), Ok(VA), "bare");
+    }
+
+    #[test]
+    fn accessed_and_dirty() {
+        let mut cpu: cpu::RiscV32 = machine(PTE_V | PTE_R | PTE_W);
+        assert_eq!(probe(&mut cpu, VA + 4), Some(PAGE + 4));
+        assert_eq!(leaf(&mut cpu) & (PTE_A | PTE_D), 0, "probing leaves them alone");
+        assert_eq!(translate(&mut cpu, VA, Access::Load), Ok(PAGE));
+        assert_eq!(leaf(&mut cpu) & (PTE_A | PTE_D), PTE_A, "a load sets A");
+        assert_eq!(translate(&mut cpu, VA, Access::Store), Ok(PAGE));
+        assert_eq!(leaf(&mut cpu) & (PTE_A | PTE_D), PTE_A | PTE_D, "a store sets D too, even after the load filled the TLB");
+    }
+
+    #[test]
+    fn permissions() {
+        let cases: [(&str, u32, u8, u32, Access, bool); 14] = [
+            // name, leaf flags, privilege, mstatus, access, allowed
+            ("S loads an S page", PTE_R, 1, 0, Access::Load, true),
+            ("U loads an S page", PTE_R, 0, 0, Access::Load, false),
+            ("U loads a U page", PTE_R | PTE_U, 0, 0, Access::Load, true),
+            ("S loads a U page", PTE_R | PTE_U, 1, 0, Access::Load, false),
+            ("S loads a U page with SUM", PTE_R | PTE_U, 1, MSTATUS_SUM, Access::Load, true),
+            ("S stores to a U page with SUM", PTE_R | PTE_W | PTE_U, 1, MSTATUS_SUM, Access::Store, true),
+            ("S fetches from a U page with SUM", PTE_X | PTE_U, 1, MSTATUS_SUM, Access::Fetch, false),
+            ("load from execute-only", PTE_X, 1, 0, Access::Load, false),
+            ("load from execute-only with MXR", PTE_X, 1, MSTATUS_MXR, Access::Load, true),
+            ("store to read-only", PTE_R, 1, 0, Access::Store, false),
+            ("fetch from read-only", PTE_R, 1, 0, Access::Fetch, false),
+            ("fetch from executable", PTE_X, 1, 0, Access::Fetch, true),
+            ("write without read is reserved", PTE_W, 1, 0, Access::Load, false),
+            ("invalid", PTE_R | PTE_W | PTE_X, 1, 0, Access::Load, false),
+        ];
+        for (name, flags, privilege, mstatus, access, allowed) in cases {
+            let valid: u32 = if name == "invalid" { 0 } else { PTE_V };
+            let mut cpu: cpu::RiscV32 = machine(flags | valid | PTE_A | PTE_D);
+            cpu.privilege = privilege;
+            cpu.regs.csr.mstatus |= mstatus;
+            let expected: Result<u32, trap::Trap> = if allowed { Ok(PAGE) } else { Err(access.page_fault()) };
+            assert_eq!(translate(&mut cpu, VA, access), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn faults() {
+        let cases: [(Access, trap::Trap, u32); 3] = [
+            (Access::Fetch, trap::Trap::InstructionPageFault, 12),
+            (Access::Load, trap::Trap::LoadPageFault, 13),
+            (Access::Store, trap::Trap::StorePageFault, 15),
+        ];
+        for (access, trap, cause) in cases {
+            let mut cpu: cpu::RiscV32 = machine(RWX);
+            assert_eq!(translate(&mut cpu, UNMAPPED + 0x10, access), Err(trap), "{:?}", access);
+            assert_eq!((cpu.regs.csr.mcause, cpu.regs.csr.mtval, cpu.privilege, cpu.regs.pc), (cause, UNMAPPED + 0x10, 3, RAM_BASE + 0x40), "{:?}", access);
+        }
+    }
+
+    #[test]
+    fn mprv() {
+        let mut cpu: cpu::RiscV32 = machine(RWX);
+        cpu.privilege = 3;
+        assert_eq!(translate(&mut cpu, VA, Access::Load), Ok(VA), "M-mode isn't translated");
+        cpu.regs.csr.mstatus |= MSTATUS_MPRV | MPP_S;
+        assert_eq!(translate(&mut cpu, VA, Access::Load), Ok(PAGE), "unless MPRV says to translate as MPP");
+        assert_eq!(translate(&mut cpu, VA, Access::Store), Ok(PAGE));
+        assert_eq!(translate(&mut cpu, VA, Access::Fetch), Ok(VA), "fetches never are");
+        assert_eq!(probe(&mut cpu, VA), Some(PAGE), "the debugger sees what loads see");
+        cpu.regs.csr.mstatus &= !MPP_S; // MPP = U
+        assert_eq!(translate(&mut cpu, VA, Access::Load), Err(trap::Trap::LoadPageFault), "U can't touch an S page");
+
+        let mut cpu: cpu::RiscV32 = machine(RWX);
+        cpu.regs.csr.mstatus |= MSTATUS_MPRV; // MPP = U, left over from M-mode
+        assert_eq!(translate(&mut cpu, VA, Access::Load), Ok(PAGE), "MPRV means nothing outside M-mode");
+
+        // firmware touching S memory through MPRV, then going back to S-mode
+        let mut cpu: cpu::RiscV32 = machine(RWX);
+        testing::write_word(&mut cpu, RAM_BASE, testing::asm::MRET);
+        cpu.regs.pc = RAM_BASE;
+        cpu.privilege = 3;
+        cpu.regs.csr.mepc = VA;
+        cpu.regs.csr.mstatus |= MSTATUS_MPRV | MPP_S;
+        assert_eq!(testing::run(&mut cpu, 1), None);
+        assert_eq!((cpu.privilege, cpu.regs.csr.mstatus & MSTATUS_MPRV), (1, 0), "mret to S clears MPRV");
+        assert_eq!(translate(&mut cpu, VA, Access::Load), Ok(PAGE));
+    }
+}
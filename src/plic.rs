@@ -0,0 +1,139 @@
+use crate::bus::Device;
+
+pub const PLIC_BASE: u32 = 0x0C00_0000;
+pub const PLIC_SIZE: u32 = 0x400_0000;
+// register offsets
+pub const PLIC_PENDING: u32 = 0x1000;
+pub const PLIC_ENABLE: u32 = 0x2000; // + 0x80 per context
+pub const PLIC_CONTEXT: u32 = 0x20_0000; // + 0x1000 per context, threshold at +0 and claim/complete at +4
+
+pub const SOURCES: usize = 32; // source 0 doesn't exist, it's what a claim returns when nothing is pending
+pub const UART_IRQ: u32 = 10; // same line as on QEMU's virt machine
+
+const CONTEXTS: usize = 2; // hart 0 M-mode, hart 0 S-mode
+const CONTEXT_EIP: [u32; CONTEXTS] = [1 << 11, 1 << 9]; // MEIP, SEIP
+const PRIORITY_MASK: u32 = 0x7;
+
+pub struct PLIC {
+    priority: [u32; SOURCES],
+    level: u32, // one bit per source, the state of its interrupt line
+    claimed: u32, // sources claimed but not yet completed, their gateway is closed
+    enable: [u32; CONTEXTS],
+    threshold: [u32; CONTEXTS],
+}
+
+impl PLIC {
+    pub fn new() -> PLIC {
+        return PLIC {
+            priority: [0; SOURCES],
+            level: 0,
+            claimed: 0,
+            enable: [0; CONTEXTS],
+            threshold: [0; CONTEXTS],
+        };
+    }
+    fn pending(&self) -> u32 {
+        return self.level & !self.claimed & !1;
+    }
+    /// Highest priority source that can interrupt `context`, ties going to the lowest ID.
+    fn best(&self, context: usize) -> u32 {
+        let candidates: u32 = self.pending() & self.enable[context];
+        let mut best: u32 = 0;
+        let mut best_priority: u32 = self.threshold[context];
+        for source in 1..SOURCES as u32 {
+            if candidates & (1 << source) != 0 && self.priority[source as usize] > best_priority {
+                best = source;
+                best_priority = self.priority[source as usize];
+            }
+        }
+        return best;
+    }
+    fn read_register(&mut self, offset: u32) -> u32 {
+        if (offset as usize) < SOURCES * 4 {
+            return self.priority[(offset / 4) as usize];
+        }
+        if offset == PLIC_PENDING {
+            return self.pending();
+        }
+        if offset >= PLIC_ENABLE && offset < PLIC_ENABLE + 0x80 * CONTEXTS as u32 {
+            let context: usize = ((offset - PLIC_ENABLE) / 0x80) as usize;
+            return if (offset - PLIC_ENABLE) & 0x7F == 0 { self.enable[context] } else { 0 };
+        }
+        if offset >= PLIC_CONTEXT && offset < PLIC_CONTEXT + 0x1000 * CONTEXTS as u32 {
+            let context: usize = ((offset - PLIC_CONTEXT) / 0x1000) as usize;
+            match (offset - PLIC_CONTEXT) % 0x1000 {
+                0 => return self.threshold[context],
+                4 => { // claim
+                    let source: u32 = self.best(context);
+                    if source != 0 {
+                        self.claimed |= 1 << source;
+                    }
+                    return source;
+                },
+                _ => return 0,
+            }
+        }
+        return 0; // unimplemented registers read as zero
+    }
+    fn write_register(&mut self, offset: u32, data: u32) {
+        if (offset as usize) < SOURCES * 4 {
+            if offset != 0 {
+                self.priority[(offset / 4) as usize] = data & PRIORITY_MASK;
+            }
+            return;
+        }
+        if offset >= PLIC_ENABLE && offset < PLIC_ENABLE + 0x80 * CONTEXTS as u32 {
+            let context: usize = ((offset - PLIC_ENABLE) / 0x80) as usize;
+            if (offset - PLIC_ENABLE) & 0x7F == 0 {
+                self.enable[context] = data & !1;
+            }
+            return;
+        }
+        if offset >= PLIC_CONTEXT && offset < PLIC_CONTEXT + 0x1000 * CONTEXTS as u32 {
+            let context: usize = ((offset - PLIC_CONTEXT) / 0x1000) as usize;
+            match (offset - PLIC_CONTEXT) % 0x1000 {
+                0 => self.threshold[context] = data & PRIORITY_MASK,
+                4 if (data as usize) < SOURCES && self.enable[context] & (1 << data) != 0 => self.claimed &= !(1 << data), // complete, ignored for sources this context can't see
+                _ => {},
+            }
+        }
+        // pending bits are read-only, other writes are ignored
+    }
+}
+
+impl Device for PLIC {
+    fn read(&mut self, offset: u32, size: u32) -> Option<u64> {
+        if size != 4 || offset & 0x3 != 0 { // registers are 32 bits wide
+            return None;
+        }
+        return Some(self.read_register(offset) as u64);
+    }
+    fn write(&mut self, offset: u32, size: u32, data: u64) -> Option<()> {
+        if size != 4 || offset & 0x3 != 0 {
+            return None;
+        }
+        self.write_register(offset, data as u32);
+        return Some(());
+    }
+    fn reset(&mut self) {
+        self.priority.fill(0);
+        self.level = 0;
+        self.claimed = 0;
+        self.enable.fill(0);
+        self.threshold.fill(0);
+    }
+    /// Lines are level-triggered, so a device keeps its line high until serviced.
+    fn set_irq_lines(&mut self, lines: u32) {
+        self.level = lines & !1;
+    }
+    /// Reflects the PLIC's outputs into MEIP and SEIP.
+    fn mip(&self) -> u32 {
+        let mut mip: u32 = 0;
+        for (context, eip) in CONTEXT_EIP.iter().enumerate() {
+            if self.best(context) != 0 {
+                mip |= eip;
+            }
+        }
+        return mip;
+    }
+}
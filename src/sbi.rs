@@ -0,0 +1,393 @@
+use colored::Colorize;
+
+use crate::cpu;
+use crate::mmu;
+use crate::clint;
+use crate::log;
+use crate::uart;
+
+// extension IDs, passed in a7
+const EID_LEGACY_PUTCHAR: u32 = 0x01;
+const EID_LEGACY_GETCHAR: u32 = 0x02;
+const EID_BASE: u32 = 0x10;
+const EID_TIME: u32 = 0x54494D45; // "TIME"
+const EID_IPI: u32 = 0x735049; // "sPI"
+const EID_RFENCE: u32 = 0x52464E43; // "RFNC"
+const EID_HSM: u32 = 0x48534D; // "HSM"
+const EID_SRST: u32 = 0x53525354; // "SRST"
+const EID_DBCN: u32 = 0x4442434E; // "DBCN"
+
+const SUPPORTED: [u32; 9] = [EID_LEGACY_PUTCHAR, EID_LEGACY_GETCHAR, EID_BASE, EID_TIME, EID_IPI, EID_RFENCE, EID_HSM, EID_SRST, EID_DBCN];
+
+const SPEC_VERSION: u32 = 2 << 24; // v2.0
+const IMPL_ID: u32 = 0x4D52; // not a registered ID, "MR" for marv
+const IMPL_VERSION: u32 = 0x0001; // v0.1
+
+// error codes, returned in a0
+const SUCCESS: i32 = 0;
+const ERR_NOT_SUPPORTED: i32 = -2;
+const ERR_INVALID_PARAM: i32 = -3;
+const ERR_ALREADY_AVAILABLE: i32 = -6;
+
+const HSM_STARTED: u32 = 0;
+const HSM_SUSPEND_RETENTIVE: u32 = 0x0000_0000;
+const HSM_SUSPEND_NON_RETENTIVE: u32 = 0x8000_0000;
+
+// exceptions and interrupts OpenSBI hands straight to the kernel
+const DELEGATED_EXCEPTIONS: u32 = (1 << 0) | (1 << 3) | (1 << 8) | (1 << 12) | (1 << 13) | (1 << 15);
+const DELEGATED_INTERRUPTS: u32 = cpu::MIP_SSIP | cpu::MIP_STIP | (1 << 9);
+
+const MTIMECMP: u32 = clint::CLINT_BASE + clint::CLINT_MTIMECMP;
+
+fn uart_register(cpu: &mut cpu::RiscV32, offset: u32) -> u8 {
+    return cpu.bus.read(uart::UART_BASE + offset, 1).unwrap_or(0) as u8;
+}
+
+/// Sends a byte through the UART the way a firmware driver would, waiting for room in the transmitter. HTIF's console
+/// goes through here too.
+pub fn putc(cpu: &mut cpu::RiscV32, byte: u8) {
+    while uart_register(cpu, uart::UART_LSR) & uart::LSR_THRE == 0 {
+        cpu.bus.tick(1); // time passes while we spin, interrupt lines are latched again once the guest resumes
+    }
+    cpu.bus.write(uart::UART_BASE + uart::UART_THR, 1, byte as u64);
+}
+
+/// Takes the next byte the UART received, if there is one.
+pub fn getc(cpu: &mut cpu::RiscV32) -> Option<u8> {
+    if uart_register(cpu, uart::UART_LSR) & uart::LSR_DR == 0 {
+        return None;
+    }
+    return Some(uart_register(cpu, uart::UART_RBR));
+}
+
+/// Returns whether the hart mask selects hart 0, the only hart we have, or `None` if it names a hart that doesn't exist.
+fn selects_hart0(mask: u32, base: u32) -> Option<bool> {
+    if base == u32::MAX { // a base of -1 means every hart, regardless of the mask
+        return Some(true);
+    }
+    if base != 0 {
+        return if mask == 0 { Some(false) } else { None };
+    }
+    if mask & !0x1 != 0 {
+        return None;
+    }
+    return Some(mask & 0x1 != 0);
+}
+
+fn base(cpu: &mut cpu::RiscV32, fid: u32) -> (i32, u32) {
+    match fid {
+        0 => return (SUCCESS, SPEC_VERSION),
+        1 => return (SUCCESS, IMPL_ID),
+        2 => return (SUCCESS, IMPL_VERSION),
+        3 => return (SUCCESS, SUPPORTED.contains(&cpu.regs.x[10]) as u32),
+        4 => return (SUCCESS, cpu.regs.csr.mvendorid),
+        5 => return (SUCCESS, cpu.regs.csr.marchid),
+        6 => return (SUCCESS, cpu.regs.csr.mimpid),
+        _ => return (ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn time(cpu: &mut cpu::RiscV32, fid: u32) -> (i32, u32) {
+    if fid != 0 {
+        return (ERR_NOT_SUPPORTED, 0);
+    }
+    let stime: u64 = ((cpu.regs.x[11] as u64) << 32) | cpu.regs.x[10] as u64;
+    cpu.bus.write(MTIMECMP, 8, stime);
+    cpu.regs.csr.mip &= !cpu::MIP_STIP; // the pending timer interrupt is acknowledged by programming the next one
+    return (SUCCESS, 0);
+}
+
+fn ipi(cpu: &mut cpu::RiscV32, fid: u32) -> (i32, u32) {
+    if fid != 0 {
+        return (ERR_NOT_SUPPORTED, 0);
+    }
+    match selects_hart0(cpu.regs.x[10], cpu.regs.x[11]) {
+        Some(true) => cpu.regs.csr.mip |= cpu::MIP_SSIP,
+        Some(false) => {},
+        None => return (ERR_INVALID_PARAM, 0),
+    }
+    return (SUCCESS, 0);
+}
+
+fn rfence(cpu: &mut cpu::RiscV32, fid: u32) -> (i32, u32) {
+    let selected: bool = match selects_hart0(cpu.regs.x[10], cpu.regs.x[11]) {
+        Some(selected) => selected,
+        None => return (ERR_INVALID_PARAM, 0),
+    };
+    let (start, size, asid): (u32, u32, u32) = (cpu.regs.x[12], cpu.regs.x[13], cpu.regs.x[14]);
+    let asid: Option<u32> = match fid {
+        0 => return (SUCCESS, 0), // remote FENCE.I, instruction fetches are always coherent here
+        1 => None,
+        2 => Some(asid),
+        _ => return (ERR_NOT_SUPPORTED, 0), // hypervisor fences
+    };
+    if !selected {
+        return (SUCCESS, 0);
+    }
+    if (start == 0 && size == 0) || size == u32::MAX || size / mmu::PAGE_SIZE > 64 { // a full flush is cheaper than walking a big range
+        cpu.tlb.flush(None, asid);
+        return (SUCCESS, 0);
+    }
+    let mut page: u32 = start & !(mmu::PAGE_SIZE - 1);
+    while page < start.wrapping_add(size) {
+        cpu.tlb.flush(Some(page), asid);
+        page = page.wrapping_add(mmu::PAGE_SIZE);
+        if page == 0 {
+            break;
+        }
+    }
+    return (SUCCESS, 0);
+}
+
+fn hsm(cpu: &mut cpu::RiscV32, fid: u32) -> (i32, u32) {
+    match fid {
+        0 => return (if cpu.regs.x[10] == 0 { ERR_ALREADY_AVAILABLE } else { ERR_INVALID_PARAM }, 0), // hart_start
+        1 => { // hart_stop, with a single hart there's nothing left to run
+            log::logln!(Sbi, Info, "\n[sbi] hart 0 stopped");
+            cpu.status = false;
+            return (SUCCESS, 0);
+        },
+        2 => return if cpu.regs.x[10] == 0 { (SUCCESS, HSM_STARTED) } else { (ERR_INVALID_PARAM, 0) }, // hart_get_status
+        3 => match cpu.regs.x[10] { // hart_suspend
+            HSM_SUSPEND_RETENTIVE => return (SUCCESS, 0), // like WFI, pending interrupts are taken right after the ecall
+            HSM_SUSPEND_NON_RETENTIVE => {
+                let (resume_addr, opaque): (u32, u32) = (cpu.regs.x[11], cpu.regs.x[12]);
+                cpu.regs.csr.satp = 0;
+                cpu.tlb.flush(None, None);
+                cpu.regs.csr.mstatus &= !(1 << 1); // resume with SIE cleared
+                cpu.regs.pc = resume_addr.wrapping_sub(cpu.ilen); // the run loop steps over the ecall
+                return (SUCCESS, opaque); // resumes with a0 = hartid (0, same as SUCCESS) and a1 = opaque
+            },
+            _ => return (ERR_INVALID_PARAM, 0),
+        },
+        _ => return (ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn srst(cpu: &mut cpu::RiscV32, fid: u32) -> (i32, u32) {
+    if fid != 0 {
+        return (ERR_NOT_SUPPORTED, 0);
+    }
+    let reason: &str = match cpu.regs.x[11] {
+        0 => "no reason",
+        1 => "system failure",
+        _ => "implementation specific reason",
+    };
+    match cpu.regs.x[10] {
+        0 => log::logln!(Sbi, Info, "\n[sbi] shutdown requested ({})", reason),
+        1 | 2 => log::logln!(Sbi, Info, "\n[sbi] reboot requested ({}), halting instead", reason),
+        _ => return (ERR_INVALID_PARAM, 0),
+    }
+    cpu.exit_code = if cpu.regs.x[11] == 1 { 1 } else { 0 }; // a system failure is reported to the host
+    cpu.status = false;
+    return (SUCCESS, 0);
+}
+
+fn dbcn(cpu: &mut cpu::RiscV32, fid: u32) -> (i32, u32) {
+    let (count, base_lo, base_hi): (u32, u32, u32) = (cpu.regs.x[10], cpu.regs.x[11], cpu.regs.x[12]);
+    match fid {
+        0 | 1 if base_hi != 0 => return (ERR_INVALID_PARAM, 0), // physical addresses are 32 bits wide
+        0 => { // console_write
+            for i in 0..count {
+                let byte: u8 = match cpu.bus.read(base_lo.wrapping_add(i), 1) {
+                    Some(byte) => byte as u8,
+                    None => return (ERR_INVALID_PARAM, 0),
+                };
+                putc(cpu, byte);
+            }
+            return (SUCCESS, count);
+        },
+        1 => { // console_read
+            let mut read: u32 = 0;
+            while read < count {
+                match getc(cpu) {
+                    Some(byte) => {
+                        let paddr: u32 = base_lo.wrapping_add(read);
+                        if cpu.bus.write(paddr, 1, byte as u64).is_none() {
+                            return (ERR_INVALID_PARAM, 0);
+                        }
+                        cpu.snoop_store(paddr, 1);
+                        read += 1;
+                    },
+                    None => break,
+                }
+            }
+            return (SUCCESS, read);
+        },
+        2 => { // console_write_byte
+            putc(cpu, cpu.regs.x[10] as u8);
+            return (SUCCESS, 0);
+        },
+        _ => return (ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+/// Services an `ecall` from S-mode, leaving the results in a0/a1 as the SBI calling convention expects.
+pub fn call(cpu: &mut cpu::RiscV32) {
+    let (eid, fid): (u32, u32) = (cpu.regs.x[17], cpu.regs.x[16]);
+    match eid {
+        EID_LEGACY_PUTCHAR => {
+            putc(cpu, cpu.regs.x[10] as u8);
+            cpu.regs.x[10] = 0;
+            return;
+        },
+        EID_LEGACY_GETCHAR => {
+            cpu.regs.x[10] = match getc(cpu) {
+                Some(byte) => byte as u32,
+                None => u32::MAX, // -1, nothing to read
+            };
+            return;
+        },
+        _ => {},
+    }
+    let (error, value): (i32, u32) = match eid {
+        EID_BASE => base(cpu, fid),
+        EID_TIME => time(cpu, fid),
+        EID_IPI => ipi(cpu, fid),
+        EID_RFENCE => rfence(cpu, fid),
+        EID_HSM => hsm(cpu, fid),
+        EID_SRST => srst(cpu, fid),
+        EID_DBCN => dbcn(cpu, fid),
+        _ => (ERR_NOT_SUPPORTED, 0),
+    };
+    cpu.regs.x[10] = error as u32;
+    cpu.regs.x[11] = value;
+}
+
+/// Hands the hart over to the kernel in S-mode, with the same delegation OpenSBI sets up.
+pub fn boot(cpu: &mut cpu::RiscV32) {
+    log::log!(Sbi, Info, "{} delegating exceptions and interrupts to S-mode...", "[sbi]".purple());
+    cpu.regs.csr.medeleg = DELEGATED_EXCEPTIONS;
+    cpu.regs.csr.mideleg = DELEGATED_INTERRUPTS;
+    cpu.regs.csr.mcounteren = 0x7; // cycle, time and instret readable from S-mode
+    log::logln!(Sbi, Info, "{}", "done".green());
+    log::log!(Sbi, Info, "{} disarming timer...", "[sbi]".purple());
+    cpu.bus.write(MTIMECMP, 8, u64::MAX);
+    cpu.regs.csr.mip &= !cpu::MIP_STIP;
+    log::logln!(Sbi, Info, "{}", "done".green());
+    log::log!(Sbi, Info, "{} switching to S-mode...", "[sbi]".purple());
+    cpu.regs.csr.satp = 0;
+    cpu.regs.csr.mstatus &= !((3 << 11) | (1 << 1)); // clear MPP and SIE, the kernel enables interrupts itself
+    cpu.regs.csr.mstatus |= 1 << 11; // MPP = S, as if we had just executed mret
+    cpu.privilege = 1;
+    log::logln!(Sbi, Info, "{}, SBI v{}.{} ready", "done".green(), SPEC_VERSION >> 24, SPEC_VERSION & 0xFFFFFF);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, asm, DATA, RAM_BASE};
+
+    /// Calls `eid`/`fid` the way an S-mode kernel would, with `args` in a0 onwards, and returns a0/a1.
+    fn sbi(cpu: &mut cpu::RiscV32, eid: u32, fid: u32, args: &[u32]) -> (i32, u32) {
+        cpu.regs.x[17] = eid;
+        cpu.regs.x[16] = fid;
+        for (i, arg) in args.iter().enumerate() {
+            cpu.regs.x[10 + i] = *arg;
+        }
+        call(cpu);
+        return (cpu.regs.x[10] as i32, cpu.regs.x[11]);
+    }
+
+    #[test]
+    fn probe_extension() {
+        let mut cpu: cpu::RiscV32 = testing::machine();
+        let cases: [(&str, u32, u32); 5] = [
+            ("base", EID_BASE, 1),
+            ("time", EID_TIME, 1),
+            ("dbcn", EID_DBCN, 1),
+            ("pmu", 0x504D55, 0),
+            ("sta", 0x535441, 0),
+        ];
+        for (name, eid, expect) in cases {
+            assert_eq!(sbi(&mut cpu, EID_BASE, 3, &[eid]), (SUCCESS, expect), "{}", name);
+        }
+        assert_eq!(sbi(&mut cpu, EID_BASE, 0, &[]), (SUCCESS, SPEC_VERSION));
+        assert_eq!(sbi(&mut cpu, EID_BASE, 7, &[]).0, ERR_NOT_SUPPORTED);
+        assert_eq!(sbi(&mut cpu, 0x504D55, 0, &[]).0, ERR_NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn set_timer() {
+        let mut cpu: cpu::RiscV32 = testing::machine();
+        cpu.regs.csr.mip |= cpu::MIP_STIP;
+        assert_eq!(sbi(&mut cpu, EID_TIME, 0, &[0x9ABC_DEF0, 0x1234]), (SUCCESS, 0));
+        assert_eq!(cpu.bus.read(MTIMECMP, 8), Some(0x1234_9ABC_DEF0));
+        assert_eq!(cpu.regs.csr.mip & cpu::MIP_STIP, 0, "programming the next timer acknowledges the last one");
+        assert_eq!(sbi(&mut cpu, EID_TIME, 1, &[]).0, ERR_NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn rfence_hart_mask() {
+        let cases: [(&str, u32, u32, i32, bool); 6] = [
+            ("hart 0", 0x1, 0, SUCCESS, true),
+            ("no harts", 0x0, 0, SUCCESS, false),
+            ("all harts, mask ignored", 0x0, u32::MAX, SUCCESS, true),
+            ("hart 1 upwards, empty", 0x0, 1, SUCCESS, false),
+            ("hart 1 upwards", 0x1, 1, ERR_INVALID_PARAM, false),
+            ("harts 0 and 1", 0x3, 0, ERR_INVALID_PARAM, false),
+        ];
+        for (name, mask, base, error, flushed) in cases {
+            assert_eq!(selects_hart0(mask, base).is_some(), error == SUCCESS, "{}", name);
+            let mut cpu: cpu::RiscV32 = testing::machine();
+            cpu.tlb.insert(0x400, 0, 0x80004, 0xCF, 0, false);
+            assert_eq!(sbi(&mut cpu, EID_RFENCE, 1, &[mask, base, 0x0040_0000, mmu::PAGE_SIZE]), (error, 0), "{}", name);
+            assert_eq!(cpu.tlb.lookup(0x400, 0, false).is_none(), flushed, "{}", name);
+        }
+    }
+
+    #[test]
+    fn non_retentive_suspend() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::ECALL]);
+        cpu.sbi = true;
+        cpu.privilege = 1;
+        let root: u32 = RAM_BASE + 0x1000;
+        testing::write_word(&mut cpu, root + 4 * (RAM_BASE >> 22), (RAM_BASE >> 12) << 10 | 0xCF); // identity superpage, VRWXAD
+        cpu.regs.csr.satp = 0x8000_0000 | root >> 12;
+        cpu.regs.csr.mstatus |= 1 << 1; // SIE
+        cpu.regs.x[17] = EID_HSM;
+        cpu.regs.x[16] = 3;
+        cpu.regs.x[10] = HSM_SUSPEND_NON_RETENTIVE;
+        cpu.regs.x[11] = RAM_BASE + 0x100;
+        cpu.regs.x[12] = 0xCAFE;
+        assert_eq!(testing::run(&mut cpu, 1), None);
+        assert_eq!(cpu.regs.pc, RAM_BASE + 0x100, "resumes at resume_addr");
+        assert_eq!((cpu.regs.x[10], cpu.regs.x[11]), (0, 0xCAFE), "with a0 = hartid and a1 = opaque");
+        assert_eq!((cpu.regs.csr.satp, cpu.regs.csr.mstatus & (1 << 1), cpu.privilege), (0, 0, 1), "translation and interrupts off, still in S-mode");
+        assert_eq!(sbi(&mut cpu, EID_HSM, 3, &[0x1234]).0, ERR_INVALID_PARAM);
+    }
+
+    #[test]
+    fn system_reset() {
+        let cases: [(&str, u32, u32, i32, i32, bool); 4] = [
+            ("shutdown", 0, 0, SUCCESS, 0, false),
+            ("shutdown on a system failure", 0, 1, SUCCESS, 1, false),
+            ("cold reboot on a system failure", 1, 1, SUCCESS, 1, false),
+            ("unknown reset type", 3, 1, ERR_INVALID_PARAM, 0, true),
+        ];
+        for (name, reset_type, reason, error, exit_code, running) in cases {
+            let mut cpu: cpu::RiscV32 = testing::machine();
+            cpu.status = true;
+            assert_eq!(sbi(&mut cpu, EID_SRST, 0, &[reset_type, reason]).0, error, "{}", name);
+            assert_eq!((cpu.exit_code, cpu.status), (exit_code, running), "{}", name);
+        }
+    }
+
+    #[test]
+    fn debug_console() {
+        let mut cpu: cpu::RiscV32 = testing::machine();
+        cpu.bus.write(uart::UART_BASE + uart::UART_FCR, 1, 0x01); // FIFOs on
+        cpu.bus.write(uart::UART_BASE + uart::UART_MCR, 1, 0x10); // loopback, whatever is written comes back in
+        cpu.bus.write(DATA, 4, u32::from_le_bytes(*b"marv") as u64);
+        assert_eq!(sbi(&mut cpu, EID_DBCN, 0, &[4, DATA, 0]), (SUCCESS, 4));
+        cpu.bus.tick(4);
+        cpu.reservation = cpu::Reservation { address: DATA + 0x100, valid: true };
+        assert_eq!(sbi(&mut cpu, EID_DBCN, 1, &[8, DATA + 0x100, 0]), (SUCCESS, 4), "only what has arrived");
+        assert_eq!(testing::read_word(&mut cpu, DATA + 0x100), u32::from_le_bytes(*b"marv"));
+        assert!(!cpu.reservation.valid, "the read is a store as far as LR/SC is concerned");
+
+        assert_eq!(sbi(&mut cpu, EID_DBCN, 0, &[4, DATA, 1]), (ERR_INVALID_PARAM, 0), "write above 4 GiB");
+        assert_eq!(sbi(&mut cpu, EID_DBCN, 1, &[4, DATA, 1]), (ERR_INVALID_PARAM, 0), "read above 4 GiB");
+        assert_eq!(sbi(&mut cpu, EID_DBCN, 0, &[4, 0x7000_0000, 0]), (ERR_INVALID_PARAM, 0), "unmapped");
+    }
+}
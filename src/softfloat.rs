@@ -0,0 +1,525 @@
+// IEEE 754 binary32/binary64 arithmetic with RISC-V rounding modes, exception flags and canonical NaNs.
+// Values travel as raw bit patterns in a u64, binary32 values use the low 32 bits.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Format {
+    exp_bits: u32,
+    man_bits: u32,
+}
+
+pub const F32: Format = Format { exp_bits: 8, man_bits: 23 };
+pub const F64: Format = Format { exp_bits: 11, man_bits: 52 };
+
+pub const NV: u8 = 1 << 4; // invalid operation
+pub const DZ: u8 = 1 << 3; // divide by zero
+pub const OF: u8 = 1 << 2; // overflow
+pub const UF: u8 = 1 << 1; // underflow
+pub const NX: u8 = 1 << 0; // inexact
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoundingMode {
+    Rne, // round to nearest, ties to even
+    Rtz, // round towards zero
+    Rdn, // round down (towards -inf)
+    Rup, // round up (towards +inf)
+    Rmm, // round to nearest, ties to max magnitude
+}
+
+impl RoundingMode {
+    pub fn from(rm: u8) -> Option<RoundingMode> {
+        match rm {
+            0b000 => return Some(RoundingMode::Rne),
+            0b001 => return Some(RoundingMode::Rtz),
+            0b010 => return Some(RoundingMode::Rdn),
+            0b011 => return Some(RoundingMode::Rup),
+            0b100 => return Some(RoundingMode::Rmm),
+            _ => return None,
+        }
+    }
+}
+
+impl Format {
+    fn bias(self) -> i32 {
+        return (1 << (self.exp_bits - 1)) - 1;
+    }
+    fn emin(self) -> i32 {
+        return 1 - self.bias();
+    }
+    fn emax(self) -> i32 {
+        return self.bias();
+    }
+    fn exp_mask(self) -> u64 {
+        return (1 << self.exp_bits) - 1;
+    }
+    fn frac_mask(self) -> u64 {
+        return (1 << self.man_bits) - 1;
+    }
+    pub fn sign_bit(self) -> u64 {
+        return 1 << (self.exp_bits + self.man_bits);
+    }
+    pub fn canonical_nan(self) -> u64 {
+        return (self.exp_mask() << self.man_bits) | (1 << (self.man_bits - 1));
+    }
+    fn zero(self, sign: bool) -> u64 {
+        return if sign { self.sign_bit() } else { 0 };
+    }
+    fn inf(self, sign: bool) -> u64 {
+        return self.zero(sign) | (self.exp_mask() << self.man_bits);
+    }
+    fn max_finite(self, sign: bool) -> u64 {
+        return self.zero(sign) | ((self.exp_mask() - 1) << self.man_bits) | self.frac_mask();
+    }
+    fn is_nan(self, bits: u64) -> bool {
+        return (bits >> self.man_bits) & self.exp_mask() == self.exp_mask() && bits & self.frac_mask() != 0;
+    }
+    fn is_snan(self, bits: u64) -> bool {
+        return self.is_nan(bits) && bits & (1 << (self.man_bits - 1)) == 0;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Value {
+    NaN,
+    Inf(bool),
+    Zero(bool),
+    Finite(bool, i32, u128), // sign, exponent, mantissa: the value is mantissa * 2^exponent
+}
+
+fn unpack(fmt: Format, bits: u64) -> Value {
+    let sign: bool = bits & fmt.sign_bit() != 0;
+    let biased: u64 = (bits >> fmt.man_bits) & fmt.exp_mask();
+    let frac: u64 = bits & fmt.frac_mask();
+    if biased == fmt.exp_mask() {
+        return if frac != 0 { Value::NaN } else { Value::Inf(sign) };
+    }
+    if biased == 0 {
+        if frac == 0 {
+            return Value::Zero(sign);
+        }
+        return Value::Finite(sign, fmt.emin() - fmt.man_bits as i32, frac as u128); // subnormal
+    }
+    return Value::Finite(sign, biased as i32 - fmt.bias() - fmt.man_bits as i32, (frac | (1 << fmt.man_bits)) as u128);
+}
+
+/// Returns the canonical NaN if any operand is a NaN, raising NV for signaling ones.
+fn propagate_nan(fmt: Format, operands: &[u64], flags: &mut u8) -> Option<u64> {
+    if operands.iter().any(|&op| fmt.is_snan(op)) {
+        *flags |= NV;
+    }
+    if operands.iter().any(|&op| fmt.is_nan(op)) {
+        return Some(fmt.canonical_nan());
+    }
+    return None;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Rest { // how the bits discarded by rounding compare to half a unit in the last place
+    Zero,
+    Below,
+    Half,
+    Above,
+}
+
+/// Shifts `man` right by `shift` bits; `sticky` means the exact value lies strictly above `man`.
+fn shift_right(man: u128, shift: u32, sticky: bool) -> (u128, Rest) {
+    if man == 0 && !sticky {
+        return (0, Rest::Zero);
+    }
+    if shift == 0 {
+        return (man, if sticky { Rest::Below } else { Rest::Zero });
+    }
+    if shift > 128 {
+        return (0, Rest::Below);
+    }
+    let (kept, rest): (u128, u128) = if shift == 128 {
+        (0, man)
+    } else {
+        (man >> shift, man & ((1 << shift) - 1))
+    };
+    let half: u128 = 1 << (shift - 1);
+    if rest == 0 && !sticky {
+        return (kept, Rest::Zero);
+    }
+    if rest < half {
+        return (kept, Rest::Below);
+    }
+    if rest == half && !sticky {
+        return (kept, Rest::Half);
+    }
+    return (kept, Rest::Above);
+}
+
+fn round_up(kept: u128, rest: Rest, sign: bool, rm: RoundingMode) -> bool {
+    if rest == Rest::Zero {
+        return false;
+    }
+    match rm {
+        RoundingMode::Rne => return rest == Rest::Above || (rest == Rest::Half && kept & 1 != 0),
+        RoundingMode::Rtz => return false,
+        RoundingMode::Rdn => return sign,
+        RoundingMode::Rup => return !sign,
+        RoundingMode::Rmm => return rest == Rest::Above || rest == Rest::Half,
+    }
+}
+
+/// Rounds `man * 2^exp` (plus a sticky fraction below it) into `fmt`, accruing exception flags.
+fn round_pack(fmt: Format, sign: bool, exp: i32, man: u128, sticky: bool, rm: RoundingMode, flags: &mut u8) -> u64 {
+    if man == 0 {
+        return fmt.zero(sign);
+    }
+    let precision: u32 = fmt.man_bits + 1;
+    let e: i32 = exp + (127 - man.leading_zeros() as i32); // the value lies in [2^e, 2^(e + 1))
+    let mut q: i32 = e.max(fmt.emin()) - fmt.man_bits as i32; // weight of the result's last mantissa bit
+    let (mut m, rest): (u128, Rest) = if q >= exp {
+        shift_right(man, (q - exp) as u32, sticky)
+    } else {
+        (man << (exp - q), if sticky { Rest::Below } else { Rest::Zero })
+    };
+    if round_up(m, rest, sign, rm) {
+        m += 1;
+        if m >> precision != 0 {
+            m >>= 1;
+            q += 1;
+        }
+    }
+    if rest != Rest::Zero {
+        *flags |= NX;
+        if e < fmt.emin() { // tininess is detected after rounding, as if the exponent range were unbounded
+            let qu: i32 = e - fmt.man_bits as i32;
+            let (mu, restu): (u128, Rest) = if qu >= exp {
+                shift_right(man, (qu - exp) as u32, sticky)
+            } else {
+                (man << (exp - qu), Rest::Zero)
+            };
+            let carried: bool = round_up(mu, restu, sign, rm) && (mu + 1) >> precision != 0;
+            if !(carried && e + 1 == fmt.emin()) {
+                *flags |= UF;
+            }
+        }
+    }
+    if m >> fmt.man_bits == 0 { // subnormal, or zero after rounding
+        return fmt.zero(sign) | m as u64;
+    }
+    if q + fmt.man_bits as i32 > fmt.emax() {
+        *flags |= OF | NX;
+        let to_inf: bool = match rm {
+            RoundingMode::Rne | RoundingMode::Rmm => true,
+            RoundingMode::Rtz => false,
+            RoundingMode::Rdn => sign,
+            RoundingMode::Rup => !sign,
+        };
+        return if to_inf { fmt.inf(sign) } else { fmt.max_finite(sign) };
+    }
+    let biased: u64 = (q + fmt.man_bits as i32 + fmt.bias()) as u64;
+    return fmt.zero(sign) | (biased << fmt.man_bits) | (m as u64 & fmt.frac_mask());
+}
+
+fn widen(exp: i32, man: u128, msb: u32) -> (i32, u128) {
+    let shift: i32 = msb as i32 - (127 - man.leading_zeros() as i32);
+    return (exp - shift, man << shift);
+}
+
+fn add_finite(fmt: Format, a: (bool, i32, u128), b: (bool, i32, u128), rm: RoundingMode, flags: &mut u8) -> u64 {
+    let (sa, ea, ma): (bool, i32, u128) = a;
+    let (sb, eb, mb): (bool, i32, u128) = b;
+    let (ea, ma): (i32, u128) = widen(ea, ma, 125); // leaves room for the carry and plenty of guard bits
+    let (eb, mb): (i32, u128) = widen(eb, mb, 125);
+    let ((sbig, ebig, mbig), (ssmall, esmall, msmall)) = if ea > eb || (ea == eb && ma >= mb) {
+        ((sa, ea, ma), (sb, eb, mb))
+    } else {
+        ((sb, eb, mb), (sa, ea, ma))
+    };
+    let d: u32 = (ebig - esmall) as u32;
+    let (aligned, sticky): (u128, bool) = if d >= 128 {
+        (0, true)
+    } else {
+        (msmall >> d, d > 0 && msmall & ((1 << d) - 1) != 0)
+    };
+    if sbig == ssmall {
+        return round_pack(fmt, sbig, ebig, mbig + aligned, sticky, rm, flags);
+    }
+    let diff: u128 = mbig - aligned - sticky as u128; // borrow from the discarded bits
+    if diff == 0 && !sticky {
+        return fmt.zero(rm == RoundingMode::Rdn);
+    }
+    return round_pack(fmt, sbig, ebig, diff, sticky, rm, flags);
+}
+
+pub fn add(fmt: Format, a: u64, b: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+    if let Some(nan) = propagate_nan(fmt, &[a, b], flags) {
+        return nan;
+    }
+    match (unpack(fmt, a), unpack(fmt, b)) {
+        (Value::Inf(sa), Value::Inf(sb)) if sa != sb => {
+            *flags |= NV;
+            return fmt.canonical_nan();
+        },
+        (Value::Inf(s), _) | (_, Value::Inf(s)) => return fmt.inf(s),
+        (Value::Zero(sa), Value::Zero(sb)) => return fmt.zero(if sa == sb { sa } else { rm == RoundingMode::Rdn }),
+        (Value::Zero(_), _) => return b,
+        (_, Value::Zero(_)) => return a,
+        (Value::Finite(sa, ea, ma), Value::Finite(sb, eb, mb)) => return add_finite(fmt, (sa, ea, ma), (sb, eb, mb), rm, flags),
+        _ => return fmt.canonical_nan(), // NaNs were handled above
+    }
+}
+
+pub fn sub(fmt: Format, a: u64, b: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+    if let Some(nan) = propagate_nan(fmt, &[a, b], flags) {
+        return nan;
+    }
+    return add(fmt, a, b ^ fmt.sign_bit(), rm, flags);
+}
+
+pub fn mul(fmt: Format, a: u64, b: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+    if let Some(nan) = propagate_nan(fmt, &[a, b], flags) {
+        return nan;
+    }
+    match (unpack(fmt, a), unpack(fmt, b)) {
+        (Value::Inf(_), Value::Zero(_)) | (Value::Zero(_), Value::Inf(_)) => {
+            *flags |= NV;
+            return fmt.canonical_nan();
+        },
+        (Value::Inf(sa), Value::Inf(sb) | Value::Finite(sb, _, _)) => return fmt.inf(sa != sb),
+        (Value::Finite(sa, _, _), Value::Inf(sb)) => return fmt.inf(sa != sb),
+        (Value::Zero(sa), Value::Zero(sb) | Value::Finite(sb, _, _)) => return fmt.zero(sa != sb),
+        (Value::Finite(sa, _, _), Value::Zero(sb)) => return fmt.zero(sa != sb),
+        (Value::Finite(sa, ea, ma), Value::Finite(sb, eb, mb)) => return round_pack(fmt, sa != sb, ea + eb, ma * mb, false, rm, flags),
+        _ => return fmt.canonical_nan(),
+    }
+}
+
+/// Computes `a * b + c` with a single rounding.
+pub fn fma(fmt: Format, a: u64, b: u64, c: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+    let (va, vb): (Value, Value) = (unpack(fmt, a), unpack(fmt, b));
+    if let (Value::Inf(_), Value::Zero(_)) | (Value::Zero(_), Value::Inf(_)) = (va, vb) {
+        *flags |= NV; // raised even when the addend is a quiet NaN
+        propagate_nan(fmt, &[c], flags);
+        return fmt.canonical_nan();
+    }
+    if let Some(nan) = propagate_nan(fmt, &[a, b, c], flags) {
+        return nan;
+    }
+    let product: Value = match (va, vb) {
+        (Value::Inf(sa), Value::Inf(sb) | Value::Finite(sb, _, _)) | (Value::Finite(sa, _, _), Value::Inf(sb)) => Value::Inf(sa != sb),
+        (Value::Zero(sa), Value::Zero(sb) | Value::Finite(sb, _, _)) | (Value::Finite(sa, _, _), Value::Zero(sb)) => Value::Zero(sa != sb),
+        (Value::Finite(sa, ea, ma), Value::Finite(sb, eb, mb)) => Value::Finite(sa != sb, ea + eb, ma * mb),
+        _ => return fmt.canonical_nan(),
+    };
+    match (product, unpack(fmt, c)) {
+        (Value::Inf(sp), Value::Inf(sc)) if sp != sc => {
+            *flags |= NV;
+            return fmt.canonical_nan();
+        },
+        (Value::Inf(s), _) | (_, Value::Inf(s)) => return fmt.inf(s),
+        (Value::Zero(sp), Value::Zero(sc)) => return fmt.zero(if sp == sc { sp } else { rm == RoundingMode::Rdn }),
+        (Value::Zero(_), _) => return c,
+        (Value::Finite(sp, ep, mp), Value::Zero(_)) => return round_pack(fmt, sp, ep, mp, false, rm, flags),
+        (Value::Finite(sp, ep, mp), Value::Finite(sc, ec, mc)) => return add_finite(fmt, (sp, ep, mp), (sc, ec, mc), rm, flags),
+        _ => return fmt.canonical_nan(),
+    }
+}
+
+pub fn div(fmt: Format, a: u64, b: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+    if let Some(nan) = propagate_nan(fmt, &[a, b], flags) {
+        return nan;
+    }
+    match (unpack(fmt, a), unpack(fmt, b)) {
+        (Value::Inf(_), Value::Inf(_)) | (Value::Zero(_), Value::Zero(_)) => {
+            *flags |= NV;
+            return fmt.canonical_nan();
+        },
+        (Value::Inf(sa), Value::Zero(sb) | Value::Finite(sb, _, _)) => return fmt.inf(sa != sb),
+        (Value::Zero(sa) | Value::Finite(sa, _, _), Value::Inf(sb)) => return fmt.zero(sa != sb),
+        (Value::Zero(sa), Value::Finite(sb, _, _)) => return fmt.zero(sa != sb),
+        (Value::Finite(sa, _, _), Value::Zero(sb)) => {
+            *flags |= DZ;
+            return fmt.inf(sa != sb);
+        },
+        (Value::Finite(sa, ea, ma), Value::Finite(sb, eb, mb)) => {
+            let (ea, ma): (i32, u128) = widen(ea, ma, 126); // quotient keeps at least 73 significant bits
+            return round_pack(fmt, sa != sb, ea - eb, ma / mb, ma % mb != 0, rm, flags);
+        },
+        _ => return fmt.canonical_nan(),
+    }
+}
+
+fn isqrt(n: u128) -> u128 {
+    let mut rem: u128 = n;
+    let mut root: u128 = 0;
+    let mut bit: u128 = 1 << 126;
+    while bit > n {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rem >= root + bit {
+            rem -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    return root;
+}
+
+pub fn sqrt(fmt: Format, a: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+    if let Some(nan) = propagate_nan(fmt, &[a], flags) {
+        return nan;
+    }
+    match unpack(fmt, a) {
+        Value::Zero(s) => return fmt.zero(s),
+        Value::Inf(false) => return fmt.inf(false),
+        Value::Inf(true) | Value::Finite(true, _, _) => {
+            *flags |= NV;
+            return fmt.canonical_nan();
+        },
+        Value::Finite(false, exp, man) => {
+            let (mut exp, mut man): (i32, u128) = widen(exp, man, 124);
+            if exp & 1 != 0 { // the exponent must be even to halve it
+                exp -= 1;
+                man <<= 1;
+            }
+            let root: u128 = isqrt(man);
+            return round_pack(fmt, false, exp / 2, root, root * root != man, rm, flags);
+        },
+        Value::NaN => return fmt.canonical_nan(),
+    }
+}
+
+fn order_key(fmt: Format, bits: u64) -> i128 {
+    let magnitude: i128 = (bits & !fmt.sign_bit()) as i128;
+    return if bits & fmt.sign_bit() != 0 { -magnitude } else { magnitude };
+}
+
+/// Quiet comparison, only signaling NaNs raise NV.
+pub fn eq(fmt: Format, a: u64, b: u64, flags: &mut u8) -> bool {
+    if propagate_nan(fmt, &[a, b], flags).is_some() {
+        return false;
+    }
+    return order_key(fmt, a) == order_key(fmt, b);
+}
+
+/// Signaling comparison, any NaN raises NV.
+pub fn lt(fmt: Format, a: u64, b: u64, flags: &mut u8) -> bool {
+    if fmt.is_nan(a) || fmt.is_nan(b) {
+        *flags |= NV;
+        return false;
+    }
+    return order_key(fmt, a) < order_key(fmt, b);
+}
+
+pub fn le(fmt: Format, a: u64, b: u64, flags: &mut u8) -> bool {
+    if fmt.is_nan(a) || fmt.is_nan(b) {
+        *flags |= NV;
+        return false;
+    }
+    return order_key(fmt, a) <= order_key(fmt, b);
+}
+
+/// IEEE 754-2019 minimumNumber/maximumNumber: a single NaN operand yields the other one, and -0 < +0.
+pub fn min_max(fmt: Format, a: u64, b: u64, max: bool, flags: &mut u8) -> u64 {
+    if fmt.is_snan(a) || fmt.is_snan(b) {
+        *flags |= NV;
+    }
+    match (fmt.is_nan(a), fmt.is_nan(b)) {
+        (true, true) => return fmt.canonical_nan(),
+        (true, false) => return b,
+        (false, true) => return a,
+        _ => {},
+    }
+    let (ka, kb): (i128, i128) = (order_key(fmt, a), order_key(fmt, b));
+    if ka == kb {
+        return if max { a & b } else { a | b }; // only differs for zeros of opposite sign
+    }
+    return if (ka < kb) != max { a } else { b };
+}
+
+pub fn classify(fmt: Format, bits: u64) -> u32 {
+    let biased: u64 = (bits >> fmt.man_bits) & fmt.exp_mask();
+    match unpack(fmt, bits) {
+        Value::Inf(true) => return 1 << 0,
+        Value::Finite(true, _, _) if biased != 0 => return 1 << 1,
+        Value::Finite(true, _, _) => return 1 << 2,
+        Value::Zero(true) => return 1 << 3,
+        Value::Zero(false) => return 1 << 4,
+        Value::Finite(false, _, _) if biased == 0 => return 1 << 5,
+        Value::Finite(false, _, _) => return 1 << 6,
+        Value::Inf(false) => return 1 << 7,
+        Value::NaN if fmt.is_snan(bits) => return 1 << 8,
+        Value::NaN => return 1 << 9,
+    }
+}
+
+/// Converts to a 32-bit integer, saturating and raising NV when the rounded value does not fit.
+pub fn to_int(fmt: Format, a: u64, signed: bool, rm: RoundingMode, flags: &mut u8) -> u32 {
+    let saturate = |negative: bool| -> u32 {
+        match (signed, negative) {
+            (true, false) => return i32::MAX as u32,
+            (true, true) => return i32::MIN as u32,
+            (false, false) => return u32::MAX,
+            (false, true) => return 0,
+        }
+    };
+    let (sign, exp, man): (bool, i32, u128) = match unpack(fmt, a) {
+        Value::NaN => {
+            *flags |= NV;
+            return saturate(false);
+        },
+        Value::Inf(s) => {
+            *flags |= NV;
+            return saturate(s);
+        },
+        Value::Zero(_) => return 0,
+        Value::Finite(s, e, m) => (s, e, m),
+    };
+    let (mut m, rest): (u128, Rest) = if exp >= 0 {
+        if exp >= 64 {
+            *flags |= NV;
+            return saturate(sign);
+        }
+        (man << exp, Rest::Zero)
+    } else {
+        shift_right(man, (-exp) as u32, false)
+    };
+    if round_up(m, rest, sign, rm) {
+        m += 1;
+    }
+    let limit: u128 = match (signed, sign) {
+        (true, false) => i32::MAX as u128,
+        (true, true) => 1 << 31,
+        (false, false) => u32::MAX as u128,
+        (false, true) => 0,
+    };
+    if m > limit {
+        *flags |= NV;
+        return saturate(sign);
+    }
+    if rest != Rest::Zero {
+        *flags |= NX;
+    }
+    return if sign { (m as u32).wrapping_neg() } else { m as u32 };
+}
+
+pub fn from_int(fmt: Format, value: u32, signed: bool, rm: RoundingMode, flags: &mut u8) -> u64 {
+    let (sign, magnitude): (bool, u32) = if signed && (value as i32) < 0 {
+        (true, (value as i32).unsigned_abs())
+    } else {
+        (false, value)
+    };
+    return round_pack(fmt, sign, 0, magnitude as u128, false, rm, flags);
+}
+
+/// Converts between formats (FCVT.S.D / FCVT.D.S).
+pub fn convert(from: Format, to: Format, a: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+    match unpack(from, a) {
+        Value::NaN => {
+            if from.is_snan(a) {
+                *flags |= NV;
+            }
+            return to.canonical_nan();
+        },
+        Value::Inf(s) => return to.inf(s),
+        Value::Zero(s) => return to.zero(s),
+        Value::Finite(s, e, m) => return round_pack(to, s, e, m, false, rm, flags),
+    }
+}
@@ -0,0 +1,217 @@
+use crate::cpu;
+use crate::io;
+use crate::log;
+use crate::trap;
+
+pub const RAM_BASE: u32 = 0x8000_0000;
+pub const RAM_SIZE: u32 = 0x1_0000; // 64K, room for code, data and a page table or two
+pub const DATA: u32 = RAM_BASE + 0x8000; // scratch area tests load from and store to
+
+/// A hart in M-mode with a tiny RAM and no console, about to execute the first word of RAM.
+pub fn machine() -> cpu::RiscV32 {
+    log::apply_filter(&[(None, log::Level::Off)]); // the reset banner would bury the test output
+    let mut cpu: cpu::RiscV32 = cpu::RiscV32::with_config(cpu::MachineConfig {
+        ram_size: RAM_SIZE,
+        serial: io::SerialBackend::Null,
+        ..cpu::MachineConfig::default()
+    }).unwrap();
+    cpu.reset();
+    cpu.regs.pc = RAM_BASE;
+    return cpu;
+}
+
+/// `machine()` with `code` at the start of RAM.
+pub fn program(code: &[u32]) -> cpu::RiscV32 {
+    let mut cpu: cpu::RiscV32 = machine();
+    load(&mut cpu, RAM_BASE, code);
+    return cpu;
+}
+
+/// Places more code, a trap handler say, at `paddr`.
+pub fn load(cpu: &mut cpu::RiscV32, paddr: u32, code: &[u32]) {
+    for (i, word) in code.iter().enumerate() {
+        write_word(cpu, paddr + 4 * i as u32, *word);
+    }
+}
+
+/// Steps `steps` instructions, stopping at the first trap, which has been taken by the time it's returned.
+pub fn run(cpu: &mut cpu::RiscV32, steps: usize) -> Option<trap::Trap> {
+    for _ in 0..steps {
+        if let Some(trap) = cpu.step() {
+            return Some(trap);
+        }
+    }
+    return None;
+}
+
+pub fn read_word(cpu: &mut cpu::RiscV32, paddr: u32) -> u32 {
+    return cpu.bus.read(paddr, 4).unwrap() as u32;
+}
+
+pub fn write_word(cpu: &mut cpu::RiscV32, paddr: u32, data: u32) {
+    cpu.bus.write(paddr, 4, data as u64).unwrap();
+}
+
+/// Runs `code` from the start of RAM with `regs` set, and checks it ran through without trapping and left `expect` in the registers.
+pub fn check(name: &str, code: &[u32], regs: &[(u8, u32)], expect: &[(u8, u32)]) {
+    let mut cpu: cpu::RiscV32 = program(code);
+    for (reg, value) in regs {
+        cpu.regs.write(*reg, *value);
+    }
+    assert_eq!(run(&mut cpu, code.len()), None, "{} trapped", name);
+    for (reg, value) in expect {
+        assert_eq!(cpu.regs.read(*reg), *value, "{}: x{} is 0x{:08X}, expected 0x{:08X}", name, reg, cpu.regs.read(*reg), value);
+    }
+}
+
+/// Encodes instructions, just enough of an assembler to write test programs.
+pub mod asm {
+    pub fn r(opcode: u32, rd: u8, funct3: u32, rs1: u8, rs2: u8, funct7: u32) -> u32 {
+        return funct7 << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | funct3 << 12 | (rd as u32) << 7 | opcode;
+    }
+    pub fn i(opcode: u32, rd: u8, funct3: u32, rs1: u8, imm: i32) -> u32 {
+        return (imm as u32 & 0xFFF) << 20 | (rs1 as u32) << 15 | funct3 << 12 | (rd as u32) << 7 | opcode;
+    }
+    pub fn s(opcode: u32, funct3: u32, rs1: u8, rs2: u8, imm: i32) -> u32 {
+        let imm: u32 = imm as u32;
+        return (imm >> 5 & 0x7F) << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | funct3 << 12 | (imm & 0x1F) << 7 | opcode;
+    }
+    pub fn b(funct3: u32, rs1: u8, rs2: u8, offset: i32) -> u32 {
+        let imm: u32 = offset as u32;
+        return (imm >> 12 & 0x1) << 31 | (imm >> 5 & 0x3F) << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | funct3 << 12 | (imm >> 1 & 0xF) << 8 | (imm >> 11 & 0x1) << 7 | 0b1100011;
+    }
+    pub fn u(opcode: u32, rd: u8, imm: u32) -> u32 {
+        return imm << 12 | (rd as u32) << 7 | opcode;
+    }
+    pub fn j(rd: u8, offset: i32) -> u32 {
+        let imm: u32 = offset as u32;
+        return (imm >> 20 & 0x1) << 31 | (imm >> 1 & 0x3FF) << 21 | (imm >> 11 & 0x1) << 20 | (imm >> 12 & 0xFF) << 12 | (rd as u32) << 7 | 0b1101111;
+    }
+
+    // RV32I
+    pub fn lui(rd: u8, imm: u32) -> u32 { return u(0b0110111, rd, imm); }
+    pub fn auipc(rd: u8, imm: u32) -> u32 { return u(0b0010111, rd, imm); }
+    pub fn jal(rd: u8, offset: i32) -> u32 { return j(rd, offset); }
+    pub fn jalr(rd: u8, rs1: u8, imm: i32) -> u32 { return i(0b1100111, rd, 0b000, rs1, imm); }
+    pub fn beq(rs1: u8, rs2: u8, offset: i32) -> u32 { return b(0b000, rs1, rs2, offset); }
+    pub fn bne(rs1: u8, rs2: u8, offset: i32) -> u32 { return b(0b001, rs1, rs2, offset); }
+    pub fn blt(rs1: u8, rs2: u8, offset: i32) -> u32 { return b(0b100, rs1, rs2, offset); }
+    pub fn bge(rs1: u8, rs2: u8, offset: i32) -> u32 { return b(0b101, rs1, rs2, offset); }
+    pub fn bltu(rs1: u8, rs2: u8, offset: i32) -> u32 { return b(0b110, rs1, rs2, offset); }
+    pub fn bgeu(rs1: u8, rs2: u8, offset: i32) -> u32 { return b(0b111, rs1, rs2, offset); }
+    pub fn lb(rd: u8, rs1: u8, imm: i32) -> u32 { return i(0b0000011, rd, 0b000, rs1, imm); }
+    pub fn lh(rd: u8, rs1: u8, imm: i32) -> u32 { return i(0b0000011, rd, 0b001, rs1, imm); }
+    pub fn lw(rd: u8, rs1: u8, imm: i32) -> u32 { return i(0b0000011, rd, 0b010, rs1, imm); }
+    pub fn lbu(rd: u8, rs1: u8, imm: i32) -> u32 { return i(0b0000011, rd, 0b100, rs1, imm); }
+    pub fn lhu(rd: u8, rs1: u8, imm: i32) -> u32 { return i(0b0000011, rd, 0b101, rs1, imm); }
+    pub fn sb(rs2: u8, rs1: u8, imm: i32) -> u32 { return s(0b0100011, 0b000, rs1, rs2, imm); }
+    pub fn sh(rs2: u8, rs1: u8, imm: i32) -> u32 { return s(0b0100011, 0b001, rs1, rs2, imm); }
+    pub fn sw(rs2: u8, rs1: u8, imm: i32) -> u32 { return s(0b0100011, 0b010, rs1, rs2, imm); }
+    pub fn addi(rd: u8, rs1: u8, imm: i32) -> u32 { return i(0b0010011, rd, 0b000, rs1, imm); }
+    pub fn slti(rd: u8, rs1: u8, imm: i32) -> u32 { return i(0b0010011, rd, 0b010, rs1, imm); }
+    pub fn sltiu(rd: u8, rs1: u8, imm: i32) -> u32 { return i(0b0010011, rd, 0b011, rs1, imm); }
+    pub fn xori(rd: u8, rs1: u8, imm: i32) -> u32 { return i(0b0010011, rd, 0b100, rs1, imm); }
+    pub fn ori(rd: u8, rs1: u8, imm: i32) -> u32 { return i(0b0010011, rd, 0b110, rs1, imm); }
+    pub fn andi(rd: u8, rs1: u8, imm: i32) -> u32 { return i(0b0010011, rd, 0b111, rs1, imm); }
+    pub fn slli(rd: u8, rs1: u8, shamt: u8) -> u32 { return i(0b0010011, rd, 0b001, rs1, shamt as i32); }
+    pub fn srli(rd: u8, rs1: u8, shamt: u8) -> u32 { return i(0b0010011, rd, 0b101, rs1, shamt as i32); }
+    pub fn srai(rd: u8, rs1: u8, shamt: u8) -> u32 { return i(0b0010011, rd, 0b101, rs1, 0x400 | shamt as i32); }
+    pub fn add(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b000, rs1, rs2, 0b0000000); }
+    pub fn sub(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b000, rs1, rs2, 0b0100000); }
+    pub fn sll(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b001, rs1, rs2, 0b0000000); }
+    pub fn slt(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b010, rs1, rs2, 0b0000000); }
+    pub fn sltu(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b011, rs1, rs2, 0b0000000); }
+    pub fn xor(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b100, rs1, rs2, 0b0000000); }
+    pub fn srl(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b101, rs1, rs2, 0b0000000); }
+    pub fn sra(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b101, rs1, rs2, 0b0100000); }
+    pub fn or(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b110, rs1, rs2, 0b0000000); }
+    pub fn and(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b111, rs1, rs2, 0b0000000); }
+    pub fn fence(pred: u8, succ: u8) -> u32 { return i(0b0001111, 0, 0b000, 0, ((pred as i32) << 4) | succ as i32); }
+    pub const FENCE_TSO: u32 = 0x8330_000F;
+    pub const PAUSE: u32 = 0x0100_000F;
+    pub const ECALL: u32 = 0x0000_0073;
+    pub const EBREAK: u32 = 0x0010_0073;
+    pub const SRET: u32 = 0x1020_0073;
+    pub const MRET: u32 = 0x3020_0073;
+    pub const WFI: u32 = 0x1050_0073;
+
+    // RV32M
+    pub fn mul(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b000, rs1, rs2, 0b0000001); }
+    pub fn mulh(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b001, rs1, rs2, 0b0000001); }
+    pub fn mulhsu(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b010, rs1, rs2, 0b0000001); }
+    pub fn mulhu(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b011, rs1, rs2, 0b0000001); }
+    pub fn div(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b100, rs1, rs2, 0b0000001); }
+    pub fn divu(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b101, rs1, rs2, 0b0000001); }
+    pub fn rem(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b110, rs1, rs2, 0b0000001); }
+    pub fn remu(rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0110011, rd, 0b111, rs1, rs2, 0b0000001); }
+
+    // RV32A, without the aq and rl bits
+    pub fn amo(funct5: u32, rd: u8, rs1: u8, rs2: u8) -> u32 { return r(0b0101111, rd, 0b010, rs1, rs2, funct5 << 2); }
+    pub fn lr_w(rd: u8, rs1: u8) -> u32 { return amo(0b00010, rd, rs1, 0); }
+    pub fn sc_w(rd: u8, rs1: u8, rs2: u8) -> u32 { return amo(0b00011, rd, rs1, rs2); }
+    pub fn amoswap_w(rd: u8, rs1: u8, rs2: u8) -> u32 { return amo(0b00001, rd, rs1, rs2); }
+    pub fn amoadd_w(rd: u8, rs1: u8, rs2: u8) -> u32 { return amo(0b00000, rd, rs1, rs2); }
+    pub fn amoxor_w(rd: u8, rs1: u8, rs2: u8) -> u32 { return amo(0b00100, rd, rs1, rs2); }
+    pub fn amoand_w(rd: u8, rs1: u8, rs2: u8) -> u32 { return amo(0b01100, rd, rs1, rs2); }
+    pub fn amoor_w(rd: u8, rs1: u8, rs2: u8) -> u32 { return amo(0b01000, rd, rs1, rs2); }
+    pub fn amomin_w(rd: u8, rs1: u8, rs2: u8) -> u32 { return amo(0b10000, rd, rs1, rs2); }
+    pub fn amomax_w(rd: u8, rs1: u8, rs2: u8) -> u32 { return amo(0b10100, rd, rs1, rs2); }
+    pub fn amominu_w(rd: u8, rs1: u8, rs2: u8) -> u32 { return amo(0b11000, rd, rs1, rs2); }
+    pub fn amomaxu_w(rd: u8, rs1: u8, rs2: u8) -> u32 { return amo(0b11100, rd, rs1, rs2); }
+
+    // Zicsr
+    pub fn csrrw(rd: u8, rs1: u8, csr: u16) -> u32 { return i(0b1110011, rd, 0b001, rs1, csr as i32); }
+    pub fn csrrs(rd: u8, rs1: u8, csr: u16) -> u32 { return i(0b1110011, rd, 0b010, rs1, csr as i32); }
+    pub fn csrrc(rd: u8, rs1: u8, csr: u16) -> u32 { return i(0b1110011, rd, 0b011, rs1, csr as i32); }
+    pub fn csrrwi(rd: u8, zimm: u8, csr: u16) -> u32 { return i(0b1110011, rd, 0b101, zimm, csr as i32); }
+    pub fn csrrsi(rd: u8, zimm: u8, csr: u16) -> u32 { return i(0b1110011, rd, 0b110, zimm, csr as i32); }
+    pub fn csrrci(rd: u8, zimm: u8, csr: u16) -> u32 { return i(0b1110011, rd, 0b111, zimm, csr as i32); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::asm::*;
+
+    /// The encoder against what llvm-mc assembles the same instructions to.
+    #[test]
+    fn encodings_match_the_assembler() {
+        let cases: [(u32, u32, &str); 33] = [
+            (lui(3, 0x12345), 0x123451B7, "lui gp, 0x12345"),
+            (auipc(3, 0xFFFFF), 0xFFFFF197, "auipc gp, 0xfffff"),
+            (jal(1, -2048), 0x801FF0EF, "jal -2048"),
+            (jalr(1, 2, -1), 0xFFF100E7, "jalr ra, -1(sp)"),
+            (beq(1, 2, -4096), 0x80208063, "beq ra, sp, -4096"),
+            (bgeu(1, 2, 4094), 0x7E20FFE3, "bgeu ra, sp, 4094"),
+            (lb(3, 1, -1), 0xFFF08183, "lb gp, -1(ra)"),
+            (lhu(3, 1, 2047), 0x7FF0D183, "lhu gp, 2047(ra)"),
+            (sb(2, 1, -2048), 0x80208023, "sb sp, -2048(ra)"),
+            (sw(1, 2, -4), 0xFE112E23, "sw ra, -4(sp)"),
+            (sw(31, 5, 300), 0x13F2A623, "sw t6, 300(t0)"),
+            (addi(3, 1, -1), 0xFFF08193, "addi gp, ra, -1"),
+            (sltiu(3, 1, -1), 0xFFF0B193, "sltiu gp, ra, -1"),
+            (andi(3, 1, 2047), 0x7FF0F193, "andi gp, ra, 2047"),
+            (slli(3, 1, 31), 0x01F09193, "slli gp, ra, 31"),
+            (srli(3, 1, 1), 0x0010D193, "srli gp, ra, 1"),
+            (srai(3, 1, 17), 0x4110D193, "srai gp, ra, 17"),
+            (sub(3, 1, 2), 0x402081B3, "sub gp, ra, sp"),
+            (sra(31, 30, 29), 0x41DF5FB3, "sra t6, t5, t4"),
+            (fence(0b0011, 0b0001), 0x0310000F, "fence rw, w"),
+            (mulhsu(3, 1, 2), 0x0220A1B3, "mulhsu gp, ra, sp"),
+            (remu(3, 1, 2), 0x0220F1B3, "remu gp, ra, sp"),
+            (lr_w(3, 1), 0x1000A1AF, "lr.w gp, (ra)"),
+            (sc_w(4, 1, 2), 0x1820A22F, "sc.w tp, sp, (ra)"),
+            (amoswap_w(3, 1, 2), 0x0820A1AF, "amoswap.w gp, sp, (ra)"),
+            (amomaxu_w(3, 1, 2), 0xE020A1AF, "amomaxu.w gp, sp, (ra)"),
+            (amoand_w(3, 1, 2), 0x6020A1AF, "amoand.w gp, sp, (ra)"),
+            (csrrw(3, 1, 0x340), 0x340091F3, "csrrw gp, mscratch, ra"),
+            (csrrs(3, 0, 0xF14), 0xF14021F3, "csrr gp, mhartid"),
+            (csrrci(3, 31, 0x300), 0x300FF1F3, "csrrci gp, mstatus, 31"),
+            (csrrwi(0, 1, 0xFFF), 0xFFF0D073, "csrwi 0xfff, 1"),
+            (csrrsi(0, 0, 0), 0x00006073, "csrrsi zero, ustatus, 0"),
+            (csrrc(0, 0, 0), 0x00003073, "csrrc zero, ustatus, zero"),
+        ];
+        for (encoded, expected, text) in cases {
+            assert_eq!(encoded, expected, "{}: 0x{:08X}, expected 0x{:08X}", text, encoded, expected);
+        }
+    }
+}
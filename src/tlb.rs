@@ -0,0 +1,231 @@
+use std::fmt;
+
+pub const DEFAULT_ENTRIES: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    vpn: u32,
+    asid: u32,
+    ppn: u32, // physical page backing this 4 KiB page, superpages are cached one page at a time
+    pte: u32, // leaf PTE flags, permissions are rechecked on every hit
+    level: u32,
+}
+
+const PTE_G: u32 = 1 << 5;
+
+struct Cache {
+    entries: Vec<Option<Entry>>,
+    hits: u64,
+    misses: u64,
+}
+
+impl Cache {
+    fn new(size: usize) -> Cache {
+        return Cache {
+            entries: vec![None; size.max(1)],
+            hits: 0,
+            misses: 0,
+        };
+    }
+    fn index(&self, vpn: u32) -> usize {
+        return vpn as usize % self.entries.len();
+    }
+}
+
+/// Direct-mapped software TLB, either unified or split into data and instruction halves.
+pub struct Tlb {
+    caches: Vec<Cache>, // [data] when unified, [data, instruction] when split
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TlbStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl TlbStats {
+    pub fn hit_rate(&self) -> f64 {
+        let total: u64 = self.hits + self.misses;
+        return if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        };
+    }
+}
+
+impl fmt::Display for TlbStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} hits, {} misses ({:.2}% hit rate)", self.hits, self.misses, self.hit_rate() * 100.0)
+    }
+}
+
+impl Tlb {
+    pub fn new(entries: usize, split: bool) -> Tlb {
+        let mut caches: Vec<Cache> = vec![Cache::new(entries)];
+        if split {
+            caches.push(Cache::new(entries));
+        }
+        return Tlb {
+            caches: caches,
+        };
+    }
+
+    fn cache(&mut self, fetch: bool) -> &mut Cache {
+        let i: usize = if fetch { self.caches.len() - 1 } else { 0 };
+        return &mut self.caches[i];
+    }
+
+    /// Looks up a translation, returning the physical page number and the cached leaf PTE.
+    pub fn lookup(&mut self, vpn: u32, asid: u32, fetch: bool) -> Option<(u32, u32)> {
+        let cache: &mut Cache = self.cache(fetch);
+        let i: usize = cache.index(vpn);
+        if let Some(entry) = cache.entries[i] {
+            if entry.vpn == vpn && (entry.asid == asid || entry.pte & PTE_G != 0) {
+                cache.hits += 1;
+                return Some((entry.ppn, entry.pte));
+            }
+        }
+        cache.misses += 1;
+        return None;
+    }
+
+    pub fn insert(&mut self, vpn: u32, asid: u32, ppn: u32, pte: u32, level: u32, fetch: bool) {
+        let cache: &mut Cache = self.cache(fetch);
+        let i: usize = cache.index(vpn);
+        cache.entries[i] = Some(Entry {
+            vpn: vpn,
+            asid: asid,
+            ppn: ppn,
+            pte: pte,
+            level: level,
+        });
+    }
+
+    /// Implements SFENCE.VMA: `vaddr` restricts the flush to one page, `asid` to non-global entries of one address space.
+    pub fn flush(&mut self, vaddr: Option<u32>, asid: Option<u32>) {
+        for cache in self.caches.iter_mut() {
+            for slot in cache.entries.iter_mut() {
+                if let Some(entry) = slot {
+                    let page_match: bool = match vaddr {
+                        None => true,
+                        Some(va) if entry.level == 1 => entry.vpn >> 10 == va >> 22, // superpage fragments go together
+                        Some(va) => entry.vpn == va >> 12,
+                    };
+                    let asid_match: bool = match asid {
+                        None => true,
+                        Some(id) => entry.asid == id && entry.pte & PTE_G == 0,
+                    };
+                    if page_match && asid_match {
+                        *slot = None;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> TlbStats {
+        let mut stats: TlbStats = TlbStats { hits: 0, misses: 0 };
+        for cache in self.caches.iter() {
+            stats.hits += cache.hits;
+            stats.misses += cache.misses;
+        }
+        return stats;
+    }
+}
+
+impl fmt::Display for Tlb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.caches.len() > 1 {
+            let data: TlbStats = TlbStats { hits: self.caches[0].hits, misses: self.caches[0].misses };
+            let instr: TlbStats = TlbStats { hits: self.caches[1].hits, misses: self.caches[1].misses };
+            write!(f, "dTLB: {}, iTLB: {}", data, instr)
+        } else {
+            write!(f, "TLB: {}", self.stats())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Tlb, PTE_G};
+    use crate::cpu;
+    use crate::io;
+    use crate::mmu;
+    use crate::testing;
+
+    const PTE: u32 = 0xCF; // valid, readable, writable, executable, accessed and dirty
+
+    #[test]
+    fn hit() {
+        let mut tlb: Tlb = Tlb::new(16, false);
+        assert_eq!(tlb.lookup(0x12345, 1, false), None);
+        tlb.insert(0x12345, 1, 0x80010, PTE, 0, false);
+        assert_eq!(tlb.lookup(0x12345, 1, false), Some((0x80010, PTE)));
+        assert_eq!(tlb.lookup(0x12345, 1, true), Some((0x80010, PTE)), "unified, fetches share the entries");
+        assert_eq!(tlb.lookup(0x12345, 2, false), None, "another address space");
+        assert_eq!(tlb.lookup(0x12355, 1, false), None, "same slot, another page");
+        assert_eq!((tlb.stats().hits, tlb.stats().misses), (2, 3));
+    }
+
+    #[test]
+    fn split() {
+        let mut tlb: Tlb = Tlb::new(16, true);
+        tlb.insert(0x12345, 1, 0x80010, PTE, 0, true);
+        assert_eq!(tlb.lookup(0x12345, 1, false), None, "data side doesn't see instruction entries");
+        assert_eq!(tlb.lookup(0x12345, 1, true), Some((0x80010, PTE)));
+        tlb.flush(None, None);
+        assert_eq!(tlb.lookup(0x12345, 1, true), None, "flushes cover both sides");
+    }
+
+    #[test]
+    fn sfence_by_address() {
+        let mut tlb: Tlb = Tlb::new(16, false);
+        tlb.insert(0x12345, 1, 0x80010, PTE, 0, false);
+        tlb.insert(0x12346, 1, 0x80011, PTE, 0, false);
+        tlb.insert(0x00400, 1, 0x80400, PTE, 1, false); // part of the superpage at 0x00400000
+        tlb.flush(Some(0x12345678), None);
+        assert_eq!(tlb.lookup(0x12345, 1, false), None);
+        assert_eq!(tlb.lookup(0x12346, 1, false), Some((0x80011, PTE)));
+        tlb.flush(Some(0x007F_F000), None);
+        assert_eq!(tlb.lookup(0x00400, 1, false), None, "any address in a superpage flushes all of it");
+    }
+
+    #[test]
+    fn sfence_by_asid() {
+        let mut tlb: Tlb = Tlb::new(16, false);
+        tlb.insert(0x1, 1, 0x80001, PTE, 0, false);
+        tlb.insert(0x2, 2, 0x80002, PTE, 0, false);
+        tlb.insert(0x3, 1, 0x80003, PTE | PTE_G, 0, false);
+        tlb.flush(None, Some(1));
+        assert_eq!(tlb.lookup(0x1, 1, false), None);
+        assert_eq!(tlb.lookup(0x2, 2, false), Some((0x80002, PTE)), "other address spaces stay");
+        assert_eq!(tlb.lookup(0x3, 1, false), Some((0x80003, PTE | PTE_G)), "global entries survive an ASID flush");
+        assert_eq!(tlb.lookup(0x3, 7, false), Some((0x80003, PTE | PTE_G)), "and match every ASID");
+        tlb.flush(Some(0x3000), Some(2));
+        assert_eq!(tlb.lookup(0x3, 1, false), Some((0x80003, PTE | PTE_G)));
+        tlb.flush(Some(0x3000), None);
+        assert_eq!(tlb.lookup(0x3, 1, false), None, "but not an address flush for all of them");
+    }
+
+    #[test]
+    fn satp_write_flushes() {
+        let mut cpu: cpu::RiscV32 = testing::machine();
+        cpu.tlb.insert(0x12345, 0, 0x80010, PTE | PTE_G, 0, false);
+        assert!(cpu.write_csr(0x180, mmu::SATP_MODE).is_none());
+        assert_eq!(cpu.tlb.lookup(0x12345, 0, false), None);
+    }
+
+    #[test]
+    fn configured_size() {
+        let cpu: cpu::RiscV32 = cpu::RiscV32::with_config(cpu::MachineConfig {
+            ram_size: testing::RAM_SIZE,
+            serial: io::SerialBackend::Null,
+            tlb_entries: 4,
+            tlb_split: true,
+            ..cpu::MachineConfig::default()
+        }).unwrap();
+        assert_eq!(cpu.tlb.caches.len(), 2);
+        assert!(cpu.tlb.caches.iter().all(|cache| cache.entries.len() == 4));
+    }
+}
@@ -1,7 +1,9 @@
-use crate::{cpu, extensions::Execute};
+use crate::{cpu, extensions::Execute, log, mmu, sbi};
+
+const MSTATUS_TW: u32 = 1 << 21; // timeout wait, WFI is illegal below M-mode
 
 #[allow(dead_code)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Trap {
     MisalignedInstructionAddress = 0,
     InstructionAccessFault = 1,
@@ -26,32 +28,39 @@ pub enum Trap {
 }
 
 impl Trap {
-    fn handle_smode(cpu: &mut cpu::RiscV32, cause: u32, val: u32) {
+    pub fn handle_smode(cpu: &mut cpu::RiscV32, cause: u32, val: u32) {
         cpu.regs.csr.sepc = cpu.regs.pc;
         cpu.regs.csr.scause = cause;
         cpu.regs.csr.stval = val;
-        let sie: u8 = ((cpu.regs.csr.sstatus >> 1) & 0x1) as u8; // get field SIE of sstatus
-        cpu.regs.csr.sstatus &= !((1 << 8) | (1 << 5) | (1 << 1)); // clear SPP, SIE and SPIE
-        cpu.regs.csr.sstatus |= (sie << 5) as u32; // set SPIE to previous value of SIE
-        cpu.regs.csr.sstatus |= ((cpu.privilege & 0x1) as u32) << 8; // set SPP to current privilege
+        let sie: u8 = ((cpu.regs.csr.mstatus >> 1) & 0x1) as u8; // get field SIE of sstatus
+        cpu.regs.csr.mstatus &= !((1 << 8) | (1 << 5) | (1 << 1)); // clear SPP, SIE and SPIE
+        cpu.regs.csr.mstatus |= (sie << 5) as u32; // set SPIE to previous value of SIE
+        cpu.regs.csr.mstatus |= ((cpu.privilege & 0x1) as u32) << 8; // set SPP to current privilege
         cpu.privilege = 1; // set privilege to S-mode
         cpu.regs.pc = cpu.regs.csr.stvec & !0x3; // set PC to stvec, aligned to 4 bytes
     }
 
-    fn handle_mmode(cpu: &mut cpu::RiscV32, cause: u32, val: u32) {
+    pub fn handle_mmode(cpu: &mut cpu::RiscV32, cause: u32, val: u32) {
         cpu.regs.csr.mepc = cpu.regs.pc;
         cpu.regs.csr.mcause = cause;
         cpu.regs.csr.mtval = val;
-        let sie: u8 = ((cpu.regs.csr.mstatus >> 1) & 0x1) as u8;
-        cpu.regs.csr.mstatus &= !((3 << 8) | (1 << 5) | (1 << 1));
-        cpu.regs.csr.mstatus |= (sie << 5) as u32;
-        cpu.regs.csr.mstatus |= ((cpu.privilege & 0x3) as u32) << 8;
+        let mie: u8 = ((cpu.regs.csr.mstatus >> 3) & 0x1) as u8; // get field MIE of mstatus
+        cpu.regs.csr.mstatus &= !((3 << 11) | (1 << 7) | (1 << 3)); // clear MPP, MIE and MPIE, sstatus bits share this register
+        cpu.regs.csr.mstatus |= (mie << 7) as u32; // set MPIE to previous value of MIE
+        cpu.regs.csr.mstatus |= ((cpu.privilege & 0x3) as u32) << 11; // set MPP to current privilege
         cpu.privilege = 3;
         cpu.regs.pc = cpu.regs.csr.mtvec & !0x3;
     }
 
     pub fn take(code: Trap, cpu: &mut cpu::RiscV32, val: u32) -> Trap {
-        if cpu.regs.csr.medeleg & (1 << code as u32) > 0 {
+        cpu.reservation.valid = false; // a trap may switch contexts, so an outstanding LR can't pair with a later SC
+        if cpu.sbi && matches!(code, Trap::SModeEnvCall) { // the firmware lives in the emulator, no M-mode handler to run
+            sbi::call(cpu);
+            return code;
+        }
+        let delegated: bool = cpu.privilege < 3 && cpu.regs.csr.medeleg & (1 << code as u32) != 0; // M-mode traps are never delegated
+        log::logln!(Trap, Debug, "[trap] {:?} at PC 0x{:08X}, tval 0x{:08X}, taken in {}-mode", code, cpu.regs.pc, val, if delegated { 'S' } else { 'M' });
+        if delegated {
             Trap::handle_smode(cpu, code as u32, val);
         } else {
             Trap::handle_mmode(cpu, code as u32, val);
@@ -59,28 +68,29 @@ impl Trap {
         return code;
     }
 
-    pub fn display(self, cpu: &cpu::RiscV32) {
+    /// Reports a trap raised at `pc` that ended the run.
+    pub fn display(self, cpu: &cpu::RiscV32, pc: u32) {
         match self { // [ ] find a way to show additional information (like mtval and privilege), maybe using enum for name and struct for data
-            Trap::MisalignedInstructionAddress => eprintln!("[EXCEPTION] Misaligned instruction address"),
-            Trap::InstructionAccessFault => eprintln!("[EXCEPTION] Instruction access fault"),
-            Trap::IllegalInstruction => eprintln!("[EXCEPTION] Illegal instruction at PC: [0x{:08X}]\n{}", if cpu.privilege == 3 { cpu.regs.csr.mepc } else { cpu.regs.csr.sepc }, cpu),
-            Trap::Breakpoint => eprintln!("[EXCEPTION] Breakpoint"),
-            Trap::MisalignedLoadAddr => eprintln!("[EXCEPTION] Misaligned load address"),
-            Trap::LoadAccessFault => eprintln!("[EXCEPTION] Load access fault"),
-            Trap::MisalignedStoreAddr => eprintln!("[EXCEPTION] Misaligned store address"),
-            Trap::StoreAccessFault => eprintln!("[EXCEPTION] Store access fault"),
-            Trap::UModeEnvCall => eprintln!("[EXCEPTION] User mode environment call"),
-            Trap::SModeEnvCall => eprintln!("[EXCEPTION] Supervisor mode environment call"),
-            Trap::Res0 => eprintln!("[EXCEPTION] Reserved"),
-            Trap::MModeEnvCall => eprintln!("[EXCEPTION] Machine mode environment call"),
-            Trap::InstructionPageFault => eprintln!("[EXCEPTION] Instruction page fault"),
-            Trap::LoadPageFault => eprintln!("[EXCEPTION] Load page fault"),
-            Trap::Res1 => eprintln!("[EXCEPTION] Reserved"),
-            Trap::StorePageFault => eprintln!("[EXCEPTION] Store page fault"),
-            Trap::DoubleTrap => eprintln!("[EXCEPTION] Double trap"),
-            Trap::Res2 => eprintln!("[EXCEPTION] Reserved"),
-            Trap::SoftwareCheck => eprintln!("[EXCEPTION] Software check"),
-            Trap::HardwareError => eprintln!("[EXCEPTION] Hardware error"),
+            Trap::MisalignedInstructionAddress => log::logln!(Trap, Error, "[EXCEPTION] Misaligned instruction address"),
+            Trap::InstructionAccessFault => log::logln!(Trap, Error, "[EXCEPTION] Instruction access fault"),
+            Trap::IllegalInstruction => log::logln!(Trap, Error, "[EXCEPTION] Illegal instruction at PC: [0x{:08X}]\n{}", pc, cpu),
+            Trap::Breakpoint => log::logln!(Trap, Error, "[EXCEPTION] Breakpoint"),
+            Trap::MisalignedLoadAddr => log::logln!(Trap, Error, "[EXCEPTION] Misaligned load address"),
+            Trap::LoadAccessFault => log::logln!(Trap, Error, "[EXCEPTION] Load access fault"),
+            Trap::MisalignedStoreAddr => log::logln!(Trap, Error, "[EXCEPTION] Misaligned store address"),
+            Trap::StoreAccessFault => log::logln!(Trap, Error, "[EXCEPTION] Store access fault"),
+            Trap::UModeEnvCall => log::logln!(Trap, Error, "[EXCEPTION] User mode environment call"),
+            Trap::SModeEnvCall => log::logln!(Trap, Error, "[EXCEPTION] Supervisor mode environment call"),
+            Trap::Res0 => log::logln!(Trap, Error, "[EXCEPTION] Reserved"),
+            Trap::MModeEnvCall => log::logln!(Trap, Error, "[EXCEPTION] Machine mode environment call"),
+            Trap::InstructionPageFault => log::logln!(Trap, Error, "[EXCEPTION] Instruction page fault"),
+            Trap::LoadPageFault => log::logln!(Trap, Error, "[EXCEPTION] Load page fault"),
+            Trap::Res1 => log::logln!(Trap, Error, "[EXCEPTION] Reserved"),
+            Trap::StorePageFault => log::logln!(Trap, Error, "[EXCEPTION] Store page fault"),
+            Trap::DoubleTrap => log::logln!(Trap, Error, "[EXCEPTION] Double trap"),
+            Trap::Res2 => log::logln!(Trap, Error, "[EXCEPTION] Reserved"),
+            Trap::SoftwareCheck => log::logln!(Trap, Error, "[EXCEPTION] Software check"),
+            Trap::HardwareError => log::logln!(Trap, Error, "[EXCEPTION] Hardware error"),
         }
     }
 }
@@ -89,33 +99,167 @@ impl Trap {
 pub enum TrapRetInstruction {
     Sret,
     Mret,
+    Wfi,
 }
 
 impl Execute for TrapRetInstruction {
     fn execute(self, cpu: &mut crate::cpu::RiscV32) -> Option<self::Trap> {
         match self {
             TrapRetInstruction::Sret => {
-                cpu.regs.pc = cpu.read_csr(0x141).unwrap();
-                let mut sstatus: u32 = cpu.read_csr(0x100).unwrap();
+                let sepc: u32 = match cpu.read_csr(0x141) { // illegal below S-mode
+                    Ok(sepc) => sepc,
+                    Err(trap) => return Some(trap),
+                };
+                let mut sstatus: u32 = cpu.regs.csr.mstatus & cpu::SSTATUS_MASK;
                 let spp: u8 = ((sstatus >> 8) & 0x1) as u8; // get field SPP of sstatus
                 cpu.privilege = spp; // restore previous privilege from SPP
                 let spie: u8 = ((sstatus >> 5) & 0x1) as u8; // get field SPIE of sstatus
                 sstatus &= !(2 | (1 << 8)); // clear SIE field and set SPP field to 0
                 sstatus |= ((spie << 1) | (1 << 5)) as u32; // restore field SIE of sstatus from SPIE and set SPIE to 1
-                cpu.write_csr(0x100, sstatus)?; // flush the updated sstatus back to the CSR
+                cpu.regs.csr.mstatus = (cpu.regs.csr.mstatus & !cpu::SSTATUS_MASK) | sstatus; // flush the updated sstatus back to the CSR
+                cpu.regs.csr.mstatus &= !mmu::MSTATUS_MPRV; // SRET never returns to M-mode
+                cpu.resync = true; // SIE may be back on
+                cpu.regs.pc = sepc.wrapping_sub(cpu.ilen); // the step adds the instruction length back
                 return None;
             },
             TrapRetInstruction::Mret => {
-                cpu.regs.pc = cpu.read_csr(0x341).unwrap();
-                let mut mstatus: u32 = cpu.read_csr(0x300).unwrap();
+                let mepc: u32 = match cpu.read_csr(0x341) { // illegal below M-mode
+                    Ok(mepc) => mepc,
+                    Err(trap) => return Some(trap),
+                };
+                let mut mstatus: u32 = cpu.regs.csr.mstatus;
                 let mpp: u8 = ((mstatus >> 11) & 0x3) as u8;
                 cpu.privilege = mpp;
                 let mpie: u8 = ((mstatus >> 7) & 0x1) as u8;
                 mstatus &= !(8 | (3 << 11));
                 mstatus |= ((mpie << 3) | (1 << 7)) as u32;
-                cpu.write_csr(0x300, mstatus)?;
+                if mpp != 3 {
+                    mstatus &= !mmu::MSTATUS_MPRV; // leaving M-mode, loads and stores are translated as the new mode's again
+                }
+                cpu.regs.csr.mstatus = mstatus;
+                cpu.resync = true; // MIE may be back on, or a lower privilege makes interrupts takeable
+                cpu.regs.pc = mepc.wrapping_sub(cpu.ilen);
+                return None;
+            },
+            TrapRetInstruction::Wfi => {
+                if cpu.privilege == 0 || (cpu.privilege == 1 && cpu.regs.csr.mstatus & MSTATUS_TW != 0) {
+                    return Some(Trap::take(Trap::IllegalInstruction, cpu, cpu.regs.pc));
+                }
+                cpu.resync = true; // nothing to wait for here, the batch ends and a pending interrupt is taken right away
                 return None;
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Trap;
+    use crate::cpu;
+    use crate::testing::{self, asm, RAM_BASE};
+
+    const MPP: u32 = 3 << 11;
+    const MPIE: u32 = 1 << 7;
+    const MIE: u32 = 1 << 3;
+    const SPP: u32 = 1 << 8;
+    const SPIE: u32 = 1 << 5;
+    const SIE: u32 = 1 << 1;
+    const MPRV: u32 = 1 << 17;
+
+    // handler that steps the saved pc over the ecall and returns, csr being mepc or sepc
+    fn skip_handler(csr: u16, ret: u32) -> [u32; 4] {
+        return [asm::csrrs(5, 0, csr), asm::addi(5, 5, 4), asm::csrrw(0, 5, csr), ret];
+    }
+
+    #[test]
+    fn mret() {
+        let cases: [(&str, u32, u8, u32); 6] = [
+            // name, mstatus before, privilege and mstatus after
+            ("to U", MPIE, 0, MPIE | MIE),
+            ("to S", 1 << 11 | MPIE, 1, MPIE | MIE),
+            ("to M", MPP, 3, MPIE),
+            ("leaves SIE alone", MPP | SIE | SPIE, 3, MPIE | SIE | SPIE),
+            ("to S clears MPRV", 1 << 11 | MPRV, 1, MPIE),
+            ("to M keeps MPRV", MPP | MPRV, 3, MPIE | MPRV),
+        ];
+        for (name, before, privilege, after) in cases {
+            let mut cpu: cpu::RiscV32 = testing::program(&[asm::MRET]);
+            cpu.regs.csr.mstatus = before;
+            cpu.regs.csr.mepc = RAM_BASE + 0x40;
+            assert_eq!(testing::run(&mut cpu, 1), None, "{}", name);
+            assert_eq!((cpu.privilege, cpu.regs.csr.mstatus, cpu.regs.pc), (privilege, after, RAM_BASE + 0x40), "{}", name);
+        }
+    }
+
+    #[test]
+    fn sret() {
+        let cases: [(&str, u32, u8, u32); 4] = [
+            ("to U", SPIE, 0, SPIE | SIE),
+            ("to S", SPP, 1, SPIE),
+            ("leaves MIE alone", MIE | MPIE | SPIE, 0, MIE | MPIE | SPIE | SIE),
+            ("clears MPRV", SPP | MPRV, 1, SPIE),
+        ];
+        for (name, before, privilege, after) in cases {
+            let mut cpu: cpu::RiscV32 = testing::program(&[asm::SRET]);
+            cpu.privilege = 1;
+            cpu.regs.csr.mstatus = before;
+            cpu.regs.csr.sepc = RAM_BASE + 0x40;
+            assert_eq!(testing::run(&mut cpu, 1), None, "{}", name);
+            assert_eq!((cpu.privilege, cpu.regs.csr.mstatus, cpu.regs.pc), (privilege, after, RAM_BASE + 0x40), "{}", name);
+        }
+    }
+
+    #[test]
+    fn returns_from_too_low() {
+        let cases: [(&str, u32, u8); 3] = [
+            ("mret from S", asm::MRET, 1),
+            ("mret from U", asm::MRET, 0),
+            ("sret from U", asm::SRET, 0),
+        ];
+        for (name, instruction, privilege) in cases {
+            let mut cpu: cpu::RiscV32 = testing::program(&[instruction]);
+            cpu.privilege = privilege;
+            cpu.regs.csr.mtvec = RAM_BASE + 0x40;
+            assert_eq!(testing::run(&mut cpu, 1), Some(Trap::IllegalInstruction), "{}", name);
+            assert_eq!((cpu.privilege, cpu.regs.csr.mepc, cpu.regs.pc), (3, RAM_BASE, RAM_BASE + 0x40), "{}", name);
+        }
+    }
+
+    #[test]
+    fn ecall_round_trip() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::ECALL, asm::addi(3, 0, 1)]);
+        testing::load(&mut cpu, RAM_BASE + 0x40, &skip_handler(0x341, asm::MRET));
+        cpu.regs.csr.mtvec = RAM_BASE + 0x40;
+        cpu.privilege = 0;
+        assert_eq!(testing::run(&mut cpu, 1), Some(Trap::UModeEnvCall));
+        assert_eq!((cpu.privilege, cpu.regs.pc, cpu.regs.csr.mcause), (3, RAM_BASE + 0x40, 8));
+        assert_eq!(testing::run(&mut cpu, 5), None);
+        assert_eq!((cpu.privilege, cpu.regs.pc, cpu.regs.read(3)), (0, RAM_BASE + 8, 1), "back in U-mode past the ecall");
+    }
+
+    #[test]
+    fn delegated_ecall() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[asm::ECALL, asm::addi(3, 0, 1)]);
+        testing::load(&mut cpu, RAM_BASE + 0x80, &skip_handler(0x141, asm::SRET));
+        cpu.regs.csr.mtvec = RAM_BASE + 0x40;
+        cpu.regs.csr.stvec = RAM_BASE + 0x80;
+        cpu.regs.csr.medeleg = 1 << Trap::UModeEnvCall as u32;
+        cpu.privilege = 0;
+        assert_eq!(testing::run(&mut cpu, 1), Some(Trap::UModeEnvCall));
+        assert_eq!((cpu.privilege, cpu.regs.pc), (1, RAM_BASE + 0x80), "taken in S-mode");
+        assert_eq!((cpu.regs.csr.sepc, cpu.regs.csr.scause, cpu.regs.csr.mstatus & SPP), (RAM_BASE, 8, 0));
+        assert_eq!(cpu.regs.csr.mcause, 0, "M-mode never sees it");
+        assert_eq!(testing::run(&mut cpu, 5), None);
+        assert_eq!((cpu.privilege, cpu.regs.pc, cpu.regs.read(3)), (0, RAM_BASE + 8, 1));
+    }
+
+    #[test]
+    fn m_mode_traps_stay_in_m_mode() {
+        let mut cpu: cpu::RiscV32 = testing::program(&[0]);
+        cpu.regs.csr.mtvec = RAM_BASE + 0x40;
+        cpu.regs.csr.stvec = RAM_BASE + 0x80;
+        cpu.regs.csr.medeleg = 1 << Trap::IllegalInstruction as u32;
+        assert_eq!(testing::run(&mut cpu, 1), Some(Trap::IllegalInstruction));
+        assert_eq!((cpu.privilege, cpu.regs.pc, cpu.regs.csr.mcause, cpu.regs.csr.scause), (3, RAM_BASE + 0x40, 2, 0), "medeleg doesn't apply to M-mode");
+    }
+}
@@ -1,88 +1,487 @@
-use std::io::Write;
+use std::collections::VecDeque;
 
-//use std::collections::VecDeque;
+use crate::bus::Device;
+use crate::halt;
 use crate::io;
-
-pub struct UART {
-    //pub rxtx: u8,
-    //pub lsr: u8,
-    pub kbd: io::KbdIn,
-    //pub fifo: VecDeque<u8>, // disable FIFO
-}
+use crate::log;
 
 pub const UART_BASE: u32 = 0x1000_0000;
-pub const UART_THR: u32 = UART_BASE + 0x00;
-pub const UART_RBR: u32 = UART_BASE + 0x00;
-pub const UART_LSR: u32 = UART_BASE + 0x05;
-pub const UART_END: u32 = UART_BASE + 0x07;
+pub const UART_SIZE: u32 = 0x100;
+// register offsets, one byte apart
+pub const UART_THR: u32 = 0x00; // write, DLAB = 0
+pub const UART_RBR: u32 = 0x00; // read, DLAB = 0
+pub const UART_DLL: u32 = 0x00; // DLAB = 1
+pub const UART_IER: u32 = 0x01; // DLAB = 0
+pub const UART_DLM: u32 = 0x01; // DLAB = 1
+pub const UART_IIR: u32 = 0x02; // read
+pub const UART_FCR: u32 = 0x02; // write
+pub const UART_LCR: u32 = 0x03;
+pub const UART_MCR: u32 = 0x04;
+pub const UART_LSR: u32 = 0x05;
+pub const UART_MSR: u32 = 0x06;
+pub const UART_SCR: u32 = 0x07;
+
+const FIFO_SIZE: usize = 16;
+const RX_TIMEOUT: u32 = 4096; // ticks without RX activity before a character timeout, roughly 4 character times
+
+const IER_RDA: u8 = 1 << 0; // received data available
+const IER_THRE: u8 = 1 << 1; // transmitter holding register empty
+const IER_RLS: u8 = 1 << 2; // receiver line status
+const IER_MS: u8 = 1 << 3; // modem status
+
+const IIR_NONE: u8 = 0x01;
+const IIR_MS: u8 = 0x00;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_RLS: u8 = 0x06;
+const IIR_TIMEOUT: u8 = 0x0C;
+const IIR_FIFO: u8 = 0xC0; // both FIFOs enabled
 
-const THRE_TEMT: u8 = (1 << 6) | (1 << 5);
-const DR: u8 = 1 << 0;
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
 
-pub fn match_addr(address: u32) -> bool {
-   return address >= (UART_BASE as u32) && address <= (UART_END as u32);
+const LCR_DLAB: u8 = 1 << 7;
+
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT1: u8 = 1 << 2;
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOP: u8 = 1 << 4;
+
+pub const LSR_DR: u8 = 1 << 0;
+const LSR_OE: u8 = 1 << 1;
+const LSR_ERRORS: u8 = 0x1E; // OE, PE, FE and BI
+pub const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+const MSR_CTS: u8 = 1 << 4;
+const MSR_DSR: u8 = 1 << 5;
+const MSR_RI: u8 = 1 << 6;
+const MSR_DCD: u8 = 1 << 7;
+const MSR_DELTAS: u8 = 0x0F;
+
+/// 16550A with 16-byte FIFOs, wired to a host console: the terminal, a file or nothing.
+pub struct UART {
+    console: io::Console,
+    rx: VecDeque<u8>,
+    tx: VecDeque<u8>,
+    ier: u8,
+    fcr: u8, // only the enable bit and the trigger level are kept
+    lcr: u8,
+    mcr: u8,
+    lsr: u8, // error bits only, DR/THRE/TEMT are derived from the FIFOs
+    msr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    thre_pending: bool, // THRE interrupt, cleared by reading IIR or writing THR
+    idle: u32, // ticks since the last RX FIFO activity
+    error: Option<std::io::Error>, // first failed write to the console, not yet reported
 }
 
 impl UART {
-    pub fn new() -> UART {
-        return UART {
-        /*rxtx: 0,
-            lsr: 0,
-            fifo: VecDeque::with_capacity(0),*/ // disable FIFO
-            kbd: io::KbdIn::new()
+    pub fn new(backend: &io::SerialBackend) -> Result<UART, halt::EmuError> {
+        let console: io::Console = match io::Console::open(backend) {
+            Ok(console) => console,
+            Err(e) => return Err(halt::EmuError::Io(format!("serial backend {}", backend), e)),
         };
+        return Ok(UART {
+            console: console,
+            rx: VecDeque::with_capacity(FIFO_SIZE),
+            tx: VecDeque::with_capacity(FIFO_SIZE),
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            lsr: 0,
+            msr: 0,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+            thre_pending: false,
+            idle: 0,
+            error: None,
+        });
+    }
+    fn fifo_enabled(&self) -> bool {
+        return self.fcr & FCR_ENABLE != 0;
+    }
+    fn capacity(&self) -> usize {
+        return if self.fifo_enabled() { FIFO_SIZE } else { 1 };
+    }
+    fn rx_trigger(&self) -> usize {
+        if !self.fifo_enabled() {
+            return 1;
+        }
+        match self.fcr >> 6 {
+            0 => return 1,
+            1 => return 4,
+            2 => return 8,
+            _ => return 14,
+        }
+    }
+    fn line_status(&self) -> u8 {
+        let mut lsr: u8 = self.lsr;
+        if !self.rx.is_empty() {
+            lsr |= LSR_DR;
+        }
+        if self.tx.is_empty() {
+            lsr |= LSR_THRE | LSR_TEMT; // bytes go out in one tick, so the shift register empties with the FIFO
+        }
+        return lsr;
+    }
+    /// Queues a byte coming in on the RX line, flagging an overrun if there's no room for it.
+    fn receive(&mut self, byte: u8) {
+        if self.rx.len() >= self.capacity() {
+            self.lsr |= LSR_OE;
+            return;
+        }
+        self.rx.push_back(byte);
+        self.idle = 0;
     }
-    pub fn reset(&mut self) {
-        /*self.rxtx = 0;
-        self.lsr = THRE_TEMT; // transmitter holding register empty, data holding register empty
-        self.fifo.reserve(16); // disable FIFO*/
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & MCR_LOOP != 0 { // loopback, TX is wired straight into RX
+            self.receive(byte);
+        } else if let Err(e) = self.console.write_byte(byte) {
+            self.error.get_or_insert(e);
+        }
+    }
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_RLS != 0 && self.lsr & LSR_ERRORS != 0 {
+            return IIR_RLS;
+        }
+        if self.ier & IER_RDA != 0 && !self.rx.is_empty() {
+            if self.rx.len() >= self.rx_trigger() {
+                return IIR_RDA;
+            }
+            if self.idle >= RX_TIMEOUT {
+                return IIR_TIMEOUT;
+            }
+        }
+        if self.ier & IER_THRE != 0 && self.thre_pending {
+            return IIR_THRE;
+        }
+        if self.ier & IER_MS != 0 && self.msr & MSR_DELTAS != 0 {
+            return IIR_MS;
+        }
+        return IIR_NONE;
     }
-    pub fn read(&mut self, address: u32) -> Option<u8> {
-        let data: Option<u8> = self.kbd.try_read_byte();
-        match address {
+    fn set_mcr(&mut self, data: u8) {
+        let old: u8 = self.modem_inputs();
+        self.mcr = data & 0x1F;
+        let new: u8 = self.modem_inputs();
+        let mut deltas: u8 = ((old ^ new) >> 4) & 0xB; // DCTS, DDSR and DDCD flag any change
+        if old & MSR_RI != 0 && new & MSR_RI == 0 {
+            deltas |= 1 << 2; // TERI only flags the trailing edge of RI
+        }
+        self.msr = new | (self.msr & MSR_DELTAS) | deltas;
+    }
+    /// Upper half of MSR: the modem control outputs in loopback, otherwise a host that's always ready.
+    fn modem_inputs(&self) -> u8 {
+        if self.mcr & MCR_LOOP == 0 {
+            return MSR_CTS | MSR_DSR | MSR_DCD;
+        }
+        let mut msr: u8 = 0;
+        if self.mcr & MCR_RTS != 0 {
+            msr |= MSR_CTS;
+        }
+        if self.mcr & MCR_DTR != 0 {
+            msr |= MSR_DSR;
+        }
+        if self.mcr & MCR_OUT1 != 0 {
+            msr |= MSR_RI;
+        }
+        if self.mcr & MCR_OUT2 != 0 {
+            msr |= MSR_DCD;
+        }
+        return msr;
+    }
+    fn read_register(&mut self, offset: u32) -> u8 {
+        let dlab: bool = self.lcr & LCR_DLAB != 0;
+        match offset {
+            UART_DLL if dlab => return self.dll,
             UART_RBR => {
-                return data;
-                /*if self.lsr & DR != 0 {
-                    let data: u8 = self.rxtx;
-                    if self.fifo.len() > 0 {
-                        self.rxtx = self.fifo.pop_front().unwrap();
-                    } else {
-                        self.lsr &= !DR; // clear data ready bit
-                        self.lsr |= THRE_TEMT; // set transmitter holding register empty
-                    }
-                    //self.lsr &= !DR; // clear data ready bit
-                    //self.lsr |= THRE_TEMT; // set transmitter holding register empty
-                    return Some(data);
+                self.idle = 0;
+                return self.rx.pop_front().unwrap_or(0);
+            },
+            UART_DLM if dlab => return self.dlm,
+            UART_IER => return self.ier,
+            UART_IIR => {
+                let id: u8 = self.interrupt_id();
+                if id == IIR_THRE {
+                    self.thre_pending = false; // reading IIR acknowledges a THRE interrupt
                 }
-
-                return None; // no data ready*/
-
-
+                return id | if self.fifo_enabled() { IIR_FIFO } else { 0 };
             },
+            UART_LCR => return self.lcr,
+            UART_MCR => return self.mcr,
             UART_LSR => {
-                return Some(THRE_TEMT | if let Some(_) = data {
-                    DR
-                } else {
-                    0
-                });
+                let lsr: u8 = self.line_status();
+                self.lsr &= !LSR_ERRORS; // error bits clear on read
+                return lsr;
             },
-            _ => return None,
+            UART_MSR => {
+                let msr: u8 = self.msr;
+                self.msr &= !MSR_DELTAS; // delta bits clear on read
+                return msr;
+            },
+            UART_SCR => return self.scr,
+            _ => return 0,
         }
     }
-    pub fn write(&mut self, address: u32, data: u8) {
-        match address {
+    fn write_register(&mut self, offset: u32, data: u8) {
+        let dlab: bool = self.lcr & LCR_DLAB != 0;
+        if offset != UART_THR || dlab { // every transmitted byte would drown the rest
+            log::logln!(Uart, Debug, "[uart] register {} <- 0x{:02X}{}", offset, data, if dlab { " (DLAB)" } else { "" });
+        }
+        match offset {
+            UART_DLL if dlab => self.dll = data,
             UART_THR => {
-                /*if self.lsr & THRE_TEMT != 0  {
-                    self.rxtx = data;
-                    self.lsr &= !THRE_TEMT; // clear transmitter holding register empty bit
-                } else {
-                    self.fifo.push_back(data);
-                } // disable FIFO
-                self.lsr |= DR; // set data ready bit*/
-                print!("{}", data as char);
-                std::io::stdout().flush().unwrap();
+                if self.tx.len() < self.capacity() { // a full FIFO drops the byte, like the real chip
+                    self.tx.push_back(data);
+                }
+                self.thre_pending = false;
+            },
+            UART_DLM if dlab => self.dlm = data,
+            UART_IER => {
+                let enabling_thre: bool = self.ier & IER_THRE == 0 && data & IER_THRE != 0;
+                self.ier = data & 0x0F;
+                if enabling_thre && self.tx.is_empty() {
+                    self.thre_pending = true; // the 16550 raises THRE right away if it's enabled while empty
+                }
             },
+            UART_FCR => {
+                if (data ^ self.fcr) & FCR_ENABLE != 0 { // toggling the FIFOs flushes both of them
+                    self.rx.clear();
+                    self.tx.clear();
+                }
+                if data & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                }
+                if data & FCR_CLEAR_TX != 0 {
+                    self.tx.clear();
+                }
+                self.fcr = data & 0xC1;
+            },
+            UART_LCR => self.lcr = data,
+            UART_MCR => self.set_mcr(data),
+            UART_LSR => {}, // read-only, writing it only works in factory test mode
+            UART_MSR => {},
+            UART_SCR => self.scr = data,
             _ => {},
         }
     }
 }
+
+impl Device for UART {
+    fn read(&mut self, offset: u32, size: u32) -> Option<u64> {
+        if size != 1 { // byte-wide registers only, as the devicetree advertises
+            return None;
+        }
+        return Some(self.read_register(offset) as u64);
+    }
+    fn write(&mut self, offset: u32, size: u32, data: u64) -> Option<()> {
+        if size != 1 {
+            return None;
+        }
+        self.write_register(offset, data as u8);
+        return Some(());
+    }
+    fn reset(&mut self) {
+        self.rx.clear();
+        self.tx.clear();
+        self.ier = 0;
+        self.fcr = 0;
+        self.lcr = 0x03; // 8N1
+        self.mcr = 0;
+        self.lsr = 0;
+        self.msr = MSR_CTS | MSR_DSR | MSR_DCD; // the host side is always ready
+        self.scr = 0;
+        self.dll = 0x01; // 115200 baud with the usual 1.8432 MHz clock
+        self.dlm = 0;
+        self.thre_pending = false;
+        self.idle = 0;
+    }
+    /// Sends a byte from the TX FIFO per tick and picks up host input.
+    fn tick(&mut self, ticks: u32) {
+        for _ in 0..ticks {
+            match self.tx.pop_front() {
+                Some(byte) => self.transmit(byte),
+                None => break,
+            }
+            if self.tx.is_empty() {
+                self.thre_pending = true;
+            }
+        }
+        self.idle = self.idle.saturating_add(ticks);
+        if self.mcr & MCR_LOOP == 0 && self.rx.len() < self.capacity() { // keystrokes wait in the channel while the FIFO is full
+            if let Some(byte) = self.console.try_read_byte() {
+                self.receive(byte);
+            }
+        }
+    }
+    fn irq(&self) -> bool {
+        return self.interrupt_id() != IIR_NONE;
+    }
+    fn take_error(&mut self) -> Option<std::io::Error> {
+        return self.error.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uart() -> UART {
+        let mut uart: UART = UART::new(&io::SerialBackend::Null).unwrap();
+        uart.reset();
+        return uart;
+    }
+
+    fn read(uart: &mut UART, offset: u32) -> u8 {
+        return uart.read(offset, 1).unwrap() as u8;
+    }
+
+    fn write(uart: &mut UART, offset: u32, data: u8) {
+        uart.write(offset, 1, data as u64).unwrap();
+    }
+
+    #[test]
+    fn rx_fifo() {
+        let mut uart: UART = uart();
+        write(&mut uart, UART_FCR, FCR_ENABLE);
+        for byte in 0..FIFO_SIZE as u8 {
+            uart.receive(byte);
+        }
+        assert_eq!(read(&mut uart, UART_LSR) & (LSR_DR | LSR_OE), LSR_DR, "16 bytes fit");
+        uart.receive(0xFF);
+        assert_eq!(read(&mut uart, UART_LSR) & LSR_OE, LSR_OE, "the 17th overruns");
+        assert_eq!(read(&mut uart, UART_LSR) & LSR_OE, 0, "and reading LSR clears it");
+        for byte in 0..FIFO_SIZE as u8 {
+            assert_eq!(read(&mut uart, UART_RBR), byte);
+        }
+        assert_eq!(read(&mut uart, UART_LSR) & LSR_DR, 0, "the overrunning byte was lost");
+
+        write(&mut uart, UART_FCR, 0);
+        uart.receive(1);
+        uart.receive(2);
+        assert_eq!(read(&mut uart, UART_LSR) & LSR_OE, LSR_OE, "a single holding register without the FIFO");
+        assert_eq!((read(&mut uart, UART_RBR), read(&mut uart, UART_LSR) & LSR_DR), (1, 0));
+        assert_eq!(uart.read(UART_RBR, 4), None, "byte-wide only");
+    }
+
+    #[test]
+    fn trigger_level() {
+        let mut uart: UART = uart();
+        write(&mut uart, UART_FCR, FCR_ENABLE | 2 << 6); // trigger at 8 bytes
+        write(&mut uart, UART_IER, IER_RDA);
+        for byte in 0..7 {
+            uart.receive(byte);
+        }
+        assert_eq!(read(&mut uart, UART_IIR), IIR_FIFO | IIR_NONE, "below the trigger level");
+        uart.receive(7);
+        assert_eq!(read(&mut uart, UART_IIR), IIR_FIFO | IIR_RDA);
+        assert!(uart.irq());
+    }
+
+    #[test]
+    fn character_timeout() {
+        let mut uart: UART = uart();
+        write(&mut uart, UART_FCR, FCR_ENABLE | 2 << 6);
+        write(&mut uart, UART_IER, IER_RDA);
+        uart.receive(b'x');
+        uart.tick(RX_TIMEOUT - 1);
+        assert!(!uart.irq(), "not timed out yet");
+        uart.tick(1);
+        assert_eq!(read(&mut uart, UART_IIR), IIR_FIFO | IIR_TIMEOUT, "a character timeout for the byte below the trigger level");
+        uart.receive(b'y');
+        assert!(!uart.irq(), "new data restarts the timeout");
+        uart.tick(RX_TIMEOUT);
+        assert_eq!(read(&mut uart, UART_RBR), b'x');
+        assert!(!uart.irq(), "and so does reading");
+    }
+
+    #[test]
+    fn thre() {
+        let mut uart: UART = uart();
+        assert_eq!(read(&mut uart, UART_LSR) & (LSR_THRE | LSR_TEMT), LSR_THRE | LSR_TEMT);
+        write(&mut uart, UART_IER, IER_THRE);
+        assert_eq!(read(&mut uart, UART_IIR), IIR_THRE, "raised as soon as it's enabled while empty");
+        assert_eq!(read(&mut uart, UART_IIR), IIR_NONE, "reading IIR acknowledges it");
+        write(&mut uart, UART_THR, b'a');
+        assert_eq!(read(&mut uart, UART_LSR) & LSR_THRE, 0);
+        uart.tick(1);
+        assert_eq!((read(&mut uart, UART_LSR) & LSR_THRE, read(&mut uart, UART_IIR)), (LSR_THRE, IIR_THRE), "raised again once it's sent");
+        write(&mut uart, UART_THR, b'b');
+        assert_eq!(read(&mut uart, UART_IIR), IIR_NONE, "writing THR clears it too");
+    }
+
+    #[test]
+    fn interrupt_priority() {
+        let mut uart: UART = uart();
+        write(&mut uart, UART_FCR, FCR_ENABLE);
+        write(&mut uart, UART_IER, IER_RDA | IER_THRE | IER_RLS | IER_MS); // THRE pending, the FIFO is empty
+        write(&mut uart, UART_MCR, MCR_LOOP | MCR_RTS); // DSR and DCD drop, a modem status change
+        for byte in 0..=FIFO_SIZE as u8 {
+            uart.receive(byte); // the last one overruns
+        }
+        assert_eq!(read(&mut uart, UART_IIR), IIR_FIFO | IIR_RLS, "line status first");
+        read(&mut uart, UART_LSR);
+        assert_eq!(read(&mut uart, UART_IIR), IIR_FIFO | IIR_RDA, "then received data");
+        write(&mut uart, UART_FCR, FCR_ENABLE | FCR_CLEAR_RX);
+        assert_eq!(read(&mut uart, UART_IIR), IIR_FIFO | IIR_THRE, "then THRE, acknowledged by this read");
+        assert_eq!(read(&mut uart, UART_IIR), IIR_FIFO | IIR_MS, "then modem status");
+        assert_eq!(read(&mut uart, UART_MSR) & MSR_DELTAS, 0b1010, "DDSR and DDCD");
+        assert_eq!(read(&mut uart, UART_IIR), IIR_FIFO | IIR_NONE);
+    }
+
+    #[test]
+    fn loopback() {
+        let mut uart: UART = uart();
+        write(&mut uart, UART_MCR, MCR_LOOP | MCR_DTR | MCR_RTS | MCR_OUT1 | MCR_OUT2);
+        assert_eq!(read(&mut uart, UART_MSR) & 0xF0, MSR_CTS | MSR_DSR | MSR_RI | MSR_DCD, "outputs looped back to the inputs");
+        write(&mut uart, UART_THR, 0x55);
+        assert_eq!(read(&mut uart, UART_LSR) & LSR_DR, 0);
+        uart.tick(1);
+        assert_eq!((read(&mut uart, UART_LSR) & LSR_DR, read(&mut uart, UART_RBR)), (LSR_DR, 0x55), "echoed into RX");
+        write(&mut uart, UART_MCR, MCR_LOOP);
+        assert_eq!(read(&mut uart, UART_MSR), MSR_DELTAS, "every input drops, and RI on its trailing edge sets TERI");
+        write(&mut uart, UART_MCR, 0);
+        assert_eq!(read(&mut uart, UART_MSR) & 0xF0, MSR_CTS | MSR_DSR | MSR_DCD, "out of loopback the host is always ready");
+    }
+
+    #[test]
+    fn divisor_latch() {
+        let mut uart: UART = uart();
+        assert_eq!((read(&mut uart, UART_LCR), read(&mut uart, UART_IER)), (0x03, 0));
+        write(&mut uart, UART_LCR, LCR_DLAB | 0x03);
+        assert_eq!((read(&mut uart, UART_DLL), read(&mut uart, UART_DLM)), (0x01, 0x00), "115200 baud after reset");
+        write(&mut uart, UART_DLL, 0x0C);
+        write(&mut uart, UART_DLM, 0x0F);
+        assert_eq!((read(&mut uart, UART_DLL), read(&mut uart, UART_DLM)), (0x0C, 0x0F));
+        write(&mut uart, UART_LCR, 0x03);
+        assert_eq!(read(&mut uart, UART_IER), 0, "IER untouched by the DLM write");
+        assert_eq!(read(&mut uart, UART_LSR) & LSR_THRE, LSR_THRE, "nothing queued by the DLL write");
+        write(&mut uart, UART_IER, IER_RDA);
+        write(&mut uart, UART_LCR, LCR_DLAB | 0x03);
+        assert_eq!((read(&mut uart, UART_DLL), read(&mut uart, UART_DLM)), (0x0C, 0x0F), "nor the divisor by the IER write");
+    }
+
+    #[test]
+    fn fifo_resets() {
+        let mut uart: UART = uart();
+        write(&mut uart, UART_FCR, FCR_ENABLE);
+        uart.receive(1);
+        write(&mut uart, UART_THR, 2);
+        write(&mut uart, UART_FCR, FCR_ENABLE | FCR_CLEAR_RX);
+        assert_eq!(read(&mut uart, UART_LSR) & (LSR_DR | LSR_THRE), 0, "RX cleared, TX still holds a byte");
+        write(&mut uart, UART_FCR, FCR_ENABLE | FCR_CLEAR_TX);
+        assert_eq!(read(&mut uart, UART_LSR) & LSR_THRE, LSR_THRE);
+        uart.receive(1);
+        write(&mut uart, UART_THR, 2);
+        write(&mut uart, UART_FCR, 0);
+        assert_eq!(read(&mut uart, UART_LSR) & (LSR_DR | LSR_THRE), LSR_THRE, "turning the FIFOs off flushes both");
+        assert_eq!(read(&mut uart, UART_IIR) & IIR_FIFO, 0);
+    }
+}